@@ -0,0 +1,80 @@
+use codecrafters_redis::store::{
+    coords::{decode, haversine, GeoUnit, Point},
+    core::InMemoryStore,
+    geo::{GeoAddOptions, GeoOrigin, GeoSearchQuery, GeoShape},
+    sorted_set::get_sorted_set,
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::Rng;
+use tokio::runtime::Runtime;
+
+const KEY: &str = "points";
+
+fn populate(rt: &Runtime, size: usize) -> InMemoryStore {
+    let store = InMemoryStore::default();
+    let mut rng = rand::rng();
+    rt.block_on(async {
+        for i in 0..size {
+            let point = Point {
+                lat: rng.random_range(-85.0..85.0),
+                lon: rng.random_range(-180.0..180.0),
+            };
+            store
+                .geoadd(
+                    KEY.into(),
+                    vec![(point, i.to_string())],
+                    GeoAddOptions::default(),
+                )
+                .await
+                .unwrap();
+        }
+    });
+    store
+}
+
+/// The pre-index implementation: decode and measure every member of the key.
+async fn full_scan(store: &InMemoryStore, center: &Point, radius_m: f64) -> Vec<String> {
+    let data = store.data.lock().await;
+    let Some(set) = get_sorted_set(&data, KEY) else {
+        return vec![];
+    };
+    set.set
+        .iter()
+        .filter(|(_, score)| haversine(center, &decode(**score)) <= radius_m)
+        .map(|(member, _)| member.clone())
+        .collect()
+}
+
+fn bench_geosearch(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let center = Point {
+        lat: 48.8566,
+        lon: 2.3522,
+    };
+    let query = GeoSearchQuery {
+        origin: GeoOrigin::LonLat(center.clone()),
+        shape: GeoShape::Radius(100.0),
+        unit: GeoUnit::Kilometers,
+        sort: None,
+        count: None,
+        with_coord: false,
+        with_dist: false,
+        with_hash: false,
+    };
+
+    let mut group = c.benchmark_group("geosearch_100km");
+    group.sample_size(10);
+    for size in [10_000, 100_000, 1_000_000] {
+        let store = populate(&rt, size);
+        group.bench_with_input(BenchmarkId::new("indexed", size), &store, |b, store| {
+            b.iter(|| rt.block_on(store.geosearch(KEY.into(), &query)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("full_scan", size), &store, |b, store| {
+            b.iter(|| rt.block_on(full_scan(store, &center, 100_000.0)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_geosearch);
+criterion_main!(benches);
@@ -0,0 +1,122 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::server::config::get_config_value;
+
+/// The `client-output-buffer-limit` for pub/sub clients: a subscriber is disconnected once
+/// its queued messages exceed `hard` bytes, or stay over `soft` bytes for `soft_seconds`.
+/// A limit of 0 is disabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputBufferLimit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+impl Default for OutputBufferLimit {
+    fn default() -> Self {
+        Self {
+            hard: 32 * 1024 * 1024,
+            soft: 8 * 1024 * 1024,
+            soft_seconds: 60,
+        }
+    }
+}
+
+impl OutputBufferLimit {
+    /// The pubsub limit from `--client-output-buffer-limit`, given as `<class> <hard> <soft>
+    /// <seconds>` groups like `"pubsub 32mb 8mb 60"`. Other classes are ignored.
+    pub fn from_config() -> Self {
+        get_config_value("client-output-buffer-limit")
+            .and_then(|value| Self::parse(&value))
+            .unwrap_or_default()
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let parts = value.split_whitespace().collect::<Vec<_>>();
+        let mut limit = None;
+        for group in parts.chunks(4) {
+            let [class, hard, soft, seconds] = group else {
+                return None;
+            };
+            let parsed = Self {
+                hard: parse_memory(hard)?,
+                soft: parse_memory(soft)?,
+                soft_seconds: seconds.parse().ok()?,
+            };
+            if class.eq_ignore_ascii_case("pubsub") {
+                limit = Some(parsed);
+            }
+        }
+        limit
+    }
+
+    fn is_exceeded(&self, buffer: &OutputBuffer, queued: usize) -> bool {
+        if self.hard > 0 && queued > self.hard {
+            return true;
+        }
+        let mut since = buffer.over_soft_limit_since.lock().unwrap();
+        if self.soft == 0 || queued <= self.soft {
+            *since = None;
+            return false;
+        }
+        since.get_or_insert_with(Instant::now).elapsed() >= Duration::from_secs(self.soft_seconds)
+    }
+}
+
+/// Parses a size like `32mb`, `512k` or `1024`.
+fn parse_memory(value: &str) -> Option<usize> {
+    let value = value.to_lowercase();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+/// The messages queued for a subscriber but not yet taken by its connection. Shared by the
+/// manager, which fills it, and the subscriber's connection, which drains it.
+#[derive(Default)]
+pub struct OutputBuffer {
+    bytes: AtomicUsize,
+    /// When the buffer went over the soft limit, while it still is.
+    over_soft_limit_since: Mutex<Option<Instant>>,
+    /// Set once the subscriber went over its limit and is to be disconnected.
+    disconnected: AtomicBool,
+}
+
+impl OutputBuffer {
+    /// Accounts for a message about to be queued. Returns false, marking the subscriber
+    /// disconnected, if that takes the buffer over `limit`.
+    pub(super) fn reserve(&self, size: usize, limit: &OutputBufferLimit) -> bool {
+        let queued = self.bytes.fetch_add(size, Ordering::Relaxed) + size;
+        if limit.is_exceeded(self, queued) {
+            self.disconnected.store(true, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    pub(super) fn release(&self, size: usize) {
+        self.bytes.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+}
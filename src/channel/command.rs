@@ -1,9 +1,18 @@
 use crate::{common::parse_string_args, protocol::Data};
 
 pub enum ChannelCommand {
-    Subscribe(String),
+    Subscribe(Vec<String>),
+    /// Channels to unsubscribe from, or every channel when empty.
     Unsubscribe(Vec<String>),
+    PSubscribe(Vec<String>),
+    /// Patterns to unsubscribe from, or every pattern when empty.
+    PUnsubscribe(Vec<String>),
+    SSubscribe(Vec<String>),
+    /// Shard channels to unsubscribe from, or every shard channel when empty.
+    SUnsubscribe(Vec<String>),
     Ping,
+    Quit,
+    Reset,
     Invalid(String),
 }
 
@@ -22,9 +31,15 @@ impl From<&[Data]> for ChannelCommand {
             return Self::Invalid("No command found".to_string());
         };
         match (command.to_uppercase().as_str(), &val[1..]) {
-            ("SUBSCRIBE", [Data::BStr(channel)]) => Self::Subscribe(channel.into()),
+            ("SUBSCRIBE", [_, ..]) => Self::Subscribe(parse_string_args(&val[1..])),
             ("UNSUBSCRIBE", ..) => Self::Unsubscribe(parse_string_args(&val[1..])),
+            ("PSUBSCRIBE", [_, ..]) => Self::PSubscribe(parse_string_args(&val[1..])),
+            ("PUNSUBSCRIBE", ..) => Self::PUnsubscribe(parse_string_args(&val[1..])),
+            ("SSUBSCRIBE", [_, ..]) => Self::SSubscribe(parse_string_args(&val[1..])),
+            ("SUNSUBSCRIBE", ..) => Self::SUnsubscribe(parse_string_args(&val[1..])),
             ("PING", []) => Self::Ping,
+            ("QUIT", []) => Self::Quit,
+            ("RESET", []) => Self::Reset,
             (val, ..) => Self::Invalid(val.into()),
         }
     }
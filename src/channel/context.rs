@@ -1,74 +1,186 @@
 use anyhow::Result;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use uuid::Uuid;
 
 use crate::{
     command::response::{encode_array_of_bstrings, encode_resp_array},
-    common::{encode_bstring, encode_error, encode_int},
+    common::{encode_bstring, encode_error, encode_int, encode_sstring, null},
 };
 
-use super::{ChannelCommand, ChannelManager};
+use super::{buffer::OutputBuffer, ChannelCommand, ChannelManager};
 
 pub struct SubscriptionContext {
-    rx: mpsc::Receiver<String>,
+    rx: mpsc::UnboundedReceiver<String>,
+    buffer: Arc<OutputBuffer>,
     subscription_id: Uuid,
     manager: ChannelManager,
 }
 
 impl SubscriptionContext {
     pub async fn new(manager: ChannelManager) -> Self {
-        let (tx, rx) = mpsc::channel(100);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (subscription_id, buffer) = manager.init(tx).await;
         Self {
-            subscription_id: manager.init(tx).await,
+            subscription_id,
+            buffer,
             manager,
             rx,
         }
     }
 
+    /// The next published message, or `None` once the subscriber was disconnected for
+    /// going over its output buffer limit.
     pub async fn receive_publish(&mut self) -> Option<String> {
-        self.rx.recv().await
+        let message = self.rx.recv().await?;
+        if self.buffer.is_disconnected() {
+            return None;
+        }
+        self.buffer.release(message.len());
+        Some(message)
+    }
+
+    /// Whether any channel or pattern subscription is left. The connection leaves subscribe
+    /// mode once there are none.
+    pub async fn is_subscribed(&self) -> bool {
+        self.manager.count(self.subscription_id).await > 0
+            || self.manager.shard_count(self.subscription_id).await > 0
+    }
+
+    /// Drops every subscription.
+    pub async fn quit(&self) {
+        self.manager.quit(self.subscription_id).await;
     }
 
     pub async fn process_command(&self, command: ChannelCommand) -> Result<String> {
         match command {
-            ChannelCommand::Subscribe(channel) => self.subscribe(channel).await,
+            ChannelCommand::Subscribe(channels) => self.subscribe(channels).await,
             ChannelCommand::Unsubscribe(channels) => self.unsubscribe(channels).await,
+            ChannelCommand::PSubscribe(patterns) => self.psubscribe(patterns).await,
+            ChannelCommand::PUnsubscribe(patterns) => self.punsubscribe(patterns).await,
+            ChannelCommand::SSubscribe(channels) => self.ssubscribe(channels).await,
+            ChannelCommand::SUnsubscribe(channels) => self.sunsubscribe(channels).await,
             ChannelCommand::Ping => Ok(encode_array_of_bstrings(&["pong".into(), "".to_string()])),
+            ChannelCommand::Quit => {
+                self.quit().await;
+                Ok(encode_sstring("OK"))
+            }
+            ChannelCommand::Reset => {
+                self.manager.reset(self.subscription_id).await;
+                Ok(encode_sstring("RESET"))
+            }
             ChannelCommand::Invalid(command) => {
                 Ok(encode_error(format!("Can't execute '{command}'").as_str()))
             }
         }
     }
 
-    async fn unsubscribe(&self, channels: Vec<String>) -> Result<String> {
-        let len = self
-            .manager
-            .unsubscribe(self.subscription_id, channels.clone())
-            .await?;
-
-        Ok(encode_resp_array(
-            vec![
-                encode_bstring("unsubscribe"),
-                encode_bstring(channels.first().unwrap()),
-                encode_int(len as i64),
-            ]
-            .as_slice(),
-        ))
+    /// Subscribes to each channel, with one reply per channel.
+    async fn subscribe(&self, channels: Vec<String>) -> Result<String> {
+        let mut replies = String::new();
+        for channel in channels {
+            let count = self
+                .manager
+                .subscribe(self.subscription_id, channel.clone())
+                .await?;
+            replies.push_str(&subscription_reply("subscribe", &channel, count));
+        }
+        Ok(replies)
+    }
+
+    async fn unsubscribe(&self, mut channels: Vec<String>) -> Result<String> {
+        if channels.is_empty() {
+            channels = self.manager.channels(self.subscription_id).await;
+        }
+        if channels.is_empty() {
+            let count = self.manager.count(self.subscription_id).await;
+            return Ok(empty_unsubscribe_reply("unsubscribe", count));
+        }
+        let mut replies = String::new();
+        for channel in channels {
+            let count = self
+                .manager
+                .unsubscribe(self.subscription_id, &channel)
+                .await?;
+            replies.push_str(&subscription_reply("unsubscribe", &channel, count));
+        }
+        Ok(replies)
     }
 
-    async fn subscribe(&self, channel: String) -> Result<String> {
-        let count = self
-            .manager
-            .subscribe(self.subscription_id, channel.clone())
-            .await?;
-
-        Ok(encode_resp_array(
-            vec![
-                encode_bstring("subscribe"),
-                encode_bstring(&channel),
-                encode_int(count as i64),
-            ]
-            .as_slice(),
-        ))
+    /// Subscribes to each pattern, with one reply per pattern.
+    async fn psubscribe(&self, patterns: Vec<String>) -> Result<String> {
+        let mut replies = String::new();
+        for pattern in patterns {
+            let count = self
+                .manager
+                .psubscribe(self.subscription_id, pattern.clone())
+                .await?;
+            replies.push_str(&subscription_reply("psubscribe", &pattern, count));
+        }
+        Ok(replies)
     }
+
+    async fn punsubscribe(&self, mut patterns: Vec<String>) -> Result<String> {
+        if patterns.is_empty() {
+            patterns = self.manager.patterns(self.subscription_id).await;
+        }
+        if patterns.is_empty() {
+            let count = self.manager.count(self.subscription_id).await;
+            return Ok(empty_unsubscribe_reply("punsubscribe", count));
+        }
+        let mut replies = String::new();
+        for pattern in patterns {
+            let count = self
+                .manager
+                .punsubscribe(self.subscription_id, &pattern)
+                .await?;
+            replies.push_str(&subscription_reply("punsubscribe", &pattern, count));
+        }
+        Ok(replies)
+    }
+
+    /// Subscribes to each shard channel, with one reply per channel.
+    async fn ssubscribe(&self, channels: Vec<String>) -> Result<String> {
+        let mut replies = String::new();
+        for channel in channels {
+            let count = self
+                .manager
+                .ssubscribe(self.subscription_id, channel.clone())
+                .await?;
+            replies.push_str(&subscription_reply("ssubscribe", &channel, count));
+        }
+        Ok(replies)
+    }
+
+    async fn sunsubscribe(&self, mut channels: Vec<String>) -> Result<String> {
+        if channels.is_empty() {
+            channels = self.manager.shard_channels(self.subscription_id).await;
+        }
+        if channels.is_empty() {
+            let count = self.manager.shard_count(self.subscription_id).await;
+            return Ok(empty_unsubscribe_reply("sunsubscribe", count));
+        }
+        let mut replies = String::new();
+        for channel in channels {
+            let count = self
+                .manager
+                .sunsubscribe(self.subscription_id, &channel)
+                .await?;
+            replies.push_str(&subscription_reply("sunsubscribe", &channel, count));
+        }
+        Ok(replies)
+    }
+}
+
+/// The reply to an unsubscribe without arguments when there is nothing to unsubscribe from.
+fn empty_unsubscribe_reply(kind: &str, count: usize) -> String {
+    encode_resp_array(&[encode_bstring(kind), null(), encode_int(count as i64)])
+}
+
+fn subscription_reply(kind: &str, name: &str, count: usize) -> String {
+    encode_resp_array(&[
+        encode_bstring(kind),
+        encode_bstring(name),
+        encode_int(count as i64),
+    ])
 }
@@ -1,71 +1,425 @@
 use anyhow::{bail, Result};
-use futures::future::join_all;
 use hashbrown::{HashMap, HashSet};
-use std::sync::Arc;
-use tokio::sync::{mpsc::Sender, Mutex};
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::{mpsc::UnboundedSender, Mutex};
 use uuid::Uuid;
 
-use crate::command::response::encode_array_of_bstrings;
+use crate::{
+    command::response::{encode_array_of_bstrings, encode_resp_array},
+    common::{encode_bstring, encode_int, glob_match, key_slot},
+};
 
-type Subscription = (Sender<String>, HashSet<String>);
+use super::buffer::{OutputBuffer, OutputBufferLimit};
 
-#[derive(Clone, Default)]
+/// Shard channel subscribers by key slot, then by channel.
+type ShardIndex = HashMap<u16, HashMap<String, HashSet<Uuid>>>;
+
+#[derive(Clone)]
+pub enum PubSubSubcommand {
+    /// Channels with at least one subscriber, optionally matching a pattern.
+    Channels(Option<String>),
+    NumSub(Vec<String>),
+    NumPat,
+    /// Shard channels with at least one subscriber, optionally matching a pattern.
+    ShardChannels(Option<String>),
+    ShardNumSub(Vec<String>),
+}
+
+struct Subscription {
+    tx: UnboundedSender<String>,
+    buffer: Arc<OutputBuffer>,
+    channels: HashSet<String>,
+    /// Glob patterns subscribed to with PSUBSCRIBE.
+    patterns: HashSet<String>,
+    /// Shard channels subscribed to with SSUBSCRIBE, which are counted separately.
+    shard_channels: HashSet<String>,
+}
+
+impl Subscription {
+    /// Subscriptions of both kinds, which subscribe replies report.
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.tx.is_closed() || self.buffer.is_disconnected()
+    }
+}
+
+/// Routes published messages to subscribers. Messages are queued without waiting on the
+/// subscriber, up to its output buffer limit.
+#[derive(Clone)]
 pub struct ChannelManager {
     subscribers: Arc<Mutex<HashMap<Uuid, Subscription>>>,
+    /// Locked after `subscribers` when both are needed.
+    shards: Arc<Mutex<ShardIndex>>,
+    limit: OutputBufferLimit,
+    /// Subscribers disconnected for going over the output buffer limit.
+    disconnections: Arc<AtomicUsize>,
+}
+
+impl Default for ChannelManager {
+    fn default() -> Self {
+        Self::new(OutputBufferLimit::from_config())
+    }
 }
 
 impl ChannelManager {
-    pub async fn init(&self, tx: Sender<String>) -> Uuid {
+    pub fn new(limit: OutputBufferLimit) -> Self {
+        Self {
+            subscribers: Arc::default(),
+            shards: Arc::default(),
+            limit,
+            disconnections: Arc::default(),
+        }
+    }
+
+    /// Registers a subscriber, returning its ID and the buffer its connection drains.
+    pub async fn init(&self, tx: UnboundedSender<String>) -> (Uuid, Arc<OutputBuffer>) {
         let id = Uuid::new_v4();
-        self.subscribers
-            .lock()
-            .await
-            .insert(id, (tx, HashSet::new()));
-        id
+        let buffer = Arc::new(OutputBuffer::default());
+        let subscription = Subscription {
+            tx,
+            buffer: buffer.clone(),
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            shard_channels: HashSet::new(),
+        };
+        self.subscribers.lock().await.insert(id, subscription);
+        (id, buffer)
     }
 
-    pub async fn unsubscribe(&self, id: Uuid, channels: Vec<String>) -> Result<usize> {
+    /// Removes one channel subscription, returning the remaining count.
+    pub async fn unsubscribe(&self, id: Uuid, channel: &str) -> Result<usize> {
         let mut subscribers = self.subscribers.lock().await;
-        if let Some((_, subscribed_channels)) = subscribers.get_mut(&id) {
-            subscribed_channels.retain(|c| !channels.contains(c));
-            return Ok(subscribed_channels.len());
+        if let Some(subscription) = subscribers.get_mut(&id) {
+            subscription.channels.remove(channel);
+            return Ok(subscription.count());
         }
         bail!("Subscriber with ID {} not found", id);
     }
 
     pub async fn subscribe(&self, id: Uuid, channel: String) -> Result<usize> {
         let mut subscribers = self.subscribers.lock().await;
-        if let Some((_, channels)) = subscribers.get_mut(&id) {
-            channels.insert(channel);
-            return Ok(channels.len());
+        if let Some(subscription) = subscribers.get_mut(&id) {
+            subscription.channels.insert(channel);
+            return Ok(subscription.count());
+        }
+        bail!("Subscriber with ID {} not found", id);
+    }
+
+    pub async fn psubscribe(&self, id: Uuid, pattern: String) -> Result<usize> {
+        let mut subscribers = self.subscribers.lock().await;
+        if let Some(subscription) = subscribers.get_mut(&id) {
+            subscription.patterns.insert(pattern);
+            return Ok(subscription.count());
         }
         bail!("Subscriber with ID {} not found", id);
     }
 
+    /// Removes one pattern subscription, returning the remaining count.
+    pub async fn punsubscribe(&self, id: Uuid, pattern: &str) -> Result<usize> {
+        let mut subscribers = self.subscribers.lock().await;
+        if let Some(subscription) = subscribers.get_mut(&id) {
+            subscription.patterns.remove(pattern);
+            return Ok(subscription.count());
+        }
+        bail!("Subscriber with ID {} not found", id);
+    }
+
+    pub async fn ssubscribe(&self, id: Uuid, channel: String) -> Result<usize> {
+        let mut subscribers = self.subscribers.lock().await;
+        let Some(subscription) = subscribers.get_mut(&id) else {
+            bail!("Subscriber with ID {} not found", id);
+        };
+        self.shards
+            .lock()
+            .await
+            .entry(key_slot(&channel))
+            .or_default()
+            .entry(channel.clone())
+            .or_default()
+            .insert(id);
+        subscription.shard_channels.insert(channel);
+        Ok(subscription.shard_channels.len())
+    }
+
+    /// Removes one shard channel subscription, returning the remaining shard channel count.
+    pub async fn sunsubscribe(&self, id: Uuid, channel: &str) -> Result<usize> {
+        let mut subscribers = self.subscribers.lock().await;
+        let Some(subscription) = subscribers.get_mut(&id) else {
+            bail!("Subscriber with ID {} not found", id);
+        };
+        subscription.shard_channels.remove(channel);
+        remove_shard_subscriber(&mut *self.shards.lock().await, id, channel);
+        Ok(subscription.shard_channels.len())
+    }
+
+    /// The number of channels and patterns a subscriber is subscribed to.
+    pub async fn count(&self, id: Uuid) -> usize {
+        self.subscribers
+            .lock()
+            .await
+            .get(&id)
+            .map_or(0, Subscription::count)
+    }
+
+    /// The number of shard channels a subscriber is subscribed to.
+    pub async fn shard_count(&self, id: Uuid) -> usize {
+        self.subscribers
+            .lock()
+            .await
+            .get(&id)
+            .map_or(0, |subscription| subscription.shard_channels.len())
+    }
+
+    /// The shard channels a subscriber is subscribed to, in no particular order.
+    pub async fn shard_channels(&self, id: Uuid) -> Vec<String> {
+        self.subscribers
+            .lock()
+            .await
+            .get(&id)
+            .map(|subscription| subscription.shard_channels.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The channels a subscriber is subscribed to, in no particular order.
+    pub async fn channels(&self, id: Uuid) -> Vec<String> {
+        self.subscribers
+            .lock()
+            .await
+            .get(&id)
+            .map(|subscription| subscription.channels.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The patterns a subscriber is subscribed to, in no particular order.
+    pub async fn patterns(&self, id: Uuid) -> Vec<String> {
+        self.subscribers
+            .lock()
+            .await
+            .get(&id)
+            .map(|subscription| subscription.patterns.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Drops every subscription, keeping the subscriber.
+    pub async fn reset(&self, id: Uuid) {
+        if let Some(subscription) = self.subscribers.lock().await.get_mut(&id) {
+            subscription.channels.clear();
+            subscription.patterns.clear();
+            let mut shards = self.shards.lock().await;
+            for channel in subscription.shard_channels.drain() {
+                remove_shard_subscriber(&mut shards, id, &channel);
+            }
+        }
+    }
+
     pub async fn quit(&self, id: Uuid) {
+        self.reset(id).await;
         self.subscribers.lock().await.remove(&id);
     }
 
-    pub async fn publish(&self, channel: String, message: String) -> usize {
+    /// Answers PUBSUB introspection. Pattern subscribers aren't counted by NUMSUB.
+    pub async fn pubsub(&self, subcommand: PubSubSubcommand) -> String {
         let mut subscribers = self.subscribers.lock().await;
-        subscribers.retain(|_, (tx, _)| !tx.is_closed());
-
-        let futures = subscribers
-            .iter_mut()
-            .filter_map(|(_, (tx, channels))| {
-                if channels.contains(&channel) {
-                    Some(tx.send(get_message(&channel, &message)))
-                } else {
-                    None
+        self.remove_closed(&mut subscribers).await;
+        let active = subscribers.values();
+        match subcommand {
+            PubSubSubcommand::Channels(pattern) => {
+                let channels = active
+                    .flat_map(|subscription| subscription.channels.iter().cloned())
+                    .filter(|channel| {
+                        pattern
+                            .as_ref()
+                            .is_none_or(|pattern| glob_match(pattern, channel))
+                    })
+                    .collect::<BTreeSet<_>>();
+                encode_array_of_bstrings(&channels.into_iter().collect::<Vec<_>>())
+            }
+            PubSubSubcommand::NumSub(channels) => {
+                let items = channels
+                    .iter()
+                    .flat_map(|channel| {
+                        let count = active
+                            .clone()
+                            .filter(|subscription| subscription.channels.contains(channel))
+                            .count();
+                        [encode_bstring(channel), encode_int(count as i64)]
+                    })
+                    .collect::<Vec<_>>();
+                encode_resp_array(&items)
+            }
+            PubSubSubcommand::NumPat => {
+                let patterns = active
+                    .flat_map(|subscription| subscription.patterns.iter())
+                    .collect::<HashSet<_>>();
+                encode_int(patterns.len() as i64)
+            }
+            PubSubSubcommand::ShardChannels(pattern) => {
+                let shards = self.shards.lock().await;
+                let channels = shards
+                    .values()
+                    .flat_map(|channels| channels.keys().cloned())
+                    .filter(|channel| {
+                        pattern
+                            .as_ref()
+                            .is_none_or(|pattern| glob_match(pattern, channel))
+                    })
+                    .collect::<BTreeSet<_>>();
+                encode_array_of_bstrings(&channels.into_iter().collect::<Vec<_>>())
+            }
+            PubSubSubcommand::ShardNumSub(channels) => {
+                let shards = self.shards.lock().await;
+                let items = channels
+                    .iter()
+                    .flat_map(|channel| {
+                        let count = shard_subscribers(&shards, channel).map_or(0, HashSet::len);
+                        [encode_bstring(channel), encode_int(count as i64)]
+                    })
+                    .collect::<Vec<_>>();
+                encode_resp_array(&items)
+            }
+        }
+    }
+
+    /// Sends `message` as an `smessage` to the shard channel's subscribers, found through
+    /// the channel's key slot. Returns the number of subscribers reached.
+    pub async fn spublish(&self, channel: String, message: String) -> usize {
+        let mut subscribers = self.subscribers.lock().await;
+        self.remove_closed(&mut subscribers).await;
+        let shards = self.shards.lock().await;
+        let Some(ids) = shard_subscribers(&shards, &channel) else {
+            return 0;
+        };
+
+        let smessage = encode_array_of_bstrings(&["smessage".into(), channel, message]);
+        let count = ids
+            .iter()
+            .filter_map(|id| subscribers.get(id))
+            .filter(|subscription| self.deliver(subscription, smessage.clone()))
+            .count();
+        drop(shards);
+        self.remove_closed(&mut subscribers).await;
+        count
+    }
+
+    /// The counters reported in INFO's stats section.
+    pub async fn stats(&self) -> Vec<(&'static str, usize)> {
+        let mut subscribers = self.subscribers.lock().await;
+        self.remove_closed(&mut subscribers).await;
+        let channels = subscribers
+            .values()
+            .flat_map(|subscription| subscription.channels.iter())
+            .collect::<HashSet<_>>();
+        let patterns = subscribers
+            .values()
+            .flat_map(|subscription| subscription.patterns.iter())
+            .collect::<HashSet<_>>();
+        let shard_channels = self.shards.lock().await.values().map(HashMap::len).sum();
+        vec![
+            ("pubsub_channels", channels.len()),
+            ("pubsub_patterns", patterns.len()),
+            ("pubsub_shardchannels", shard_channels),
+            (
+                "client_output_buffer_limit_disconnections",
+                self.disconnections.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+
+    /// Queues a message for a subscriber. Returns false, without queueing it, when the
+    /// message takes the subscriber over its output buffer limit; it's then disconnected.
+    fn deliver(&self, subscription: &Subscription, message: String) -> bool {
+        if subscription.is_closed() {
+            return false;
+        }
+        if !subscription.buffer.reserve(message.len(), &self.limit) {
+            self.disconnections.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        subscription.tx.send(message).is_ok()
+    }
+
+    /// Drops subscribers whose connection has gone, along with their shard subscriptions.
+    async fn remove_closed(&self, subscribers: &mut HashMap<Uuid, Subscription>) {
+        let mut shards = self.shards.lock().await;
+        subscribers.retain(|id, subscription| {
+            let open = !subscription.is_closed();
+            if !open {
+                for channel in &subscription.shard_channels {
+                    remove_shard_subscriber(&mut shards, *id, channel);
                 }
+            }
+            open
+        });
+    }
+
+    /// Sends `message` to the channel's subscribers, and as a `pmessage` once per matching
+    /// pattern. Returns the number of messages sent.
+    pub async fn publish(&self, channel: String, message: String) -> usize {
+        let mut subscribers = self.subscribers.lock().await;
+        self.remove_closed(&mut subscribers).await;
+
+        let count = subscribers
+            .values()
+            .map(|subscription| {
+                let direct = subscription
+                    .channels
+                    .contains(&channel)
+                    .then(|| get_message(&channel, &message));
+                let patterned = subscription
+                    .patterns
+                    .iter()
+                    .filter(|pattern| glob_match(pattern, &channel))
+                    .map(|pattern| get_pmessage(pattern, &channel, &message));
+                direct
+                    .into_iter()
+                    .chain(patterned)
+                    .filter(|message| self.deliver(subscription, message.to_string()))
+                    .count()
             })
-            .collect::<Vec<_>>();
-        let count = futures.len();
-        let _ = join_all(futures).await;
+            .sum();
+        self.remove_closed(&mut subscribers).await;
         count
     }
 }
 
+fn shard_subscribers<'a>(shards: &'a ShardIndex, channel: &str) -> Option<&'a HashSet<Uuid>> {
+    shards.get(&key_slot(channel))?.get(channel)
+}
+
+fn remove_shard_subscriber(shards: &mut ShardIndex, id: Uuid, channel: &str) {
+    let slot = key_slot(channel);
+    let Some(channels) = shards.get_mut(&slot) else {
+        return;
+    };
+    if let Some(ids) = channels.get_mut(channel) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            channels.remove(channel);
+        }
+    }
+    if channels.is_empty() {
+        shards.remove(&slot);
+    }
+}
+
 fn get_message(channel: &str, message: &str) -> String {
     encode_array_of_bstrings(vec!["message".into(), channel.into(), message.into()].as_slice())
 }
+
+fn get_pmessage(pattern: &str, channel: &str, message: &str) -> String {
+    encode_array_of_bstrings(&[
+        "pmessage".into(),
+        pattern.into(),
+        channel.into(),
+        message.into(),
+    ])
+}
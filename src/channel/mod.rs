@@ -1,5 +1,7 @@
+mod buffer;
 mod command;
 pub mod context;
 mod manager;
+pub use buffer::OutputBufferLimit;
 pub use command::ChannelCommand;
-pub use manager::ChannelManager;
+pub use manager::{ChannelManager, PubSubSubcommand};
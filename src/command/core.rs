@@ -1,10 +1,24 @@
-use super::handlers::get_timestamp;
+use super::{handlers::get_timestamp, stream_handlers::XInfoSubcommand};
 use crate::{
+    channel::PubSubSubcommand,
     common::parse_string_args,
-    protocol::{Data, RedisArray},
-    store::{coords::Point, value::Value},
+    protocol::Data,
+    scripting::{
+        functions::{FunctionSubcommand, RestorePolicy},
+        ScriptSubcommand,
+    },
+    store::{
+        consumer_group::{AutoClaimOptions, ClaimOptions, PendingFilter, XGroupSubcommand},
+        coords::{validate_coords, GeoUnit, Point},
+        geo::{GeoAddOptions, GeoOrigin, GeoSearchQuery, GeoShape, GeoSort},
+        stream::{StreamEntry, StreamId, TrimOptions, TrimStrategy},
+        tracking::{ClientSubcommand, TrackingOptions},
+        value::Value,
+        watch::WatchedKey,
+    },
 };
 use rust_decimal::Decimal;
+use std::ops::Bound;
 
 #[derive(Clone)]
 pub enum Command {
@@ -15,11 +29,21 @@ pub enum Command {
         key: String,
         value: Value,
         expiry: Option<u64>,
-        raw_command: String,
     },
     ConfigGet(String),
+    ConfigSet(String, String),
+    /// The protocol version to switch to, if given.
+    Hello(Option<String>),
+    Client(ClientSubcommand),
     Keys(String),
-    Info,
+    /// The section to report, lowercased, or every section.
+    Info(Option<String>),
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    /// Whether to save first: SAVE, NOSAVE, or only if save points are configured.
+    Shutdown(Option<bool>),
     Psync(String, String),
     Replconf,
     ReplconfGetAck(String),
@@ -32,30 +56,89 @@ pub enum Command {
     XAdd {
         key: String,
         id: String,
-        entry: (String, String),
+        entry: StreamEntry,
+        trim: Option<TrimOptions>,
+        no_mkstream: bool,
+    },
+    XTrim {
+        key: String,
+        trim: TrimOptions,
     },
     XRange {
         key: String,
         start: String,
         end: String,
+        count: Option<usize>,
+        rev: bool,
+    },
+    XLen(String),
+    XDel {
+        key: String,
+        ids: Vec<StreamId>,
+    },
+    XSetId {
+        key: String,
+        last_id: StreamId,
+        entries_added: Option<u64>,
+        max_deleted_id: Option<StreamId>,
     },
+    XInfo(XInfoSubcommand),
     XRead {
         streams: Vec<(String, String)>,
+        count: Option<usize>,
         block: Option<u64>,
     },
+    XGroup {
+        key: String,
+        group: String,
+        subcommand: XGroupSubcommand,
+    },
+    XReadGroup {
+        group: String,
+        consumer: String,
+        streams: Vec<(String, String)>,
+        count: Option<usize>,
+        block: Option<u64>,
+        no_ack: bool,
+    },
+    XAck {
+        key: String,
+        group: String,
+        ids: Vec<StreamId>,
+    },
+    XPending {
+        key: String,
+        group: String,
+        filter: Option<PendingFilter>,
+    },
+    XClaim {
+        key: String,
+        group: String,
+        consumer: String,
+        min_idle: u64,
+        ids: Vec<StreamId>,
+        options: ClaimOptions,
+    },
+    XAutoClaim {
+        key: String,
+        group: String,
+        consumer: String,
+        min_idle: u64,
+        options: AutoClaimOptions,
+    },
     Incr {
         key: String,
-        raw_command: String,
     },
+    Del(Vec<String>),
     Multi,
     Exec,
     Invalid,
+    Error(String),
     Discard,
     ListPush {
         key: String,
         values: Vec<String>,
         is_left: bool,
-        raw_command: String,
     },
     LRange {
         key: String,
@@ -64,10 +147,39 @@ pub enum Command {
     },
     LLen(String),
     LPop(String, usize),
-    BLPop(Vec<String>, u64),
-    Transaction(Vec<Command>),
-    Subscribe(String),
+    /// Keys and timeout, where `None` never blocks (inside transactions).
+    BLPop(Vec<String>, Option<u64>),
+    Transaction(Vec<Command>, Vec<WatchedKey>),
+    Watch(Vec<String>),
+    Unwatch,
+    Subscribe(Vec<String>),
+    PSubscribe(Vec<String>),
+    /// Outside subscribe mode, only replies that nothing is subscribed.
+    Unsubscribe(Vec<String>),
+    PUnsubscribe(Vec<String>),
+    SSubscribe(Vec<String>),
+    SUnsubscribe(Vec<String>),
+    PubSub(PubSubSubcommand),
     Publish(String, String),
+    SPublish(String, String),
+    Eval {
+        script: String,
+        keys: Vec<String>,
+        args: Vec<String>,
+    },
+    EvalSha {
+        sha: String,
+        keys: Vec<String>,
+        args: Vec<String>,
+    },
+    Script(ScriptSubcommand),
+    FCall {
+        function: String,
+        keys: Vec<String>,
+        args: Vec<String>,
+        read_only: bool,
+    },
+    Function(FunctionSubcommand),
     ZAdd {
         key: String,
         score: Decimal,
@@ -87,8 +199,8 @@ pub enum Command {
     ZRem(String, String),
     Geoadd {
         key: String,
-        point: Point,
-        member: String,
+        members: Vec<(Point, String)>,
+        options: GeoAddOptions,
     },
     Geopos {
         key: String,
@@ -98,12 +210,21 @@ pub enum Command {
         key: String,
         from: String,
         to: String,
+        unit: GeoUnit,
+    },
+    Geohash {
+        key: String,
+        members: Vec<String>,
     },
     Geosearch {
         key: String,
-        point: Point,
-        radius: f64,
-        unit: String,
+        query: GeoSearchQuery,
+    },
+    GeoSearchStore {
+        destination: String,
+        key: String,
+        query: GeoSearchQuery,
+        store_dist: bool,
     },
 }
 
@@ -132,21 +253,44 @@ impl From<&[Data]> for Command {
                 key: key.into(),
                 value: value.to_string().into(),
                 expiry: get_timestamp(expiry_ms),
-                raw_command: get_raw_array_command(val),
+            },
+            (
+                "SET",
+                [Data::BStr(key), Data::BStr(value), Data::BStr(param), Data::BStr(unix_ms)],
+            ) if param.eq_ignore_ascii_case("PXAT") => match unix_ms.parse::<u64>() {
+                Ok(expiry) => Command::Set {
+                    key: key.into(),
+                    value: value.to_string().into(),
+                    expiry: Some(expiry),
+                },
+                Err(_) => Command::Invalid,
             },
             ("SET", [Data::BStr(key), Data::BStr(value)]) => Command::Set {
                 key: key.into(),
                 value: value.to_string().into(),
                 expiry: None,
-                raw_command: get_raw_array_command(val),
             },
             ("CONFIG", [Data::BStr(arg), Data::BStr(key)]) if arg.eq_ignore_ascii_case("GET") => {
                 Command::ConfigGet(key.into())
             }
-            ("KEYS", [Data::BStr(pattern)]) => Command::Keys(pattern.into()),
-            ("INFO", [Data::BStr(section)]) if section.eq_ignore_ascii_case("REPLICATION") => {
-                Command::Info
+            ("CONFIG", [Data::BStr(arg), Data::BStr(key), Data::BStr(value)])
+                if arg.eq_ignore_ascii_case("SET") =>
+            {
+                Command::ConfigSet(key.into(), value.into())
             }
+            ("KEYS", [Data::BStr(pattern)]) => Command::Keys(pattern.into()),
+            ("INFO", []) => Command::Info(None),
+            ("INFO", [Data::BStr(section)]) => Command::Info(Some(section.to_lowercase())),
+            ("SAVE", []) => Command::Save,
+            ("BGSAVE", []) => Command::BgSave,
+            ("LASTSAVE", []) => Command::LastSave,
+            ("BGREWRITEAOF", []) => Command::BgRewriteAof,
+            ("SHUTDOWN", []) => Command::Shutdown(None),
+            ("SHUTDOWN", [Data::BStr(option)]) => match option.to_uppercase().as_str() {
+                "SAVE" => Command::Shutdown(Some(true)),
+                "NOSAVE" => Command::Shutdown(Some(false)),
+                _ => Command::Invalid,
+            },
             ("PSYNC", [Data::BStr(replica_id), Data::BStr(offset)]) => {
                 Command::Psync(replica_id.into(), offset.into())
             }
@@ -170,36 +314,106 @@ impl From<&[Data]> for Command {
                 }
             }
             ("TYPE", [Data::BStr(key)]) => Command::Type(key.into()),
-            (
-                "XADD",
-                [Data::BStr(key), Data::BStr(id), Data::BStr(entry_key), Data::BStr(entry_value)],
-            ) => Command::XAdd {
-                key: key.into(),
-                id: id.into(),
-                entry: (entry_key.into(), entry_value.into()),
-            },
-            ("XRANGE", [Data::BStr(key), Data::BStr(start), Data::BStr(end)]) => Command::XRange {
-                key: key.into(),
-                start: start.into(),
-                end: end.into(),
-            },
-            ("XREAD", ..) => parse_xread(val),
-            ("INCR", [Data::BStr(key)]) => Command::Incr {
-                key: key.into(),
-                raw_command: get_raw_array_command(val),
+            ("XADD", [Data::BStr(key), ..]) => {
+                parse_xadd(key, &parse_string_args(&val[2..])).unwrap_or(Command::Invalid)
+            }
+            ("XTRIM", [Data::BStr(key), ..]) => {
+                match parse_trim_options(&parse_string_args(&val[2..])) {
+                    Some((trim, consumed)) if consumed == val.len() - 2 => Command::XTrim {
+                        key: key.into(),
+                        trim,
+                    },
+                    _ => Command::Invalid,
+                }
+            }
+            ("XRANGE", [Data::BStr(key), Data::BStr(start), Data::BStr(end), ..]) => {
+                parse_xrange(key, start, end, &parse_string_args(&val[4..]), false)
+            }
+            ("XREVRANGE", [Data::BStr(key), Data::BStr(end), Data::BStr(start), ..]) => {
+                parse_xrange(key, start, end, &parse_string_args(&val[4..]), true)
+            }
+            ("XLEN", [Data::BStr(key)]) => Command::XLen(key.into()),
+            ("XDEL", [Data::BStr(key), _, ..]) => match parse_stream_ids(&val[2..]) {
+                Some(ids) => Command::XDel {
+                    key: key.into(),
+                    ids,
+                },
+                None => Command::Invalid,
             },
+            ("XSETID", [Data::BStr(key), Data::BStr(last_id), ..]) => {
+                parse_xsetid(key, last_id, &parse_string_args(&val[3..]))
+                    .unwrap_or(Command::Invalid)
+            }
+            ("XINFO", [Data::BStr(subcommand), ..]) => {
+                match parse_xinfo(subcommand, &parse_string_args(&val[2..])) {
+                    Some(subcommand) => Command::XInfo(subcommand),
+                    None => Command::Invalid,
+                }
+            }
+            ("XREAD", ..) => parse_xread(&parse_string_args(&val[1..])).unwrap_or(Command::Invalid),
+            ("XGROUP", [Data::BStr(subcommand), Data::BStr(key), Data::BStr(group), ..]) => {
+                match parse_xgroup(subcommand, &parse_string_args(&val[4..])) {
+                    Some(subcommand) => Command::XGroup {
+                        key: key.into(),
+                        group: group.into(),
+                        subcommand,
+                    },
+                    None => Command::Invalid,
+                }
+            }
+            ("XREADGROUP", ..) => {
+                parse_xreadgroup(&parse_string_args(&val[1..])).unwrap_or(Command::Invalid)
+            }
+            ("XACK", [Data::BStr(key), Data::BStr(group), _, ..]) => {
+                match parse_stream_ids(&val[3..]) {
+                    Some(ids) => Command::XAck {
+                        key: key.into(),
+                        group: group.into(),
+                        ids,
+                    },
+                    None => Command::Invalid,
+                }
+            }
+            ("XPENDING", [Data::BStr(key), Data::BStr(group), ..]) => {
+                let args = parse_string_args(&val[3..]);
+                match args.is_empty() {
+                    true => Command::XPending {
+                        key: key.into(),
+                        group: group.into(),
+                        filter: None,
+                    },
+                    false => match parse_pending_filter(&args) {
+                        Some(filter) => Command::XPending {
+                            key: key.into(),
+                            group: group.into(),
+                            filter: Some(filter),
+                        },
+                        None => Command::Invalid,
+                    },
+                }
+            }
+            ("XCLAIM", [Data::BStr(key), Data::BStr(group), Data::BStr(consumer), ..]) => {
+                parse_xclaim(key, group, consumer, &parse_string_args(&val[4..]))
+                    .unwrap_or(Command::Invalid)
+            }
+            ("XAUTOCLAIM", [Data::BStr(key), Data::BStr(group), Data::BStr(consumer), ..]) => {
+                parse_xautoclaim(key, group, consumer, &parse_string_args(&val[4..]))
+                    .unwrap_or(Command::Invalid)
+            }
+            ("INCR", [Data::BStr(key)]) => Command::Incr { key: key.into() },
+            ("DEL", [_, ..]) => Command::Del(parse_string_args(&val[1..])),
             ("MULTI", ..) => Command::Multi,
             ("EXEC", ..) => Command::Exec,
             ("DISCARD", ..) => Command::Discard,
+            ("WATCH", [_, ..]) => Command::Watch(parse_string_args(&val[1..])),
+            ("UNWATCH", []) => Command::Unwatch,
             ("RPUSH", [Data::BStr(key), ..]) => Command::ListPush {
                 key: key.into(),
-                raw_command: get_raw_array_command(val),
                 values: parse_string_args(&val[2..]),
                 is_left: false,
             },
             ("LPUSH", [Data::BStr(key), ..]) => Command::ListPush {
                 key: key.into(),
-                raw_command: get_raw_array_command(val),
                 values: parse_string_args(&val[2..]),
                 is_left: true,
             },
@@ -218,10 +432,75 @@ impl From<&[Data]> for Command {
             }
             ("LPOP", [Data::BStr(key)]) => Command::LPop(key.into(), 1),
             ("BLPOP", [..]) => parse_blpop(&val[1..]),
-            ("SUBSCRIBE", [Data::BStr(channel)]) => Command::Subscribe(channel.into()),
+            ("SUBSCRIBE", [_, ..]) => Command::Subscribe(parse_string_args(&val[1..])),
+            ("PSUBSCRIBE", [_, ..]) => Command::PSubscribe(parse_string_args(&val[1..])),
+            ("UNSUBSCRIBE", ..) => Command::Unsubscribe(parse_string_args(&val[1..])),
+            ("PUNSUBSCRIBE", ..) => Command::PUnsubscribe(parse_string_args(&val[1..])),
+            ("SSUBSCRIBE", [_, ..]) => Command::SSubscribe(parse_string_args(&val[1..])),
+            ("SUNSUBSCRIBE", ..) => Command::SUnsubscribe(parse_string_args(&val[1..])),
+            ("SPUBLISH", [Data::BStr(channel), Data::BStr(message)]) => {
+                Self::SPublish(channel.to_string(), message.to_string())
+            }
+            ("HELLO", []) => Command::Hello(None),
+            ("HELLO", [Data::BStr(version), ..]) => Command::Hello(Some(version.into())),
+            ("CLIENT", [Data::BStr(subcommand), ..]) => {
+                match parse_client(subcommand, &parse_string_args(&val[2..])) {
+                    Some(subcommand) => Command::Client(subcommand),
+                    None => Command::Invalid,
+                }
+            }
+            ("PUBSUB", [Data::BStr(subcommand), ..]) => {
+                match parse_pubsub(subcommand, &parse_string_args(&val[2..])) {
+                    Some(subcommand) => Command::PubSub(subcommand),
+                    None => Command::Invalid,
+                }
+            }
             ("PUBLISH", [Data::BStr(channel), Data::BStr(message)]) => {
                 Self::Publish(channel.to_string(), message.to_string())
             }
+            ("EVAL", [Data::BStr(script), Data::BStr(numkeys), ..]) => {
+                match parse_keys_and_args(numkeys, &parse_string_args(&val[3..])) {
+                    Some((keys, args)) => Command::Eval {
+                        script: script.into(),
+                        keys,
+                        args,
+                    },
+                    None => Command::Invalid,
+                }
+            }
+            ("EVALSHA", [Data::BStr(sha), Data::BStr(numkeys), ..]) => {
+                match parse_keys_and_args(numkeys, &parse_string_args(&val[3..])) {
+                    Some((keys, args)) => Command::EvalSha {
+                        sha: sha.into(),
+                        keys,
+                        args,
+                    },
+                    None => Command::Invalid,
+                }
+            }
+            ("SCRIPT", [Data::BStr(subcommand), ..]) => {
+                match parse_script(subcommand, &parse_string_args(&val[2..])) {
+                    Some(subcommand) => Command::Script(subcommand),
+                    None => Command::Invalid,
+                }
+            }
+            (name @ ("FCALL" | "FCALL_RO"), [Data::BStr(function), Data::BStr(numkeys), ..]) => {
+                match parse_keys_and_args(numkeys, &parse_string_args(&val[3..])) {
+                    Some((keys, args)) => Command::FCall {
+                        function: function.into(),
+                        keys,
+                        args,
+                        read_only: name == "FCALL_RO",
+                    },
+                    None => Command::Invalid,
+                }
+            }
+            ("FUNCTION", [Data::BStr(subcommand), ..]) => {
+                match parse_function(subcommand, &parse_string_args(&val[2..])) {
+                    Some(subcommand) => Command::Function(subcommand),
+                    None => Command::Invalid,
+                }
+            }
             ("ZADD", [Data::BStr(key), Data::BStr(score), Data::BStr(member)]) => Self::ZAdd {
                 key: key.clone(),
                 score: Decimal::from_str_exact(score).unwrap_or_default(),
@@ -247,14 +526,7 @@ impl From<&[Data]> for Command {
             ("ZREM", [Data::BStr(key), Data::BStr(member)]) => {
                 Command::ZRem(key.into(), member.into())
             }
-            (
-                "GEOADD",
-                [Data::BStr(key), Data::BStr(long), Data::BStr(lat), Data::BStr(member)],
-            ) => Self::Geoadd {
-                key: key.into(),
-                point: Point::new(lat, long),
-                member: member.into(),
-            },
+            ("GEOADD", [Data::BStr(key), ..]) => parse_geoadd(key, &parse_string_args(&val[2..])),
             ("GEOPOS", [Data::BStr(key), ..]) => Self::Geopos {
                 key: key.into(),
                 members: parse_string_args(&val[2..]),
@@ -264,17 +536,74 @@ impl From<&[Data]> for Command {
                     key: key.into(),
                     from: member.into(),
                     to: member_two.into(),
+                    unit: GeoUnit::Meters,
                 }
             }
             (
-                "GEOSEARCH",
-                [Data::BStr(key), _, Data::BStr(long), Data::BStr(lat), _, Data::BStr(radius), Data::BStr(unit)],
-            ) => Self::Geosearch {
+                "GEODIST",
+                [Data::BStr(key), Data::BStr(member), Data::BStr(member_two), Data::BStr(unit)],
+            ) => match GeoUnit::try_from(unit.as_str()) {
+                Ok(unit) => Self::Geodist {
+                    key: key.into(),
+                    from: member.into(),
+                    to: member_two.into(),
+                    unit,
+                },
+                Err(e) => Command::Error(e.to_string()),
+            },
+            ("GEOHASH", [Data::BStr(key), ..]) => Self::Geohash {
                 key: key.into(),
-                point: Point::new(lat, long),
-                radius: radius.parse::<f64>().unwrap_or_default(),
-                unit: unit.into(),
+                members: parse_string_args(&val[2..]),
             },
+            ("GEOSEARCH", [Data::BStr(key), ..]) => {
+                match parse_geo_query(&parse_string_args(&val[2..])) {
+                    Ok((query, false)) => Self::Geosearch {
+                        key: key.into(),
+                        query,
+                    },
+                    Ok(_) => Command::Error("syntax error".into()),
+                    Err(e) => Command::Error(e),
+                }
+            }
+            ("GEOSEARCHSTORE", [Data::BStr(destination), Data::BStr(key), ..]) => {
+                match parse_geo_query(&parse_string_args(&val[3..])) {
+                    Ok((query, store_dist)) if !query.has_with_flags() => Self::GeoSearchStore {
+                        destination: destination.into(),
+                        key: key.into(),
+                        query,
+                        store_dist,
+                    },
+                    Ok(_) => Command::Error("syntax error".into()),
+                    Err(e) => Command::Error(e),
+                }
+            }
+            (
+                "GEORADIUS",
+                [Data::BStr(key), Data::BStr(long), Data::BStr(lat), Data::BStr(radius), Data::BStr(unit), ..],
+            ) => {
+                let args = vec![
+                    "FROMLONLAT".into(),
+                    long.into(),
+                    lat.into(),
+                    "BYRADIUS".into(),
+                    radius.into(),
+                    unit.into(),
+                ];
+                parse_georadius(key, args, parse_string_args(&val[6..]))
+            }
+            (
+                "GEORADIUSBYMEMBER",
+                [Data::BStr(key), Data::BStr(member), Data::BStr(radius), Data::BStr(unit), ..],
+            ) => {
+                let args = vec![
+                    "FROMMEMBER".into(),
+                    member.into(),
+                    "BYRADIUS".into(),
+                    radius.into(),
+                    unit.into(),
+                ];
+                parse_georadius(key, args, parse_string_args(&val[5..]))
+            }
             _ => Command::Invalid,
         }
     }
@@ -284,9 +613,44 @@ impl Command {
     pub fn is_blocking(&self) -> bool {
         matches!(
             self,
-            Command::XRead { block: Some(_), .. } | Command::BLPop(..)
+            Command::XRead { block: Some(_), .. }
+                | Command::XReadGroup { block: Some(_), .. }
+                | Command::BLPop(_, Some(_))
         )
     }
+
+    /// The keys a read-only command reads, which client-side caching tracks.
+    pub fn read_keys(&self) -> Vec<String> {
+        match self {
+            Command::Get(key)
+            | Command::Type(key)
+            | Command::XLen(key)
+            | Command::XRange { key, .. }
+            | Command::LRange { key, .. }
+            | Command::LLen(key)
+            | Command::ZRank { key, .. }
+            | Command::ZRange { key, .. }
+            | Command::ZCard(key)
+            | Command::ZScore(key, _)
+            | Command::Geopos { key, .. }
+            | Command::Geodist { key, .. }
+            | Command::Geohash { key, .. }
+            | Command::Geosearch { key, .. } => vec![key.clone()],
+            Command::XRead { streams, .. } => streams.iter().map(|(key, _)| key.clone()).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Transactions run atomically, so blocking commands inside them time out straight
+    /// away instead of waiting.
+    pub fn into_non_blocking(mut self) -> Self {
+        match &mut self {
+            Command::XRead { block, .. } | Command::XReadGroup { block, .. } => *block = None,
+            Command::BLPop(_, block) => *block = None,
+            _ => (),
+        }
+        self
+    }
 }
 
 fn is_number(val: &str) -> bool {
@@ -294,26 +658,443 @@ fn is_number(val: &str) -> bool {
         .all(|c| c.is_ascii_digit() || c == '.' || c == '-')
 }
 
-fn parse_xread(val: &[Data]) -> Command {
-    let mut stream_start = 2usize;
-    let block = match &val[1..=2] {
-        [Data::BStr(arg), Data::BStr(ms)] if arg.eq_ignore_ascii_case("BLOCK") && is_number(ms) => {
-            stream_start = 4;
-            Some(ms.parse::<u64>().unwrap())
+/// Splits EVAL's arguments into `numkeys` keys and the remaining arguments.
+fn parse_keys_and_args(numkeys: &str, args: &[String]) -> Option<(Vec<String>, Vec<String>)> {
+    let numkeys = numkeys.parse::<usize>().ok().filter(|n| *n <= args.len())?;
+    let (keys, args) = args.split_at(numkeys);
+    Some((keys.to_vec(), args.to_vec()))
+}
+
+fn parse_pubsub(subcommand: &str, args: &[String]) -> Option<PubSubSubcommand> {
+    match (subcommand.to_uppercase().as_str(), args) {
+        ("CHANNELS", []) => Some(PubSubSubcommand::Channels(None)),
+        ("CHANNELS", [pattern]) => Some(PubSubSubcommand::Channels(Some(pattern.clone()))),
+        ("NUMSUB", _) => Some(PubSubSubcommand::NumSub(args.to_vec())),
+        ("NUMPAT", []) => Some(PubSubSubcommand::NumPat),
+        ("SHARDCHANNELS", []) => Some(PubSubSubcommand::ShardChannels(None)),
+        ("SHARDCHANNELS", [pattern]) => {
+            Some(PubSubSubcommand::ShardChannels(Some(pattern.clone())))
+        }
+        ("SHARDNUMSUB", _) => Some(PubSubSubcommand::ShardNumSub(args.to_vec())),
+        _ => None,
+    }
+}
+
+fn parse_client(subcommand: &str, args: &[String]) -> Option<ClientSubcommand> {
+    match (subcommand.to_uppercase().as_str(), args) {
+        ("ID", []) => Some(ClientSubcommand::Id),
+        ("GETREDIR", []) => Some(ClientSubcommand::GetRedir),
+        ("CACHING", [mode]) => match mode.to_uppercase().as_str() {
+            "YES" => Some(ClientSubcommand::Caching(true)),
+            "NO" => Some(ClientSubcommand::Caching(false)),
+            _ => None,
+        },
+        ("TRACKING", [switch, options @ ..]) => match switch.to_uppercase().as_str() {
+            "ON" => Some(ClientSubcommand::Tracking(Some(parse_tracking(options)?))),
+            "OFF" => Some(ClientSubcommand::Tracking(None)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn parse_tracking(args: &[String]) -> Option<TrackingOptions> {
+    let mut options = TrackingOptions::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.to_uppercase().as_str() {
+            "REDIRECT" => options.redirect = Some(iter.next()?.parse().ok()?),
+            "PREFIX" => options.prefixes.push(iter.next()?.clone()),
+            "BCAST" => options.bcast = true,
+            "OPTIN" => options.optin = true,
+            "OPTOUT" => options.optout = true,
+            "NOLOOP" => options.noloop = true,
+            _ => return None,
+        }
+    }
+    Some(options)
+}
+
+fn parse_script(subcommand: &str, args: &[String]) -> Option<ScriptSubcommand> {
+    match (subcommand.to_uppercase().as_str(), args) {
+        ("LOAD", [body]) => Some(ScriptSubcommand::Load(body.clone())),
+        ("EXISTS", [_, ..]) => Some(ScriptSubcommand::Exists(args.to_vec())),
+        ("FLUSH", []) => Some(ScriptSubcommand::Flush),
+        ("FLUSH", [mode]) if ["ASYNC", "SYNC"].contains(&mode.to_uppercase().as_str()) => {
+            Some(ScriptSubcommand::Flush)
         }
+        ("KILL", []) => Some(ScriptSubcommand::Kill),
         _ => None,
+    }
+}
+
+fn parse_function(subcommand: &str, args: &[String]) -> Option<FunctionSubcommand> {
+    match (subcommand.to_uppercase().as_str(), args) {
+        ("LOAD", [code]) => Some(FunctionSubcommand::Load {
+            code: code.clone(),
+            replace: false,
+        }),
+        ("LOAD", [replace, code]) if replace.eq_ignore_ascii_case("REPLACE") => {
+            Some(FunctionSubcommand::Load {
+                code: code.clone(),
+                replace: true,
+            })
+        }
+        ("DELETE", [name]) => Some(FunctionSubcommand::Delete(name.clone())),
+        ("FLUSH", []) => Some(FunctionSubcommand::Flush),
+        ("FLUSH", [mode]) if ["ASYNC", "SYNC"].contains(&mode.to_uppercase().as_str()) => {
+            Some(FunctionSubcommand::Flush)
+        }
+        ("LIST", _) => {
+            let (mut pattern, mut with_code) = (None, false);
+            let mut iter = args.iter();
+            while let Some(arg) = iter.next() {
+                match arg.to_uppercase().as_str() {
+                    "LIBRARYNAME" => pattern = Some(iter.next()?.clone()),
+                    "WITHCODE" => with_code = true,
+                    _ => return None,
+                }
+            }
+            Some(FunctionSubcommand::List { pattern, with_code })
+        }
+        ("DUMP", []) => Some(FunctionSubcommand::Dump),
+        ("RESTORE", [payload, policy @ ..]) => {
+            let policy = match policy {
+                [] => RestorePolicy::Append,
+                [policy] => match policy.to_uppercase().as_str() {
+                    "APPEND" => RestorePolicy::Append,
+                    "REPLACE" => RestorePolicy::Replace,
+                    "FLUSH" => RestorePolicy::Flush,
+                    _ => return None,
+                },
+                _ => return None,
+            };
+            Some(FunctionSubcommand::Restore {
+                payload: payload.clone(),
+                policy,
+            })
+        }
+        ("STATS", []) => Some(FunctionSubcommand::Stats),
+        ("KILL", []) => Some(FunctionSubcommand::Kill),
+        _ => None,
+    }
+}
+
+fn parse_xread(args: &[String]) -> Option<Command> {
+    let (mut count, mut block) = (None, None);
+    let mut iter = args.iter();
+    loop {
+        match iter.next()?.to_uppercase().as_str() {
+            "COUNT" => count = Some(iter.next()?.parse().ok()?),
+            "BLOCK" => block = Some(iter.next()?.parse().ok()?),
+            "STREAMS" => break,
+            _ => return None,
+        }
+    }
+    let rest = iter.as_slice();
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        return None;
+    }
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+    Some(Command::XRead {
+        streams: keys.iter().cloned().zip(ids.iter().cloned()).collect(),
+        count,
+        block,
+    })
+}
+
+fn parse_xrange(key: &str, start: &str, end: &str, args: &[String], rev: bool) -> Command {
+    let count = match args {
+        [] => None,
+        [arg, count] if arg.eq_ignore_ascii_case("COUNT") => match count.parse::<i64>() {
+            Ok(count) => Some(count.max(0) as usize),
+            Err(_) => return Command::Invalid,
+        },
+        _ => return Command::Invalid,
     };
-    let mid = val[stream_start..].len() / 2;
-    let (left, right) = val[stream_start..].split_at(mid);
-    let streams = left
+    Command::XRange {
+        key: key.into(),
+        start: start.into(),
+        end: end.into(),
+        count,
+        rev,
+    }
+}
+
+fn parse_stream_ids(val: &[Data]) -> Option<Vec<StreamId>> {
+    parse_string_args(val)
         .iter()
-        .zip(right.iter())
-        .filter_map(|(l, r)| match (l, r) {
-            (Data::BStr(stream), Data::BStr(id)) => Some((stream.into(), id.into())),
-            _ => None,
-        })
+        .map(|id| StreamId::parse(id, 0).ok())
+        .collect()
+}
+
+fn parse_xsetid(key: &str, last_id: &str, args: &[String]) -> Option<Command> {
+    let (mut entries_added, mut max_deleted_id) = (None, None);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.to_uppercase().as_str() {
+            "ENTRIESADDED" => entries_added = Some(iter.next()?.parse().ok()?),
+            "MAXDELETEDID" => max_deleted_id = Some(StreamId::parse(iter.next()?, 0).ok()?),
+            _ => return None,
+        }
+    }
+    Some(Command::XSetId {
+        key: key.into(),
+        last_id: StreamId::parse(last_id, 0).ok()?,
+        entries_added,
+        max_deleted_id,
+    })
+}
+
+fn parse_xinfo(subcommand: &str, args: &[String]) -> Option<XInfoSubcommand> {
+    match (subcommand.to_uppercase().as_str(), args) {
+        ("STREAM", [key]) => Some(XInfoSubcommand::Stream {
+            key: key.clone(),
+            full: None,
+        }),
+        ("STREAM", [key, full]) if full.eq_ignore_ascii_case("FULL") => {
+            Some(XInfoSubcommand::Stream {
+                key: key.clone(),
+                full: Some(10),
+            })
+        }
+        ("STREAM", [key, full, count_arg, count])
+            if full.eq_ignore_ascii_case("FULL") && count_arg.eq_ignore_ascii_case("COUNT") =>
+        {
+            Some(XInfoSubcommand::Stream {
+                key: key.clone(),
+                full: Some(count.parse().ok()?),
+            })
+        }
+        ("GROUPS", [key]) => Some(XInfoSubcommand::Groups(key.clone())),
+        ("CONSUMERS", [key, group]) => Some(XInfoSubcommand::Consumers {
+            key: key.clone(),
+            group: group.clone(),
+        }),
+        _ => None,
+    }
+}
+
+fn parse_xgroup(subcommand: &str, args: &[String]) -> Option<XGroupSubcommand> {
+    match (subcommand.to_uppercase().as_str(), args) {
+        ("CREATE", [id, options @ ..]) => {
+            let (mut mkstream, mut entries_read) = (false, None);
+            let mut iter = options.iter();
+            while let Some(option) = iter.next() {
+                match option.to_uppercase().as_str() {
+                    "MKSTREAM" => mkstream = true,
+                    "ENTRIESREAD" => entries_read = Some(iter.next()?.parse().ok()?),
+                    _ => return None,
+                }
+            }
+            Some(XGroupSubcommand::Create {
+                id: id.clone(),
+                mkstream,
+                entries_read,
+            })
+        }
+        ("SETID", [id]) => Some(XGroupSubcommand::SetId {
+            id: id.clone(),
+            entries_read: None,
+        }),
+        ("SETID", [id, option, entries_read]) if option.eq_ignore_ascii_case("ENTRIESREAD") => {
+            Some(XGroupSubcommand::SetId {
+                id: id.clone(),
+                entries_read: Some(entries_read.parse().ok()?),
+            })
+        }
+        ("DESTROY", []) => Some(XGroupSubcommand::Destroy),
+        ("CREATECONSUMER", [consumer]) => Some(XGroupSubcommand::CreateConsumer(consumer.clone())),
+        ("DELCONSUMER", [consumer]) => Some(XGroupSubcommand::DelConsumer(consumer.clone())),
+        _ => None,
+    }
+}
+
+fn parse_xreadgroup(args: &[String]) -> Option<Command> {
+    let [group_arg, group, consumer, rest @ ..] = args else {
+        return None;
+    };
+    if !group_arg.eq_ignore_ascii_case("GROUP") {
+        return None;
+    }
+    let (mut count, mut block, mut no_ack) = (None, None, false);
+    let mut iter = rest.iter();
+    loop {
+        match iter.next()?.to_uppercase().as_str() {
+            "COUNT" => count = Some(iter.next()?.parse().ok()?),
+            "BLOCK" => block = Some(iter.next()?.parse().ok()?),
+            "NOACK" => no_ack = true,
+            "STREAMS" => break,
+            _ => return None,
+        }
+    }
+    let rest = iter.as_slice();
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        return None;
+    }
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+    Some(Command::XReadGroup {
+        group: group.clone(),
+        consumer: consumer.clone(),
+        streams: keys.iter().cloned().zip(ids.iter().cloned()).collect(),
+        count,
+        block,
+        no_ack,
+    })
+}
+
+/// Parses the extended XPENDING form: `[IDLE min-idle] start end count [consumer]`.
+fn parse_pending_filter(args: &[String]) -> Option<PendingFilter> {
+    let (min_idle, args) = match args {
+        [idle, min_idle, rest @ ..] if idle.eq_ignore_ascii_case("IDLE") => {
+            (Some(min_idle.parse().ok()?), rest)
+        }
+        _ => (None, args),
+    };
+    let (start, end, count, consumer) = match args {
+        [start, end, count] => (start, end, count, None),
+        [start, end, count, consumer] => (start, end, count, Some(consumer.clone())),
+        _ => return None,
+    };
+    Some(PendingFilter {
+        min_idle,
+        range: (
+            StreamId::parse_range_start(start).ok()?,
+            StreamId::parse_range_end(end).ok()?,
+        ),
+        count: count.parse::<i64>().ok()?.max(0) as usize,
+        consumer,
+    })
+}
+
+fn parse_xclaim(key: &str, group: &str, consumer: &str, args: &[String]) -> Option<Command> {
+    let (min_idle, rest) = args.split_first()?;
+    let mut iter = rest.iter().peekable();
+    let mut ids = vec![];
+    while let Some(id) = iter.next_if(|arg| StreamId::parse(arg, 0).is_ok()) {
+        ids.push(StreamId::parse(id, 0).ok()?);
+    }
+    if ids.is_empty() {
+        return None;
+    }
+    let mut options = ClaimOptions::default();
+    while let Some(arg) = iter.next() {
+        match arg.to_uppercase().as_str() {
+            "IDLE" => options.idle = Some(iter.next()?.parse().ok()?),
+            "TIME" => options.time = Some(iter.next()?.parse().ok()?),
+            "RETRYCOUNT" => options.retry_count = Some(iter.next()?.parse().ok()?),
+            "FORCE" => options.force = true,
+            "JUSTID" => options.just_id = true,
+            "LASTID" => options.last_id = Some(StreamId::parse(iter.next()?, 0).ok()?),
+            _ => return None,
+        }
+    }
+    Some(Command::XClaim {
+        key: key.into(),
+        group: group.into(),
+        consumer: consumer.into(),
+        min_idle: min_idle.parse().ok()?,
+        ids,
+        options,
+    })
+}
+
+fn parse_xautoclaim(key: &str, group: &str, consumer: &str, args: &[String]) -> Option<Command> {
+    let [min_idle, start, options @ ..] = args else {
+        return None;
+    };
+    let (mut count, mut just_id) = (100, false);
+    let mut iter = options.iter();
+    while let Some(arg) = iter.next() {
+        match arg.to_uppercase().as_str() {
+            "COUNT" => count = iter.next()?.parse().ok().filter(|c| *c > 0)?,
+            "JUSTID" => just_id = true,
+            _ => return None,
+        }
+    }
+    let start = match StreamId::parse_range_start(start).ok()? {
+        Bound::Included(id) => id,
+        Bound::Excluded(id) => StreamId::new(id.ms, id.seq.checked_add(1)?),
+        Bound::Unbounded => StreamId::MIN,
+    };
+    Some(Command::XAutoClaim {
+        key: key.into(),
+        group: group.into(),
+        consumer: consumer.into(),
+        min_idle: min_idle.parse().ok()?,
+        options: AutoClaimOptions {
+            start,
+            count,
+            just_id,
+        },
+    })
+}
+
+fn parse_xadd(key: &str, args: &[String]) -> Option<Command> {
+    let mut rest = args;
+    let (mut trim, mut no_mkstream) = (None, false);
+    loop {
+        match rest.first()?.to_uppercase().as_str() {
+            "NOMKSTREAM" => {
+                no_mkstream = true;
+                rest = &rest[1..];
+            }
+            "MAXLEN" | "MINID" => {
+                let (options, consumed) = parse_trim_options(rest)?;
+                trim = Some(options);
+                rest = &rest[consumed..];
+            }
+            _ => break,
+        }
+    }
+    let (id, fields) = rest.split_first()?;
+    if fields.is_empty() || !fields.len().is_multiple_of(2) {
+        return None;
+    }
+    let entry = fields
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
         .collect();
-    Command::XRead { streams, block }
+    Some(Command::XAdd {
+        key: key.into(),
+        id: id.clone(),
+        entry,
+        trim,
+        no_mkstream,
+    })
+}
+
+/// Parses `MAXLEN|MINID [=|~] threshold [LIMIT count]`, returning the options and the
+/// number of arguments consumed.
+fn parse_trim_options(args: &[String]) -> Option<(TrimOptions, usize)> {
+    let mut consumed = 1;
+    let approximate = args.get(1).is_some_and(|arg| arg == "~");
+    if matches!(args.get(1).map(String::as_str), Some("~" | "=")) {
+        consumed += 1;
+    }
+    let threshold = args.get(consumed)?;
+    consumed += 1;
+    let strategy = match args.first()?.to_uppercase().as_str() {
+        "MAXLEN" => TrimStrategy::MaxLen(threshold.parse().ok()?),
+        "MINID" => TrimStrategy::MinId(StreamId::parse(threshold, 0).ok()?),
+        _ => return None,
+    };
+    let limit = match args.get(consumed) {
+        Some(arg) if arg.eq_ignore_ascii_case("LIMIT") => {
+            if !approximate {
+                return None;
+            }
+            consumed += 2;
+            Some(args.get(consumed - 1)?.parse().ok()?)
+        }
+        _ => None,
+    };
+    let options = TrimOptions {
+        strategy,
+        approximate,
+        limit,
+    };
+    Some((options, consumed))
 }
 
 fn parse_blpop(val: &[Data]) -> Command {
@@ -329,9 +1110,147 @@ fn parse_blpop(val: &[Data]) -> Command {
         }
         _ => return Command::Invalid,
     };
-    Command::BLPop(keys, block as u64)
+    Command::BLPop(keys, Some(block as u64))
 }
 
-fn get_raw_array_command(val: &[Data]) -> String {
-    RedisArray(val.to_vec()).into()
+fn parse_geoadd(key: &str, args: &[String]) -> Command {
+    let mut options = GeoAddOptions::default();
+    let mut start = 0;
+    for arg in args {
+        match arg.to_uppercase().as_str() {
+            "NX" => options.nx = true,
+            "XX" => options.xx = true,
+            "CH" => options.ch = true,
+            _ => break,
+        }
+        start += 1;
+    }
+    let triples = &args[start..];
+    if (options.nx && options.xx) || triples.is_empty() || !triples.len().is_multiple_of(3) {
+        return Command::Invalid;
+    }
+    let members = triples
+        .chunks(3)
+        .map(|c| (Point::new(&c[1], &c[0]), c[2].clone()))
+        .collect();
+    Command::Geoadd {
+        key: key.into(),
+        members,
+        options,
+    }
+}
+
+fn parse_geo_query(args: &[String]) -> Result<(GeoSearchQuery, bool), String> {
+    let syntax_error = || "syntax error".to_string();
+    let (mut origin, mut shape, mut unit) = (None, None, None);
+    let (mut sort, mut count, mut store_dist) = (None, None, false);
+    let (mut with_coord, mut with_dist, mut with_hash) = (false, false, false);
+    let mut iter = args.iter().peekable();
+    while let Some(arg) = iter.next() {
+        let upper = arg.to_uppercase();
+        if matches!(upper.as_str(), "FROMMEMBER" | "FROMLONLAT") && origin.is_some() {
+            return Err("exactly one of FROMMEMBER or FROMLONLAT can be specified".into());
+        }
+        if matches!(upper.as_str(), "BYRADIUS" | "BYBOX") && shape.is_some() {
+            return Err("exactly one of BYRADIUS and BYBOX can be specified".into());
+        }
+        let mut next = || iter.next().ok_or_else(syntax_error);
+        match upper.as_str() {
+            "FROMMEMBER" => origin = Some(GeoOrigin::Member(next()?.clone())),
+            "FROMLONLAT" => {
+                let (long, lat) = (parse_float(next()?)?, parse_float(next()?)?);
+                let point = Point { lat, lon: long };
+                if let Some(err) = validate_coords(&point) {
+                    return Err(err);
+                }
+                origin = Some(GeoOrigin::LonLat(point));
+            }
+            "BYRADIUS" => {
+                let radius = next()?.parse().map_err(|_| "need numeric radius")?;
+                shape = Some(GeoShape::Radius(radius));
+                unit = Some(parse_unit(next()?)?);
+            }
+            "BYBOX" => {
+                let width = next()?.parse().map_err(|_| "need numeric width")?;
+                let height = next()?.parse().map_err(|_| "need numeric height")?;
+                shape = Some(GeoShape::Box { width, height });
+                unit = Some(parse_unit(next()?)?);
+            }
+            "ASC" => sort = Some(GeoSort::Asc),
+            "DESC" => sort = Some(GeoSort::Desc),
+            "COUNT" => {
+                let n = next()?
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or("COUNT must be > 0")?;
+                let any = iter.next_if(|a| a.eq_ignore_ascii_case("ANY")).is_some();
+                count = Some((n, any));
+            }
+            "WITHCOORD" => with_coord = true,
+            "WITHDIST" => with_dist = true,
+            "WITHHASH" => with_hash = true,
+            "STOREDIST" => store_dist = true,
+            _ => return Err(syntax_error()),
+        }
+    }
+    let query = GeoSearchQuery {
+        origin: origin.ok_or("exactly one of FROMMEMBER or FROMLONLAT can be specified")?,
+        shape: shape.ok_or("exactly one of BYRADIUS and BYBOX can be specified")?,
+        unit: unit.ok_or_else(syntax_error)?,
+        sort,
+        count,
+        with_coord,
+        with_dist,
+        with_hash,
+    };
+    Ok((query, store_dist))
+}
+
+fn parse_float(arg: &str) -> Result<f64, String> {
+    arg.parse::<f64>()
+        .ok()
+        .filter(|f| f.is_finite())
+        .ok_or_else(|| "value is not a valid float".into())
+}
+
+fn parse_unit(arg: &str) -> Result<GeoUnit, String> {
+    GeoUnit::try_from(arg).map_err(|e| e.to_string())
+}
+
+/// Parses GEORADIUS and GEORADIUSBYMEMBER once rewritten into their GEOSEARCH
+/// form `args`, looking for STORE/STOREDIST only among the trailing `options`.
+fn parse_georadius(key: &str, mut args: Vec<String>, mut options: Vec<String>) -> Command {
+    let mut destination = None;
+    while let Some(pos) = options
+        .iter()
+        .position(|a| a.eq_ignore_ascii_case("STORE") || a.eq_ignore_ascii_case("STOREDIST"))
+    {
+        let Some(dest) = options.get(pos + 1).cloned() else {
+            return Command::Error("syntax error".into());
+        };
+        destination = Some((dest, options[pos].eq_ignore_ascii_case("STOREDIST")));
+        options.drain(pos..=pos + 1);
+    }
+    args.extend(options);
+    match (parse_geo_query(&args), destination) {
+        (Ok((query, false)), None) => Command::Geosearch {
+            key: key.into(),
+            query,
+        },
+        (Ok((query, false)), Some((destination, store_dist))) if !query.has_with_flags() => {
+            Command::GeoSearchStore {
+                destination,
+                key: key.into(),
+                query,
+                store_dist,
+            }
+        }
+        (Ok((query, false)), Some(_)) if query.has_with_flags() => Command::Error(
+            "STORE option in GEORADIUS is not compatible with WITHDIST, WITHHASH and WITHCOORD options"
+                .into(),
+        ),
+        (Ok(_), _) => Command::Error("syntax error".into()),
+        (Err(e), _) => Command::Error(e),
+    }
 }
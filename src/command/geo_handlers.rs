@@ -0,0 +1,46 @@
+use super::response::{error_response, CommandResponse};
+use crate::{
+    protocol::Data,
+    store::{
+        core::InMemoryStore,
+        geo::{GeoMatch, GeoSearchQuery},
+    },
+};
+use rust_decimal::prelude::ToPrimitive;
+
+pub async fn geosearch(
+    key: String,
+    query: GeoSearchQuery,
+    store: &InMemoryStore,
+) -> CommandResponse {
+    match store.geosearch(key, &query).await {
+        Ok(matches) => {
+            let items = matches
+                .into_iter()
+                .map(|m| map_geo_match(m, &query))
+                .collect::<Vec<_>>();
+            CommandResponse::Single(String::from(&Data::Array(items)))
+        }
+        Err(e) => error_response(&e.to_string()),
+    }
+}
+
+fn map_geo_match(geo_match: GeoMatch, query: &GeoSearchQuery) -> Data {
+    if !query.has_with_flags() {
+        return Data::BStr(geo_match.member);
+    }
+    let mut item = vec![Data::BStr(geo_match.member)];
+    if query.with_dist {
+        item.push(Data::BStr(format!("{:.4}", geo_match.distance)));
+    }
+    if query.with_hash {
+        item.push(Data::Int(geo_match.score.to_i64().unwrap_or_default()));
+    }
+    if query.with_coord {
+        item.push(Data::Array(vec![
+            Data::BStr(geo_match.point.lon.to_string()),
+            Data::BStr(geo_match.point.lat.to_string()),
+        ]));
+    }
+    Data::Array(item)
+}
@@ -57,20 +57,54 @@ pub async fn psync(tx: &Sender<Vec<u8>>, ctx: &ServerContext) -> Result<()> {
     Ok(())
 }
 
-pub async fn info(context: &ServerContext) -> String {
-    context
-        .state
-        .lock()
-        .await
-        .get_section("replication")
-        .map(|x| {
-            x.iter()
-                .map(|(k, v)| format!("{k}:{v}"))
-                .collect::<Vec<String>>()
-                .join("\r\n")
-        })
-        .map(|s| encode_bstring(&s))
-        .unwrap_or_default()
+/// INFO's sections, each under a `# Section` header. Unknown sections are left out.
+pub async fn info(context: &ServerContext, section: Option<&str>) -> String {
+    let all = section.is_none_or(|s| ["all", "default", "everything"].contains(&s));
+    let mut sections = vec![];
+    if all || section == Some("persistence") {
+        let aof = match &context.aof {
+            Some(aof) => aof.info(),
+            None => vec![
+                ("aof_enabled", "0".into()),
+                ("aof_rewrite_in_progress", "0".into()),
+                ("aof_last_bgrewrite_status", "ok".into()),
+            ],
+        };
+        let persistence = context
+            .store
+            .persistence_info()
+            .into_iter()
+            .chain(aof)
+            .map(|(k, v)| format!("{k}:{v}"))
+            .collect();
+        sections.push(("Persistence", persistence));
+    }
+    if all || section == Some("replication") {
+        let replication = context
+            .state
+            .lock()
+            .await
+            .get_section("replication")
+            .map(|x| x.iter().map(|(k, v)| format!("{k}:{v}")).collect())
+            .unwrap_or_default();
+        sections.push(("Replication", replication));
+    }
+    if all || section == Some("stats") {
+        let stats = context
+            .channels
+            .stats()
+            .await
+            .into_iter()
+            .map(|(k, v)| format!("{k}:{v}"))
+            .collect();
+        sections.push(("Stats", stats));
+    }
+    let info = sections
+        .into_iter()
+        .map(|(name, lines): (&str, Vec<String>)| format!("# {name}\r\n{}", lines.join("\r\n")))
+        .collect::<Vec<_>>()
+        .join("\r\n\r\n");
+    encode_bstring(&info)
 }
 
 pub async fn wait(ctx: &ServerContext, min_num_acks: i64, timeout_ms: u64) -> i64 {
@@ -85,10 +119,12 @@ pub async fn wait(ctx: &ServerContext, min_num_acks: i64, timeout_ms: u64) -> i6
 pub async fn type_handler(key: &str, store: &InMemoryStore) -> String {
     match store.get(key).await {
         Some(value) => match value {
-            Value::String(_) => "string".to_string(),
+            Value::String(_) | Value::Integer(_) => "string".to_string(),
             Value::List(_) => "list".to_string(),
             Value::Stream { .. } => "stream".to_string(),
-            _ => panic!("Unexpected value type"),
+            Value::Set(_) => "set".to_string(),
+            Value::Hash(_) => "hash".to_string(),
+            Value::SortedSet(_) => "zset".to_string(),
         },
         None => "none".to_string(),
     }
@@ -1,5 +1,7 @@
 pub mod core;
+pub mod geo_handlers;
 pub mod handlers;
+pub mod replication;
 pub mod response;
 pub mod send;
 pub mod stream_handlers;
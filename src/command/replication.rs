@@ -0,0 +1,263 @@
+use super::core::Command;
+use crate::{
+    scripting::functions::{FunctionSubcommand, RestorePolicy},
+    store::{
+        consumer_group::XGroupSubcommand,
+        geo::{GeoOrigin, GeoSearchQuery, GeoShape, GeoSort},
+        stream::{TrimOptions, TrimStrategy},
+        value::Value,
+    },
+};
+
+impl Command {
+    /// Whether the command may modify the dataset, and so is propagated to replicas once it
+    /// has. Consumer group reads and claims aren't included: they propagate their effects.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Incr { .. }
+                | Command::Del(..)
+                | Command::ListPush { .. }
+                | Command::LPop(..)
+                | Command::BLPop(..)
+                | Command::XAdd { .. }
+                | Command::XTrim { .. }
+                | Command::XDel { .. }
+                | Command::XSetId { .. }
+                | Command::XGroup { .. }
+                | Command::XAck { .. }
+                | Command::ZAdd { .. }
+                | Command::ZRem(..)
+                | Command::Geoadd { .. }
+                | Command::GeoSearchStore { .. }
+        ) || matches!(self, Command::Function(subcommand) if subcommand.is_write())
+    }
+
+    /// The arguments that replay a write command on a replica. Expiries are absolute, so
+    /// replicas expire keys at the same moment as the master. Other commands have none.
+    pub fn to_args(&self) -> Vec<String> {
+        match self {
+            Command::Set {
+                key,
+                value: Value::String(value),
+                expiry,
+            } => {
+                let mut args = vec!["SET".into(), key.clone(), value.clone()];
+                if let Some(expiry) = expiry {
+                    args.extend(["PXAT".into(), expiry.to_string()]);
+                }
+                args
+            }
+            Command::Incr { key } => vec!["INCR".into(), key.clone()],
+            Command::Del(keys) => ["DEL".into()].into_iter().chain(keys.clone()).collect(),
+            Command::ListPush {
+                key,
+                values,
+                is_left,
+            } => {
+                let name = if *is_left { "LPUSH" } else { "RPUSH" };
+                [name.into(), key.clone()]
+                    .into_iter()
+                    .chain(values.iter().cloned())
+                    .collect()
+            }
+            Command::LPop(key, count) => vec!["LPOP".into(), key.clone(), count.to_string()],
+            Command::XAdd {
+                key,
+                id,
+                entry,
+                trim,
+                no_mkstream,
+            } => {
+                let mut args = vec!["XADD".into(), key.clone()];
+                if *no_mkstream {
+                    args.push("NOMKSTREAM".into());
+                }
+                if let Some(trim) = trim {
+                    args.extend(trim_args(trim));
+                }
+                args.push(id.clone());
+                for (field, value) in entry {
+                    args.extend([field.clone(), value.clone()]);
+                }
+                args
+            }
+            Command::XTrim { key, trim } => {
+                let mut args = vec!["XTRIM".into(), key.clone()];
+                args.extend(trim_args(trim));
+                args
+            }
+            Command::XDel { key, ids } => {
+                let mut args = vec!["XDEL".into(), key.clone()];
+                args.extend(ids.iter().map(ToString::to_string));
+                args
+            }
+            Command::XSetId {
+                key,
+                last_id,
+                entries_added,
+                max_deleted_id,
+            } => {
+                let mut args = vec!["XSETID".into(), key.clone(), last_id.to_string()];
+                if let Some(entries_added) = entries_added {
+                    args.extend(["ENTRIESADDED".into(), entries_added.to_string()]);
+                }
+                if let Some(max_deleted_id) = max_deleted_id {
+                    args.extend(["MAXDELETEDID".into(), max_deleted_id.to_string()]);
+                }
+                args
+            }
+            Command::XGroup {
+                key,
+                group,
+                subcommand,
+            } => xgroup_args(key, group, subcommand),
+            Command::XAck { key, group, ids } => {
+                let mut args = vec!["XACK".into(), key.clone(), group.clone()];
+                args.extend(ids.iter().map(ToString::to_string));
+                args
+            }
+            Command::ZAdd { key, score, member } => {
+                vec![
+                    "ZADD".into(),
+                    key.clone(),
+                    score.to_string(),
+                    member.clone(),
+                ]
+            }
+            Command::ZRem(key, member) => vec!["ZREM".into(), key.clone(), member.clone()],
+            Command::Function(subcommand) => function_args(subcommand),
+            Command::Geoadd {
+                key,
+                members,
+                options,
+            } => {
+                let mut args = vec!["GEOADD".into(), key.clone()];
+                for (flag, set) in [("NX", options.nx), ("XX", options.xx), ("CH", options.ch)] {
+                    if set {
+                        args.push(flag.into());
+                    }
+                }
+                for (point, member) in members {
+                    args.extend([point.lon.to_string(), point.lat.to_string(), member.clone()]);
+                }
+                args
+            }
+            Command::GeoSearchStore {
+                destination,
+                key,
+                query,
+                store_dist,
+            } => {
+                let mut args = vec!["GEOSEARCHSTORE".into(), destination.clone(), key.clone()];
+                args.extend(geo_query_args(query));
+                if *store_dist {
+                    args.push("STOREDIST".into());
+                }
+                args
+            }
+            _ => vec![],
+        }
+    }
+}
+
+fn trim_args(trim: &TrimOptions) -> Vec<String> {
+    let (name, threshold) = match &trim.strategy {
+        TrimStrategy::MaxLen(len) => ("MAXLEN", len.to_string()),
+        TrimStrategy::MinId(id) => ("MINID", id.to_string()),
+    };
+    let operator = if trim.approximate { "~" } else { "=" };
+    let mut args = vec![name.into(), operator.into(), threshold];
+    if let Some(limit) = trim.limit {
+        args.extend(["LIMIT".into(), limit.to_string()]);
+    }
+    args
+}
+
+fn xgroup_args(key: &str, group: &str, subcommand: &XGroupSubcommand) -> Vec<String> {
+    let (name, rest) = match subcommand {
+        XGroupSubcommand::Create {
+            id,
+            mkstream,
+            entries_read,
+        } => {
+            let mut rest = vec![id.clone()];
+            if *mkstream {
+                rest.push("MKSTREAM".into());
+            }
+            if let Some(entries_read) = entries_read {
+                rest.extend(["ENTRIESREAD".into(), entries_read.to_string()]);
+            }
+            ("CREATE", rest)
+        }
+        XGroupSubcommand::SetId { id, entries_read } => {
+            let mut rest = vec![id.clone()];
+            if let Some(entries_read) = entries_read {
+                rest.extend(["ENTRIESREAD".into(), entries_read.to_string()]);
+            }
+            ("SETID", rest)
+        }
+        XGroupSubcommand::Destroy => ("DESTROY", vec![]),
+        XGroupSubcommand::CreateConsumer(consumer) => ("CREATECONSUMER", vec![consumer.clone()]),
+        XGroupSubcommand::DelConsumer(consumer) => ("DELCONSUMER", vec![consumer.clone()]),
+    };
+    let mut args = vec!["XGROUP".into(), name.into(), key.into(), group.into()];
+    args.extend(rest);
+    args
+}
+
+fn geo_query_args(query: &GeoSearchQuery) -> Vec<String> {
+    let mut args = match &query.origin {
+        GeoOrigin::Member(member) => vec!["FROMMEMBER".into(), member.clone()],
+        GeoOrigin::LonLat(point) => vec![
+            "FROMLONLAT".into(),
+            point.lon.to_string(),
+            point.lat.to_string(),
+        ],
+    };
+    match query.shape {
+        GeoShape::Radius(radius) => args.extend(["BYRADIUS".into(), radius.to_string()]),
+        GeoShape::Box { width, height } => {
+            args.extend(["BYBOX".into(), width.to_string(), height.to_string()])
+        }
+    }
+    args.push(query.unit.to_string());
+    match query.sort {
+        Some(GeoSort::Asc) => args.push("ASC".into()),
+        Some(GeoSort::Desc) => args.push("DESC".into()),
+        None => (),
+    }
+    if let Some((count, any)) = query.count {
+        args.extend(["COUNT".into(), count.to_string()]);
+        if any {
+            args.push("ANY".into());
+        }
+    }
+    args
+}
+
+fn function_args(subcommand: &FunctionSubcommand) -> Vec<String> {
+    let mut args = vec!["FUNCTION".to_string()];
+    match subcommand {
+        FunctionSubcommand::Load { code, replace } => {
+            args.push("LOAD".into());
+            if *replace {
+                args.push("REPLACE".into());
+            }
+            args.push(code.clone());
+        }
+        FunctionSubcommand::Delete(name) => args.extend(["DELETE".into(), name.clone()]),
+        FunctionSubcommand::Flush => args.push("FLUSH".into()),
+        FunctionSubcommand::Restore { payload, policy } => {
+            let policy = match policy {
+                RestorePolicy::Append => "APPEND",
+                RestorePolicy::Replace => "REPLACE",
+                RestorePolicy::Flush => "FLUSH",
+            };
+            args.extend(["RESTORE".into(), payload.clone(), policy.into()]);
+        }
+        _ => return vec![],
+    }
+    args
+}
@@ -9,6 +9,12 @@ pub enum CommandResponse {
     ReplconfAck,
 }
 
+impl CommandResponse {
+    pub fn is_error(&self) -> bool {
+        matches!(self, CommandResponse::Single(data) if data.starts_with('-'))
+    }
+}
+
 impl From<CommandResponse> for String {
     fn from(response: CommandResponse) -> Self {
         match response {
@@ -75,6 +81,14 @@ pub fn array_response(items: Vec<String>) -> CommandResponse {
     CommandResponse::Single(encode_array_of_bstrings(items.as_slice()))
 }
 
+pub fn optional_array_response(items: Vec<Option<String>>) -> CommandResponse {
+    let items = items
+        .iter()
+        .map(|item| item.as_deref().map(encode_bstring).unwrap_or_else(null))
+        .collect::<Vec<_>>();
+    CommandResponse::Single(encode_resp_array(&items))
+}
+
 pub fn array_of_arrays_response(items: Vec<Vec<String>>) -> CommandResponse {
     let mut result = String::new();
     result.push_str(&format!("*{}\r\n", items.len()));
@@ -1,122 +1,501 @@
-use super::response::CommandResponse;
+use super::response::{error_response, null_array_response, CommandResponse};
 use crate::{
     protocol::Data,
-    store::{core::InMemoryStore, stream::StreamQueryResult, subscribe::wait_for_new_data},
+    store::{
+        consumer_group::{
+            AutoClaimResult, ConsumerGroup, GroupEntries, GroupOutcome, PendingDetail,
+            PendingSummary,
+        },
+        core::InMemoryStore,
+        stream::{
+            get_unix_ms, Stream, StreamEntry, StreamId, StreamRange, STREAM_NODE_MAX_ENTRIES,
+        },
+        subscribe::DataWaiter,
+    },
 };
-use std::ops::Bound::{self, *};
+use anyhow::{bail, Result};
+use std::{
+    ops::Bound::*,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone)]
+pub enum XInfoSubcommand {
+    /// `full` holds the FULL form's COUNT.
+    Stream {
+        key: String,
+        full: Option<usize>,
+    },
+    Groups(String),
+    Consumers {
+        key: String,
+        group: String,
+    },
+}
 
 #[derive(Debug)]
 pub struct StreamData {
     pub key: String,
-    pub entries: Vec<(String, Vec<(String, String)>)>,
+    pub entries: Vec<(StreamId, Vec<(String, String)>)>,
 }
 
 pub struct StreamFilter {
     pub key: String,
-    pub range: (Bound<String>, Bound<String>),
+    pub range: StreamRange,
+    pub count: Option<usize>,
 }
 
 pub async fn xread(
     streams: Vec<(String, String)>,
+    count: Option<usize>,
     block: Option<u64>,
     store: &InMemoryStore,
 ) -> Option<CommandResponse> {
-    let key_ranges = streams
-        .iter()
-        .map(|(key, id)| StreamFilter {
-            key: key.clone(),
-            range: (Excluded(id.clone()), Unbounded),
-        })
-        .collect();
-    let filtered_streams = match (store.get_filtered_streams(key_ranges).await, block) {
-        (
-            StreamQueryResult {
-                data: Some(data), ..
+    let mut key_ids = vec![];
+    for (key, id) in streams {
+        let id = match id.as_str() {
+            "$" => store.stream_last_id(&key).await.unwrap_or(StreamId::MIN),
+            _ => match StreamId::parse(&id, 0) {
+                Ok(id) => id,
+                Err(e) => return Some(error_response(&e.to_string())),
             },
-            None,
-            ..,
-        ) => Some(data),
-        (
-            StreamQueryResult {
-                data: None,
-                max_ids,
-            },
-            Some(timeout),
-        ) => {
-            let updated_key = wait_for_new_data(
-                streams
-                    .iter()
-                    .map(|f| &f.0)
-                    .cloned()
-                    .collect::<Vec<_>>()
-                    .as_slice(),
-                timeout,
-                store,
-            )
-            .await?;
-            let mut id = streams
-                .iter()
-                .find_map(|(key, id)| if key == &updated_key { Some(id) } else { None })
-                .cloned()?;
-            if id == "$" {
-                id = max_ids
-                    .into_iter()
-                    .find_map(|(key, id)| if key == updated_key { id } else { None })
-                    .unwrap_or("0-0".to_string());
+        };
+        key_ids.push((key, id));
+    }
+    let filters = || {
+        key_ids
+            .iter()
+            .map(|(key, id)| StreamFilter {
+                key: key.clone(),
+                range: (Excluded(*id), Unbounded),
+                count,
+            })
+            .collect::<Vec<_>>()
+    };
+    let keys = key_ids
+        .iter()
+        .map(|(key, _)| key.clone())
+        .collect::<Vec<_>>();
+    let deadline = block.map(Deadline::new);
+    let filtered_streams = loop {
+        let waiter = match deadline.as_ref().and_then(Deadline::remaining) {
+            Some(timeout) => Some((DataWaiter::new(&keys, store).await, timeout)),
+            None => None,
+        };
+        match (store.get_filtered_streams(filters()).await, waiter) {
+            (Some(data), waiter) => {
+                if let Some((waiter, _)) = waiter {
+                    waiter.cancel(store).await;
+                }
+                break data;
             }
-            store
-                .get_filtered_streams(vec![StreamFilter {
-                    key: updated_key,
-                    range: (Excluded(id.clone()), Unbounded),
-                }])
-                .await
-                .data
-        }
-        _ => None,
-    }?;
+            // Whatever woke us up, answer with every requested stream that has new data.
+            (None, Some((waiter, timeout))) => waiter.wait(timeout, store).await?,
+            (None, None) => return None,
+        };
+    };
     let arrays = map_xread_response(filtered_streams);
     Some(CommandResponse::Single(String::from(&arrays)))
 }
 
+/// Time left for a blocking read, where a zero BLOCK timeout waits forever.
+struct Deadline(Option<Instant>);
+
+impl Deadline {
+    fn new(timeout_ms: u64) -> Self {
+        Self((timeout_ms > 0).then(|| Instant::now() + Duration::from_millis(timeout_ms)))
+    }
+
+    /// Milliseconds to pass to `DataWaiter::wait`, or `None` once expired.
+    fn remaining(&self) -> Option<u64> {
+        let Some(deadline) = self.0 else {
+            return Some(0);
+        };
+        let remaining = deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis() as u64;
+        (remaining > 0).then_some(remaining)
+    }
+}
+
 pub async fn xrange(
     key: String,
     start: String,
     end: String,
+    count: Option<usize>,
+    rev: bool,
     store: &InMemoryStore,
-) -> Option<CommandResponse> {
-    let range = match (start.as_str(), end.as_str()) {
-        ("-", "+") => (Unbounded, Unbounded),
-        ("-", _) => (Unbounded, Included(end)),
-        (_, "+") => (Included(start), Unbounded),
-        _ => (Included(start), Included(end)),
+) -> CommandResponse {
+    let range = match (
+        StreamId::parse_range_start(&start),
+        StreamId::parse_range_end(&end),
+    ) {
+        (Ok(start), Ok(end)) => (start, end),
+        (Err(e), _) | (_, Err(e)) => return error_response(&e.to_string()),
     };
-    let key_ranges = vec![StreamFilter { key, range }];
-    let filtered_stream = store.get_filtered_streams(key_ranges).await;
-    let stream = filtered_stream.data?.into_iter().next()?;
-    let arrays = map_xrange_response(stream.entries);
-    Some(CommandResponse::Single(String::from(&arrays)))
+    match store.stream_range(&key, range, count, rev).await {
+        Ok(entries) => CommandResponse::Single(String::from(&map_xrange_response(entries))),
+        Err(e) => error_response(&e.to_string()),
+    }
 }
 
-fn map_xrange_response(entries: Vec<(String, Vec<(String, String)>)>) -> Data {
+pub async fn xinfo(subcommand: XInfoSubcommand, store: &InMemoryStore) -> CommandResponse {
+    let key = match &subcommand {
+        XInfoSubcommand::Stream { key, .. }
+        | XInfoSubcommand::Groups(key)
+        | XInfoSubcommand::Consumers { key, .. } => key.clone(),
+    };
+    let info = store
+        .read_stream(&key, |stream| map_xinfo(subcommand, stream, &key))
+        .await;
+    match info.and_then(|info| info) {
+        Ok(data) => CommandResponse::Single(String::from(&data)),
+        Err(e) => error_response(&e.to_string()),
+    }
+}
+
+fn map_xinfo(subcommand: XInfoSubcommand, stream: &Stream, key: &str) -> Result<Data> {
+    let now = get_unix_ms();
+    let data = match subcommand {
+        XInfoSubcommand::Stream { full: None, .. } => map_stream_info(stream),
+        XInfoSubcommand::Stream {
+            full: Some(count), ..
+        } => map_stream_info_full(stream, count),
+        XInfoSubcommand::Groups(_) => Data::Array(
+            stream
+                .groups
+                .iter()
+                .map(|(name, group)| map_group_info(stream, name, group))
+                .collect(),
+        ),
+        XInfoSubcommand::Consumers { group, .. } => match stream.groups.get(&group) {
+            Some(group) => Data::Array(
+                group
+                    .consumers
+                    .iter()
+                    .map(|(name, consumer)| {
+                        let inactive = consumer
+                            .active_time
+                            .map_or(-1, |active| now.saturating_sub(active) as i64);
+                        Data::Array(vec![
+                            Data::BStr("name".into()),
+                            Data::BStr(name.clone()),
+                            Data::BStr("pending".into()),
+                            Data::Int(consumer.pending.len() as i64),
+                            Data::BStr("idle".into()),
+                            Data::Int(now.saturating_sub(consumer.seen_time) as i64),
+                            Data::BStr("inactive".into()),
+                            Data::Int(inactive),
+                        ])
+                    })
+                    .collect(),
+            ),
+            None => bail!("NOGROUP No such consumer group '{group}' for key name '{key}'"),
+        },
+    };
+    Ok(data)
+}
+
+/// Fields shared by the summary and FULL forms of XINFO STREAM.
+fn stream_info_header(stream: &Stream) -> Vec<Data> {
+    // Entries are kept in a flat map rather than a radix tree of listpacks, so report
+    // the node count Redis would have with full nodes.
+    let nodes = stream.len().div_ceil(STREAM_NODE_MAX_ENTRIES) as i64;
+    vec![
+        Data::BStr("length".into()),
+        Data::Int(stream.len() as i64),
+        Data::BStr("radix-tree-keys".into()),
+        Data::Int(nodes),
+        Data::BStr("radix-tree-nodes".into()),
+        Data::Int(nodes + 1),
+        Data::BStr("last-generated-id".into()),
+        Data::BStr(stream.last_id.to_string()),
+        Data::BStr("max-deleted-entry-id".into()),
+        Data::BStr(stream.max_deleted_id.to_string()),
+        Data::BStr("entries-added".into()),
+        Data::Int(stream.entries_added as i64),
+        Data::BStr("recorded-first-entry-id".into()),
+        Data::BStr(stream.first_id().unwrap_or_default().to_string()),
+    ]
+}
+
+fn map_stream_info(stream: &Stream) -> Data {
+    let edge_entry = |entry: Option<(&StreamId, &StreamEntry)>| match entry {
+        Some((id, entry)) => map_entry(*id, entry.clone()),
+        None => Data::Null,
+    };
+    let mut info = stream_info_header(stream);
+    info.extend([
+        Data::BStr("groups".into()),
+        Data::Int(stream.groups.len() as i64),
+        Data::BStr("first-entry".into()),
+        edge_entry(stream.entries.first_key_value()),
+        Data::BStr("last-entry".into()),
+        edge_entry(stream.entries.last_key_value()),
+    ]);
+    Data::Array(info)
+}
+
+/// XINFO STREAM FULL, with at most `count` entries and PEL items (0 for all of them).
+fn map_stream_info_full(stream: &Stream, count: usize) -> Data {
+    let count = Some(count).filter(|c| *c > 0).unwrap_or(usize::MAX);
+    let groups = stream
+        .groups
+        .iter()
+        .map(|(name, group)| {
+            let pending = group
+                .pending
+                .iter()
+                .take(count)
+                .map(|(id, entry)| {
+                    Data::Array(vec![
+                        Data::BStr(id.to_string()),
+                        Data::BStr(entry.consumer.clone()),
+                        Data::Int(entry.delivery_time as i64),
+                        Data::Int(entry.delivery_count as i64),
+                    ])
+                })
+                .collect();
+            let consumers = group
+                .consumers
+                .iter()
+                .map(|(name, consumer)| {
+                    let pending = consumer
+                        .pending
+                        .iter()
+                        .take(count)
+                        .filter_map(|id| Some((id, group.pending.get(id)?)))
+                        .map(|(id, entry)| {
+                            Data::Array(vec![
+                                Data::BStr(id.to_string()),
+                                Data::Int(entry.delivery_time as i64),
+                                Data::Int(entry.delivery_count as i64),
+                            ])
+                        })
+                        .collect();
+                    Data::Array(vec![
+                        Data::BStr("name".into()),
+                        Data::BStr(name.clone()),
+                        Data::BStr("seen-time".into()),
+                        Data::Int(consumer.seen_time as i64),
+                        Data::BStr("active-time".into()),
+                        Data::Int(consumer.active_time.map_or(-1, |t| t as i64)),
+                        Data::BStr("pel-count".into()),
+                        Data::Int(consumer.pending.len() as i64),
+                        Data::BStr("pending".into()),
+                        Data::Array(pending),
+                    ])
+                })
+                .collect();
+            let mut info = map_group_fields(stream, name, group);
+            info.extend([
+                Data::BStr("pel-count".into()),
+                Data::Int(group.pending.len() as i64),
+                Data::BStr("pending".into()),
+                Data::Array(pending),
+                Data::BStr("consumers".into()),
+                Data::Array(consumers),
+            ]);
+            Data::Array(info)
+        })
+        .collect();
+    let mut info = stream_info_header(stream);
+    info.extend([
+        Data::BStr("entries".into()),
+        map_xrange_response(stream.range((Unbounded, Unbounded), Some(count), false)),
+        Data::BStr("groups".into()),
+        Data::Array(groups),
+    ]);
+    Data::Array(info)
+}
+
+fn map_group_info(stream: &Stream, name: &str, group: &ConsumerGroup) -> Data {
+    let mut info = vec![
+        Data::BStr("name".into()),
+        Data::BStr(name.into()),
+        Data::BStr("consumers".into()),
+        Data::Int(group.consumers.len() as i64),
+        Data::BStr("pending".into()),
+        Data::Int(group.pending.len() as i64),
+    ];
+    info.extend(map_group_fields(stream, name, group).into_iter().skip(2));
+    Data::Array(info)
+}
+
+/// Name, last delivered ID, entries read and lag of a group.
+fn map_group_fields(stream: &Stream, name: &str, group: &ConsumerGroup) -> Vec<Data> {
+    let optional_int = |value: Option<u64>| value.map_or(Data::Null, |v| Data::Int(v as i64));
+    vec![
+        Data::BStr("name".into()),
+        Data::BStr(name.into()),
+        Data::BStr("last-delivered-id".into()),
+        Data::BStr(group.last_delivered_id.to_string()),
+        Data::BStr("entries-read".into()),
+        optional_int(group.entries_read),
+        Data::BStr("lag".into()),
+        optional_int(stream.group_lag(group)),
+    ]
+}
+
+/// XREADGROUP only blocks when every stream asks for new entries (`>`) and none were
+/// available.
+pub async fn xreadgroup(
+    group: &str,
+    consumer: &str,
+    streams: &[(String, String)],
+    count: Option<usize>,
+    block: Option<u64>,
+    no_ack: bool,
+    store: &InMemoryStore,
+) -> Result<GroupOutcome<Vec<(String, GroupEntries)>>> {
+    let deadline = block.map(Deadline::new);
+    let keys = streams
+        .iter()
+        .map(|(key, _)| key.clone())
+        .collect::<Vec<_>>();
+    let blocks = streams.iter().all(|(_, id)| id == ">");
+    loop {
+        let waiter = match deadline.as_ref().and_then(Deadline::remaining) {
+            Some(timeout) if blocks => Some((DataWaiter::new(&keys, store).await, timeout)),
+            _ => None,
+        };
+        let outcome = store
+            .read_group(group, consumer, streams, count, no_ack)
+            .await;
+        let Some((waiter, timeout)) = waiter else {
+            return outcome;
+        };
+        let outcome = match outcome {
+            Ok(outcome) if outcome.result.is_empty() => outcome,
+            outcome => {
+                waiter.cancel(store).await;
+                return outcome;
+            }
+        };
+        // Another consumer of the group may have taken the new entries first, in which
+        // case keep waiting.
+        if waiter.wait(timeout, store).await.is_none() {
+            return Ok(outcome);
+        }
+    }
+}
+
+pub fn map_xreadgroup_response(streams: Vec<(String, GroupEntries)>) -> CommandResponse {
+    if streams.is_empty() {
+        return null_array_response();
+    }
+    let arrays = Data::Array(
+        streams
+            .into_iter()
+            .map(|(key, entries)| Data::Array(vec![Data::BStr(key), map_group_entries(entries)]))
+            .collect(),
+    );
+    CommandResponse::Single(String::from(&arrays))
+}
+
+pub fn map_claim_response(entries: GroupEntries, just_id: bool) -> CommandResponse {
+    let data = match just_id {
+        true => map_ids(entries.into_iter().map(|(id, _)| id)),
+        false => map_group_entries(entries),
+    };
+    CommandResponse::Single(String::from(&data))
+}
+
+pub fn map_autoclaim_response(result: AutoClaimResult, just_id: bool) -> CommandResponse {
+    let claimed = match just_id {
+        true => map_ids(result.claimed.into_iter().map(|(id, _)| id)),
+        false => map_group_entries(result.claimed),
+    };
+    let data = Data::Array(vec![
+        Data::BStr(result.next_id.to_string()),
+        claimed,
+        map_ids(result.deleted.into_iter()),
+    ]);
+    CommandResponse::Single(String::from(&data))
+}
+
+pub fn map_pending_summary(summary: PendingSummary) -> CommandResponse {
+    let (first, last) = match summary.bounds {
+        Some((first, last)) => (Data::BStr(first.to_string()), Data::BStr(last.to_string())),
+        None => (Data::Null, Data::Null),
+    };
+    let consumers = match summary.consumers.is_empty() {
+        true => Data::Null,
+        false => Data::Array(
+            summary
+                .consumers
+                .into_iter()
+                .map(|(name, count)| {
+                    Data::Array(vec![Data::BStr(name), Data::BStr(count.to_string())])
+                })
+                .collect(),
+        ),
+    };
+    let data = Data::Array(vec![
+        Data::Int(summary.count as i64),
+        first,
+        last,
+        consumers,
+    ]);
+    CommandResponse::Single(String::from(&data))
+}
+
+pub fn map_pending_details(details: Vec<PendingDetail>) -> CommandResponse {
+    let data = Data::Array(
+        details
+            .into_iter()
+            .map(|detail| {
+                Data::Array(vec![
+                    Data::BStr(detail.id.to_string()),
+                    Data::BStr(detail.consumer),
+                    Data::Int(detail.idle as i64),
+                    Data::Int(detail.delivery_count as i64),
+                ])
+            })
+            .collect(),
+    );
+    CommandResponse::Single(String::from(&data))
+}
+
+/// Like XRANGE entries, except that entries deleted from the stream since they were
+/// delivered show up as nil.
+fn map_group_entries(entries: GroupEntries) -> Data {
     Data::Array(
         entries
             .into_iter()
-            .map(|(id, entries)| {
-                vec![
-                    Data::BStr(id.clone()),
-                    Data::Array(
-                        entries
-                            .into_iter()
-                            .flat_map(|(k, v)| vec![Data::BStr(k), Data::BStr(v)])
-                            .collect::<Vec<_>>(),
-                    ),
-                ]
+            .map(|(id, entry)| match entry {
+                Some(entry) => map_entry(id, entry),
+                None => Data::Array(vec![Data::BStr(id.to_string()), Data::Null]),
             })
-            .map(Data::Array)
+            .collect(),
+    )
+}
+
+fn map_ids(ids: impl Iterator<Item = StreamId>) -> Data {
+    Data::Array(ids.map(|id| Data::BStr(id.to_string())).collect())
+}
+
+fn map_xrange_response(entries: Vec<(StreamId, Vec<(String, String)>)>) -> Data {
+    Data::Array(
+        entries
+            .into_iter()
+            .map(|(id, entry)| map_entry(id, entry))
             .collect::<Vec<_>>(),
     )
 }
 
+fn map_entry(id: StreamId, entry: Vec<(String, String)>) -> Data {
+    Data::Array(vec![
+        Data::BStr(id.to_string()),
+        Data::Array(
+            entry
+                .into_iter()
+                .flat_map(|(k, v)| vec![Data::BStr(k), Data::BStr(v)])
+                .collect::<Vec<_>>(),
+        ),
+    ])
+}
+
 fn map_xread_response(streams: Vec<StreamData>) -> Data {
     Data::Array(
         streams
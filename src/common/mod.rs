@@ -14,13 +14,169 @@ pub fn encode_sstring(val: &str) -> String {
 }
 
 pub fn encode_int(val: i64) -> String {
-    format!(":{}{}\r\n", if val < 0 { "-" } else { "+" }, val)
+    format!(":{val}\r\n")
 }
 
+/// Error codes sent as-is instead of under the generic `ERR` prefix.
+const ERROR_CODES: [&str; 10] = [
+    "WRONGTYPE",
+    "BUSYGROUP",
+    "NOGROUP",
+    "EXECABORT",
+    "NOSCRIPT",
+    "BUSY",
+    "NOTBUSY",
+    "UNKILLABLE",
+    "READONLY",
+    "NOPROTO",
+];
+
 pub fn encode_error(val: &str) -> String {
+    let code = val.split_once(' ').map_or(val, |(code, _)| code);
+    if ERROR_CODES.contains(&code) {
+        return format!("-{val}\r\n");
+    }
     format!("-ERR {val}\r\n")
 }
 
+/// Matches `text` against a glob-style pattern supporting `*`, `?`, `[...]` classes (with
+/// `^` negation and `a-z` ranges) and `\` escapes.
+///
+/// Only the most recent `*` is backtracked to, as a later one can match anything an
+/// earlier one could, so matching takes O(pattern × text) time whatever the pattern.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let Some(tokens) = glob_tokens(&pattern.chars().collect::<Vec<_>>()) else {
+        return false;
+    };
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // The token after the last `*` and the text position it was last tried from.
+    let mut backtrack = None;
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(GlobToken::Star) => {
+                p += 1;
+                backtrack = Some((p, t));
+                continue;
+            }
+            Some(token) if token.matches(text[t]) => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+        let Some((star_p, star_t)) = backtrack else {
+            return false;
+        };
+        // Let the star swallow one more character and retry from there.
+        (p, t) = (star_p, star_t + 1);
+        backtrack = Some((p, t));
+    }
+    tokens[p..]
+        .iter()
+        .all(|token| matches!(token, GlobToken::Star))
+}
+
+enum GlobToken {
+    /// Any run of characters. Consecutive stars are collapsed into one.
+    Star,
+    Any,
+    Class {
+        negated: bool,
+        /// Inclusive ranges, with single characters as ranges of one.
+        ranges: Vec<(char, char)>,
+    },
+    Literal(char),
+}
+
+impl GlobToken {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            GlobToken::Star | GlobToken::Any => true,
+            GlobToken::Class { negated, ranges } => {
+                ranges.iter().any(|(low, high)| (low..=high).contains(&&c)) != *negated
+            }
+            GlobToken::Literal(literal) => *literal == c,
+        }
+    }
+}
+
+/// Splits a pattern into tokens, or `None` if it has an unterminated class, which
+/// matches nothing.
+fn glob_tokens(mut pattern: &[char]) -> Option<Vec<GlobToken>> {
+    let mut tokens = vec![];
+    loop {
+        let (token, rest) = match pattern {
+            [] => return Some(tokens),
+            ['*', rest @ ..] => {
+                if matches!(tokens.last(), Some(GlobToken::Star)) {
+                    pattern = rest;
+                    continue;
+                }
+                (GlobToken::Star, rest)
+            }
+            ['?', rest @ ..] => (GlobToken::Any, rest),
+            ['[', rest @ ..] => {
+                let (negated, class) = match rest {
+                    ['^', class @ ..] => (true, class),
+                    class => (false, class),
+                };
+                let end = class.iter().position(|&c| c == ']')?;
+                let mut ranges = vec![];
+                let mut i = 0;
+                while i < end {
+                    if i + 2 < end && class[i + 1] == '-' {
+                        ranges.push((class[i].min(class[i + 2]), class[i].max(class[i + 2])));
+                        i += 3;
+                    } else {
+                        ranges.push((class[i], class[i]));
+                        i += 1;
+                    }
+                }
+                (GlobToken::Class { negated, ranges }, &class[end + 1..])
+            }
+            ['\\', escaped, rest @ ..] | [escaped, rest @ ..] => {
+                (GlobToken::Literal(*escaped), rest)
+            }
+        };
+        tokens.push(token);
+        pattern = rest;
+    }
+}
+
+/// Number of hash slots keys are spread over in cluster mode.
+const CLUSTER_SLOTS: u16 = 16384;
+
+/// The cluster hash slot of a key. Only the part between the first `{` and the next `}`
+/// is hashed when it's non-empty, so related keys can share a slot.
+pub fn key_slot(key: &str) -> u16 {
+    let bytes = key.as_bytes();
+    let hashed = bytes
+        .iter()
+        .position(|&b| b == b'{')
+        .and_then(|start| {
+            let tag = &bytes[start + 1..];
+            let end = tag.iter().position(|&b| b == b'}')?;
+            (end > 0).then(|| &tag[..end])
+        })
+        .unwrap_or(bytes);
+    crc16(hashed) % CLUSTER_SLOTS
+}
+
+/// CRC16-CCITT (XMODEM), as used for cluster key slots.
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
 pub fn parse_string_args(val: &[Data]) -> Vec<String> {
     val.iter()
         .filter_map(|x| {
@@ -3,5 +3,6 @@ pub mod command;
 pub mod common;
 pub mod protocol;
 pub mod rdb;
+pub mod scripting;
 pub mod server;
 pub mod store;
@@ -2,53 +2,126 @@ use anyhow::Result;
 use codecrafters_redis::{
     command::core::Command,
     server::{
+        aof::{AofConfig, AppendOnlyFile, FsyncPolicy},
         config::get_config_value,
         connection_handler::{ChannelType, ConnectionHandler},
         context::ServerContext,
         replica::init_replica,
     },
+    store::{
+        persistence::{rdb_path, save_points},
+        tracking::run_as_client,
+    },
 };
+use std::time::Duration;
 use tokio::{
     net::TcpListener,
+    signal::unix::{signal, SignalKind},
     sync::mpsc::{self, Receiver},
+    time::interval,
 };
 
+/// How often expired keys are removed without waiting for them to be read.
+const ACTIVE_EXPIRY_INTERVAL: Duration = Duration::from_millis(100);
+/// How often save points are checked against the writes since the last save.
+const SAVE_POINT_INTERVAL: Duration = Duration::from_secs(1);
+/// How often appended commands are flushed to disk with `appendfsync everysec`.
+const AOF_FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let listen_port = get_config_value("port").unwrap_or("6379".to_string());
     let listener = TcpListener::bind(format!("127.0.0.1:{listen_port}")).await?;
     let (tx, rx) = mpsc::channel::<ChannelType>(100);
-    let context = ServerContext::default();
+    let mut context = ServerContext::default();
+    // The append-only file replaces the snapshot, and is only written to once replayed.
+    if let Some(config) = AofConfig::from_config() {
+        context.aof = Some(AppendOnlyFile::open(config, &context).await?);
+    }
 
     let context_clone = context.clone();
-    let event_loop_future = tokio::spawn(async move { event_loop(rx, context_clone).await });
+    tokio::spawn(async move { event_loop(rx, context_clone).await });
 
-    if let Some(val) = get_config_value("replicaof") {
-        let tx = tx.clone();
-        if let Err(e) = init_replica(&val, &listen_port, tx).await {
-            eprintln!("Failed to initialize replica: {e}");
+    let context_clone = context.clone();
+    tokio::spawn(async move {
+        let mut ticks = interval(ACTIVE_EXPIRY_INTERVAL);
+        loop {
+            ticks.tick().await;
+            context_clone.remove_expired().await;
+        }
+    });
+
+    if let Some(aof) = context.aof.clone() {
+        if aof.fsync_policy() == FsyncPolicy::EverySec {
+            tokio::spawn(async move {
+                let mut ticks = interval(AOF_FSYNC_INTERVAL);
+                loop {
+                    ticks.tick().await;
+                    let aof = aof.clone();
+                    if let Ok(Err(e)) = tokio::task::spawn_blocking(move || aof.fsync()).await {
+                        eprintln!("Failed to fsync the append only file: {e}");
+                    }
+                }
+            });
         }
     }
 
-    while let Ok((stream, _)) = listener.accept().await {
-        let context = context.clone();
-        let tx = tx.clone();
+    let points = save_points();
+    if !points.is_empty() {
+        let store = context.store.clone();
         tokio::spawn(async move {
-            if let Err(e) = ConnectionHandler::new(stream, tx, context).handle().await {
-                eprintln!("Connection ended with error: {e}");
+            let mut ticks = interval(SAVE_POINT_INTERVAL);
+            loop {
+                ticks.tick().await;
+                store.save_if_due(&points, rdb_path()).await;
             }
         });
     }
 
-    event_loop_future.await?;
-    Ok(())
+    if let Some(val) = get_config_value("replicaof") {
+        let tx = tx.clone();
+        if let Err(e) = init_replica(&val, &listen_port, tx).await {
+            eprintln!("Failed to initialize replica: {e}");
+        }
+    }
+
+    // Returning from main drops the runtime, which closes every connection.
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let context = context.clone();
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = ConnectionHandler::new(stream, tx, context).handle().await {
+                            eprintln!("Connection ended with error: {e}");
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept connection: {e}"),
+            },
+            _ = context.shutdown.notified() => break,
+            _ = terminate.recv() => if shutdown_on_signal(&context, "SIGTERM").await {
+                break;
+            },
+            _ = interrupt.recv() => if shutdown_on_signal(&context, "SIGINT").await {
+                break;
+            },
+        }
+    }
+    // Exits rather than dropping the runtime, which would wait for a script still running
+    // after SHUTDOWN NOSAVE.
+    std::process::exit(0)
 }
 
 async fn event_loop(mut rx: Receiver<ChannelType>, context: ServerContext) -> () {
-    while let Some((task, result_tx)) = rx.recv().await {
+    while let Some((task, result_tx, client)) = rx.recv().await {
         match task {
-            Command::Transaction(commands) => {
-                let result = context.process_transaction(commands).await;
+            Command::Transaction(commands, watched) => {
+                let result =
+                    run_as_client(client, context.process_transaction(commands, watched)).await;
                 if result_tx.is_some() && result_tx.unwrap().send(result).is_err() {
                     eprintln!("Failed to send response to connection handler.");
                 }
@@ -56,14 +129,15 @@ async fn event_loop(mut rx: Receiver<ChannelType>, context: ServerContext) -> ()
             _ if task.is_blocking() => {
                 let context = context.clone();
                 tokio::spawn(async move {
-                    let result = context.execute_command(task).await;
+                    let command = context.store.run_exclusive(context.execute_command(task));
+                    let result = run_as_client(client, command).await;
                     if let Some(tx) = result_tx {
                         let _ = tx.send(result);
                     }
                 });
             }
             _ => {
-                let result = context.execute_command(task).await;
+                let result = run_as_client(client, context.execute_command(task)).await;
                 if result_tx.is_some() && result_tx.unwrap().send(result).is_err() {
                     eprintln!("Failed to send response to connection handler.");
                 }
@@ -71,3 +145,16 @@ async fn event_loop(mut rx: Receiver<ChannelType>, context: ServerContext) -> ()
         }
     }
 }
+
+/// Saves like SHUTDOWN does, returning whether to exit. If the save fails the server keeps
+/// running.
+async fn shutdown_on_signal(context: &ServerContext, name: &str) -> bool {
+    eprintln!("Received {name}, shutting down");
+    match context.prepare_shutdown(None, &rdb_path()).await {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to save before shutting down, not exiting: {e}");
+            false
+        }
+    }
+}
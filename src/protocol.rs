@@ -10,13 +10,24 @@ pub enum Data {
     Int(i64),
     Array(Vec<Data>),
     SimpleError(String),
+    Null,
 }
 impl Data {
     pub fn deserialize(val: &str) -> (Self, usize) {
+        Self::try_deserialize(val).expect("Unsupported data type")
+    }
+
+    /// Parses the first value in `val`, or `None` if it's malformed, cut short, or of a
+    /// type this parser doesn't know, such as the RESP3 ones.
+    pub fn try_deserialize(val: &str) -> Option<(Self, usize)> {
         match val {
+            _ if val.starts_with("$-1\r\n") || val.starts_with("*-1\r\n") => Some((Data::Null, 5)),
             _ if val.starts_with("$") => parse_bulk_string(val),
             _ if val.starts_with("+") => parse_simple_string(val),
-            _ => panic!("Unsupported data type"),
+            _ if val.starts_with("-") => parse_error(val),
+            _ if val.starts_with(":") => parse_integer(val),
+            _ if val.starts_with("*") => parse_array(val),
+            _ => None,
         }
     }
 }
@@ -25,8 +36,9 @@ impl From<&Data> for String {
         match data {
             Data::BStr(s) => format!("${}\r\n{}\r\n", s.len(), s),
             Data::SStr(s) => format!("+{s}\r\n"),
-            Data::Int(i) => format!(":{}{}\r\n", if *i < 0 { "-" } else { "+" }, i),
+            Data::Int(i) => format!(":{i}\r\n"),
             Data::SimpleError(e) => format!("-ERR {e}\r\n"),
+            Data::Null => "$-1\r\n".to_string(),
             Data::Array(arr) => {
                 let mut result = String::new();
                 result.push_str(&format!("*{}\r\n", arr.len()));
@@ -62,24 +74,49 @@ impl From<RedisArray> for String {
     }
 }
 
-pub fn get_len(val: &str) -> (usize, usize) {
-    let len_str = &val[1..val.find(CRLF).unwrap()];
-    (len_str.parse().unwrap(), len_str.len() + CRLF_LEN + 1)
+pub fn get_len(val: &str) -> Option<(usize, usize)> {
+    let len_str = val.get(1..val.find(CRLF)?)?;
+    Some((len_str.parse().ok()?, len_str.len() + CRLF_LEN + 1))
 }
 
-fn parse_bulk_string(val: &str) -> (Data, usize) {
-    let (data_len, data_start) = get_len(val);
+fn parse_bulk_string(val: &str) -> Option<(Data, usize)> {
+    let (data_len, data_start) = get_len(val)?;
     let data_end = data_start + data_len;
-    (
-        Data::BStr(val[data_start..data_end].to_string()),
+    Some((
+        Data::BStr(val.get(data_start..data_end)?.to_string()),
         data_end + CRLF_LEN,
-    )
+    ))
 }
 
-fn parse_simple_string(val: &str) -> (Data, usize) {
-    let data_end = val.find(CRLF).unwrap();
-    (
-        Data::SStr(val[1..data_end].to_string()),
-        data_end + CRLF_LEN,
-    )
+/// The text of a single-line value, between its type prefix and the CRLF, and the
+/// length of the whole line.
+fn parse_line(val: &str) -> Option<(&str, usize)> {
+    let data_end = val.find(CRLF)?;
+    Some((&val[1..data_end], data_end + CRLF_LEN))
+}
+
+fn parse_simple_string(val: &str) -> Option<(Data, usize)> {
+    let (line, len) = parse_line(val)?;
+    Some((Data::SStr(line.to_string()), len))
+}
+
+fn parse_error(val: &str) -> Option<(Data, usize)> {
+    let (line, len) = parse_line(val)?;
+    Some((Data::SimpleError(line.to_string()), len))
+}
+
+fn parse_integer(val: &str) -> Option<(Data, usize)> {
+    let (line, len) = parse_line(val)?;
+    Some((Data::Int(line.parse().ok()?), len))
+}
+
+fn parse_array(val: &str) -> Option<(Data, usize)> {
+    let (len, mut offset) = get_len(val)?;
+    let mut items = Vec::with_capacity(len);
+    for _ in 0..len {
+        let (item, consumed) = Data::try_deserialize(val.get(offset..)?)?;
+        items.push(item);
+        offset += consumed;
+    }
+    Some((Data::Array(items), offset))
 }
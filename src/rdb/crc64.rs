@@ -0,0 +1,28 @@
+//! CRC-64/Jones, the checksum RDB files end with: reflected, polynomial
+//! 0xad93d23594c935a9, zero initial value.
+
+use once_cell::sync::Lazy;
+
+const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+static TABLE: Lazy<[u64; 256]> = Lazy::new(|| {
+    let mut table = [0; 256];
+    for (byte, entry) in table.iter_mut().enumerate() {
+        let mut crc = byte as u64;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ POLY,
+                _ => crc >> 1,
+            };
+        }
+        *entry = crc;
+    }
+    table
+});
+
+/// Continues `crc` over `bytes`; start from 0.
+pub fn crc64(crc: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(crc, |crc, byte| {
+        TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
@@ -0,0 +1,139 @@
+use super::{
+    length_encoded_value::{get_length, get_raw_string, LengthEncodedValue},
+    packed::{intset, listpack, ziplist, zipmap},
+    rdb_file::RdbValue,
+    stream::decode_stream,
+    util::CheckedBuf,
+};
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+
+pub const TYPE_STRING: u8 = 0;
+pub const TYPE_LIST: u8 = 1;
+pub const TYPE_SET: u8 = 2;
+pub const TYPE_ZSET: u8 = 3;
+pub const TYPE_HASH: u8 = 4;
+pub const TYPE_ZSET_2: u8 = 5;
+pub const TYPE_HASH_ZIPMAP: u8 = 9;
+pub const TYPE_LIST_ZIPLIST: u8 = 10;
+pub const TYPE_SET_INTSET: u8 = 11;
+pub const TYPE_ZSET_ZIPLIST: u8 = 12;
+pub const TYPE_HASH_ZIPLIST: u8 = 13;
+pub const TYPE_LIST_QUICKLIST: u8 = 14;
+pub const TYPE_STREAM_LISTPACKS: u8 = 15;
+pub const TYPE_HASH_LISTPACK: u8 = 16;
+pub const TYPE_ZSET_LISTPACK: u8 = 17;
+pub const TYPE_LIST_QUICKLIST_2: u8 = 18;
+pub const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+pub const TYPE_SET_LISTPACK: u8 = 20;
+pub const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+/// Quicklist 2 nodes holding a single large element rather than a listpack.
+const QUICKLIST_NODE_PLAIN: usize = 1;
+
+/// Reads a value of the given type code.
+pub fn decode_value(bytes: &mut Bytes, value_type: u8) -> Result<RdbValue> {
+    let value = match value_type {
+        TYPE_STRING => RdbValue::String(LengthEncodedValue::get_as_string(bytes)?),
+        TYPE_LIST => RdbValue::List(strings(bytes)?),
+        TYPE_SET => RdbValue::Set(strings(bytes)?),
+        TYPE_ZSET => RdbValue::SortedSet(sorted_set(bytes, false)?),
+        TYPE_ZSET_2 => RdbValue::SortedSet(sorted_set(bytes, true)?),
+        TYPE_HASH => {
+            let len = get_length(bytes)?;
+            RdbValue::Hash(pairs(strings_n(bytes, len * 2)?))
+        }
+        TYPE_HASH_ZIPMAP => RdbValue::Hash(pairs(zipmap(get_raw_string(bytes)?)?)),
+        TYPE_LIST_ZIPLIST => RdbValue::List(ziplist(get_raw_string(bytes)?)?),
+        TYPE_SET_INTSET => RdbValue::Set(intset(get_raw_string(bytes)?)?),
+        TYPE_SET_LISTPACK => RdbValue::Set(listpack(get_raw_string(bytes)?)?),
+        TYPE_ZSET_ZIPLIST => RdbValue::SortedSet(scored(ziplist(get_raw_string(bytes)?)?)?),
+        TYPE_ZSET_LISTPACK => RdbValue::SortedSet(scored(listpack(get_raw_string(bytes)?)?)?),
+        TYPE_HASH_ZIPLIST => RdbValue::Hash(pairs(ziplist(get_raw_string(bytes)?)?)),
+        TYPE_HASH_LISTPACK => RdbValue::Hash(pairs(listpack(get_raw_string(bytes)?)?)),
+        TYPE_LIST_QUICKLIST => {
+            let mut list = vec![];
+            for _ in 0..get_length(bytes)? {
+                list.extend(ziplist(get_raw_string(bytes)?)?);
+            }
+            RdbValue::List(list)
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            let mut list = vec![];
+            for _ in 0..get_length(bytes)? {
+                let node = get_raw_string(bytes)?;
+                match get_length(bytes)? {
+                    QUICKLIST_NODE_PLAIN => list.push(String::from_utf8_lossy(&node).to_string()),
+                    _ => list.extend(listpack(node)?),
+                }
+            }
+            RdbValue::List(list)
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            RdbValue::Stream(decode_stream(bytes, value_type)?)
+        }
+        _ => bail!("Unsupported RDB value type {value_type}"),
+    };
+    Ok(value)
+}
+
+fn strings(bytes: &mut Bytes) -> Result<Vec<String>> {
+    let len = get_length(bytes)?;
+    strings_n(bytes, len)
+}
+
+fn strings_n(bytes: &mut Bytes, len: usize) -> Result<Vec<String>> {
+    (0..len)
+        .map(|_| LengthEncodedValue::get_as_string(bytes))
+        .collect()
+}
+
+/// Pairs up alternating fields and values.
+fn pairs(items: Vec<String>) -> Vec<(String, String)> {
+    let mut items = items.into_iter();
+    let mut pairs = vec![];
+    while let (Some(field), Some(value)) = (items.next(), items.next()) {
+        pairs.push((field, value));
+    }
+    pairs
+}
+
+/// Pairs up alternating members and scores, as packed sorted sets store them.
+fn scored(items: Vec<String>) -> Result<Vec<(String, f64)>> {
+    pairs(items)
+        .into_iter()
+        .map(|(member, score)| match score.parse() {
+            Ok(score) => Ok((member, score)),
+            Err(_) => bail!("Invalid sorted set score '{score}'"),
+        })
+        .collect()
+}
+
+/// Members and scores, stored as binary doubles in ZSET_2 and as strings before it.
+fn sorted_set(bytes: &mut Bytes, binary: bool) -> Result<Vec<(String, f64)>> {
+    (0..get_length(bytes)?)
+        .map(|_| {
+            let member = LengthEncodedValue::get_as_string(bytes)?;
+            let score = match binary {
+                true => bytes.take_f64_le()?,
+                false => string_score(bytes)?,
+            };
+            Ok((member, score))
+        })
+        .collect()
+}
+
+fn string_score(bytes: &mut Bytes) -> Result<f64> {
+    match bytes.take_u8()? {
+        253 => Ok(f64::NAN),
+        254 => Ok(f64::INFINITY),
+        255 => Ok(f64::NEG_INFINITY),
+        len => {
+            let score = bytes.take_bytes(len as usize)?;
+            let score = String::from_utf8_lossy(&score);
+            score
+                .parse()
+                .map_err(|_| anyhow!("Invalid sorted set score '{score}'"))
+        }
+    }
+}
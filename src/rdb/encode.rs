@@ -0,0 +1,257 @@
+use super::{
+    crc64::crc64,
+    decode::{TYPE_HASH, TYPE_LIST, TYPE_SET, TYPE_STREAM_LISTPACKS_3, TYPE_STRING, TYPE_ZSET_2},
+    packed::to_listpack,
+    rdb_file::{
+        OPCODE_AUX, OPCODE_EOF, OPCODE_EXPIRETIME_MS, OPCODE_FUNCTION, OPCODE_RESIZEDB,
+        OPCODE_SELECTDB,
+    },
+};
+use crate::store::{
+    sorted_set::SortedSet,
+    stream::{Stream, StreamEntry, StreamId, STREAM_NODE_MAX_ENTRIES},
+    value::{Value, ValueWrapper},
+};
+use hashbrown::HashMap;
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version 11 is the first with the stream type written here, from Redis 7.2.
+const VERSION: &[u8] = b"REDIS0011";
+
+const ENTRY_SAME_FIELDS: u64 = 2;
+
+/// Serializes a snapshot of database 0: aux fields, the code of each function library,
+/// each key with its expiry, then the CRC64 of the whole file. Collections use their plain
+/// encodings, which every version since Redis 7.2 loads. `aof_base` marks the base file of
+/// an append-only file.
+pub fn encode_snapshot(
+    data: &HashMap<String, ValueWrapper>,
+    functions: &[String],
+    aof_base: bool,
+) -> Vec<u8> {
+    let mut buf = VERSION.to_vec();
+    let ctime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    for (key, value) in [
+        ("redis-ver", "7.2.0".to_string()),
+        ("redis-bits", "64".into()),
+        ("ctime", ctime.to_string()),
+        ("aof-base", (aof_base as u8).to_string()),
+    ] {
+        buf.push(OPCODE_AUX);
+        write_string(&mut buf, key.as_bytes());
+        write_string(&mut buf, value.as_bytes());
+    }
+    for code in functions {
+        buf.push(OPCODE_FUNCTION);
+        write_string(&mut buf, code.as_bytes());
+    }
+
+    buf.push(OPCODE_SELECTDB);
+    write_length(&mut buf, 0);
+    buf.push(OPCODE_RESIZEDB);
+    write_length(&mut buf, data.len());
+    write_length(
+        &mut buf,
+        data.values().filter(|v| v.expiry.is_some()).count(),
+    );
+    for (key, wrapper) in data {
+        if let Some(expiry) = wrapper.expiry {
+            buf.push(OPCODE_EXPIRETIME_MS);
+            buf.extend_from_slice(&expiry.to_le_bytes());
+        }
+        buf.push(value_type(&wrapper.value));
+        write_string(&mut buf, key.as_bytes());
+        encode_value(&mut buf, &wrapper.value);
+    }
+
+    buf.push(OPCODE_EOF);
+    let checksum = crc64(0, &buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) | Value::Integer(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::Set(_) => TYPE_SET,
+        Value::SortedSet(_) => TYPE_ZSET_2,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Stream(_) => TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+fn encode_value(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(s) => write_string(buf, s.as_bytes()),
+        Value::Integer(i) => write_string(buf, i.to_string().as_bytes()),
+        Value::List(items) => write_strings(buf, items.len(), items.iter()),
+        Value::Set(members) => write_strings(buf, members.len(), members.iter()),
+        Value::Hash(fields) => {
+            write_length(buf, fields.len());
+            for (field, value) in fields {
+                write_string(buf, field.as_bytes());
+                write_string(buf, value.as_bytes());
+            }
+        }
+        Value::SortedSet(set) => encode_sorted_set(buf, set),
+        Value::Stream(stream) => encode_stream(buf, stream),
+    }
+}
+
+fn write_strings<'a>(buf: &mut Vec<u8>, len: usize, items: impl Iterator<Item = &'a String>) {
+    write_length(buf, len);
+    for item in items {
+        write_string(buf, item.as_bytes());
+    }
+}
+
+/// Binary double scores. Clamped infinities are written back as infinite.
+fn encode_sorted_set(buf: &mut Vec<u8>, set: &SortedSet) {
+    write_length(buf, set.set.len());
+    for (score, member) in set.scores.iter() {
+        let score = match *score {
+            score if score == Decimal::MAX => f64::INFINITY,
+            score if score == Decimal::MIN => f64::NEG_INFINITY,
+            score => score.to_f64().unwrap_or_default(),
+        };
+        write_string(buf, member.as_bytes());
+        buf.extend_from_slice(&score.to_le_bytes());
+    }
+}
+
+fn write_length(buf: &mut Vec<u8>, len: usize) {
+    match len {
+        0..=63 => buf.push(len as u8),
+        64..=16383 => buf.extend_from_slice(&[0x40 | (len >> 8) as u8, len as u8]),
+        _ => match u32::try_from(len) {
+            Ok(len) => {
+                buf.push(0x80);
+                buf.extend_from_slice(&len.to_be_bytes());
+            }
+            Err(_) => {
+                buf.push(0x81);
+                buf.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        },
+    }
+}
+
+/// Strings holding canonical integers that fit in 32 bits use the integer encodings.
+fn write_string(buf: &mut Vec<u8>, bytes: &[u8]) {
+    let integer = std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse::<i32>().ok().filter(|i| i.to_string() == s));
+    match integer {
+        Some(i) if i8::try_from(i).is_ok() => buf.extend_from_slice(&[0xC0, i as u8]),
+        Some(i) if i16::try_from(i).is_ok() => {
+            buf.push(0xC1);
+            buf.extend_from_slice(&(i as i16).to_le_bytes());
+        }
+        Some(i) => {
+            buf.push(0xC2);
+            buf.extend_from_slice(&i.to_le_bytes());
+        }
+        None => {
+            write_length(buf, bytes.len());
+            buf.extend_from_slice(bytes);
+        }
+    }
+}
+
+/// Entries in listpack nodes of up to `STREAM_NODE_MAX_ENTRIES`, keyed by their first
+/// ID, then the stream's metadata and consumer groups, as `decode_stream` reads them.
+fn encode_stream(buf: &mut Vec<u8>, stream: &Stream) {
+    let entries = stream.entries.iter().collect::<Vec<_>>();
+    let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES).collect::<Vec<_>>();
+    write_length(buf, nodes.len());
+    for node in nodes {
+        let master_id = *node[0].0;
+        write_string(buf, &raw_id(master_id));
+        write_string(buf, &to_listpack(&node_items(master_id, node)));
+    }
+
+    write_length(buf, stream.entries.len());
+    write_id(buf, stream.last_id);
+    write_id(buf, stream.first_id().unwrap_or_default());
+    write_id(buf, stream.max_deleted_id);
+    write_length(buf, stream.entries_added as usize);
+
+    write_length(buf, stream.groups.len());
+    for (name, group) in &stream.groups {
+        write_string(buf, name.as_bytes());
+        write_id(buf, group.last_delivered_id);
+        // Unknown counts are saved as -1.
+        write_length(buf, group.entries_read.unwrap_or(u64::MAX) as usize);
+        write_length(buf, group.pending.len());
+        for (id, entry) in &group.pending {
+            buf.extend_from_slice(&raw_id(*id));
+            buf.extend_from_slice(&entry.delivery_time.to_le_bytes());
+            write_length(buf, entry.delivery_count as usize);
+        }
+        write_length(buf, group.consumers.len());
+        for (name, consumer) in &group.consumers {
+            write_string(buf, name.as_bytes());
+            buf.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            let active_time = consumer.active_time.unwrap_or(consumer.seen_time);
+            buf.extend_from_slice(&active_time.to_le_bytes());
+            write_length(buf, consumer.pending.len());
+            for id in &consumer.pending {
+                buf.extend_from_slice(&raw_id(*id));
+            }
+        }
+    }
+}
+
+/// The master entry, with the first entry's fields, then each entry relative to the
+/// master ID. Entries with the master fields only store their values.
+fn node_items(master_id: StreamId, node: &[(&StreamId, &StreamEntry)]) -> Vec<String> {
+    let master_fields = node[0].1.iter().map(|(field, _)| field).collect::<Vec<_>>();
+    let mut items = vec![node.len().to_string(), "0".into()];
+    items.push(master_fields.len().to_string());
+    items.extend(master_fields.iter().map(|field| field.to_string()));
+    items.push("0".into());
+
+    for (id, entry) in node {
+        let same_fields = entry
+            .iter()
+            .map(|(field, _)| field)
+            .eq(master_fields.iter().copied());
+        let flags = if same_fields { ENTRY_SAME_FIELDS } else { 0 };
+        items.push(flags.to_string());
+        items.push((id.ms.wrapping_sub(master_id.ms) as i64).to_string());
+        items.push((id.seq.wrapping_sub(master_id.seq) as i64).to_string());
+        let count = match same_fields {
+            true => {
+                items.extend(entry.iter().map(|(_, value)| value.clone()));
+                entry.len() + 3
+            }
+            false => {
+                items.push(entry.len().to_string());
+                for (field, value) in *entry {
+                    items.extend([field.clone(), value.clone()]);
+                }
+                entry.len() * 2 + 4
+            }
+        };
+        items.push(count.to_string());
+    }
+    items
+}
+
+fn write_id(buf: &mut Vec<u8>, id: StreamId) {
+    write_length(buf, id.ms as usize);
+    write_length(buf, id.seq as usize);
+}
+
+/// A stream ID as 16 big-endian bytes.
+fn raw_id(id: StreamId) -> [u8; 16] {
+    let mut raw = [0; 16];
+    raw[..8].copy_from_slice(&id.ms.to_be_bytes());
+    raw[8..].copy_from_slice(&id.seq.to_be_bytes());
+    raw
+}
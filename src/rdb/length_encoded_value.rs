@@ -1,60 +1,101 @@
-use bytes::{Buf, Bytes};
+use super::{lzf, util::CheckedBuf};
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+const ENCODING_INT8: u8 = 0;
+const ENCODING_INT16: u8 = 1;
+const ENCODING_INT32: u8 = 2;
+const ENCODING_LZF: u8 = 3;
 
 pub enum LengthEncodedValue {
     String(String),
-    Integer(u64),
-}
-
-impl From<&mut Bytes> for LengthEncodedValue {
-    fn from(bytes: &mut Bytes) -> Self {
-        let len = bytes.get_u8();
-        match len >> 6 {
-            0b11 => LengthEncodedValue::Integer(get_integer(bytes, len)),
-            0b01 => {
-                let second_byte = bytes.get_u8();
-                LengthEncodedValue::String(get_string(bytes, len_14_bits(len, second_byte)))
-            }
-            0b00 => LengthEncodedValue::String(get_string(bytes, (len & 0b0011_1111) as usize)),
-            0b10 => {
-                let length = bytes.get_u32() as usize;
-                LengthEncodedValue::String(get_string(bytes, length))
-            }
-            _ => panic!(),
+    Integer(i64),
+}
+
+impl TryFrom<&mut Bytes> for LengthEncodedValue {
+    type Error = anyhow::Error;
+    fn try_from(bytes: &mut Bytes) -> Result<Self> {
+        match read_string(bytes)? {
+            Encoded::Raw(raw) => Ok(LengthEncodedValue::String(text(&raw))),
+            Encoded::Integer(i) => Ok(LengthEncodedValue::Integer(i)),
         }
     }
 }
 
 impl LengthEncodedValue {
-    pub fn get_as_string(bytes: &mut Bytes) -> String {
-        if let LengthEncodedValue::String(s) = LengthEncodedValue::from(bytes) {
-            s
-        } else {
-            panic!("Expected a string value")
-        }
+    /// Reads a string value, rendering integer-encoded ones in decimal.
+    pub fn get_as_string(bytes: &mut Bytes) -> Result<String> {
+        Ok(text(&get_raw_string(bytes)?))
     }
 }
 
-pub fn get_6_bit_integer(bytes: &mut Bytes) -> Option<usize> {
-    let val = bytes.get_u8();
-    if val & 0b1100_0000 != 0 {
-        return None;
+/// Reads a length: 6 bits, 14 bits, or a big-endian 32 or 64-bit number after the first
+/// byte. The `0b11` prefix marks a specially encoded string rather than a length.
+pub fn get_length(bytes: &mut Bytes) -> Result<usize> {
+    match read_length(bytes)? {
+        Length::Plain(len) => Ok(len),
+        Length::Special(encoding) => bail!("Expected a length, found string encoding {encoding}"),
     }
-    Some(val as usize)
 }
 
-fn len_14_bits(first: u8, second: u8) -> usize {
-    (((first & 0b0011_1111) as u16) << 8 | (second as u16)) as usize
+/// Reads a string as raw bytes, for the binary blobs ziplists and listpacks are stored in.
+/// Integer-encoded strings come back as their decimal digits.
+pub fn get_raw_string(bytes: &mut Bytes) -> Result<Bytes> {
+    match read_string(bytes)? {
+        Encoded::Raw(raw) => Ok(raw),
+        Encoded::Integer(i) => Ok(Bytes::from(i.to_string())),
+    }
 }
 
-fn get_string(bytes: &mut Bytes, len: usize) -> String {
-    String::from_utf8_lossy(&bytes.split_to(len)).to_string()
+enum Length {
+    Plain(usize),
+    Special(u8),
 }
 
-fn get_integer(bytes: &mut Bytes, len: u8) -> u64 {
-    match len & 0b00000011 {
-        0b00 => bytes.get_u8() as u64,
-        0b01 => bytes.get_u16_le() as u64,
-        0b10 => bytes.get_u32_le() as u64,
-        _ => panic!("Invalid integer length"),
-    }
+enum Encoded {
+    Raw(Bytes),
+    Integer(i64),
+}
+
+fn read_length(bytes: &mut Bytes) -> Result<Length> {
+    let first = bytes.take_u8()?;
+    let len = match (first >> 6, first) {
+        (0b00, _) => (first & 0b0011_1111) as usize,
+        (0b01, _) => len_14_bits(first, bytes.take_u8()?),
+        (0b10, 0x80) => bytes.take_u32()? as usize,
+        (0b10, 0x81) => bytes.take_u64()? as usize,
+        (0b10, _) => bail!("Unknown length encoding {first:#04x}"),
+        _ => return Ok(Length::Special(first & 0b0011_1111)),
+    };
+    Ok(Length::Plain(len))
+}
+
+/// Strings are a length and that many bytes, a signed little-endian integer of 1, 2 or
+/// 4 bytes, or LZF-compressed: the compressed and original lengths, then the data.
+fn read_string(bytes: &mut Bytes) -> Result<Encoded> {
+    let encoding = match read_length(bytes)? {
+        Length::Plain(len) => return Ok(Encoded::Raw(bytes.take_bytes(len)?)),
+        Length::Special(encoding) => encoding,
+    };
+    let value = match encoding {
+        ENCODING_INT8 => Encoded::Integer(bytes.take_int_le(1)?),
+        ENCODING_INT16 => Encoded::Integer(bytes.take_int_le(2)?),
+        ENCODING_INT32 => Encoded::Integer(bytes.take_int_le(4)?),
+        ENCODING_LZF => {
+            let compressed_len = get_length(bytes)?;
+            let len = get_length(bytes)?;
+            let compressed = bytes.take_bytes(compressed_len)?;
+            Encoded::Raw(lzf::decompress(&compressed, len)?.into())
+        }
+        _ => bail!("Unknown string encoding {encoding}"),
+    };
+    Ok(value)
+}
+
+fn len_14_bits(first: u8, second: u8) -> usize {
+    (((first & 0b0011_1111) as u16) << 8 | (second as u16)) as usize
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).to_string()
 }
@@ -0,0 +1,42 @@
+use anyhow::{ensure, Result};
+
+/// Decompresses LZF data into `len` bytes. Each control byte starts either a run of
+/// `ctrl + 1` literal bytes or, from 32 up, a back-reference copying `ctrl >> 5` plus 2
+/// bytes from earlier output, with a longer length in the next byte when that is 7.
+pub fn decompress(input: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(len);
+    let mut input = input.iter().copied();
+    let mut next = || {
+        input
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("LZF data ended early"))
+    };
+    while output.len() < len {
+        let ctrl = next()? as usize;
+        if ctrl < 32 {
+            for _ in 0..=ctrl {
+                output.push(next()?);
+            }
+            continue;
+        }
+        let mut run = ctrl >> 5;
+        if run == 7 {
+            run += next()? as usize;
+        }
+        let distance = ((ctrl & 0x1f) << 8) + next()? as usize + 1;
+        ensure!(
+            distance <= output.len(),
+            "LZF back-reference before the start of the data"
+        );
+        let start = output.len() - distance;
+        for i in 0..run + 2 {
+            output.push(output[start + i]);
+        }
+    }
+    ensure!(
+        output.len() == len,
+        "LZF data decompressed to {} bytes, expected {len}",
+        output.len()
+    );
+    Ok(output)
+}
@@ -1,4 +1,10 @@
+pub mod crc64;
+pub mod decode;
+pub mod encode;
 pub mod length_encoded_value;
+pub mod lzf;
+pub mod packed;
 pub mod rdb_file;
+pub mod stream;
 pub mod util;
 pub use util::hex_to_bytes;
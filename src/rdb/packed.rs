@@ -0,0 +1,215 @@
+//! The compact encodings small collections are stored in: ziplists, listpacks, intsets
+//! and zipmaps. Each is a single binary string in the RDB file.
+
+use super::util::CheckedBuf;
+use anyhow::{bail, ensure, Result};
+use bytes::{Buf, Bytes};
+
+const END: u8 = 0xFF;
+
+fn text(bytes: Bytes) -> String {
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// Sign-extends the low `bits` bits of `value`.
+fn signed(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+/// The elements of a ziplist: `<zlbytes><zltail><zllen>`, entries, then `0xFF`.
+pub fn ziplist(mut blob: Bytes) -> Result<Vec<String>> {
+    blob.take_bytes(10)?;
+    let mut entries = vec![];
+    loop {
+        let prev_len = blob.take_u8()?;
+        if prev_len == END {
+            return Ok(entries);
+        }
+        if prev_len == 0xFE {
+            blob.take_bytes(4)?;
+        }
+        let encoding = blob.take_u8()?;
+        let entry = match encoding >> 6 {
+            0b00 => text(blob.take_bytes((encoding & 0x3F) as usize)?),
+            0b01 => {
+                let len = ((encoding as usize & 0x3F) << 8) | blob.take_u8()? as usize;
+                text(blob.take_bytes(len)?)
+            }
+            0b10 => {
+                let len = blob.take_bytes(4)?.get_u32() as usize;
+                text(blob.take_bytes(len)?)
+            }
+            _ => match encoding {
+                0xC0 => blob.take_bytes(2)?.get_i16_le().to_string(),
+                0xD0 => blob.take_bytes(4)?.get_i32_le().to_string(),
+                0xE0 => blob.take_bytes(8)?.get_i64_le().to_string(),
+                0xF0 => signed(blob.take_bytes(3)?.get_uint_le(3), 24).to_string(),
+                0xFE => (blob.take_u8()? as i8).to_string(),
+                0xF1..=0xFD => ((encoding & 0x0F) - 1).to_string(),
+                _ => bail!("Unknown ziplist entry encoding {encoding:#04x}"),
+            },
+        };
+        entries.push(entry);
+    }
+}
+
+/// The elements of a listpack: `<total bytes><count>`, entries each followed by their
+/// back-length, then `0xFF`.
+pub fn listpack(mut blob: Bytes) -> Result<Vec<String>> {
+    blob.take_bytes(6)?;
+    let mut entries = vec![];
+    loop {
+        let encoding = blob.take_u8()?;
+        if encoding == END {
+            return Ok(entries);
+        }
+        let (entry, len) = match encoding {
+            0x00..=0x7F => (encoding.to_string(), 1),
+            0x80..=0xBF => {
+                let len = (encoding & 0x3F) as usize;
+                (text(blob.take_bytes(len)?), 1 + len)
+            }
+            0xC0..=0xDF => {
+                let value = ((encoding as u64 & 0x1F) << 8) | blob.take_u8()? as u64;
+                (signed(value, 13).to_string(), 2)
+            }
+            0xE0..=0xEF => {
+                let len = ((encoding as usize & 0x0F) << 8) | blob.take_u8()? as usize;
+                (text(blob.take_bytes(len)?), 2 + len)
+            }
+            0xF0 => {
+                let len = blob.take_bytes(4)?.get_u32_le() as usize;
+                (text(blob.take_bytes(len)?), 5 + len)
+            }
+            0xF1 => (blob.take_bytes(2)?.get_i16_le().to_string(), 3),
+            0xF2 => (
+                signed(blob.take_bytes(3)?.get_uint_le(3), 24).to_string(),
+                4,
+            ),
+            0xF3 => (blob.take_bytes(4)?.get_i32_le().to_string(), 5),
+            0xF4 => (blob.take_bytes(8)?.get_i64_le().to_string(), 9),
+            _ => bail!("Unknown listpack entry encoding {encoding:#04x}"),
+        };
+        blob.take_bytes(back_len_size(len))?;
+        entries.push(entry);
+    }
+}
+
+/// Bytes taken by the back-length of a listpack entry `len` bytes long.
+fn back_len_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// The members of an intset: `<encoding><length>`, then sorted little-endian integers
+/// of `encoding` bytes each.
+pub fn intset(mut blob: Bytes) -> Result<Vec<String>> {
+    let mut header = blob.take_bytes(8)?;
+    let (encoding, len) = (header.get_u32_le() as usize, header.get_u32_le() as usize);
+    ensure!(
+        [2, 4, 8].contains(&encoding),
+        "Unknown intset encoding {encoding}"
+    );
+    (0..len)
+        .map(|_| {
+            let value = blob.take_bytes(encoding)?.get_uint_le(encoding);
+            Ok(signed(value, encoding as u32 * 8).to_string())
+        })
+        .collect()
+}
+
+/// The fields and values of a zipmap, flattened: `<zmlen>`, then `<len>key<len><free>value`
+/// pairs padded by `free` bytes, then `0xFF`.
+pub fn zipmap(mut blob: Bytes) -> Result<Vec<String>> {
+    blob.take_bytes(1)?;
+    let mut entries = vec![];
+    loop {
+        let Some(len) = zipmap_len(&mut blob)? else {
+            return Ok(entries);
+        };
+        entries.push(text(blob.take_bytes(len)?));
+        let Some(len) = zipmap_len(&mut blob)? else {
+            bail!("Zipmap key without a value");
+        };
+        let free = blob.take_u8()? as usize;
+        entries.push(text(blob.take_bytes(len)?));
+        blob.take_bytes(free)?;
+    }
+}
+
+fn zipmap_len(blob: &mut Bytes) -> Result<Option<usize>> {
+    match blob.take_u8()? {
+        END => Ok(None),
+        254 => Ok(Some(blob.take_bytes(4)?.get_u32_le() as usize)),
+        len => Ok(Some(len as usize)),
+    }
+}
+
+/// Encodes items as a listpack, storing canonical integers in the integer encodings.
+pub fn to_listpack(items: &[String]) -> Vec<u8> {
+    let mut body = vec![];
+    for item in items {
+        let start = body.len();
+        match item.parse::<i64>() {
+            Ok(value) if value.to_string() == *item => encode_int(&mut body, value),
+            _ => encode_str(&mut body, item.as_bytes()),
+        }
+        let len = body.len() - start;
+        encode_back_len(&mut body, len);
+    }
+    let mut blob = Vec::with_capacity(body.len() + 7);
+    blob.extend_from_slice(&(body.len() as u32 + 7).to_le_bytes());
+    // Counts that don't fit are stored as the largest one, meaning unknown.
+    blob.extend_from_slice(&(items.len().min(u16::MAX as usize) as u16).to_le_bytes());
+    blob.extend_from_slice(&body);
+    blob.push(END);
+    blob
+}
+
+fn encode_int(body: &mut Vec<u8>, value: i64) {
+    match value {
+        0..=127 => body.push(value as u8),
+        -4096..=4095 => {
+            let value = value as u16 & 0x1FFF;
+            body.extend_from_slice(&[0xC0 | (value >> 8) as u8, value as u8]);
+        }
+        _ => {
+            let (encoding, len) = match value {
+                -32768..=32767 => (0xF1, 2),
+                -8388608..=8388607 => (0xF2, 3),
+                -2147483648..=2147483647 => (0xF3, 4),
+                _ => (0xF4, 8),
+            };
+            body.push(encoding);
+            body.extend_from_slice(&value.to_le_bytes()[..len]);
+        }
+    }
+}
+
+fn encode_str(body: &mut Vec<u8>, bytes: &[u8]) {
+    match bytes.len() {
+        len @ 0..=63 => body.push(0x80 | len as u8),
+        len @ 64..=4095 => body.extend_from_slice(&[0xE0 | (len >> 8) as u8, len as u8]),
+        len => {
+            body.push(0xF0);
+            body.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+    }
+    body.extend_from_slice(bytes);
+}
+
+/// The entry length, big-endian in 7-bit groups with the high bit set on all but the
+/// first, so it can be read backwards from the next entry.
+fn encode_back_len(body: &mut Vec<u8>, len: usize) {
+    let size = back_len_size(len);
+    for i in (0..size).rev() {
+        let group = ((len >> (7 * i)) & 0x7F) as u8;
+        body.push(if i == size - 1 { group } else { group | 0x80 });
+    }
+}
@@ -1,111 +1,185 @@
-use super::length_encoded_value::{get_6_bit_integer, LengthEncodedValue};
-use anyhow::Result;
-use bytes::{Buf, Bytes};
+use super::{
+    crc64::crc64,
+    decode::decode_value,
+    length_encoded_value::{get_length, get_raw_string, LengthEncodedValue},
+    util::CheckedBuf,
+};
+use crate::store::stream::Stream;
+use anyhow::{bail, ensure, Context, Result};
+use bytes::Bytes;
 use hashbrown::HashMap;
 
-const AUXILIARY_FIELD: u8 = 0xFA;
-const _EXPIRE_MS: u8 = 0xFD;
-const _EXPIRE_S: u8 = 0xFC;
-const _EOF: u8 = 0xFF;
-const DB_SELECTOR: u8 = 0xFE;
+const MAGIC: &[u8] = b"REDIS";
+/// The newest format this loader understands, written by Redis 7.4.
+const MAX_VERSION: u32 = 12;
+/// Files from this version on end with a CRC64 of everything before it.
+const CHECKSUM_VERSION: u32 = 5;
+
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+pub(super) const OPCODE_FUNCTION: u8 = 0xF5;
+const OPCODE_FUNCTION_PRE_GA: u8 = 0xF6;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+pub(super) const OPCODE_AUX: u8 = 0xFA;
+pub(super) const OPCODE_RESIZEDB: u8 = 0xFB;
+pub(super) const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+pub(super) const OPCODE_SELECTDB: u8 = 0xFE;
+pub(super) const OPCODE_EOF: u8 = 0xFF;
+
+const MODULE_OPCODE_EOF: usize = 0;
+const MODULE_OPCODE_SINT: usize = 1;
+const MODULE_OPCODE_UINT: usize = 2;
+const MODULE_OPCODE_FLOAT: usize = 3;
+const MODULE_OPCODE_DOUBLE: usize = 4;
+const MODULE_OPCODE_STRING: usize = 5;
 
-#[derive(Debug)]
 pub struct RdbFile {
-    _header: Vec<u8>,
-    _metadata: Vec<u8>,
+    pub version: u32,
+    pub aux: HashMap<String, String>,
+    /// The source code of each function library.
+    pub functions: Vec<String>,
     pub sections: Vec<DatabaseSection>,
 }
 
-#[derive(Debug)]
 pub struct DatabaseSection {
-    pub index: u8,
+    pub index: usize,
     pub data: HashMap<String, (RdbValue, Option<u64>)>,
 }
 
-#[derive(Debug)]
 pub enum RdbValue {
     String(String),
+    List(Vec<String>),
+    Set(Vec<String>),
+    SortedSet(Vec<(String, f64)>),
+    Hash(Vec<(String, String)>),
+    Stream(Stream),
 }
 
 impl TryFrom<&mut Bytes> for RdbFile {
     type Error = anyhow::Error;
-    fn try_from(bytes: &mut Bytes) -> Result<Self> {
-        let _header = split_until_value(bytes, AUXILIARY_FIELD).to_vec();
-        bytes.advance(1);
-        let _metadata = split_until_value(bytes, DB_SELECTOR).to_vec();
-        let mut sections = Vec::new();
-
-        while bytes.has_remaining() {
-            if bytes.chunk()[0] != DB_SELECTOR {
-                break;
-            }
-            sections.push(DatabaseSection::try_from(&mut *bytes)?);
-        }
 
-        Ok(RdbFile {
-            _header,
-            _metadata,
-            sections,
-        })
-    }
-}
-
-impl TryFrom<&mut Bytes> for DatabaseSection {
-    type Error = anyhow::Error;
+    /// Reads the header, then follows the opcode stream up to EOF. Keys are preceded by
+    /// their value type, and optionally by their expiry and eviction hints.
     fn try_from(bytes: &mut Bytes) -> Result<Self> {
-        bytes.advance(1);
-        let index = bytes.get_u8();
-        bytes.advance(1); // skip FB = resizedb
-        let Some(section_size) = get_6_bit_integer(bytes) else {
-            anyhow::bail!("Expected 6 bit integer for section size");
+        let file = bytes.clone();
+        let version = read_header(bytes)?;
+        let mut rdb = RdbFile {
+            version,
+            aux: HashMap::new(),
+            functions: Vec::new(),
+            sections: Vec::new(),
         };
-        let _expiry_size = get_6_bit_integer(bytes);
-        let mut section_data = HashMap::new();
+        let mut expiry = None;
 
-        for _ in 0..section_size {
-            let mut value_type = bytes.get_u8();
-            let expiry = match value_type {
-                0xFD => get_expiry(bytes, true),
-                0xFC => get_expiry(bytes, false),
-                _ => None,
-            };
-            if expiry.is_some() {
-                value_type = bytes.get_u8();
+        loop {
+            match bytes.take_u8().context("RDB file ended before EOF")? {
+                OPCODE_EOF => break,
+                OPCODE_AUX => {
+                    let key = LengthEncodedValue::get_as_string(bytes)?;
+                    let value = LengthEncodedValue::get_as_string(bytes)?;
+                    rdb.aux.insert(key, value);
+                }
+                OPCODE_SELECTDB => {
+                    let index = get_length(bytes)?;
+                    rdb.sections.push(DatabaseSection {
+                        index,
+                        data: HashMap::new(),
+                    });
+                }
+                OPCODE_RESIZEDB => {
+                    let (size, _expires_size) = (get_length(bytes)?, get_length(bytes)?);
+                    rdb.section().data.reserve(size);
+                }
+                OPCODE_SLOT_INFO => {
+                    // Slot, key count and expiring key count, for cluster mode.
+                    for _ in 0..3 {
+                        get_length(bytes)?;
+                    }
+                }
+                OPCODE_EXPIRETIME => expiry = Some(1000 * bytes.take_u32_le()? as u64),
+                OPCODE_EXPIRETIME_MS => expiry = Some(bytes.take_u64_le()?),
+                OPCODE_IDLE => {
+                    get_length(bytes)?;
+                }
+                OPCODE_FREQ => {
+                    bytes.take_u8()?;
+                }
+                OPCODE_FUNCTION => rdb
+                    .functions
+                    .push(LengthEncodedValue::get_as_string(bytes)?),
+                OPCODE_FUNCTION_PRE_GA => {
+                    bail!("Functions saved by a Redis 7.0 release candidate are not supported")
+                }
+                OPCODE_MODULE_AUX => skip_module_aux(bytes)?,
+                value_type => {
+                    let key = LengthEncodedValue::get_as_string(bytes)?;
+                    let value = decode_value(bytes, value_type)
+                        .with_context(|| format!("Failed to load key '{key}'"))?;
+                    rdb.section().data.insert(key, (value, expiry.take()));
+                }
             }
-            let (k, v) = decode_kv(&mut *bytes, value_type);
-            section_data.insert(k, (v, expiry));
         }
 
-        Ok(DatabaseSection {
-            index,
-            data: section_data,
-        })
+        if version >= CHECKSUM_VERSION {
+            let expected = crc64(0, &file[..file.len() - bytes.len()]);
+            let checksum = bytes
+                .take_u64_le()
+                .context("RDB file is missing its checksum")?;
+            // Files saved with `rdbchecksum no` have a zero checksum.
+            ensure!(
+                checksum == 0 || checksum == expected,
+                "RDB checksum mismatch: file has {checksum:#018x}, contents hash to {expected:#018x}"
+            );
+        }
+        Ok(rdb)
     }
 }
 
-fn get_expiry(bytes: &mut Bytes, is_sec: bool) -> Option<u64> {
-    match is_sec {
-        false => Some(bytes.get_u64_le()),
-        true => Some(1000 * bytes.get_u32_le() as u64),
+impl RdbFile {
+    /// The database keys are being loaded into. Files may omit SELECTDB for database 0.
+    fn section(&mut self) -> &mut DatabaseSection {
+        if self.sections.is_empty() {
+            self.sections.push(DatabaseSection {
+                index: 0,
+                data: HashMap::new(),
+            });
+        }
+        self.sections.last_mut().unwrap()
     }
 }
 
-fn decode_kv(bytes: &mut Bytes, _value_type: u8) -> (String, RdbValue) {
-    let key = LengthEncodedValue::get_as_string(bytes);
-    let value = RdbValue::String(
-        if let LengthEncodedValue::String(val) = LengthEncodedValue::from(bytes) {
-            val
-        } else {
-            panic!("Expected a string value")
-        },
+/// Reads `REDIS` and the four digit version after it.
+fn read_header(bytes: &mut Bytes) -> Result<u32> {
+    let header = bytes.take_bytes(9).context("Not an RDB file: too short")?;
+    ensure!(header.starts_with(MAGIC), "Not an RDB file: bad magic");
+    let version = std::str::from_utf8(&header[MAGIC.len()..])
+        .ok()
+        .and_then(|version| version.parse().ok())
+        .context("Not an RDB file: bad version")?;
+    ensure!(
+        (1..=MAX_VERSION).contains(&version),
+        "Unsupported RDB version {version}"
     );
-    (key, value)
+    Ok(version)
 }
 
-fn split_until_value(bytes: &mut Bytes, value: u8) -> Bytes {
-    let len = bytes
-        .iter()
-        .position(|&b| b == value)
-        .unwrap_or(bytes.len());
-    bytes.split_to(len)
+/// Module data isn't loaded, but it is self-describing: a module ID and when it was
+/// saved, then typed values up to an EOF marker.
+fn skip_module_aux(bytes: &mut Bytes) -> Result<()> {
+    let (_module_id, _when_opcode, _when) =
+        (get_length(bytes)?, get_length(bytes)?, get_length(bytes)?);
+    loop {
+        match get_length(bytes)? {
+            MODULE_OPCODE_EOF => return Ok(()),
+            MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                get_length(bytes)?;
+            }
+            MODULE_OPCODE_FLOAT => bytes.take_bytes(4).map(|_| ())?,
+            MODULE_OPCODE_DOUBLE => bytes.take_bytes(8).map(|_| ())?,
+            MODULE_OPCODE_STRING => get_raw_string(bytes).map(|_| ())?,
+            opcode => bail!("Unknown module data opcode {opcode}"),
+        }
+    }
 }
@@ -0,0 +1,153 @@
+use super::{
+    decode::{TYPE_STREAM_LISTPACKS_2, TYPE_STREAM_LISTPACKS_3},
+    length_encoded_value::{get_length, get_raw_string, LengthEncodedValue},
+    packed::listpack,
+    util::CheckedBuf,
+};
+use crate::store::{
+    consumer_group::{Consumer, ConsumerGroup, PendingEntry},
+    stream::{Stream, StreamEntry, StreamId},
+};
+use anyhow::{anyhow, ensure, Result};
+use bytes::{Buf, Bytes};
+use std::vec::IntoIter;
+
+const ENTRY_DELETED: u64 = 1;
+const ENTRY_SAME_FIELDS: u64 = 2;
+
+/// Reads a stream: its entries in listpack nodes, its metadata, then its consumer groups.
+/// The second and third versions add the deletion and read counters, and consumers'
+/// active time.
+pub fn decode_stream(bytes: &mut Bytes, value_type: u8) -> Result<Stream> {
+    let mut stream = Stream::default();
+    for _ in 0..get_length(bytes)? {
+        let master_id = raw_id(get_raw_string(bytes)?)?;
+        let items = listpack(get_raw_string(bytes)?)?;
+        decode_node(&mut stream, master_id, &mut Items(items.into_iter()))?;
+    }
+    let len = get_length(bytes)? as u64;
+    stream.last_id = id(bytes)?;
+    stream.entries_added = len;
+    if value_type >= TYPE_STREAM_LISTPACKS_2 {
+        let _first_id = id(bytes)?;
+        stream.max_deleted_id = id(bytes)?;
+        stream.entries_added = get_length(bytes)? as u64;
+    }
+
+    for _ in 0..get_length(bytes)? {
+        let name = LengthEncodedValue::get_as_string(bytes)?;
+        let mut group = ConsumerGroup {
+            last_delivered_id: id(bytes)?,
+            ..Default::default()
+        };
+        if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            // -1, for unknown, is saved as the largest length.
+            group.entries_read = Some(get_length(bytes)? as u64).filter(|read| *read != u64::MAX);
+        }
+        for _ in 0..get_length(bytes)? {
+            let id = raw_id(bytes.take_bytes(16)?)?;
+            let entry = PendingEntry {
+                consumer: String::new(),
+                delivery_time: bytes.take_u64_le()?,
+                delivery_count: get_length(bytes)? as u64,
+            };
+            group.pending.insert(id, entry);
+        }
+        for _ in 0..get_length(bytes)? {
+            let consumer_name = LengthEncodedValue::get_as_string(bytes)?;
+            let seen_time = bytes.take_u64_le()?;
+            let active_time = match value_type >= TYPE_STREAM_LISTPACKS_3 {
+                true => Some(bytes.take_u64_le()?),
+                false => None,
+            };
+            let mut consumer = Consumer {
+                seen_time,
+                active_time,
+                pending: Default::default(),
+            };
+            for _ in 0..get_length(bytes)? {
+                let id = raw_id(bytes.take_bytes(16)?)?;
+                let entry = group.pending.get_mut(&id).ok_or_else(|| {
+                    anyhow!("Consumer '{consumer_name}' owns {id}, which isn't pending")
+                })?;
+                entry.consumer = consumer_name.clone();
+                consumer.pending.insert(id);
+            }
+            group.consumers.insert(consumer_name, consumer);
+        }
+        stream.groups.insert(name, group);
+    }
+    Ok(stream)
+}
+
+/// Reads the entries of a listpack node. It starts with a master entry: the live and
+/// deleted entry counts, and the fields entries flagged SAMEFIELDS share. Entry IDs are
+/// stored relative to the node's master ID.
+fn decode_node(stream: &mut Stream, master_id: StreamId, items: &mut Items) -> Result<()> {
+    let count = items.next_int()? + items.next_int()?;
+    let master_fields = (0..items.next_int()?)
+        .map(|_| items.next())
+        .collect::<Result<Vec<_>>>()?;
+    items.next()?;
+
+    for _ in 0..count {
+        let flags = items.next_int()?;
+        let id = StreamId::new(
+            master_id.ms.wrapping_add(items.next_delta()?),
+            master_id.seq.wrapping_add(items.next_delta()?),
+        );
+        let entry: StreamEntry = match flags & ENTRY_SAME_FIELDS {
+            0 => (0..items.next_int()?)
+                .map(|_| Ok((items.next()?, items.next()?)))
+                .collect::<Result<_>>()?,
+            _ => master_fields
+                .iter()
+                .map(|field| Ok((field.clone(), items.next()?)))
+                .collect::<Result<_>>()?,
+        };
+        // The entry's element count, for iterating backwards.
+        items.next()?;
+        if flags & ENTRY_DELETED == 0 {
+            stream.entries.insert(id, entry);
+        }
+    }
+    Ok(())
+}
+
+struct Items(IntoIter<String>);
+
+impl Items {
+    fn next(&mut self) -> Result<String> {
+        self.0
+            .next()
+            .ok_or_else(|| anyhow!("Stream listpack ended early"))
+    }
+
+    /// A signed difference from the master ID, as two's complement. Sequence numbers
+    /// smaller than the master's give negative ones.
+    fn next_delta(&mut self) -> Result<u64> {
+        let item = self.next()?;
+        item.parse::<i64>()
+            .map(|delta| delta as u64)
+            .map_err(|_| anyhow!("Expected a number in stream listpack, got '{item}'"))
+    }
+
+    fn next_int(&mut self) -> Result<u64> {
+        let item = self.next()?;
+        item.parse()
+            .map_err(|_| anyhow!("Expected a number in stream listpack, got '{item}'"))
+    }
+}
+
+fn id(bytes: &mut Bytes) -> Result<StreamId> {
+    Ok(StreamId::new(
+        get_length(bytes)? as u64,
+        get_length(bytes)? as u64,
+    ))
+}
+
+/// A stream ID as 16 big-endian bytes, as node keys and pending entries store them.
+fn raw_id(mut raw: Bytes) -> Result<StreamId> {
+    ensure!(raw.len() == 16, "Expected a 16 byte stream ID");
+    Ok(StreamId::new(raw.get_u64(), raw.get_u64()))
+}
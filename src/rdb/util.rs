@@ -1,3 +1,6 @@
+use anyhow::{ensure, Result};
+use bytes::{Buf, Bytes};
+
 pub fn hex_to_bytes(hex: &str) -> Vec<u8> {
     hex.as_bytes()
         .chunks(2)
@@ -14,3 +17,50 @@ pub fn get_empty_rdb_file_bytes() -> Vec<u8> {
      "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2"
     )
 }
+
+/// Reads that fail on truncated input instead of panicking like `Buf`'s getters.
+pub trait CheckedBuf {
+    fn take_bytes(&mut self, len: usize) -> Result<Bytes>;
+
+    fn take_u8(&mut self) -> Result<u8> {
+        Ok(self.take_bytes(1)?[0])
+    }
+
+    fn take_u32(&mut self) -> Result<u32> {
+        Ok(self.take_bytes(4)?.get_u32())
+    }
+
+    fn take_u64(&mut self) -> Result<u64> {
+        Ok(self.take_bytes(8)?.get_u64())
+    }
+
+    fn take_u32_le(&mut self) -> Result<u32> {
+        Ok(self.take_bytes(4)?.get_u32_le())
+    }
+
+    fn take_u64_le(&mut self) -> Result<u64> {
+        Ok(self.take_bytes(8)?.get_u64_le())
+    }
+
+    fn take_f64_le(&mut self) -> Result<f64> {
+        Ok(self.take_bytes(8)?.get_f64_le())
+    }
+
+    /// A little-endian two's complement integer of `len` bytes.
+    fn take_int_le(&mut self, len: usize) -> Result<i64> {
+        let value = self.take_bytes(len)?.get_uint_le(len);
+        let shift = 64 - 8 * len as u32;
+        Ok(((value << shift) as i64) >> shift)
+    }
+}
+
+impl CheckedBuf for Bytes {
+    fn take_bytes(&mut self, len: usize) -> Result<Bytes> {
+        ensure!(
+            self.remaining() >= len,
+            "Unexpected end of RDB data: wanted {len} bytes, {} left",
+            self.remaining()
+        );
+        Ok(self.split_to(len))
+    }
+}
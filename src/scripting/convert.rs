@@ -0,0 +1,76 @@
+use crate::{
+    command::response::encode_resp_array,
+    common::{encode_bstring, encode_int, encode_sstring, null},
+    protocol::Data,
+};
+use mlua::{Lua, Table, Value};
+
+/// Converts a command's reply into the value `redis.call` returns: nil replies become
+/// `false`, and status and error replies become `{ok = ...}` and `{err = ...}` tables.
+/// Replies of other types, such as RESP3 maps, are an error.
+pub fn to_lua<'lua>(lua: &'lua Lua, response: &str) -> mlua::Result<Value<'lua>> {
+    if response.is_empty() {
+        return Ok(Value::Nil);
+    }
+    match Data::try_deserialize(response) {
+        Some((data, _)) => data_to_lua(lua, data),
+        None => Err(mlua::Error::RuntimeError(
+            "Reply type not supported in scripts".into(),
+        )),
+    }
+}
+
+fn data_to_lua(lua: &Lua, data: Data) -> mlua::Result<Value<'_>> {
+    let value = match data {
+        Data::BStr(s) => Value::String(lua.create_string(&s)?),
+        Data::Int(i) => Value::Integer(i),
+        Data::Null => Value::Boolean(false),
+        Data::SStr(s) => Value::Table(reply_table(lua, "ok", s)?),
+        Data::SimpleError(e) => Value::Table(reply_table(lua, "err", e)?),
+        Data::Array(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for item in items {
+                table.push(data_to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+    };
+    Ok(value)
+}
+
+pub(crate) fn reply_table<'lua>(
+    lua: &'lua Lua,
+    field: &str,
+    message: String,
+) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set(field, message)?;
+    Ok(table)
+}
+
+/// Converts a script's return value into a reply. Numbers are truncated to integers,
+/// `true` is 1, `false` is nil, and arrays stop at their first nil.
+pub fn from_lua(value: &Value) -> String {
+    match value {
+        Value::Boolean(true) => encode_int(1),
+        Value::Integer(i) => encode_int(*i),
+        Value::Number(n) => encode_int(*n as i64),
+        Value::String(s) => encode_bstring(&s.to_string_lossy()),
+        Value::Table(table) => {
+            if let Ok(Some(err)) = table.raw_get::<_, Option<String>>("err") {
+                return format!("-{err}\r\n");
+            }
+            if let Ok(Some(ok)) = table.raw_get::<_, Option<String>>("ok") {
+                return encode_sstring(&ok);
+            }
+            let items = table
+                .clone()
+                .sequence_values::<Value>()
+                .map_while(Result::ok)
+                .map(|item| from_lua(&item))
+                .collect::<Vec<_>>();
+            encode_resp_array(&items)
+        }
+        _ => null(),
+    }
+}
@@ -0,0 +1,491 @@
+use super::{error_message, RunningScript, ScriptEngine};
+use crate::{
+    command::response::{
+        bstring_response, encode_array_of_bstrings, error_response, sstring_response,
+        CommandResponse,
+    },
+    common::glob_match,
+    protocol::Data,
+    server::context::ServerContext,
+};
+use mlua::{Function, Lua, RegistryKey, Table, Value, Variadic};
+use std::{cell::RefCell, collections::BTreeMap, sync::Arc};
+
+const FLAGS: [&str; 5] = [
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+const REGISTRATION_FIELDS: [&str; 4] = ["function_name", "callback", "flags", "description"];
+
+#[derive(Clone)]
+pub enum FunctionSubcommand {
+    Load {
+        code: String,
+        replace: bool,
+    },
+    Delete(String),
+    Flush,
+    List {
+        pattern: Option<String>,
+        with_code: bool,
+    },
+    Dump,
+    Restore {
+        payload: String,
+        policy: RestorePolicy,
+    },
+    Stats,
+    Kill,
+}
+
+impl FunctionSubcommand {
+    /// Whether the subcommand changes the loaded libraries, and so is propagated.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            FunctionSubcommand::Load { .. }
+                | FunctionSubcommand::Delete(_)
+                | FunctionSubcommand::Flush
+                | FunctionSubcommand::Restore { .. }
+        )
+    }
+}
+
+/// What FUNCTION RESTORE does with the libraries that are already loaded.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RestorePolicy {
+    /// Fails if a restored library already exists.
+    Append,
+    /// Replaces libraries with the same name.
+    Replace,
+    /// Deletes every library first.
+    Flush,
+}
+
+/// A library loaded with FUNCTION LOAD. Callbacks live in the Lua registry, and are
+/// released once no library refers to them.
+#[derive(Clone)]
+pub(super) struct Library {
+    name: String,
+    code: String,
+    functions: BTreeMap<String, LibraryFunction>,
+}
+
+#[derive(Clone)]
+struct LibraryFunction {
+    callback: Arc<RegistryKey>,
+    description: Option<String>,
+    flags: Vec<String>,
+}
+
+impl LibraryFunction {
+    fn is_read_only(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "no-writes")
+    }
+}
+
+impl ScriptEngine {
+    pub fn function(&self, subcommand: FunctionSubcommand) -> CommandResponse {
+        match subcommand {
+            FunctionSubcommand::Load { code, replace } => {
+                let policy = match replace {
+                    true => RestorePolicy::Replace,
+                    false => RestorePolicy::Append,
+                };
+                match self.load_libraries(&[code], policy) {
+                    Ok(names) => bstring_response(&names[0]),
+                    Err(e) => error_response(&e),
+                }
+            }
+            FunctionSubcommand::Delete(name) => {
+                let removed = self.libraries.lock().unwrap().remove(&name);
+                match removed {
+                    Some(_) => {
+                        self.lua.lock().unwrap().expire_registry_values();
+                        sstring_response("OK")
+                    }
+                    None => error_response("Library not found"),
+                }
+            }
+            FunctionSubcommand::Flush => {
+                self.libraries.lock().unwrap().clear();
+                self.lua.lock().unwrap().expire_registry_values();
+                sstring_response("OK")
+            }
+            FunctionSubcommand::List { pattern, with_code } => self.list(pattern, with_code),
+            FunctionSubcommand::Dump => bstring_response(&self.dump()),
+            FunctionSubcommand::Restore { payload, policy } => match parse_dump(&payload) {
+                Some(codes) => match self.load_libraries(&codes, policy) {
+                    Ok(_) => sstring_response("OK"),
+                    Err(e) => error_response(&e),
+                },
+                None => error_response("payload version or checksum are wrong"),
+            },
+            FunctionSubcommand::Stats => self.stats(),
+            FunctionSubcommand::Kill => self.kill(true),
+        }
+    }
+
+    /// Calls a loaded function. `read_only` is set for FCALL_RO, and `on_replica` when this
+    /// server replicates another; either way only `no-writes` functions may run.
+    pub fn fcall(
+        &self,
+        context: &ServerContext,
+        name: String,
+        keys: Vec<String>,
+        args: Vec<String>,
+        read_only: bool,
+        on_replica: bool,
+    ) -> CommandResponse {
+        let lua = self.lua.lock().unwrap();
+        let function = self
+            .libraries
+            .lock()
+            .unwrap()
+            .values()
+            .find_map(|library| library.functions.get(&name))
+            .cloned();
+        let Some(function) = function else {
+            return error_response("Function not found");
+        };
+        if !function.is_read_only() && read_only {
+            return error_response("Can not execute a script with write flag using *_ro command.");
+        }
+        if !function.is_read_only() && on_replica {
+            return error_response("READONLY You can't write against a read only replica.");
+        }
+
+        let command = [
+            if read_only { "fcall_ro" } else { "fcall" }.to_string(),
+            name.clone(),
+            keys.len().to_string(),
+        ]
+        .into_iter()
+        .chain(keys.iter().cloned())
+        .chain(args.iter().cloned())
+        .collect();
+        let running = RunningScript::new(Some((name, command)), function.is_read_only());
+        let result = lua
+            .registry_value::<Function>(&function.callback)
+            .and_then(|callback| {
+                let keys = lua.create_sequence_from(keys)?;
+                let args = lua.create_sequence_from(args)?;
+                self.call(&lua, context, callback, (keys, args), running)
+            });
+        match result {
+            Ok(response) => CommandResponse::Single(response),
+            Err(e) => error_response(&error_message(&e)),
+        }
+    }
+
+    /// Loads every library in `codes`, or none of them if any fails to load, and returns
+    /// their names.
+    fn load_libraries(
+        &self,
+        codes: &[String],
+        policy: RestorePolicy,
+    ) -> Result<Vec<String>, String> {
+        let lua = self.lua.lock().unwrap();
+        let mut libraries = self.libraries.lock().unwrap();
+        let result = (|| {
+            let mut staged = match policy {
+                RestorePolicy::Flush => BTreeMap::new(),
+                _ => libraries.clone(),
+            };
+            let mut names = vec![];
+            for code in codes {
+                let library = load_library(&lua, code)?;
+                names.push(library.name.clone());
+                insert_library(&mut staged, library, policy == RestorePolicy::Replace)?;
+            }
+            *libraries = staged;
+            Ok(names)
+        })();
+        lua.expire_registry_values();
+        result
+    }
+
+    fn list(&self, pattern: Option<String>, with_code: bool) -> CommandResponse {
+        let libraries = self.libraries.lock().unwrap();
+        let list = libraries
+            .values()
+            .filter(|library| {
+                pattern
+                    .as_ref()
+                    .is_none_or(|pattern| glob_match(pattern, &library.name))
+            })
+            .map(|library| {
+                let functions = library
+                    .functions
+                    .iter()
+                    .map(|(name, function)| {
+                        Data::Array(vec![
+                            bstr("name"),
+                            bstr(name),
+                            bstr("description"),
+                            function.description.clone().map_or(Data::Null, Data::BStr),
+                            bstr("flags"),
+                            Data::Array(function.flags.iter().map(|f| bstr(f)).collect()),
+                        ])
+                    })
+                    .collect();
+                let mut fields = vec![
+                    bstr("library_name"),
+                    bstr(&library.name),
+                    bstr("engine"),
+                    bstr("LUA"),
+                    bstr("functions"),
+                    Data::Array(functions),
+                ];
+                if with_code {
+                    fields.extend([bstr("library_code"), bstr(&library.code)]);
+                }
+                Data::Array(fields)
+            })
+            .collect();
+        CommandResponse::Single(String::from(&Data::Array(list)))
+    }
+
+    /// Serializes every library's code, to be loaded back by FUNCTION RESTORE.
+    fn dump(&self) -> String {
+        encode_array_of_bstrings(&self.library_codes())
+    }
+
+    /// The code of every loaded library, which snapshots save.
+    pub fn library_codes(&self) -> Vec<String> {
+        self.libraries
+            .lock()
+            .unwrap()
+            .values()
+            .map(|library| library.code.clone())
+            .collect()
+    }
+
+    /// Replaces the loaded libraries with those of a snapshot.
+    pub fn restore_libraries(&self, codes: &[String]) -> Result<(), String> {
+        self.load_libraries(codes, RestorePolicy::Flush).map(|_| ())
+    }
+
+    fn stats(&self) -> CommandResponse {
+        let running = match self.running.lock().unwrap().as_ref() {
+            Some(RunningScript {
+                function: Some((name, command)),
+                started,
+                ..
+            }) => Data::Array(vec![
+                bstr("name"),
+                bstr(name),
+                bstr("command"),
+                Data::Array(command.iter().map(|arg| bstr(arg)).collect()),
+                bstr("duration_ms"),
+                Data::Int(started.elapsed().as_millis() as i64),
+            ]),
+            _ => Data::Null,
+        };
+        let libraries = self.libraries.lock().unwrap();
+        let functions = libraries
+            .values()
+            .map(|library| library.functions.len())
+            .sum::<usize>();
+        let stats = Data::Array(vec![
+            bstr("running_script"),
+            running,
+            bstr("engines"),
+            Data::Array(vec![
+                bstr("LUA"),
+                Data::Array(vec![
+                    bstr("libraries_count"),
+                    Data::Int(libraries.len() as i64),
+                    bstr("functions_count"),
+                    Data::Int(functions as i64),
+                ]),
+            ]),
+        ]);
+        CommandResponse::Single(String::from(&stats))
+    }
+}
+
+/// Runs a library's code, collecting the functions it registers with
+/// `redis.register_function`.
+fn load_library(lua: &Lua, code: &str) -> Result<Library, String> {
+    let shebang = code.lines().next().unwrap_or_default();
+    let name = parse_shebang(shebang)?;
+    // The shebang line is kept blank so that errors report the right line numbers.
+    let chunk = lua
+        .load(&code[shebang.len()..])
+        .set_name("@user_function")
+        .into_function()
+        .map_err(|e| format!("Error compiling function: {}", error_message(&e)))?;
+
+    let functions = RefCell::new(BTreeMap::new());
+    let registered = lua.scope(|scope| {
+        let register = scope.create_function(|lua, args: Variadic<Value>| {
+            let (name, function) = parse_registration(lua, args)?;
+            let mut functions = functions.borrow_mut();
+            if functions.contains_key(&name) {
+                return Err(runtime_error("Function already exists in the library"));
+            }
+            functions.insert(name, function);
+            Ok(())
+        })?;
+        let redis: Table = lua.named_registry_value("redis")?;
+        redis.set("register_function", register)?;
+        let result = chunk.call::<_, ()>(());
+        redis.set("register_function", Value::Nil)?;
+        result
+    });
+    if let Err(e) = registered {
+        return Err(format!(
+            "Error registering functions: {}",
+            error_message(&e)
+        ));
+    }
+
+    let functions = functions.into_inner();
+    if functions.is_empty() {
+        return Err("No functions registered".into());
+    }
+    Ok(Library {
+        name,
+        code: code.to_string(),
+        functions,
+    })
+}
+
+/// Parses a `#!lua name=<library>` line into the library's name.
+fn parse_shebang(line: &str) -> Result<String, String> {
+    let Some(metadata) = line.strip_prefix("#!") else {
+        return Err("Missing library metadata".into());
+    };
+    let mut parts = metadata.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(format!("Engine '{engine}' not found"));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(value) => name = Some(value),
+            None => return Err(format!("Invalid metadata value given: {part}")),
+        }
+    }
+    match name {
+        Some(name) if is_valid_name(name) => Ok(name.to_string()),
+        Some(_) => Err(
+            "Library names can only contain letters, numbers, or underscores(_) \
+                        and must be at least one character long"
+                .into(),
+        ),
+        None => Err("Library name was not given".into()),
+    }
+}
+
+/// Parses `redis.register_function(name, callback)`, or its form taking a table with
+/// `function_name`, `callback`, and optional `flags` and `description`.
+fn parse_registration<'lua>(
+    lua: &'lua Lua,
+    args: Variadic<Value<'lua>>,
+) -> mlua::Result<(String, LibraryFunction)> {
+    let (name, callback, flags, description) = match args.as_slice() {
+        [Value::String(name), Value::Function(callback)] => {
+            (name.to_str()?.to_string(), callback.clone(), vec![], None)
+        }
+        [Value::Table(table)] => {
+            for pair in table.clone().pairs::<Value, Value>() {
+                let key = match pair?.0 {
+                    Value::String(key) => key.to_str()?.to_string(),
+                    _ => String::new(),
+                };
+                if !REGISTRATION_FIELDS.contains(&key.as_str()) {
+                    return Err(runtime_error(
+                        "unknown argument given to redis.register_function",
+                    ));
+                }
+            }
+            let Some(name) = table.get::<_, Option<String>>("function_name")? else {
+                return Err(runtime_error(
+                    "redis.register_function must get a function name argument",
+                ));
+            };
+            let Some(callback) = table.get::<_, Option<Function>>("callback")? else {
+                return Err(runtime_error(
+                    "redis.register_function must get a callback argument",
+                ));
+            };
+            let flags = table.get::<_, Option<Vec<String>>>("flags")?;
+            let description = table.get::<_, Option<String>>("description")?;
+            (name, callback, flags.unwrap_or_default(), description)
+        }
+        _ => {
+            return Err(runtime_error(
+                "wrong number of arguments to redis.register_function",
+            ))
+        }
+    };
+    if !is_valid_name(&name) {
+        return Err(runtime_error(
+            "Function names can only contain letters, numbers, or underscores(_) and must be \
+             at least one character long",
+        ));
+    }
+    if flags.iter().any(|flag| !FLAGS.contains(&flag.as_str())) {
+        return Err(runtime_error("unknown flag given"));
+    }
+    let function = LibraryFunction {
+        callback: Arc::new(lua.create_registry_value(callback)?),
+        description,
+        flags,
+    };
+    Ok((name, function))
+}
+
+/// Adds `library`, unless it would replace a library without `replace` or one of its
+/// functions is already defined by another library.
+fn insert_library(
+    libraries: &mut BTreeMap<String, Library>,
+    library: Library,
+    replace: bool,
+) -> Result<(), String> {
+    if !replace && libraries.contains_key(&library.name) {
+        return Err(format!("Library '{}' already exists", library.name));
+    }
+    for name in library.functions.keys() {
+        let defined_elsewhere = libraries
+            .values()
+            .any(|other| other.name != library.name && other.functions.contains_key(name));
+        if defined_elsewhere {
+            return Err(format!("Function {name} already exists"));
+        }
+    }
+    libraries.insert(library.name.clone(), library);
+    Ok(())
+}
+
+/// Parses a FUNCTION DUMP payload: an array of bulk strings, one per library.
+fn parse_dump(payload: &str) -> Option<Vec<String>> {
+    let (count, mut rest) = payload.strip_prefix('*')?.split_once("\r\n")?;
+    let mut codes = vec![];
+    for _ in 0..count.parse::<usize>().ok()? {
+        let (len, body) = rest.strip_prefix('$')?.split_once("\r\n")?;
+        let len = len.parse::<usize>().ok()?;
+        codes.push(body.get(..len)?.to_string());
+        rest = body.get(len..)?.strip_prefix("\r\n")?;
+    }
+    rest.is_empty().then_some(codes)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn runtime_error(message: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(message.into())
+}
+
+fn bstr(s: &str) -> Data {
+    Data::BStr(s.to_string())
+}
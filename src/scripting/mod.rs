@@ -0,0 +1,430 @@
+pub mod convert;
+pub mod functions;
+
+use crate::{
+    command::{
+        core::Command,
+        response::{bstring_response, error_response, sstring_response, CommandResponse},
+    },
+    common::encode_error,
+    protocol::Data,
+    server::{config::get_config_value, context::ServerContext},
+};
+use convert::{from_lua, reply_table, to_lua};
+use functions::Library;
+use hashbrown::HashMap;
+use mlua::{Function, HookTriggers, IntoLuaMulti, Lua, LuaOptions, StdLib, Value, Variadic};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::runtime::Handle;
+
+/// How long a script may run before other clients get BUSY replies, unless overridden by
+/// `--busy-reply-threshold`.
+const DEFAULT_BUSY_THRESHOLD_MS: u64 = 5000;
+
+/// Instructions between checks for SCRIPT KILL.
+const KILL_CHECK_INTERVAL: u32 = 100_000;
+
+const ERR_KILLED: &str = "Script killed by user with SCRIPT KILL...";
+
+/// `redis.call` raises the error replies `redis.pcall` returns. Scripts and functions are
+/// run through `run`, so an error raised that way is their reply instead of a runtime error.
+///
+/// Like Redis, the globals and libraries are then made read-only so that scripts can't
+/// leave state behind for the next one, and reading an undefined global is an error.
+const PRELUDE: &str = r#"
+redis.call = function(...)
+    local reply = redis.pcall(...)
+    if type(reply) == "table" and reply.err then
+        error(reply, 2)
+    end
+    return reply
+end
+
+local function readonly(t)
+    return setmetatable({}, {
+        __index = t,
+        __newindex = function()
+            error("Attempt to modify a readonly table", 2)
+        end,
+        __metatable = false,
+    })
+end
+
+for _, name in ipairs({"redis", "string", "math", "table"}) do
+    _G[name] = readonly(_G[name])
+end
+
+setmetatable(_G, {
+    __index = function(_, name)
+        error("Script attempted to access nonexistent global variable '" .. tostring(name) .. "'", 2)
+    end,
+    __newindex = function()
+        error("Attempt to modify a readonly table", 2)
+    end,
+    __metatable = false,
+})
+
+return function(script, ...)
+    local ok, result = pcall(script, ...)
+    if ok or (type(result) == "table" and result.err) then
+        return result
+    end
+    error(result, 0)
+end
+"#;
+
+#[derive(Clone)]
+pub enum ScriptSubcommand {
+    Load(String),
+    Exists(Vec<String>),
+    Flush,
+    Kill,
+}
+
+struct RunningScript {
+    /// The name and FCALL arguments of a running function, reported by FUNCTION STATS.
+    function: Option<(String, Vec<String>)>,
+    started: Instant,
+    wrote: bool,
+    /// Set for functions flagged `no-writes`, which may not call write commands.
+    read_only: bool,
+}
+
+impl RunningScript {
+    fn new(function: Option<(String, Vec<String>)>, read_only: bool) -> Self {
+        Self {
+            function,
+            started: Instant::now(),
+            wrote: false,
+            read_only,
+        }
+    }
+}
+
+/// The Lua interpreter shared by every script and function, the cache of scripts by SHA1,
+/// and the function libraries by name.
+#[derive(Clone)]
+pub struct ScriptEngine {
+    lua: Arc<Mutex<Lua>>,
+    scripts: Arc<Mutex<HashMap<String, String>>>,
+    libraries: Arc<Mutex<BTreeMap<String, Library>>>,
+    running: Arc<Mutex<Option<RunningScript>>>,
+    kill: Arc<AtomicBool>,
+    busy_threshold: Duration,
+}
+
+impl Default for ScriptEngine {
+    fn default() -> Self {
+        let kill = Arc::new(AtomicBool::new(false));
+        let lua = new_interpreter(kill.clone()).expect("Failed to initialize Lua");
+        let busy_threshold = get_config_value("busy-reply-threshold")
+            .and_then(|ms| ms.parse().ok())
+            .unwrap_or(DEFAULT_BUSY_THRESHOLD_MS);
+        Self {
+            lua: Arc::new(Mutex::new(lua)),
+            scripts: Arc::default(),
+            libraries: Arc::default(),
+            running: Arc::default(),
+            kill,
+            busy_threshold: Duration::from_millis(busy_threshold),
+        }
+    }
+}
+
+impl ScriptEngine {
+    /// Runs `body`, caching it once it compiles. Blocks the calling thread until the
+    /// script finishes, so that nothing else runs on the event loop in the meantime.
+    pub fn eval(
+        &self,
+        context: &ServerContext,
+        body: String,
+        keys: Vec<String>,
+        args: Vec<String>,
+    ) -> CommandResponse {
+        let sha = sha1_hex(&body);
+        self.run(context, &sha, &body, keys, args)
+    }
+
+    pub fn eval_sha(
+        &self,
+        context: &ServerContext,
+        sha: String,
+        keys: Vec<String>,
+        args: Vec<String>,
+    ) -> CommandResponse {
+        let sha = sha.to_lowercase();
+        let body = self.scripts.lock().unwrap().get(&sha).cloned();
+        match body {
+            Some(body) => self.run(context, &sha, &body, keys, args),
+            None => error_response("NOSCRIPT No matching script. Please use EVAL."),
+        }
+    }
+
+    pub fn script(&self, subcommand: ScriptSubcommand) -> CommandResponse {
+        match subcommand {
+            ScriptSubcommand::Load(body) => {
+                if let Err(e) = self.lua.lock().unwrap().load(&body).into_function() {
+                    return compile_error(&e);
+                }
+                let sha = sha1_hex(&body);
+                self.scripts.lock().unwrap().insert(sha.clone(), body);
+                bstring_response(&sha)
+            }
+            ScriptSubcommand::Exists(shas) => {
+                let scripts = self.scripts.lock().unwrap();
+                let exists = shas
+                    .iter()
+                    .map(|sha| Data::Int(scripts.contains_key(&sha.to_lowercase()) as i64))
+                    .collect();
+                CommandResponse::Single(String::from(&Data::Array(exists)))
+            }
+            ScriptSubcommand::Flush => {
+                self.scripts.lock().unwrap().clear();
+                sstring_response("OK")
+            }
+            ScriptSubcommand::Kill => self.kill(false),
+        }
+    }
+
+    /// Whether a script has run for long enough that other clients are refused.
+    pub fn is_busy(&self) -> bool {
+        self.running
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|script| script.started.elapsed() >= self.busy_threshold)
+    }
+
+    /// Stops the running script, unless it has already written to the dataset. SCRIPT KILL
+    /// only stops scripts and FUNCTION KILL, with `function` set, only functions.
+    pub fn kill(&self, function: bool) -> CommandResponse {
+        let running = self.running.lock().unwrap();
+        match running
+            .as_ref()
+            .filter(|script| script.function.is_some() == function)
+        {
+            None => error_response("NOTBUSY No scripts in execution right now."),
+            Some(script) if script.wrote => error_response(
+                "UNKILLABLE Sorry the script already executed write commands against the \
+                 dataset. You can either wait the script termination or kill the server in a \
+                 hard way using the SHUTDOWN NOSAVE command.",
+            ),
+            Some(_) => {
+                self.kill.store(true, Ordering::Relaxed);
+                sstring_response("OK")
+            }
+        }
+    }
+
+    fn run(
+        &self,
+        context: &ServerContext,
+        sha: &str,
+        body: &str,
+        keys: Vec<String>,
+        args: Vec<String>,
+    ) -> CommandResponse {
+        let lua = self.lua.lock().unwrap();
+        let script = match lua.load(body).set_name("@user_script").into_function() {
+            Ok(script) => script,
+            Err(e) => return compile_error(&e),
+        };
+        self.scripts
+            .lock()
+            .unwrap()
+            .entry(sha.to_string())
+            .or_insert_with(|| body.to_string());
+        // Set around the globals' protection, and only for this call.
+        let globals = lua.globals();
+        let result = lua
+            .create_sequence_from(keys)
+            .and_then(|keys| globals.raw_set("KEYS", keys))
+            .and_then(|_| lua.create_sequence_from(args))
+            .and_then(|args| globals.raw_set("ARGV", args))
+            .and_then(|_| {
+                let running = RunningScript::new(None, false);
+                self.call(&lua, context, script, (), running)
+            });
+        let reset = globals
+            .raw_set("KEYS", Value::Nil)
+            .and_then(|_| globals.raw_set("ARGV", Value::Nil));
+        match result.and_then(|response| reset.map(|_| response)) {
+            Ok(response) => CommandResponse::Single(response),
+            Err(e) => error_response(&format!(
+                "Error running script (call to f_{sha}): {}",
+                error_message(&e)
+            )),
+        }
+    }
+
+    /// Calls `script` with `args`, tracking it as the running script so that it can be
+    /// killed, and returns its reply.
+    fn call<'lua>(
+        &self,
+        lua: &'lua Lua,
+        context: &ServerContext,
+        script: Function<'lua>,
+        args: impl IntoLuaMulti<'lua>,
+        running: RunningScript,
+    ) -> mlua::Result<String> {
+        let run: Function = lua.named_registry_value("run")?;
+        lua.set_app_data(context.clone());
+        self.kill.store(false, Ordering::Relaxed);
+        *self.running.lock().unwrap() = Some(running);
+        let result = run.call::<_, Value>((script, args));
+        *self.running.lock().unwrap() = None;
+        lua.remove_app_data::<ServerContext>();
+        result.map(|value| from_lua(&value))
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.running
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|script| script.read_only)
+    }
+
+    fn mark_write(&self) {
+        if let Some(script) = self.running.lock().unwrap().as_mut() {
+            script.wrote = true;
+        }
+    }
+}
+
+fn new_interpreter(kill: Arc<AtomicBool>) -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    )?;
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(KILL_CHECK_INTERVAL),
+        move |_, _| match kill.load(Ordering::Relaxed) {
+            true => Err(mlua::Error::RuntimeError(ERR_KILLED.into())),
+            false => Ok(()),
+        },
+    );
+
+    let redis = lua.create_table()?;
+    redis.set("pcall", lua.create_function(pcall)?)?;
+    redis.set(
+        "error_reply",
+        lua.create_function(|lua, message: String| reply_table(lua, "err", message))?,
+    )?;
+    redis.set(
+        "status_reply",
+        lua.create_function(|lua, message: String| reply_table(lua, "ok", message))?,
+    )?;
+    // Kept for loading libraries, as scripts only see a read-only view of it.
+    lua.set_named_registry_value("redis", redis.clone())?;
+    lua.globals().set("redis", redis)?;
+
+    let run: Function = lua.load(PRELUDE).set_name("@prelude").eval()?;
+    lua.set_named_registry_value("run", run)?;
+    Ok(lua)
+}
+
+/// `redis.pcall`: runs a command and returns its reply, with errors as `{err = ...}`.
+fn pcall<'lua>(lua: &'lua Lua, args: Variadic<Value<'lua>>) -> mlua::Result<Value<'lua>> {
+    if args.is_empty() {
+        return Err(mlua::Error::RuntimeError(
+            "Please specify at least one argument for this redis lib call".into(),
+        ));
+    }
+    let args = args
+        .iter()
+        .map(|arg| match arg {
+            Value::String(s) => Ok(Data::BStr(s.to_string_lossy().into_owned())),
+            Value::Integer(i) => Ok(Data::BStr(i.to_string())),
+            Value::Number(n) => Ok(Data::BStr(n.to_string())),
+            _ => Err(mlua::Error::RuntimeError(
+                "Lua redis lib command arguments must be strings or integers".into(),
+            )),
+        })
+        .collect::<mlua::Result<Vec<_>>>()?;
+    let Some(context) = lua.app_data_ref::<ServerContext>().map(|c| c.clone()) else {
+        return Err(mlua::Error::RuntimeError("No script is running".into()));
+    };
+
+    let response = match Command::from(args.as_slice()) {
+        Command::Invalid => encode_error("Unknown Redis command called from script"),
+        command if !is_allowed_in_script(&command) => {
+            encode_error("This Redis command is not allowed from script")
+        }
+        command if command.is_write() && context.scripts.is_read_only() => {
+            encode_error("Write commands are not allowed from read-only scripts.")
+        }
+        command => {
+            if command.is_write() {
+                context.scripts.mark_write();
+            }
+            let response =
+                Handle::current().block_on(context.execute_command(command.into_non_blocking()));
+            String::from(response)
+        }
+    };
+    to_lua(lua, &response)
+}
+
+fn is_allowed_in_script(command: &Command) -> bool {
+    !matches!(
+        command,
+        Command::Eval { .. }
+            | Command::EvalSha { .. }
+            | Command::Script(_)
+            | Command::FCall { .. }
+            | Command::Function(_)
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch(_)
+            | Command::Unwatch
+            | Command::Subscribe(_)
+            | Command::PSubscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PUnsubscribe(_)
+            | Command::SSubscribe(_)
+            | Command::SUnsubscribe(_)
+            | Command::Psync(..)
+            | Command::Hello(_)
+            | Command::Client(_)
+            | Command::Save
+            | Command::BgSave
+            | Command::BgRewriteAof
+            | Command::Shutdown(_)
+            | Command::Replconf
+            | Command::ReplconfGetAck(_)
+            | Command::ReplconfAck(_)
+            | Command::Wait { .. }
+    )
+}
+
+fn compile_error(e: &mlua::Error) -> CommandResponse {
+    error_response(&format!(
+        "Error compiling script (new function): {}",
+        error_message(e)
+    ))
+}
+
+/// The message Lua gave for an error, without mlua's decoration.
+fn error_message(e: &mlua::Error) -> String {
+    match e {
+        mlua::Error::SyntaxError { message, .. } | mlua::Error::RuntimeError(message) => {
+            message.clone()
+        }
+        mlua::Error::CallbackError { cause, .. } => error_message(cause),
+        e => e.to_string(),
+    }
+}
+
+pub fn sha1_hex(body: &str) -> String {
+    hex::encode(Sha1::digest(body.as_bytes()))
+}
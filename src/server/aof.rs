@@ -0,0 +1,631 @@
+use super::{config::get_config_value, context::ServerContext};
+use crate::{
+    command::{core::Command, response::encode_array_of_bstrings},
+    protocol::Data,
+    rdb::{encode::encode_snapshot, rdb_file::RdbFile},
+    store::{
+        core::{rdb_data, InMemoryStore},
+        persistence::write_atomically,
+    },
+};
+use anyhow::{bail, ensure, Context, Result};
+use bytes::Bytes;
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+pub const ERR_REWRITE_IN_PROGRESS: &str =
+    "Background append only file rewriting already in progress";
+
+/// Rewrite once the log has doubled since the last rewrite, but not below 64mb.
+const DEFAULT_REWRITE_PERCENTAGE: u64 = 100;
+const DEFAULT_REWRITE_MIN_SIZE: u64 = 64 * 1024 * 1024;
+/// How long to wait after a failed rewrite before an automatic one tries again.
+const REWRITE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// When appended commands are flushed to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every write, before it's acknowledged.
+    Always,
+    /// Once a second, losing at most a second of writes.
+    EverySec,
+    /// Whenever the operating system flushes its buffers.
+    No,
+}
+
+impl FsyncPolicy {
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy.to_lowercase().as_str() {
+            "always" => Some(FsyncPolicy::Always),
+            "everysec" => Some(FsyncPolicy::EverySec),
+            "no" => Some(FsyncPolicy::No),
+            _ => None,
+        }
+    }
+}
+
+pub struct AofConfig {
+    /// The directory holding the base, incr and manifest files.
+    pub dir: PathBuf,
+    /// The prefix of every file name, `appendonly.aof` by default.
+    pub file_name: String,
+    pub fsync: FsyncPolicy,
+    /// Growth over the size after the last rewrite that triggers another, or 0 to only
+    /// rewrite on BGREWRITEAOF.
+    pub rewrite_percentage: u64,
+    /// The size below which the log is never rewritten automatically.
+    pub rewrite_min_size: u64,
+}
+
+impl AofConfig {
+    /// The defaults for files in `dir`.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            file_name: "appendonly.aof".into(),
+            fsync: FsyncPolicy::EverySec,
+            rewrite_percentage: DEFAULT_REWRITE_PERCENTAGE,
+            rewrite_min_size: DEFAULT_REWRITE_MIN_SIZE,
+        }
+    }
+
+    /// The configured settings if `appendonly` is `yes`. Files go in `appenddirname`
+    /// inside `dir`.
+    pub fn from_config() -> Option<Self> {
+        if get_config_value("appendonly").is_none_or(|enabled| enabled != "yes") {
+            return None;
+        }
+        let dir = get_config_value("dir").unwrap_or(".".into());
+        let dir_name = get_config_value("appenddirname").unwrap_or("appendonlydir".into());
+        let mut config = Self::new(PathBuf::from(dir).join(dir_name));
+        if let Some(file_name) = get_config_value("appendfilename") {
+            config.file_name = file_name;
+        }
+        if let Some(policy) = get_config_value("appendfsync") {
+            match FsyncPolicy::parse(&policy) {
+                Some(policy) => config.fsync = policy,
+                None => eprintln!("Invalid appendfsync '{policy}', using everysec"),
+            }
+        }
+        if let Some(percentage) = get_config_value("auto-aof-rewrite-percentage") {
+            match percentage.parse() {
+                Ok(percentage) => config.rewrite_percentage = percentage,
+                Err(_) => eprintln!("Invalid auto-aof-rewrite-percentage '{percentage}'"),
+            }
+        }
+        if let Some(size) = get_config_value("auto-aof-rewrite-min-size") {
+            match parse_size(&size) {
+                Some(size) => config.rewrite_min_size = size,
+                None => eprintln!("Invalid auto-aof-rewrite-min-size '{size}'"),
+            }
+        }
+        Some(config)
+    }
+
+    fn path(&self, file: &AofFile) -> PathBuf {
+        self.dir.join(&file.name)
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.manifest", self.file_name))
+    }
+
+    fn base_file(&self, seq: u64) -> AofFile {
+        AofFile {
+            name: format!("{}.{seq}.base.rdb", self.file_name),
+            seq,
+            kind: AofFileKind::Base,
+        }
+    }
+
+    fn incr_file(&self, seq: u64) -> AofFile {
+        AofFile {
+            name: format!("{}.{seq}.incr.aof", self.file_name),
+            seq,
+            kind: AofFileKind::Incr,
+        }
+    }
+}
+
+/// Parses a byte count with an optional unit: `k`, `m` and `g` are powers of 1000, `kb`,
+/// `mb` and `gb` powers of 1024.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.to_lowercase();
+    let digits = size.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &size[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AofFileKind {
+    /// A snapshot of the data when the log was last rewritten.
+    Base,
+    /// Commands written since, replayed in order on top of the base.
+    Incr,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AofFile {
+    pub name: String,
+    pub seq: u64,
+    pub kind: AofFileKind,
+}
+
+/// The files making up the log, in Redis 7's manifest format: a line per file, such as
+/// `file appendonly.aof.1.base.rdb seq 1 type b`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub base: Option<AofFile>,
+    pub incrs: Vec<AofFile>,
+}
+
+impl Manifest {
+    /// History files, left behind by a rewrite that couldn't delete them, are skipped.
+    pub fn parse(manifest: &str) -> Result<Self> {
+        let mut parsed = Manifest::default();
+        let lines = manifest.lines().map(str::trim);
+        for line in lines.filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            ensure!(words.len() % 2 == 0, "Invalid AOF manifest line '{line}'");
+            let field = |name: &str| {
+                words
+                    .chunks(2)
+                    .find(|pair| pair[0] == name)
+                    .map(|pair| pair[1])
+                    .with_context(|| format!("AOF manifest line '{line}' has no {name}"))
+            };
+            let name = field("file")?.to_string();
+            let seq = field("seq")?
+                .parse()
+                .with_context(|| format!("Invalid seq in AOF manifest line '{line}'"))?;
+            match field("type")? {
+                "b" => {
+                    ensure!(parsed.base.is_none(), "AOF manifest has more than one base");
+                    let kind = AofFileKind::Base;
+                    parsed.base = Some(AofFile { name, seq, kind });
+                }
+                "i" => {
+                    let kind = AofFileKind::Incr;
+                    parsed.incrs.push(AofFile { name, seq, kind });
+                }
+                "h" => {}
+                kind => bail!("Unknown AOF file type '{kind}'"),
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &AofFile> {
+        self.base.iter().chain(&self.incrs)
+    }
+
+    fn next_base_seq(&self) -> u64 {
+        self.base.as_ref().map_or(1, |base| base.seq + 1)
+    }
+
+    fn next_incr_seq(&self) -> u64 {
+        self.incrs
+            .iter()
+            .map(|incr| incr.seq + 1)
+            .max()
+            .unwrap_or(1)
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for file in self.files() {
+            let kind = match file.kind {
+                AofFileKind::Base => "b",
+                AofFileKind::Incr => "i",
+            };
+            writeln!(f, "file {} seq {} type {kind}", file.name, file.seq)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the command at the start of `buf`, an array of bulk strings, returning its
+/// arguments and encoded length, or `None` if `buf` ends before it does.
+pub fn parse_command(buf: &[u8]) -> Result<Option<(Vec<String>, usize)>> {
+    let Some((count, mut pos)) = read_prefixed(buf, 0, b'*')? else {
+        return Ok(None);
+    };
+    ensure!(count > 0, "Empty command");
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let Some((len, start)) = read_prefixed(buf, pos, b'$')? else {
+            return Ok(None);
+        };
+        let end = start + len;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        ensure!(
+            &buf[end..end + 2] == b"\r\n",
+            "Bulk string not terminated by CRLF"
+        );
+        args.push(String::from_utf8_lossy(&buf[start..end]).to_string());
+        pos = end + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+/// Reads a `<prefix><number>\r\n` line at `pos`, returning the number and where the line
+/// ends.
+fn read_prefixed(buf: &[u8], pos: usize, prefix: u8) -> Result<Option<(usize, usize)>> {
+    let Some(&found) = buf.get(pos) else {
+        return Ok(None);
+    };
+    ensure!(
+        found == prefix,
+        "Expected '{}', found '{}'",
+        prefix as char,
+        found as char
+    );
+    let Some(len) = buf[pos..].windows(2).position(|w| w == b"\r\n") else {
+        return Ok(None);
+    };
+    let number = std::str::from_utf8(&buf[pos + 1..pos + len])
+        .ok()
+        .and_then(|number| number.parse().ok())
+        .context("Invalid length")?;
+    Ok(Some((number, pos + len + 2)))
+}
+
+/// Runs the commands in a file of the log. A transaction cut off by the end of the last
+/// file is dropped, along with a final command that was only partly written, and the
+/// file is truncated to what was loaded.
+async fn replay(context: &ServerContext, path: &Path, last: bool) -> Result<()> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let (mut pos, mut loaded) = (0, 0);
+    let mut transaction: Option<Vec<Command>> = None;
+    while let Some((args, len)) = parse_command(&bytes[pos..])
+        .with_context(|| format!("Bad file format in {} at offset {pos}", path.display()))?
+    {
+        pos += len;
+        match args[0].to_uppercase().as_str() {
+            "SELECT" => ensure!(
+                args.get(1).is_some_and(|db| db == "0"),
+                "Only database 0 is supported, {} selects another",
+                path.display()
+            ),
+            "MULTI" => transaction = Some(vec![]),
+            "EXEC" => {
+                let commands = transaction
+                    .take()
+                    .with_context(|| format!("EXEC without MULTI in {}", path.display()))?;
+                for command in commands {
+                    context.execute_command(command).await;
+                }
+            }
+            name => {
+                let args = args.iter().cloned().map(Data::BStr).collect::<Vec<_>>();
+                let command = Command::from(args.as_slice());
+                if matches!(command, Command::Invalid) {
+                    bail!("Unknown command '{name}' in {}", path.display());
+                }
+                match &mut transaction {
+                    Some(commands) => commands.push(command),
+                    None => {
+                        context.execute_command(command).await;
+                    }
+                }
+            }
+        }
+        if transaction.is_none() {
+            loaded = pos;
+        }
+    }
+
+    if loaded < bytes.len() {
+        ensure!(last, "{} ends with an incomplete command", path.display());
+        eprintln!(
+            "{} ends with an incomplete command or transaction, truncating it from {} to {loaded} bytes",
+            path.display(),
+            bytes.len()
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(loaded as u64))
+            .with_context(|| format!("Failed to truncate {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Loads a base file, an RDB snapshot or, from older releases, a file of commands.
+async fn load_base(context: &ServerContext, path: &Path) -> Result<()> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    context.store.data.lock().await.clear();
+    if bytes.starts_with(b"REDIS") {
+        let mut rdb = RdbFile::try_from(&mut Bytes::from(bytes))
+            .with_context(|| format!("Failed to load {}", path.display()))?;
+        context.load_functions(std::mem::take(&mut rdb.functions));
+        context.store.replace_data(rdb_data(rdb)).await;
+        Ok(())
+    } else {
+        context.load_functions(vec![]);
+        replay(context, path, false).await
+    }
+}
+
+/// Creates an incr file, starting with a SELECT so it can be replayed on its own.
+fn create_incr(path: &Path) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    file.write_all(encode_array_of_bstrings(&["SELECT".into(), "0".into()]).as_bytes())?;
+    Ok(file)
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path)
+        .map(|meta| meta.len())
+        .unwrap_or_default()
+}
+
+struct AofState {
+    manifest: Manifest,
+    /// The last incr file, which commands are appended to.
+    file: File,
+    /// Whether anything was written since the last fsync.
+    unsynced: bool,
+    current_size: u64,
+    /// The size after the last rewrite, or at startup, for automatic rewrites.
+    base_size: u64,
+    rewrite_in_progress: bool,
+    last_rewrite_ok: bool,
+    last_rewrite_try: Option<Instant>,
+}
+
+/// The append-only file: a base snapshot plus incr files of the commands written since,
+/// listed by a manifest. Every propagated command is appended to the last incr file.
+#[derive(Clone)]
+pub struct AppendOnlyFile {
+    config: Arc<AofConfig>,
+    state: Arc<Mutex<AofState>>,
+}
+
+impl AppendOnlyFile {
+    /// Replaces the data in `context` with the files the manifest lists. Without a
+    /// manifest the log starts from a base with the data already loaded.
+    pub async fn open(config: AofConfig, context: &ServerContext) -> Result<Self> {
+        let manifest_path = config.manifest_path();
+        let manifest = match fs::read_to_string(&manifest_path) {
+            Ok(manifest) => Manifest::parse(&manifest)
+                .with_context(|| format!("Failed to load {}", manifest_path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                fs::create_dir_all(&config.dir)
+                    .with_context(|| format!("Failed to create {}", config.dir.display()))?;
+                let base = config.base_file(1);
+                let data = context.store.data.lock().await;
+                let snapshot = encode_snapshot(&data, &context.store.functions(), true);
+                write_atomically(&config.path(&base), &snapshot)?;
+                let manifest = Manifest {
+                    base: Some(base),
+                    incrs: vec![],
+                };
+                return Self::start(config, manifest);
+            }
+            Err(e) => bail!("Failed to read {}: {e}", manifest_path.display()),
+        };
+
+        match &manifest.base {
+            Some(base) => load_base(context, &config.path(base)).await?,
+            None => {
+                context.load_functions(vec![]);
+                context.store.data.lock().await.clear();
+            }
+        }
+        for (i, incr) in manifest.incrs.iter().enumerate() {
+            let last = i + 1 == manifest.incrs.len();
+            replay(context, &config.path(incr), last).await?;
+        }
+        context.store.loaded();
+        Self::start(config, manifest)
+    }
+
+    /// Opens the last incr file for appending, creating one if there is none.
+    fn start(config: AofConfig, mut manifest: Manifest) -> Result<Self> {
+        let file = match manifest.incrs.last() {
+            Some(incr) => OpenOptions::new()
+                .append(true)
+                .open(config.path(incr))
+                .with_context(|| format!("Failed to open {}", incr.name))?,
+            None => {
+                let incr = config.incr_file(manifest.next_incr_seq());
+                let file = create_incr(&config.path(&incr))?;
+                manifest.incrs.push(incr);
+                write_manifest(&config, &manifest)?;
+                file
+            }
+        };
+        let size = manifest.files().map(|f| file_size(&config.path(f))).sum();
+        let state = AofState {
+            manifest,
+            file,
+            unsynced: false,
+            current_size: size,
+            base_size: size,
+            rewrite_in_progress: false,
+            last_rewrite_ok: true,
+            last_rewrite_try: None,
+        };
+        Ok(Self {
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        self.config.fsync
+    }
+
+    /// Appends encoded commands, flushing them to disk first with `appendfsync always`.
+    pub fn append(&self, commands: &str) -> Result<()> {
+        let mut state = self.lock();
+        state.file.write_all(commands.as_bytes())?;
+        state.current_size += commands.len() as u64;
+        match self.config.fsync {
+            FsyncPolicy::Always => state.file.sync_data()?,
+            _ => state.unsynced = true,
+        }
+        Ok(())
+    }
+
+    /// Flushes appended commands to disk, without blocking appends while it does.
+    pub fn fsync(&self) -> Result<()> {
+        let file = {
+            let mut state = self.lock();
+            if !std::mem::take(&mut state.unsynced) {
+                return Ok(());
+            }
+            state.file.try_clone()?
+        };
+        Ok(file.sync_data()?)
+    }
+
+    /// Whether the log grew enough since the last rewrite to rewrite it again, unless one
+    /// is running or the last one failed moments ago.
+    pub fn rewrite_due(&self) -> bool {
+        let state = self.lock();
+        let retrying = !state.last_rewrite_ok
+            && state
+                .last_rewrite_try
+                .is_some_and(|tried| tried.elapsed() < REWRITE_RETRY_DELAY);
+        let percentage = self.config.rewrite_percentage;
+        if percentage == 0
+            || retrying
+            || state.rewrite_in_progress
+            || state.current_size < self.config.rewrite_min_size
+        {
+            return false;
+        }
+        let base_size = state.base_size.max(1);
+        state.current_size.saturating_sub(base_size) * 100 / base_size >= percentage
+    }
+
+    /// Compacts the log, with the function libraries, into a new base. Commands from now on go to a new incr file,
+    /// opened while the data is copied so none are missed or written twice. The base is
+    /// encoded and written on a blocking thread, and the handle completes once the old
+    /// files were replaced or the rewrite failed.
+    pub async fn rewrite(&self, store: &InMemoryStore) -> Result<JoinHandle<()>> {
+        let (data, functions, base, incr) = {
+            let data = store.data.lock().await;
+            let mut state = self.lock();
+            if state.rewrite_in_progress {
+                bail!(ERR_REWRITE_IN_PROGRESS);
+            }
+            state.last_rewrite_try = Some(Instant::now());
+            let incr = self.config.incr_file(state.manifest.next_incr_seq());
+            let file = create_incr(&self.config.path(&incr))?;
+            let mut manifest = state.manifest.clone();
+            manifest.incrs.push(incr.clone());
+            if let Err(e) = write_manifest(&self.config, &manifest) {
+                let _ = fs::remove_file(self.config.path(&incr));
+                return Err(e);
+            }
+            let previous = std::mem::replace(&mut state.file, file);
+            if state.unsynced {
+                let _ = previous.sync_data();
+            }
+            state.manifest = manifest;
+            state.rewrite_in_progress = true;
+            let base = self.config.base_file(state.manifest.next_base_seq());
+            (data.clone(), store.functions(), base, incr)
+        };
+
+        let aof = self.clone();
+        Ok(tokio::spawn(async move {
+            let path = aof.config.path(&base);
+            let written = tokio::task::spawn_blocking(move || {
+                write_atomically(&path, &encode_snapshot(&data, &functions, true))
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|written| written);
+            let rewritten = written.and_then(|_| aof.rewritten(base, incr));
+            if let Err(e) = &rewritten {
+                eprintln!("Background append only file rewrite failed: {e}");
+            }
+            let mut state = aof.lock();
+            state.rewrite_in_progress = false;
+            state.last_rewrite_ok = rewritten.is_ok();
+        }))
+    }
+
+    /// The fields of INFO's persistence section.
+    pub fn info(&self) -> Vec<(&'static str, String)> {
+        let state = self.lock();
+        let rewrite_status = match state.last_rewrite_ok {
+            true => "ok",
+            false => "err",
+        };
+        vec![
+            ("aof_enabled", "1".into()),
+            (
+                "aof_rewrite_in_progress",
+                (state.rewrite_in_progress as u8).to_string(),
+            ),
+            ("aof_last_bgrewrite_status", rewrite_status.into()),
+            ("aof_current_size", state.current_size.to_string()),
+            ("aof_base_size", state.base_size.to_string()),
+        ]
+    }
+
+    /// Lists the new base with the incr files opened since the rewrite started, then
+    /// deletes the files it replaced.
+    fn rewritten(&self, base: AofFile, incr: AofFile) -> Result<()> {
+        let mut state = self.lock();
+        let incrs = state.manifest.incrs.iter().filter(|f| f.seq >= incr.seq);
+        let manifest = Manifest {
+            base: Some(base.clone()),
+            incrs: incrs.cloned().collect(),
+        };
+        if let Err(e) = write_manifest(&self.config, &manifest) {
+            let _ = fs::remove_file(self.config.path(&base));
+            return Err(e);
+        }
+        let previous = std::mem::replace(&mut state.manifest, manifest);
+        for file in previous.files() {
+            if !state.manifest.files().any(|kept| kept == file) {
+                let _ = fs::remove_file(self.config.path(file));
+            }
+        }
+        let size = state
+            .manifest
+            .files()
+            .map(|f| file_size(&self.config.path(f)))
+            .sum();
+        state.current_size = size;
+        state.base_size = size;
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, AofState> {
+        self.state.lock().unwrap()
+    }
+}
+
+fn write_manifest(config: &AofConfig, manifest: &Manifest) -> Result<()> {
+    write_atomically(&config.manifest_path(), manifest.to_string().as_bytes())
+}
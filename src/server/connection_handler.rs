@@ -1,24 +1,50 @@
 use super::{context::ServerContext, stream_reader::StreamReader};
 use crate::{
     channel::{context::SubscriptionContext, ChannelCommand},
-    command::{core::Command, response::CommandResponse},
-    common::{encode_error, encode_sstring},
+    command::{
+        core::Command,
+        response::{encode_array_of_bstrings, CommandResponse},
+    },
+    common::{encode_bstring, encode_error, encode_int, encode_sstring},
     protocol::Data,
+    scripting::{functions::FunctionSubcommand, ScriptSubcommand},
+    store::{
+        persistence::rdb_path,
+        tracking::{ClientSubcommand, Invalidation},
+        watch::WatchedKey,
+    },
 };
 use anyhow::{bail, Result};
 use tokio::{
     net::TcpStream,
-    sync::{mpsc::Sender, oneshot},
+    sync::{
+        mpsc::{self, Sender, UnboundedReceiver},
+        oneshot,
+    },
 };
 
-pub type ChannelType = (Command, Option<oneshot::Sender<CommandResponse>>);
+/// A command for the event loop, the channel for its response, and the client that sent
+/// it, if any.
+pub type ChannelType = (
+    Command,
+    Option<oneshot::Sender<CommandResponse>>,
+    Option<u64>,
+);
 
 pub struct ConnectionHandler {
     reader: Option<StreamReader<TcpStream>>,
     in_transaction: bool,
+    /// Set when a command failed to queue, so the transaction is aborted on EXEC.
+    transaction_failed: bool,
     transaction_commands: Vec<Command>,
+    watched_keys: Vec<WatchedKey>,
     tx: Sender<ChannelType>,
     context: ServerContext,
+    client_id: u64,
+    /// Whether HELLO switched the connection to RESP3, which gets invalidations as pushes.
+    resp3: bool,
+    /// What `CLIENT CACHING` set for the next command.
+    caching: Option<bool>,
 }
 
 impl ConnectionHandler {
@@ -26,9 +52,14 @@ impl ConnectionHandler {
         Self {
             reader: Some(StreamReader::new(stream, false)),
             in_transaction: false,
+            transaction_failed: false,
             transaction_commands: vec![],
+            watched_keys: vec![],
             tx,
             context,
+            client_id: 0,
+            resp3: false,
+            caching: None,
         }
     }
 
@@ -46,8 +77,12 @@ impl ConnectionHandler {
         bail!("No reader");
     }
 
-    async fn handle_transaction(&mut self, command: &Command) -> Result<bool> {
+    async fn handle_transaction(&mut self, command: &Command, name: &str) -> Result<bool> {
         match &command {
+            Command::Multi if self.in_transaction => {
+                let response = encode_error("MULTI calls can not be nested");
+                self.write(response.as_bytes()).await?;
+            }
             Command::Multi => {
                 self.in_transaction = true;
                 self.write(encode_sstring("OK").as_bytes()).await?;
@@ -56,24 +91,64 @@ impl ConnectionHandler {
                 let response = encode_error("EXEC without MULTI");
                 self.write(response.as_bytes()).await?;
             }
+            Command::Exec if self.transaction_failed => {
+                self.reset_transaction().await;
+                let response =
+                    encode_error("EXECABORT Transaction discarded because of previous errors.");
+                self.write(response.as_bytes()).await?;
+            }
             Command::Exec => {
                 let (result_tx, result_rx) = oneshot::channel();
-                let tr_command = Command::Transaction(self.transaction_commands.clone());
-                self.tx.send((tr_command, Some(result_tx))).await?;
+                let read_keys = self
+                    .transaction_commands
+                    .iter()
+                    .flat_map(Command::read_keys)
+                    .collect();
+                self.track_reads(read_keys).await;
+                let tr_command = Command::Transaction(
+                    self.transaction_commands.clone(),
+                    self.watched_keys.clone(),
+                );
+                self.tx
+                    .send((tr_command, Some(result_tx), Some(self.client_id)))
+                    .await?;
                 let response = result_rx.await?;
                 self.write(String::from(response).as_bytes()).await?;
-                self.transaction_commands.clear();
-                self.in_transaction = false;
+                self.reset_transaction().await;
             }
             Command::Discard if self.in_transaction => {
-                self.in_transaction = false;
-                self.transaction_commands.clear();
+                self.reset_transaction().await;
                 self.write(encode_sstring("OK").as_bytes()).await?;
             }
             Command::Discard if !self.in_transaction => {
                 let response = encode_error("DISCARD without MULTI");
                 self.write(response.as_bytes()).await?;
             }
+            Command::Watch(_) if self.in_transaction => {
+                let response = encode_error("WATCH inside MULTI is not allowed");
+                self.write(response.as_bytes()).await?;
+            }
+            Command::Watch(keys) => {
+                let keys = keys
+                    .iter()
+                    .filter(|key| !self.watched_keys.iter().any(|w| &w.key == *key))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let watched = self.context.store.watch(keys).await;
+                self.watched_keys.extend(watched);
+                self.write(encode_sstring("OK").as_bytes()).await?;
+            }
+            Command::Unwatch if !self.in_transaction => {
+                self.unwatch_all().await;
+                self.write(encode_sstring("OK").as_bytes()).await?;
+            }
+            Command::Invalid if self.in_transaction => {
+                self.transaction_failed = true;
+                let response = encode_error(&format!(
+                    "unknown command or wrong number of arguments for '{name}'"
+                ));
+                self.write(response.as_bytes()).await?;
+            }
             _ if self.in_transaction => {
                 self.transaction_commands.push(command.clone());
                 self.write(encode_sstring("QUEUED").as_bytes()).await?;
@@ -83,53 +158,260 @@ impl ConnectionHandler {
         Ok(true)
     }
 
-    async fn subscribe_mode(&mut self, channel: String) -> Result<()> {
+    async fn reset_transaction(&mut self) {
+        self.in_transaction = false;
+        self.transaction_failed = false;
+        self.transaction_commands.clear();
+        self.unwatch_all().await;
+    }
+
+    async fn unwatch_all(&mut self) {
+        let watched = std::mem::take(&mut self.watched_keys);
+        self.context.store.unwatch(&watched).await;
+    }
+
+    /// Registers the keys the next command reads with the client's tracking, if it is on.
+    /// Done before the command runs, so a write racing with the read still invalidates it.
+    async fn track_reads(&mut self, keys: Vec<String>) {
+        let caching = self.caching.take();
+        if !keys.is_empty() {
+            let store = &self.context.store;
+            store.track_reads(self.client_id, keys, caching).await;
+        }
+    }
+
+    async fn write_invalidation(
+        &mut self,
+        invalidation: Invalidation,
+        subscribed: bool,
+    ) -> Result<()> {
+        if let Some(frame) = invalidation.encode(self.resp3, subscribed) {
+            self.write(frame.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn client(&mut self, subcommand: ClientSubcommand) -> Result<String> {
+        let store = &self.context.store;
+        Ok(match subcommand {
+            ClientSubcommand::Id => encode_int(self.client_id as i64),
+            ClientSubcommand::Tracking(options) => {
+                match store.set_tracking(self.client_id, options).await {
+                    Ok(()) => encode_sstring("OK"),
+                    Err(e) => encode_error(&e.to_string()),
+                }
+            }
+            ClientSubcommand::Caching(caching) => {
+                let tracking = store.tracking(self.client_id).await;
+                match tracking {
+                    Some(options) if (caching && options.optin) || (!caching && options.optout) => {
+                        self.caching = Some(caching);
+                        return Ok(encode_sstring("OK"));
+                    }
+                    Some(options) if options.optin || options.optout => encode_error(
+                        "CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.",
+                    ),
+                    _ => encode_error(
+                        "CLIENT CACHING can be called only when the client is in tracking mode \
+                         with OPTIN or OPTOUT mode enabled",
+                    ),
+                }
+            }
+            ClientSubcommand::GetRedir => match store.tracking(self.client_id).await {
+                Some(options) => encode_int(options.redirect.map_or(0, |id| id as i64)),
+                None => encode_int(-1),
+            },
+        })
+    }
+
+    /// HELLO: switches the protocol version and replies with the connection's details,
+    /// as a map on RESP3.
+    async fn hello(&mut self, version: Option<String>) -> String {
+        match version.as_deref() {
+            None => (),
+            Some("2") => self.resp3 = false,
+            Some("3") => self.resp3 = true,
+            Some(_) => return encode_error("NOPROTO unsupported protocol version"),
+        }
+        let role = match self.context.state.lock().await.is_replica() {
+            true => "replica",
+            false => "master",
+        };
+        let fields = [
+            ("server", encode_bstring("redis")),
+            ("version", encode_bstring(env!("CARGO_PKG_VERSION"))),
+            ("proto", encode_int(if self.resp3 { 3 } else { 2 })),
+            ("id", encode_int(self.client_id as i64)),
+            ("mode", encode_bstring("standalone")),
+            ("role", encode_bstring(role)),
+            ("modules", encode_array_of_bstrings(&[])),
+        ];
+        let header = match self.resp3 {
+            true => format!("%{}\r\n", fields.len()),
+            false => format!("*{}\r\n", fields.len() * 2),
+        };
+        fields.into_iter().fold(header, |reply, (name, value)| {
+            reply + &encode_bstring(name) + &value
+        })
+    }
+
+    /// Serves subscribe-mode commands until no subscriptions are left. Returns whether the
+    /// connection is to be closed, after QUIT or going over the output buffer limit.
+    async fn subscribe_mode(
+        &mut self,
+        command: ChannelCommand,
+        invalidations: &mut UnboundedReceiver<Invalidation>,
+    ) -> Result<bool> {
         let mut sub_context = SubscriptionContext::new(self.context.channels.clone()).await;
-        let response = sub_context
-            .process_command(ChannelCommand::Subscribe(channel))
-            .await?;
+        let response = sub_context.process_command(command).await?;
         self.write(response.as_bytes()).await?;
 
-        loop {
+        while sub_context.is_subscribed().await {
             tokio::select! {
-                Ok(data) = self.read() => {
-                    if let Ok(response) = sub_context.process_command(data.into()).await {
-                        self.write(response.as_bytes()).await?
-                    } else {
+                data = self.read() => {
+                    let Ok(data) = data else {
+                        sub_context.quit().await;
+                        return Ok(true);
+                    };
+                    let command = ChannelCommand::from(data);
+                    let quit = matches!(command, ChannelCommand::Quit);
+                    let reset = matches!(command, ChannelCommand::Reset);
+                    let Ok(response) = sub_context.process_command(command).await else {
                         break;
+                    };
+                    self.write(response.as_bytes()).await?;
+                    if reset {
+                        self.reset_transaction().await;
+                        self.reset_client().await;
+                    }
+                    if quit {
+                        return Ok(true);
                     }
                 }
-                Some(publish) = sub_context.receive_publish() => {
+                publish = sub_context.receive_publish() => {
+                    // Over the output buffer limit: the connection is dropped.
+                    let Some(publish) = publish else {
+                        return Ok(true);
+                    };
                     self.write(publish.as_bytes()).await?
                 }
+                Some(invalidation) = invalidations.recv() => {
+                    self.write_invalidation(invalidation, true).await?;
+                }
             }
         }
-        Ok(())
+        sub_context.quit().await;
+        Ok(false)
+    }
+
+    /// RESET: back to RESP2, with tracking off.
+    async fn reset_client(&mut self) {
+        self.resp3 = false;
+        self.caching = None;
+        let _ = self.context.store.set_tracking(self.client_id, None).await;
     }
 
     pub async fn handle(&mut self) -> Result<()> {
+        let (invalidation_tx, mut invalidations) = mpsc::unbounded_channel();
+        self.client_id = self.context.store.register_client(invalidation_tx).await;
+        let result = self.handle_commands(&mut invalidations).await;
+        self.unwatch_all().await;
+        self.context.store.unregister_client(self.client_id).await;
+        result
+    }
+
+    async fn handle_commands(
+        &mut self,
+        invalidations: &mut UnboundedReceiver<Invalidation>,
+    ) -> Result<()> {
         loop {
-            let data = self.read().await?;
+            let data = tokio::select! {
+                data = self.read() => data?,
+                Some(invalidation) = invalidations.recv() => {
+                    self.write_invalidation(invalidation, false).await?;
+                    continue;
+                }
+            };
+            let name = command_name(&data);
             let command: Command = data.into();
 
+            if let Some(command) = channel_command(&command) {
+                if self.subscribe_mode(command, invalidations).await? {
+                    return Ok(());
+                }
+                continue;
+            }
+
             match command {
                 Command::Psync(..) => {
                     self.context.add_replica(self.reader.take().unwrap()).await;
                     return Ok(());
                 }
-                Command::Subscribe(channel) => {
-                    self.subscribe_mode(channel).await?;
+                // Answered here rather than on the event loop, which the script is holding.
+                Command::Script(ScriptSubcommand::Kill)
+                | Command::Function(FunctionSubcommand::Kill) => {
+                    let function = matches!(command, Command::Function(_));
+                    let response = String::from(self.context.scripts.kill(function));
+                    self.write(response.as_bytes()).await?;
+                    continue;
+                }
+                // The one other command a busy server takes, exiting without waiting for
+                // the script.
+                Command::Shutdown(Some(false)) if self.context.scripts.is_busy() => {
+                    let response = match self
+                        .context
+                        .prepare_shutdown(Some(false), &rdb_path())
+                        .await
+                    {
+                        Ok(()) => {
+                            self.context.shutdown.notify_one();
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to shut down: {e}");
+                            encode_error("Errors trying to SHUTDOWN. Check logs.")
+                        }
+                    };
+                    self.write(response.as_bytes()).await?;
+                    continue;
+                }
+                Command::Function(FunctionSubcommand::Stats) => {
+                    let response =
+                        String::from(self.context.scripts.function(FunctionSubcommand::Stats));
+                    self.write(response.as_bytes()).await?;
+                    continue;
+                }
+                _ if self.context.scripts.is_busy() => {
+                    let response = encode_error(
+                        "BUSY Redis is busy running a script. \
+                         You can only call SCRIPT KILL or SHUTDOWN NOSAVE.",
+                    );
+                    self.write(response.as_bytes()).await?;
+                    continue;
+                }
+                // Connection state, so answered here rather than on the event loop.
+                Command::Client(subcommand) => {
+                    let response = self.client(subcommand).await?;
+                    self.write(response.as_bytes()).await?;
+                    continue;
+                }
+                Command::Hello(version) => {
+                    let response = self.hello(version).await;
+                    self.write(response.as_bytes()).await?;
                     continue;
                 }
                 _ => (),
             }
 
-            if self.handle_transaction(&command).await? {
+            if self.handle_transaction(&command, &name).await? {
                 continue;
             }
 
+            self.track_reads(command.read_keys()).await;
             let (result_tx, result_rx) = oneshot::channel();
-            self.tx.send((command, Some(result_tx))).await?;
+            self.tx
+                .send((command, Some(result_tx), Some(self.client_id)))
+                .await?;
 
             match result_rx.await? {
                 CommandResponse::Single(response) => self.write(response.as_bytes()).await?,
@@ -143,3 +425,26 @@ impl ConnectionHandler {
         }
     }
 }
+
+/// The subscribe-mode command that (un)subscribing outside subscribe mode starts with.
+fn channel_command(command: &Command) -> Option<ChannelCommand> {
+    match command {
+        Command::Subscribe(channels) => Some(ChannelCommand::Subscribe(channels.clone())),
+        Command::PSubscribe(patterns) => Some(ChannelCommand::PSubscribe(patterns.clone())),
+        Command::Unsubscribe(channels) => Some(ChannelCommand::Unsubscribe(channels.clone())),
+        Command::PUnsubscribe(patterns) => Some(ChannelCommand::PUnsubscribe(patterns.clone())),
+        Command::SSubscribe(channels) => Some(ChannelCommand::SSubscribe(channels.clone())),
+        Command::SUnsubscribe(channels) => Some(ChannelCommand::SUnsubscribe(channels.clone())),
+        _ => None,
+    }
+}
+
+fn command_name(data: &Data) -> String {
+    match data {
+        Data::Array(items) => match items.first() {
+            Some(Data::BStr(name)) => name.to_lowercase(),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    }
+}
@@ -1,58 +1,185 @@
 use super::{
+    aof::AppendOnlyFile,
     replica::{replica_stream_handler, ReplicaManager, ReplicaState},
     stream_reader::StreamReader,
 };
 use crate::{
     command::{
         core::Command,
+        geo_handlers,
         handlers::{self},
         response::{
-            array_of_arrays_response, array_response, bstring_response, error_response,
-            int_response, null_array_response, null_response, sstring_response, CommandResponse,
+            array_of_arrays_response, array_response, bstring_response, encode_array_of_bstrings,
+            error_response, int_response, null_array_response, null_response,
+            optional_array_response, sstring_response, CommandResponse,
         },
         stream_handlers,
     },
+    scripting::ScriptEngine,
     server::{config, state::ServerState},
-    store::{core::InMemoryStore, list::blpop_handler},
+    store::{
+        consumer_group::XGroupSubcommand,
+        coords::validate_coords,
+        core::InMemoryStore,
+        list::blpop_handler,
+        persistence::{rdb_path, save_points},
+        watch::WatchedKey,
+    },
 };
-use std::sync::Arc;
+use anyhow::Result;
+use std::{cell::RefCell, future::Future, path::Path, sync::Arc};
 use tokio::{
     net::TcpStream,
-    sync::{mpsc, Mutex},
+    runtime::{Handle, RuntimeFlavor},
+    sync::{mpsc, Mutex, Notify},
 };
 
-#[derive(Clone, Default)]
+tokio::task_local! {
+    /// Commands propagated by the transaction or script running on this task, sent to
+    /// replicas as a single MULTI/EXEC block once it completes.
+    static TRANSACTION_PROPAGATION: RefCell<Vec<String>>;
+}
+
+#[derive(Clone)]
 pub struct ServerContext {
     pub store: InMemoryStore,
     pub state: Arc<Mutex<ServerState>>,
     pub replicas: Arc<Mutex<ReplicaManager>>,
     pub channels: crate::channel::ChannelManager,
+    pub scripts: ScriptEngine,
+    /// Notified once SHUTDOWN has saved, for the server to exit.
+    pub shutdown: Arc<Notify>,
+    /// Set once the append-only file is loaded, if `appendonly` is enabled.
+    pub aof: Option<AppendOnlyFile>,
+}
+
+impl Default for ServerContext {
+    /// The store publishes its keyspace notifications on the server's own channels.
+    fn default() -> Self {
+        let channels = crate::channel::ChannelManager::default();
+        let state = ServerState::default();
+        let mut store = InMemoryStore::default().with_notifications(channels.clone());
+        if state.is_replica() {
+            store = store.as_replica();
+        }
+        let context = Self {
+            store,
+            state: Arc::new(Mutex::new(state)),
+            replicas: Arc::default(),
+            channels,
+            scripts: ScriptEngine::default(),
+            shutdown: Arc::default(),
+            aof: None,
+        };
+        context.load_functions(context.store.functions());
+        context
+    }
 }
 
 impl ServerContext {
+    /// Loads the function libraries saved in a snapshot, in place of those loaded.
+    pub fn load_functions(&self, codes: Vec<String>) {
+        if let Err(e) = self.scripts.restore_libraries(&codes) {
+            eprintln!("Failed to load functions: {e}");
+        }
+        self.store.set_functions(self.scripts.library_codes());
+    }
+
+    /// Saves a final snapshot to `path` before the server exits: always with `Some(true)`,
+    /// never with `Some(false)`, and otherwise only if save points are configured. A
+    /// background save still running is waited for first, and the append-only file is
+    /// flushed to disk.
+    pub async fn prepare_shutdown(&self, save: Option<bool>, path: &Path) -> Result<()> {
+        self.store.wait_for_bgsave().await;
+        if let Some(aof) = &self.aof {
+            aof.fsync()?;
+        }
+        if save.unwrap_or_else(|| !save_points().is_empty()) {
+            self.store.save(path).await?;
+        }
+        Ok(())
+    }
+
     pub async fn execute_command(&self, request: Command) -> CommandResponse {
+        let mut propagated = request.is_write().then(|| request.clone());
+        let response = self.dispatch(request, &mut propagated).await;
+        self.propagate_expired().await;
+        if let Some(command) = propagated.filter(|_| !response.is_error()) {
+            self.propagate(encode_array_of_bstrings(&command.to_args()))
+                .await;
+        }
+        response
+    }
+
+    /// Runs a command. `propagated` starts as a copy of a write command; commands rewrite
+    /// it into a deterministic form, or clear it when they changed nothing.
+    async fn dispatch(
+        &self,
+        request: Command,
+        propagated: &mut Option<Command>,
+    ) -> CommandResponse {
         match request {
             Command::Ping => sstring_response("PONG"),
             Command::Echo(val) => bstring_response(&val),
+            Command::Error(message) => error_response(&message),
             Command::Get(key) => CommandResponse::Single(handlers::get(&key, &self.store).await),
-            Command::Set {
-                key,
-                value,
-                expiry,
-                raw_command,
-            } => {
-                self.store.set(key.to_string(), value.clone(), expiry).await;
-                self.propagate(raw_command).await;
+            Command::Set { key, value, expiry } => {
+                self.store.set(key, value, expiry).await;
                 sstring_response("OK")
             }
+            Command::ConfigGet(key) if key.eq_ignore_ascii_case("notify-keyspace-events") => {
+                array_response(vec![key, self.store.notifier.flags()])
+            }
             Command::ConfigGet(key) => match config::get_config_value(&key) {
                 Some(value) => array_response(vec![key, value]),
                 _ => null_response(),
             },
+            Command::ConfigSet(key, value)
+                if key.eq_ignore_ascii_case("notify-keyspace-events") =>
+            {
+                match self.store.notifier.set_flags(&value) {
+                    true => sstring_response("OK"),
+                    false => error_response(&format!(
+                        "Invalid argument '{value}' for CONFIG SET '{key}'"
+                    )),
+                }
+            }
+            Command::ConfigSet(key, _) => {
+                error_response(&format!("Unsupported CONFIG parameter: {key}"))
+            }
             Command::Keys(pattern) => {
                 CommandResponse::Single(handlers::keys(&pattern, &self.store).await)
             }
-            Command::Info => CommandResponse::Single(handlers::info(self).await),
+            Command::Info(section) => {
+                CommandResponse::Single(handlers::info(self, section.as_deref()).await)
+            }
+            Command::Save => match self.store.save(&rdb_path()).await {
+                Ok(()) => sstring_response("OK"),
+                Err(e) => error_response(&e.to_string()),
+            },
+            Command::BgSave => match self.store.bgsave(rdb_path()).await {
+                Ok(_) => sstring_response("Background saving started"),
+                Err(e) => error_response(&e.to_string()),
+            },
+            Command::LastSave => int_response(self.store.last_save() as i64),
+            Command::BgRewriteAof => match &self.aof {
+                Some(aof) => match aof.rewrite(&self.store).await {
+                    Ok(_) => sstring_response("Background append only file rewriting started"),
+                    Err(e) => error_response(&e.to_string()),
+                },
+                None => error_response("Append only file is disabled"),
+            },
+            Command::Shutdown(save) => match self.prepare_shutdown(save, &rdb_path()).await {
+                Ok(()) => {
+                    self.shutdown.notify_one();
+                    // The connection is closed without a reply.
+                    CommandResponse::Multiple(vec![])
+                }
+                Err(e) => {
+                    eprintln!("Failed to save before shutting down: {e}");
+                    error_response("Errors trying to SHUTDOWN. Check logs.")
+                }
+            },
             Command::Replconf => sstring_response("OK"),
             Command::ReplconfGetAck(_) => CommandResponse::ReplconfAck,
             Command::Wait {
@@ -64,40 +191,218 @@ impl ServerContext {
                     .await
                     .as_ref(),
             ),
-            Command::XAdd { key, id, entry } => match self.store.add_stream(key, id, entry).await {
-                Ok(res) => bstring_response(&res),
+            Command::XAdd {
+                key,
+                id,
+                entry,
+                trim,
+                no_mkstream,
+            } => match self
+                .store
+                .add_stream(key, id, entry, trim, no_mkstream)
+                .await
+            {
+                Ok(Some(res)) => {
+                    if let Some(Command::XAdd { id, .. }) = propagated {
+                        *id = res.clone();
+                    }
+                    bstring_response(&res)
+                }
+                Ok(None) => {
+                    propagated.take();
+                    null_response()
+                }
+                Err(e) => error_response(&e.to_string()),
+            },
+            Command::XTrim { key, trim } => match self.store.trim_stream(key, trim).await {
+                Ok(count) => {
+                    if count == 0 {
+                        propagated.take();
+                    }
+                    int_response(count as i64)
+                }
+                Err(e) => error_response(&e.to_string()),
+            },
+            Command::XRange {
+                key,
+                start,
+                end,
+                count,
+                rev,
+            } => stream_handlers::xrange(key, start, end, count, rev, &self.store).await,
+            Command::XLen(key) => match self.store.stream_len(&key).await {
+                Ok(len) => int_response(len as i64),
+                Err(e) => error_response(&e.to_string()),
+            },
+            Command::XDel { key, ids } => match self.store.delete_stream_entries(key, ids).await {
+                Ok(deleted) => {
+                    if deleted == 0 {
+                        propagated.take();
+                    }
+                    int_response(deleted as i64)
+                }
+                Err(e) => error_response(&e.to_string()),
+            },
+            Command::XSetId {
+                key,
+                last_id,
+                entries_added,
+                max_deleted_id,
+            } => match self
+                .store
+                .set_stream_id(key, last_id, entries_added, max_deleted_id)
+                .await
+            {
+                Ok(()) => sstring_response("OK"),
+                Err(e) => error_response(&e.to_string()),
+            },
+            Command::XInfo(subcommand) => stream_handlers::xinfo(subcommand, &self.store).await,
+            Command::XRead {
+                streams,
+                count,
+                block,
+            } => stream_handlers::xread(streams, count, block, &self.store)
+                .await
+                .unwrap_or(null_array_response()),
+            Command::XGroup {
+                key,
+                group,
+                subcommand,
+            } => {
+                let response = match subcommand {
+                    XGroupSubcommand::Create {
+                        id,
+                        mkstream,
+                        entries_read,
+                    } => self
+                        .store
+                        .xgroup_create(key, group, id, mkstream, entries_read)
+                        .await
+                        .map(|_| sstring_response("OK")),
+                    XGroupSubcommand::SetId { id, entries_read } => self
+                        .store
+                        .xgroup_setid(key, group, id, entries_read)
+                        .await
+                        .map(|_| sstring_response("OK")),
+                    XGroupSubcommand::Destroy => self
+                        .store
+                        .xgroup_destroy(key, group)
+                        .await
+                        .map(|destroyed| int_response(destroyed as i64)),
+                    XGroupSubcommand::CreateConsumer(consumer) => self
+                        .store
+                        .xgroup_create_consumer(key, group, consumer)
+                        .await
+                        .map(|created| int_response(created as i64)),
+                    XGroupSubcommand::DelConsumer(consumer) => self
+                        .store
+                        .xgroup_del_consumer(key, group, consumer)
+                        .await
+                        .map(|pending| int_response(pending as i64)),
+                };
+                response.unwrap_or_else(|e| error_response(&e.to_string()))
+            }
+            Command::XReadGroup {
+                group,
+                consumer,
+                streams,
+                count,
+                block,
+                no_ack,
+            } => match stream_handlers::xreadgroup(
+                &group,
+                &consumer,
+                &streams,
+                count,
+                block,
+                no_ack,
+                &self.store,
+            )
+            .await
+            {
+                Ok(outcome) => {
+                    self.propagate_effects(outcome.effects).await;
+                    stream_handlers::map_xreadgroup_response(outcome.result)
+                }
+                Err(e) => error_response(&e.to_string()),
+            },
+            Command::XAck { key, group, ids } => match self.store.ack(key, group, ids).await {
+                Ok(count) => {
+                    if count == 0 {
+                        propagated.take();
+                    }
+                    int_response(count as i64)
+                }
                 Err(e) => error_response(&e.to_string()),
             },
-            Command::XRange { key, start, end } => {
-                stream_handlers::xrange(key, start, end, &self.store)
+            Command::XPending { key, group, filter } => match filter {
+                None => match self.store.pending_summary(key, group).await {
+                    Ok(summary) => stream_handlers::map_pending_summary(summary),
+                    Err(e) => error_response(&e.to_string()),
+                },
+                Some(filter) => match self.store.pending_range(key, group, filter).await {
+                    Ok(details) => stream_handlers::map_pending_details(details),
+                    Err(e) => error_response(&e.to_string()),
+                },
+            },
+            Command::XClaim {
+                key,
+                group,
+                consumer,
+                min_idle,
+                ids,
+                options,
+            } => {
+                let just_id = options.just_id;
+                match self
+                    .store
+                    .claim(key, group, consumer, min_idle, ids, options)
                     .await
-                    .unwrap_or(null_response())
+                {
+                    Ok(outcome) => {
+                        self.propagate_effects(outcome.effects).await;
+                        stream_handlers::map_claim_response(outcome.result, just_id)
+                    }
+                    Err(e) => error_response(&e.to_string()),
+                }
             }
-            Command::XRead { streams, block } => {
-                stream_handlers::xread(streams, block, &self.store)
+            Command::XAutoClaim {
+                key,
+                group,
+                consumer,
+                min_idle,
+                options,
+            } => {
+                let just_id = options.just_id;
+                match self
+                    .store
+                    .autoclaim(key, group, consumer, min_idle, options)
                     .await
-                    .unwrap_or(null_array_response())
-            }
-            Command::Incr { key, raw_command } => {
-                let value = self.store.incr(key.clone()).await;
-                match value {
-                    0 => error_response("value is not an integer or out of range"),
-                    _ => {
-                        self.propagate(raw_command).await;
-                        int_response(value)
+                {
+                    Ok(outcome) => {
+                        self.propagate_effects(outcome.effects).await;
+                        stream_handlers::map_autoclaim_response(outcome.result, just_id)
                     }
+                    Err(e) => error_response(&e.to_string()),
                 }
             }
+            Command::Incr { key } => match self.store.incr(key).await {
+                0 => error_response("value is not an integer or out of range"),
+                value => int_response(value),
+            },
+            Command::Del(keys) => {
+                let deleted = self.store.delete(&keys).await;
+                if deleted == 0 {
+                    propagated.take();
+                }
+                int_response(deleted as i64)
+            }
             Command::ListPush {
                 key,
                 values,
-                raw_command,
                 is_left,
             } => match self.store.list_push(key, values, is_left).await {
-                Ok(len) => {
-                    self.propagate(raw_command).await;
-                    int_response(len as i64)
-                }
+                Ok(len) => int_response(len as i64),
                 Err(e) => error_response(&e.to_string()),
             },
             Command::LRange { key, start, end } => {
@@ -107,20 +412,75 @@ impl ServerContext {
             Command::LPop(key, count) => match self.store.list_pop(key, count).await {
                 Some(values) if values.len() == 1 => bstring_response(&values[0]),
                 Some(values) => array_response(values),
-                None => null_response(),
+                None => {
+                    propagated.take();
+                    null_response()
+                }
             },
             Command::BLPop(keys, block_ms) => {
-                match blpop_handler(&self.store, keys, block_ms).await {
+                let popped = blpop_handler(&self.store, keys, block_ms).await;
+                *propagated = popped
+                    .as_ref()
+                    .map(|popped| Command::LPop(popped[0].clone(), 1));
+                match popped {
                     Some(value) => array_response(value),
                     None => null_array_response(),
                 }
             }
-            Command::Multi => sstring_response("OK"),
+            Command::Eval { script, keys, args } => {
+                let _exclusive = self.store.exclusive.lock().await;
+                self.propagate_atomically(async {
+                    run_script(|| self.scripts.eval(self, script, keys, args))
+                })
+                .await
+            }
+            Command::EvalSha { sha, keys, args } => {
+                let _exclusive = self.store.exclusive.lock().await;
+                self.propagate_atomically(async {
+                    run_script(|| self.scripts.eval_sha(self, sha, keys, args))
+                })
+                .await
+            }
+            Command::Script(subcommand) => self.scripts.script(subcommand),
+            Command::FCall {
+                function,
+                keys,
+                args,
+                read_only,
+            } => {
+                let on_replica = self.state.lock().await.is_replica();
+                let _exclusive = self.store.exclusive.lock().await;
+                self.propagate_atomically(async {
+                    run_script(|| {
+                        self.scripts
+                            .fcall(self, function, keys, args, read_only, on_replica)
+                    })
+                })
+                .await
+            }
+            Command::Function(subcommand) => {
+                let response = self.scripts.function(subcommand);
+                if propagated.is_some() && !response.is_error() {
+                    self.store.set_functions(self.scripts.library_codes());
+                    self.store.mark_dirty();
+                }
+                response
+            }
+            Command::Multi | Command::Unwatch => sstring_response("OK"),
+            Command::PubSub(subcommand) => {
+                CommandResponse::Single(self.channels.pubsub(subcommand).await)
+            }
             Command::Publish(channel, message) => {
                 int_response(self.channels.publish(channel, message).await as i64)
             }
+            Command::SPublish(channel, message) => {
+                int_response(self.channels.spublish(channel, message).await as i64)
+            }
             Command::ZAdd { key, score, member } => {
-                int_response(self.store.zadd(key, score, member).await)
+                match self.store.zadd(key, score, member).await {
+                    Ok(added) => int_response(added),
+                    Err(e) => error_response(&e.to_string()),
+                }
             }
             Command::ZRank { key, member } => match self.store.zrank(key, member).await {
                 Some(rank) => int_response(rank as i64),
@@ -134,41 +494,105 @@ impl ServerContext {
                 Some(score) => bstring_response(score.to_string().as_ref()),
                 _ => null_response(),
             },
-            Command::ZRem(key, member) => int_response(self.store.zrem(key, member).await),
-            Command::Geoadd { key, point, member } => {
-                match crate::store::coords::validate_coords(&point) {
-                    None => int_response(self.store.geoadd(key, point, member).await),
-                    Some(err) => error_response(&err),
+            Command::ZRem(key, member) => match self.store.zrem(key, member).await {
+                Ok(removed) => {
+                    if removed == 0 {
+                        propagated.take();
+                    }
+                    int_response(removed)
                 }
-            }
+                Err(e) => error_response(&e.to_string()),
+            },
+            Command::Geoadd {
+                key,
+                members,
+                options,
+            } => match members.iter().find_map(|(point, _)| validate_coords(point)) {
+                None => match self.store.geoadd(key, members, options).await {
+                    Ok(count) => int_response(count),
+                    Err(e) => error_response(&e.to_string()),
+                },
+                Some(err) => error_response(&err),
+            },
             Command::Geopos { key, members } => match self.store.geopos(key, members).await {
                 arr if arr.is_empty() => null_array_response(),
                 arr => array_of_arrays_response(arr),
             },
-            Command::Geodist { key, from, to } => match self.store.geodist(key, from, to).await {
+            Command::Geodist {
+                key,
+                from,
+                to,
+                unit,
+            } => match self.store.geodist(key, from, to, unit).await {
                 Some(dist) => bstring_response(&dist),
                 None => null_response(),
             },
-            Command::Geosearch {
+            Command::Geohash { key, members } => {
+                optional_array_response(self.store.geohash(key, members).await)
+            }
+            Command::Geosearch { key, query } => {
+                geo_handlers::geosearch(key, query, &self.store).await
+            }
+            Command::GeoSearchStore {
+                destination,
                 key,
-                point,
-                radius,
-                unit,
-            } => array_response(self.store.geosearch(key, point, radius, unit).await),
+                query,
+                store_dist,
+            } => match self
+                .store
+                .geosearchstore(destination, key, &query, store_dist)
+                .await
+            {
+                Ok(len) => int_response(len),
+                Err(e) => error_response(&e.to_string()),
+            },
             _ => null_response(),
         }
     }
 
-    pub async fn process_transaction(&self, commands: Vec<Command>) -> CommandResponse {
-        let mut responses = Vec::new();
-        for command in commands {
-            if let CommandResponse::Single(res) = self.execute_command(command).await {
-                responses.push(res);
-            }
+    pub async fn process_transaction(
+        &self,
+        commands: Vec<Command>,
+        watched: Vec<WatchedKey>,
+    ) -> CommandResponse {
+        if self.store.is_dirty(&watched).await {
+            return null_array_response();
         }
+        let responses = self
+            .propagate_atomically(async {
+                let mut responses = Vec::new();
+                for command in commands {
+                    let response = self.execute_command(command.into_non_blocking()).await;
+                    responses.push(String::from(response));
+                }
+                responses
+            })
+            .await;
         CommandResponse::Multiple(responses)
     }
 
+    /// Runs `future`, sending what it propagates to replicas and the append-only file as
+    /// one MULTI/EXEC block so they apply it atomically. Nested calls join the enclosing
+    /// block.
+    async fn propagate_atomically<T>(&self, future: impl Future<Output = T>) -> T {
+        if TRANSACTION_PROPAGATION.try_with(|_| ()).is_ok() {
+            return future.await;
+        }
+        let (result, propagated) = TRANSACTION_PROPAGATION
+            .scope(RefCell::new(vec![]), async {
+                let result = future.await;
+                (result, TRANSACTION_PROPAGATION.with(RefCell::take))
+            })
+            .await;
+        if !propagated.is_empty() {
+            let mut block = encode_array_of_bstrings(&["MULTI".into()]);
+            block.extend(propagated);
+            block.push_str(&encode_array_of_bstrings(&["EXEC".into()]));
+            self.propagate_now(block).await;
+        }
+        result
+    }
+
     pub async fn add_replica(&self, reader: StreamReader<TcpStream>) {
         let (tx, rx) = mpsc::channel::<Vec<u8>>(100);
         let replica_id = uuid::Uuid::new_v4().to_string();
@@ -187,7 +611,62 @@ impl ServerContext {
         });
     }
 
+    /// Removes expired keys without waiting for them to be read. Replicas leave that to
+    /// their master, which sends them the deletions.
+    pub async fn remove_expired(&self) {
+        if self.state.lock().await.is_replica() {
+            return;
+        }
+        let _exclusive = self.store.exclusive.lock().await;
+        self.store.remove_expired().await;
+        self.propagate_expired().await;
+    }
+
+    /// Sends replicas, and the append-only file, a DEL for each key the master expired.
+    async fn propagate_expired(&self) {
+        let expired = self.store.take_expired();
+        if expired.is_empty() || self.state.lock().await.is_replica() {
+            return;
+        }
+        for key in expired {
+            self.propagate(encode_array_of_bstrings(&["DEL".into(), key]))
+                .await;
+        }
+    }
+
+    /// Sends replicas the commands that reproduce a consumer group operation's effects.
+    async fn propagate_effects(&self, effects: Vec<Vec<String>>) {
+        for effect in effects {
+            self.propagate(encode_array_of_bstrings(&effect)).await;
+        }
+    }
+
     async fn propagate(&self, command: String) {
+        let buffered = TRANSACTION_PROPAGATION
+            .try_with(|buffer| buffer.borrow_mut().push(command.clone()))
+            .is_ok();
+        if !buffered {
+            self.propagate_now(command).await;
+        }
+    }
+
+    /// Appends to the append-only file, rewriting it once it grew enough, then sends to
+    /// replicas.
+    async fn propagate_now(&self, command: String) {
+        if let Some(aof) = &self.aof {
+            if let Err(e) = aof.append(&command) {
+                eprintln!("Failed to write to the append only file: {e}");
+            }
+            if aof.rewrite_due() {
+                if let Err(e) = aof.rewrite(&self.store).await {
+                    eprintln!("Failed to start rewriting the append only file: {e}");
+                }
+            }
+        }
+        self.send_to_replicas(command).await;
+    }
+
+    async fn send_to_replicas(&self, command: String) {
         self.replicas
             .lock()
             .await
@@ -195,3 +674,14 @@ impl ServerContext {
             .await;
     }
 }
+
+/// Runs a script on this thread, holding up the event loop until it finishes. Scripts need
+/// the multi-threaded runtime, as `block_in_place` panics on a current-thread one.
+fn run_script(script: impl FnOnce() -> CommandResponse) -> CommandResponse {
+    match Handle::current().runtime_flavor() {
+        RuntimeFlavor::CurrentThread => {
+            error_response("Scripts can't run on a current-thread runtime")
+        }
+        _ => tokio::task::block_in_place(script),
+    }
+}
@@ -1,3 +1,4 @@
+pub mod aof;
 pub mod config;
 pub mod connection_handler;
 pub mod context;
@@ -1,4 +1,4 @@
-use super::stream_reader::StreamReader;
+use super::{connection_handler::ChannelType, stream_reader::StreamReader};
 use crate::{
     command::{core::Command, response::CommandResponse},
     protocol::{Data, RedisArray},
@@ -145,7 +145,7 @@ async fn send_get_ack(tx: mpsc::Sender<Vec<u8>>) -> Result<()> {
 pub async fn init_replica(
     replica_config: &str,
     listen_port: &str,
-    tx: mpsc::Sender<(Command, Option<oneshot::Sender<CommandResponse>>)>,
+    tx: mpsc::Sender<ChannelType>,
 ) -> Result<()> {
     let (host, port) = replica_config
         .split_once(char::is_whitespace)
@@ -171,14 +171,29 @@ pub async fn init_replica(
 
     tokio::spawn(async move {
         reader.reset_processed_bytes();
+        // Commands of a MULTI/EXEC block from the master, applied in one go on EXEC.
+        let mut transaction: Option<Vec<Command>> = None;
         loop {
             let Ok(data) = reader.read_redis_data().await else {
                 break;
             };
-            let command = Command::from(data);
+            let command = match (Command::from(data), transaction.as_mut()) {
+                (Command::Multi, _) => {
+                    transaction = Some(vec![]);
+                    continue;
+                }
+                (Command::Exec, Some(_)) => {
+                    Command::Transaction(transaction.take().unwrap_or_default(), vec![])
+                }
+                (command, Some(queued)) => {
+                    queued.push(command);
+                    continue;
+                }
+                (command, None) => command,
+            };
             if let Command::ReplconfGetAck(_) = &command {
                 let (result_tx, result_rx) = oneshot::channel();
-                if tx.send((command, Some(result_tx))).await.is_err() {
+                if tx.send((command, Some(result_tx), None)).await.is_err() {
                     eprintln!("Failed to send request to event loop.");
                 }
                 let response = result_rx.await;
@@ -197,7 +212,7 @@ pub async fn init_replica(
                         eprintln!("Failed to send REPLCONF ACK response.");
                     }
                 }
-            } else if tx.send((command, None)).await.is_err() {
+            } else if tx.send((command, None, None)).await.is_err() {
                 eprintln!("Failed to send request to event loop.");
             }
         }
@@ -0,0 +1,688 @@
+use super::{
+    core::InMemoryStore,
+    notify::EventClass,
+    stream::{get_unix_ms, is_valid_range, Stream, StreamEntry, StreamId, StreamRange},
+    value::{Value, ValueWrapper, ERR_WRONG_TYPE},
+};
+use anyhow::{bail, Result};
+use hashbrown::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound::*,
+};
+
+#[derive(Clone, Default)]
+pub struct ConsumerGroup {
+    pub last_delivered_id: StreamId,
+    /// Logical number of entries the group has read, `None` when it can't be known
+    /// exactly (e.g. after an arbitrary SETID).
+    pub entries_read: Option<u64>,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<String, Consumer>,
+}
+
+#[derive(Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Clone)]
+pub struct Consumer {
+    pub seen_time: u64,
+    pub active_time: Option<u64>,
+    pub pending: BTreeSet<StreamId>,
+}
+
+impl Consumer {
+    fn new(now: u64) -> Self {
+        Self {
+            seen_time: now,
+            active_time: None,
+            pending: BTreeSet::new(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum XGroupSubcommand {
+    Create {
+        id: String,
+        mkstream: bool,
+        entries_read: Option<u64>,
+    },
+    SetId {
+        id: String,
+        entries_read: Option<u64>,
+    },
+    Destroy,
+    CreateConsumer(String),
+    DelConsumer(String),
+}
+
+#[derive(Clone, Default)]
+pub struct ClaimOptions {
+    pub idle: Option<u64>,
+    pub time: Option<u64>,
+    pub retry_count: Option<u64>,
+    pub force: bool,
+    pub just_id: bool,
+    pub last_id: Option<StreamId>,
+}
+
+#[derive(Clone)]
+pub struct AutoClaimOptions {
+    pub start: StreamId,
+    pub count: usize,
+    pub just_id: bool,
+}
+
+#[derive(Clone)]
+pub struct PendingFilter {
+    pub min_idle: Option<u64>,
+    pub range: StreamRange,
+    pub count: usize,
+    pub consumer: Option<String>,
+}
+
+pub struct PendingSummary {
+    pub count: usize,
+    pub bounds: Option<(StreamId, StreamId)>,
+    pub consumers: Vec<(String, usize)>,
+}
+
+pub struct PendingDetail {
+    pub id: StreamId,
+    pub consumer: String,
+    pub idle: u64,
+    pub delivery_count: u64,
+}
+
+pub type GroupEntries = Vec<(StreamId, Option<StreamEntry>)>;
+
+/// Outcome of a consumer group operation, along with the commands that reproduce its
+/// effects deterministically on replicas.
+#[derive(Default)]
+pub struct GroupOutcome<T> {
+    pub result: T,
+    pub effects: Vec<Vec<String>>,
+}
+
+pub struct AutoClaimResult {
+    pub next_id: StreamId,
+    pub claimed: GroupEntries,
+    pub deleted: Vec<StreamId>,
+}
+
+impl ConsumerGroup {
+    fn consumer(&mut self, name: &str, now: u64) -> &mut Consumer {
+        let consumer = self
+            .consumers
+            .entry(name.to_string())
+            .or_insert_with(|| Consumer::new(now));
+        consumer.seen_time = now;
+        consumer
+    }
+
+    /// Assigns `id` to `consumer` in the pending entries list, moving it away from its
+    /// previous owner if needed.
+    fn assign(&mut self, id: StreamId, consumer: &str, delivery_time: u64, delivery_count: u64) {
+        if let Some(previous) = self.pending.get(&id) {
+            if let Some(owner) = self.consumers.get_mut(&previous.consumer) {
+                owner.pending.remove(&id);
+            }
+        }
+        self.pending.insert(
+            id,
+            PendingEntry {
+                consumer: consumer.to_string(),
+                delivery_time,
+                delivery_count,
+            },
+        );
+        self.consumer(consumer, delivery_time).pending.insert(id);
+    }
+
+    fn ack(&mut self, id: &StreamId) -> bool {
+        let Some(entry) = self.pending.remove(id) else {
+            return false;
+        };
+        if let Some(consumer) = self.consumers.get_mut(&entry.consumer) {
+            consumer.pending.remove(id);
+        }
+        true
+    }
+}
+
+impl Stream {
+    /// Whether an entry between `start` and the end of the stream was ever deleted.
+    pub(crate) fn has_tombstones(&self, start: StreamId) -> bool {
+        !self.is_empty() && self.max_deleted_id != StreamId::MIN && start <= self.max_deleted_id
+    }
+
+    /// Number of entries added to the stream up to and including `id`, when that can
+    /// be derived without scanning.
+    pub(crate) fn estimate_entries_read(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if self.is_empty() && id <= self.last_id {
+            return Some(self.entries_added);
+        }
+        if id == self.last_id {
+            return Some(self.entries_added);
+        }
+        if id > self.last_id {
+            return None;
+        }
+        let first_id = self.first_id()?;
+        if self.max_deleted_id == StreamId::MIN || self.max_deleted_id < first_id {
+            if id < first_id {
+                return Some(self.entries_added - self.len() as u64);
+            }
+            if id == first_id {
+                return Some(self.entries_added - self.len() as u64 + 1);
+            }
+        }
+        None
+    }
+
+    fn resolve_group_id(&self, id: &str) -> Result<StreamId> {
+        match id {
+            "$" => Ok(self.last_id),
+            _ => StreamId::parse(id, 0),
+        }
+    }
+
+    fn group_mut(&mut self, key: &str, group: &str) -> Result<&mut ConsumerGroup> {
+        match self.groups.get_mut(group) {
+            Some(group) => Ok(group),
+            None => bail!(no_group_error(key, group)),
+        }
+    }
+}
+
+impl InMemoryStore {
+    pub async fn xgroup_create(
+        &self,
+        key: String,
+        group: String,
+        id: String,
+        mkstream: bool,
+        entries_read: Option<u64>,
+    ) -> Result<()> {
+        let mut data = self.data.lock().await;
+        if mkstream && !data.contains_key(&key) {
+            data.insert(
+                key.clone(),
+                ValueWrapper {
+                    value: Value::Stream(Stream::default()),
+                    expiry: None,
+                },
+            );
+        }
+        let Some(stream) = get_stream_mut(&mut data, &key)? else {
+            bail!(ERR_NO_KEY);
+        };
+        let id = stream.resolve_group_id(&id)?;
+        if stream.groups.contains_key(&group) {
+            bail!(ERR_BUSY_GROUP);
+        }
+        let entries_read = entries_read.or_else(|| stream.estimate_entries_read(id));
+        stream.groups.insert(
+            group,
+            ConsumerGroup {
+                last_delivered_id: id,
+                entries_read,
+                ..Default::default()
+            },
+        );
+        self.touch(&key).await;
+        self.notify(EventClass::Stream, "xgroup-create", &key).await;
+        Ok(())
+    }
+
+    pub async fn xgroup_setid(
+        &self,
+        key: String,
+        group: String,
+        id: String,
+        entries_read: Option<u64>,
+    ) -> Result<()> {
+        let mut data = self.data.lock().await;
+        let Some(stream) = get_stream_mut(&mut data, &key)? else {
+            bail!(ERR_NO_KEY);
+        };
+        let id = stream.resolve_group_id(&id)?;
+        let entries_read = entries_read.or_else(|| stream.estimate_entries_read(id));
+        let group = stream.group_mut(&key, &group)?;
+        group.last_delivered_id = id;
+        group.entries_read = entries_read;
+        self.touch(&key).await;
+        self.notify(EventClass::Stream, "xgroup-setid", &key).await;
+        Ok(())
+    }
+
+    pub async fn xgroup_destroy(&self, key: String, group: String) -> Result<bool> {
+        let mut data = self.data.lock().await;
+        let Some(stream) = get_stream_mut(&mut data, &key)? else {
+            bail!(ERR_NO_KEY);
+        };
+        let destroyed = stream.groups.remove(&group).is_some();
+        if destroyed {
+            self.touch(&key).await;
+            self.notify(EventClass::Stream, "xgroup-destroy", &key)
+                .await;
+        }
+        Ok(destroyed)
+    }
+
+    pub async fn xgroup_create_consumer(
+        &self,
+        key: String,
+        group: String,
+        consumer: String,
+    ) -> Result<bool> {
+        let mut data = self.data.lock().await;
+        let Some(stream) = get_stream_mut(&mut data, &key)? else {
+            bail!(ERR_NO_KEY);
+        };
+        let group = stream.group_mut(&key, &group)?;
+        if group.consumers.contains_key(&consumer) {
+            return Ok(false);
+        }
+        group.consumer(&consumer, get_unix_ms());
+        self.touch(&key).await;
+        self.notify(EventClass::Stream, "xgroup-createconsumer", &key)
+            .await;
+        Ok(true)
+    }
+
+    pub async fn xgroup_del_consumer(
+        &self,
+        key: String,
+        group: String,
+        consumer: String,
+    ) -> Result<usize> {
+        let mut data = self.data.lock().await;
+        let Some(stream) = get_stream_mut(&mut data, &key)? else {
+            bail!(ERR_NO_KEY);
+        };
+        let group = stream.group_mut(&key, &group)?;
+        let Some(removed) = group.consumers.remove(&consumer) else {
+            return Ok(0);
+        };
+        for id in removed.pending.iter() {
+            group.pending.remove(id);
+        }
+        self.touch(&key).await;
+        self.notify(EventClass::Stream, "xgroup-delconsumer", &key)
+            .await;
+        Ok(removed.pending.len())
+    }
+
+    /// XREADGROUP: `>` delivers entries never delivered to the group, any other ID
+    /// re-reads the consumer's own pending entries after it. Every stream, group and ID is
+    /// checked before any group changes, so an error leaves them all untouched.
+    pub async fn read_group(
+        &self,
+        group_name: &str,
+        consumer: &str,
+        streams: &[(String, String)],
+        count: Option<usize>,
+        no_ack: bool,
+    ) -> Result<GroupOutcome<Vec<(String, GroupEntries)>>> {
+        let mut data = self.data.lock().await;
+        let mut starts = Vec::with_capacity(streams.len());
+        for (key, id) in streams {
+            match get_stream_mut(&mut data, key)? {
+                Some(stream) if stream.groups.contains_key(group_name) => (),
+                _ => bail!(no_group_error(key, group_name)),
+            }
+            starts.push(match id.as_str() {
+                ">" => None,
+                id => Some(StreamId::parse(id, 0)?),
+            });
+        }
+
+        let now = get_unix_ms();
+        let count = count.filter(|c| *c > 0).unwrap_or(usize::MAX);
+        let mut outcome = GroupOutcome::<Vec<(String, GroupEntries)>>::default();
+        for ((key, _), start) in streams.iter().zip(starts) {
+            let Some(stream) = get_stream_mut(&mut data, key)? else {
+                continue;
+            };
+            let entries: GroupEntries = match start {
+                None => {
+                    let Some(group) = stream.groups.get(group_name) else {
+                        continue;
+                    };
+                    // The read counter after each delivered entry. It can only be
+                    // incremented when nothing was deleted between the previous position
+                    // and this entry.
+                    let (mut entries_read, mut last_id) =
+                        (group.entries_read, group.last_delivered_id);
+                    let mut delivered = vec![];
+                    for (id, entry) in stream
+                        .entries
+                        .range((Excluded(last_id), Unbounded))
+                        .take(count)
+                    {
+                        entries_read = match entries_read {
+                            Some(read) if !stream.has_tombstones(last_id) => Some(read + 1),
+                            _ => stream.estimate_entries_read(*id),
+                        };
+                        last_id = *id;
+                        delivered.push((*id, entry.clone(), entries_read));
+                    }
+
+                    let Some(group) = stream.groups.get_mut(group_name) else {
+                        continue;
+                    };
+                    for (id, _, entries_read) in delivered.iter() {
+                        group.entries_read = *entries_read;
+                        group.last_delivered_id = *id;
+                        if !no_ack {
+                            group.assign(*id, consumer, now, 1);
+                            outcome
+                                .effects
+                                .push(xclaim_effect(key, group_name, consumer, *id, group));
+                        }
+                    }
+                    let state = group.consumer(consumer, now);
+                    if !delivered.is_empty() {
+                        state.active_time = Some(now);
+                        outcome.effects.push(setid_effect(key, group_name, group));
+                    }
+                    delivered
+                        .into_iter()
+                        .map(|(id, entry, _)| (id, Some(entry)))
+                        .collect()
+                }
+                Some(start) => {
+                    let Some(group) = stream.groups.get_mut(group_name) else {
+                        continue;
+                    };
+                    group
+                        .consumer(consumer, now)
+                        .pending
+                        .range((Excluded(start), Unbounded))
+                        .take(count)
+                        .map(|id| (*id, stream.entries.get(id).cloned()))
+                        .collect()
+                }
+            };
+            if start.is_some() || !entries.is_empty() {
+                outcome.result.push((key.clone(), entries));
+            }
+        }
+        Ok(outcome)
+    }
+
+    pub async fn ack(&self, key: String, group: String, ids: Vec<StreamId>) -> Result<usize> {
+        let mut data = self.data.lock().await;
+        let Some(stream) = get_stream_mut(&mut data, &key)? else {
+            return Ok(0);
+        };
+        let Some(group) = stream.groups.get_mut(&group) else {
+            return Ok(0);
+        };
+        Ok(ids.iter().filter(|id| group.ack(id)).count())
+    }
+
+    pub async fn pending_summary(&self, key: String, group: String) -> Result<PendingSummary> {
+        let mut data = self.data.lock().await;
+        let Some(stream) = get_stream_mut(&mut data, &key)? else {
+            bail!(no_group_error(&key, &group));
+        };
+        let group = stream.group_mut(&key, &group)?;
+        let bounds = group
+            .pending
+            .keys()
+            .next()
+            .zip(group.pending.keys().next_back())
+            .map(|(first, last)| (*first, *last));
+        let consumers = group
+            .consumers
+            .iter()
+            .filter(|(_, consumer)| !consumer.pending.is_empty())
+            .map(|(name, consumer)| (name.clone(), consumer.pending.len()))
+            .collect();
+        Ok(PendingSummary {
+            count: group.pending.len(),
+            bounds,
+            consumers,
+        })
+    }
+
+    pub async fn pending_range(
+        &self,
+        key: String,
+        group: String,
+        filter: PendingFilter,
+    ) -> Result<Vec<PendingDetail>> {
+        let mut data = self.data.lock().await;
+        let Some(stream) = get_stream_mut(&mut data, &key)? else {
+            bail!(no_group_error(&key, &group));
+        };
+        let group = stream.group_mut(&key, &group)?;
+        if !is_valid_range(&filter.range) {
+            return Ok(vec![]);
+        }
+        let now = get_unix_ms();
+        Ok(group
+            .pending
+            .range(filter.range)
+            .filter(|(_, entry)| {
+                filter
+                    .consumer
+                    .as_ref()
+                    .is_none_or(|c| c == &entry.consumer)
+                    && filter
+                        .min_idle
+                        .is_none_or(|idle| now.saturating_sub(entry.delivery_time) >= idle)
+            })
+            .take(filter.count)
+            .map(|(id, entry)| PendingDetail {
+                id: *id,
+                consumer: entry.consumer.clone(),
+                idle: now.saturating_sub(entry.delivery_time),
+                delivery_count: entry.delivery_count,
+            })
+            .collect())
+    }
+
+    pub async fn claim(
+        &self,
+        key: String,
+        group_name: String,
+        consumer: String,
+        min_idle: u64,
+        ids: Vec<StreamId>,
+        options: ClaimOptions,
+    ) -> Result<GroupOutcome<GroupEntries>> {
+        let mut data = self.data.lock().await;
+        let Some(stream) = get_stream_mut(&mut data, &key)? else {
+            bail!(no_group_error(&key, &group_name));
+        };
+        let entries = &stream.entries;
+        let Some(group) = stream.groups.get_mut(&group_name) else {
+            bail!(no_group_error(&key, &group_name));
+        };
+        let now = get_unix_ms();
+        let delivery_time = match (options.time, options.idle) {
+            (Some(time), _) => time,
+            (None, Some(idle)) => now.saturating_sub(idle),
+            (None, None) => now,
+        };
+        if let Some(last_id) = options.last_id.filter(|id| *id > group.last_delivered_id) {
+            group.last_delivered_id = last_id;
+        }
+
+        let mut outcome = GroupOutcome::<GroupEntries>::default();
+        for id in ids {
+            let Some(entry) = entries.get(&id) else {
+                // Entries deleted from the stream can't be claimed any more.
+                if group.ack(&id) {
+                    outcome.effects.push(xack_effect(&key, &group_name, id));
+                }
+                continue;
+            };
+            let delivery_count = match group.pending.get(&id) {
+                Some(pending) if now.saturating_sub(pending.delivery_time) < min_idle => continue,
+                Some(pending) => pending.delivery_count,
+                None if options.force => 0,
+                None => continue,
+            };
+            let delivery_count = match (options.retry_count, options.just_id) {
+                (Some(count), _) => count,
+                (None, true) => delivery_count,
+                (None, false) => delivery_count + 1,
+            };
+            group.assign(id, &consumer, delivery_time, delivery_count);
+            group.consumer(&consumer, now).active_time = Some(now);
+            outcome
+                .effects
+                .push(xclaim_effect(&key, &group_name, &consumer, id, group));
+            outcome
+                .result
+                .push((id, (!options.just_id).then(|| entry.clone())));
+        }
+        group.consumer(&consumer, now);
+        Ok(outcome)
+    }
+
+    pub async fn autoclaim(
+        &self,
+        key: String,
+        group_name: String,
+        consumer: String,
+        min_idle: u64,
+        options: AutoClaimOptions,
+    ) -> Result<GroupOutcome<AutoClaimResult>> {
+        let AutoClaimOptions {
+            start,
+            count,
+            just_id,
+        } = options;
+        let mut data = self.data.lock().await;
+        let Some(stream) = get_stream_mut(&mut data, &key)? else {
+            bail!(no_group_error(&key, &group_name));
+        };
+        let entries = &stream.entries;
+        let Some(group) = stream.groups.get_mut(&group_name) else {
+            bail!(no_group_error(&key, &group_name));
+        };
+        let now = get_unix_ms();
+        let mut outcome = GroupOutcome {
+            result: AutoClaimResult {
+                next_id: StreamId::MIN,
+                claimed: vec![],
+                deleted: vec![],
+            },
+            effects: vec![],
+        };
+
+        // Like Redis, look at no more than ten times `count` entries per call.
+        let mut attempts = count.saturating_mul(10);
+        let candidates = group
+            .pending
+            .range(start..)
+            .map(|(id, entry)| (*id, entry.delivery_time, entry.delivery_count))
+            .collect::<Vec<_>>();
+        let mut candidates = candidates.into_iter().peekable();
+        while attempts > 0 && outcome.result.claimed.len() < count {
+            let Some((id, delivery_time, delivery_count)) = candidates.next() else {
+                break;
+            };
+            attempts -= 1;
+            let Some(entry) = entries.get(&id) else {
+                group.ack(&id);
+                outcome.result.deleted.push(id);
+                outcome.effects.push(xack_effect(&key, &group_name, id));
+                continue;
+            };
+            if now.saturating_sub(delivery_time) < min_idle {
+                continue;
+            }
+            let delivery_count = delivery_count + u64::from(!just_id);
+            group.assign(id, &consumer, now, delivery_count);
+            outcome
+                .effects
+                .push(xclaim_effect(&key, &group_name, &consumer, id, group));
+            outcome
+                .result
+                .claimed
+                .push((id, (!just_id).then(|| entry.clone())));
+        }
+        outcome.result.next_id = candidates.peek().map(|c| c.0).unwrap_or(StreamId::MIN);
+        let state = group.consumer(&consumer, now);
+        if !outcome.result.claimed.is_empty() {
+            state.active_time = Some(now);
+        }
+        Ok(outcome)
+    }
+}
+
+pub(crate) fn get_stream_mut<'a>(
+    data: &'a mut HashMap<String, ValueWrapper>,
+    key: &str,
+) -> Result<Option<&'a mut Stream>> {
+    match data.get_mut(key).map(|v| &mut v.value) {
+        Some(Value::Stream(stream)) => Ok(Some(stream)),
+        Some(_) => bail!(ERR_WRONG_TYPE),
+        None => Ok(None),
+    }
+}
+
+fn xclaim_effect(
+    key: &str,
+    group_name: &str,
+    consumer: &str,
+    id: StreamId,
+    group: &ConsumerGroup,
+) -> Vec<String> {
+    let pending = &group.pending[&id];
+    vec![
+        "XCLAIM".into(),
+        key.into(),
+        group_name.into(),
+        consumer.into(),
+        "0".into(),
+        id.to_string(),
+        "TIME".into(),
+        pending.delivery_time.to_string(),
+        "RETRYCOUNT".into(),
+        pending.delivery_count.to_string(),
+        "FORCE".into(),
+        "JUSTID".into(),
+        "LASTID".into(),
+        group.last_delivered_id.to_string(),
+    ]
+}
+
+fn setid_effect(key: &str, group_name: &str, group: &ConsumerGroup) -> Vec<String> {
+    let mut effect = vec![
+        "XGROUP".into(),
+        "SETID".into(),
+        key.into(),
+        group_name.into(),
+        group.last_delivered_id.to_string(),
+    ];
+    if let Some(entries_read) = group.entries_read {
+        effect.extend(["ENTRIESREAD".into(), entries_read.to_string()]);
+    }
+    effect
+}
+
+fn xack_effect(key: &str, group_name: &str, id: StreamId) -> Vec<String> {
+    vec!["XACK".into(), key.into(), group_name.into(), id.to_string()]
+}
+
+fn no_group_error(key: &str, group: &str) -> String {
+    format!("NOGROUP No such key '{key}' or consumer group '{group}'")
+}
+
+const ERR_NO_KEY: &str = "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.";
+const ERR_BUSY_GROUP: &str = "BUSYGROUP Consumer Group name already exists";
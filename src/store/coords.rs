@@ -1,3 +1,4 @@
+use anyhow::{bail, Result};
 use rust_decimal::{
     dec,
     prelude::{FromPrimitive, ToPrimitive},
@@ -9,6 +10,62 @@ const LAT_RANGE: f64 = MAX_LAT - MIN_LAT;
 const MIN_LONG: f64 = -180.0;
 const MAX_LONG: f64 = 180.0;
 const LONG_RANGE: f64 = MAX_LONG - MIN_LONG;
+const GEO_STEP_MAX: u8 = 26;
+const EARTH_RADIUS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+const GEOHASH_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Clone, Copy)]
+pub enum GeoUnit {
+    Meters,
+    Kilometers,
+    Miles,
+    Feet,
+}
+
+impl TryFrom<&str> for GeoUnit {
+    type Error = anyhow::Error;
+    fn try_from(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "m" => Ok(Self::Meters),
+            "km" => Ok(Self::Kilometers),
+            "mi" => Ok(Self::Miles),
+            "ft" => Ok(Self::Feet),
+            _ => bail!("unsupported unit provided. please use M, KM, FT, MI"),
+        }
+    }
+}
+
+impl std::fmt::Display for GeoUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unit = match self {
+            Self::Meters => "m",
+            Self::Kilometers => "km",
+            Self::Miles => "mi",
+            Self::Feet => "ft",
+        };
+        f.write_str(unit)
+    }
+}
+
+impl GeoUnit {
+    fn meters(self) -> f64 {
+        match self {
+            Self::Meters => 1.0,
+            Self::Kilometers => 1000.0,
+            Self::Miles => 1609.34,
+            Self::Feet => 0.3048,
+        }
+    }
+
+    pub fn from_meters(self, distance: f64) -> f64 {
+        distance / self.meters()
+    }
+
+    pub fn to_meters(self, distance: f64) -> f64 {
+        distance * self.meters()
+    }
+}
 
 #[derive(Clone)]
 pub struct Point {
@@ -35,12 +92,20 @@ pub fn validate_coords(point: &Point) -> Option<String> {
 }
 
 pub fn encode(point: Point) -> Decimal {
-    let norm_lat = (2u64.pow(26) as f64 * (point.lat - MIN_LAT) / LAT_RANGE) as u32;
-    let norm_long = (2u64.pow(26) as f64 * (point.lon - MIN_LONG) / LONG_RANGE) as u32;
+    let (lat_idx, long_idx) = cell_index(&point, GEO_STEP_MAX);
+    Decimal::from_u64(interleave(lat_idx, long_idx)).unwrap_or_default()
+}
+
+fn cell_index(point: &Point, step: u8) -> (u64, u64) {
+    let cells = 2u64.pow(step as u32) as f64;
+    let lat_idx = (cells * (point.lat - MIN_LAT) / LAT_RANGE) as u64;
+    let long_idx = (cells * (point.lon - MIN_LONG) / LONG_RANGE) as u64;
+    (lat_idx, long_idx)
+}
 
-    let spread_int32_to_int64 = |v: u32| {
-        let mut v = v as u64;
-        v &= 0xFFFFFFFF;
+fn interleave(lat_idx: u64, long_idx: u64) -> u64 {
+    let spread_int32_to_int64 = |v: u64| {
+        let mut v = v & 0xFFFFFFFF;
         v = (v | (v << 16)) & 0x0000FFFF0000FFFF;
         v = (v | (v << 8)) & 0x00FF00FF00FF00FF;
         v = (v | (v << 4)) & 0x0F0F0F0F0F0F0F0F;
@@ -48,11 +113,113 @@ pub fn encode(point: Point) -> Decimal {
         v = (v | (v << 1)) & 0x5555555555555555;
         v
     };
+    spread_int32_to_int64(lat_idx) | (spread_int32_to_int64(long_idx) << 1)
+}
+
+/// Standard 11-character geohash of a stored score. Scores use the Mercator latitude
+/// limits, so the point is re-encoded against the full -90..90 range first.
+pub fn geohash(score: Decimal) -> String {
+    let point = decode(score);
+    let cells = 2u64.pow(GEO_STEP_MAX as u32) as f64;
+    let lat_idx = (cells * (point.lat + 90.0) / 180.0) as u64;
+    let long_idx = (cells * (point.lon - MIN_LONG) / LONG_RANGE) as u64;
+    let bits = interleave(lat_idx, long_idx);
+    (0..11)
+        .map(|i| {
+            let idx = match i {
+                10 => 0,
+                _ => (bits >> (2 * GEO_STEP_MAX as usize - (i + 1) * 5)) & 0x1f,
+            };
+            GEOHASH_ALPHABET[idx as usize] as char
+        })
+        .collect()
+}
+
+/// Score ranges `[min, max)` of the geohash cells covering a search area of the given
+/// half-width and half-height (in metres) around `center`: the cell holding the center
+/// plus its eight neighbours, at the finest step where those nine cells still cover the
+/// whole bounding box.
+pub fn search_cells(center: &Point, half_width: f64, half_height: f64) -> Vec<(u64, u64)> {
+    let (min, max) = bounding_box(center, half_width, half_height);
+    let mut step = estimate_step(half_width.max(half_height), center.lat);
+    while step > 0 && !neighbourhood_covers(center, step, &min, &max) {
+        step -= 1;
+    }
+    if step == 0 {
+        return vec![(0, 1 << (2 * GEO_STEP_MAX))];
+    }
+
+    let (lat_idx, long_idx) = cell_index(center, step);
+    let cells = 2i64.pow(step as u32);
+    let shift = 2 * (GEO_STEP_MAX - step);
+    let mut ranges = vec![];
+    for d_lat in -1..=1 {
+        let lat = lat_idx as i64 + d_lat;
+        if !(0..cells).contains(&lat) {
+            continue;
+        }
+        for d_long in -1..=1 {
+            let long = (long_idx as i64 + d_long).rem_euclid(cells);
+            let hash = interleave(lat as u64, long as u64);
+            ranges.push((hash << shift, (hash + 1) << shift));
+        }
+    }
+    ranges.sort_unstable();
+    ranges.dedup();
+    ranges
+}
+
+fn estimate_step(mut radius: f64, lat: f64) -> u8 {
+    if radius == 0.0 {
+        return GEO_STEP_MAX;
+    }
+    let mut step = 1i32;
+    while radius < MERCATOR_MAX {
+        radius *= 2.0;
+        step += 1;
+    }
+    step -= 2;
+    if !(-66.0..=66.0).contains(&lat) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&lat) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, GEO_STEP_MAX as i32) as u8
+}
+
+fn bounding_box(center: &Point, half_width: f64, half_height: f64) -> (Point, Point) {
+    let lat_delta = (half_height / EARTH_RADIUS).to_degrees();
+    let widest_lat = (center.lat.abs() + lat_delta).min(90.0);
+    let long_delta = match widest_lat.to_radians().cos() {
+        cos if cos > f64::EPSILON => (half_width / EARTH_RADIUS / cos).to_degrees(),
+        _ => LONG_RANGE,
+    };
+    (
+        Point {
+            lat: center.lat - lat_delta,
+            lon: center.lon - long_delta,
+        },
+        Point {
+            lat: center.lat + lat_delta,
+            lon: center.lon + long_delta,
+        },
+    )
+}
+
+fn neighbourhood_covers(center: &Point, step: u8, min: &Point, max: &Point) -> bool {
+    let (lat_idx, long_idx) = cell_index(center, step);
+    let cells = 2u64.pow(step as u32) as f64;
+    let (cell_lat, cell_long) = (LAT_RANGE / cells, LONG_RANGE / cells);
 
-    let x = spread_int32_to_int64(norm_lat);
-    let y = spread_int32_to_int64(norm_long);
-    let y_shifted = y << 1;
-    Decimal::from_u64(x | y_shifted).unwrap_or_default()
+    let lat_lo = MIN_LAT + (lat_idx as f64 - 1.0) * cell_lat;
+    let lat_hi = MIN_LAT + (lat_idx as f64 + 2.0) * cell_lat;
+    let long_lo = MIN_LONG + (long_idx as f64 - 1.0) * cell_long;
+    let long_hi = MIN_LONG + (long_idx as f64 + 2.0) * cell_long;
+
+    (lat_lo <= min.lat || lat_lo <= MIN_LAT)
+        && (lat_hi >= max.lat || lat_hi >= MAX_LAT)
+        && ((long_lo <= min.lon && long_hi >= max.lon) || long_hi - long_lo >= LONG_RANGE)
 }
 
 pub fn decode(score: Decimal) -> Point {
@@ -92,8 +259,6 @@ pub fn decode(score: Decimal) -> Point {
 }
 
 pub fn haversine(origin: &Point, destination: &Point) -> f64 {
-    const EARTH_RADIUS: f64 = 6372797.560856;
-
     let lat1 = origin.lat.to_radians();
     let lat2 = destination.lat.to_radians();
     let d_lat = lat2 - lat1;
@@ -1,5 +1,12 @@
-use super::value::{Value, ValueWrapper};
-use crate::{rdb::rdb_file::RdbFile, server::config::get_config_value};
+use super::{
+    expiry::VolatileKeys,
+    notify::{EventClass, KeyspaceNotifier},
+    persistence::{rdb_configured, rdb_path, PersistenceState},
+    tracking::TrackingTable,
+    value::{Value, ValueWrapper},
+    watch::WatchRegistry,
+};
+use crate::{channel::ChannelManager, rdb::rdb_file::RdbFile};
 use bytes::Bytes;
 use hashbrown::HashMap;
 use std::{
@@ -14,6 +21,21 @@ use uuid::Uuid;
 pub struct InMemoryStore {
     pub data: Arc<Mutex<HashMap<String, ValueWrapper>>>,
     pub subscribers: Arc<Mutex<HashMap<Uuid, super::subscribe::Subscription>>>,
+    pub watches: Arc<Mutex<WatchRegistry>>,
+    pub notifier: KeyspaceNotifier,
+    pub tracking: Arc<Mutex<TrackingTable>>,
+    pub persistence: Arc<PersistenceState>,
+    /// Held by a script for its whole run, and by whatever runs outside the event loop
+    /// (active expiry, background saves, blocking commands), so scripts run atomically.
+    pub exclusive: Arc<Mutex<()>>,
+    /// The code of every function library, saved with snapshots.
+    functions: Arc<std::sync::Mutex<Vec<String>>>,
+    /// Keys removed because they expired, until their deletion is propagated.
+    pub(super) expired: Arc<std::sync::Mutex<Vec<String>>>,
+    /// The keys active expiry samples.
+    pub(super) volatile: Arc<std::sync::Mutex<VolatileKeys>>,
+    /// Whether expired keys are left for the master's deletions, only hidden from reads.
+    replica: bool,
 }
 
 impl Default for InMemoryStore {
@@ -24,37 +46,123 @@ impl Default for InMemoryStore {
 
 impl InMemoryStore {
     fn new(data: HashMap<String, ValueWrapper>) -> Self {
+        let volatile = volatile_keys(&data);
         Self {
             data: Arc::new(Mutex::new(data)),
             subscribers: Arc::new(Mutex::new(HashMap::new())),
+            watches: Arc::new(Mutex::new(WatchRegistry::default())),
+            notifier: KeyspaceNotifier::default(),
+            tracking: Arc::new(Mutex::new(TrackingTable::default())),
+            persistence: Arc::default(),
+            exclusive: Arc::default(),
+            functions: Arc::default(),
+            expired: Arc::default(),
+            volatile: Arc::new(std::sync::Mutex::new(volatile)),
+            replica: false,
         }
     }
 
+    /// Keeps expired keys until the master deletes them, as a replica must.
+    pub fn as_replica(mut self) -> Self {
+        self.replica = true;
+        self
+    }
+
+    /// Publishes this store's keyspace notifications through `channels`.
+    pub fn with_notifications(mut self, channels: ChannelManager) -> Self {
+        self.notifier = KeyspaceNotifier::new(channels);
+        self
+    }
+
     fn init_from_file() -> Option<Self> {
-        let (dir, file_name) = (get_config_value("dir")?, get_config_value("dbfilename")?);
-        let mut bytes = read_file(PathBuf::from(dir).join(file_name))?;
-        let data = RdbFile::try_from(&mut bytes).ok()?;
-        Some(Self::from_rdb_file(data))
+        if !rdb_configured() {
+            return None;
+        }
+        let mut bytes = read_file(rdb_path())?;
+        match RdbFile::try_from(&mut bytes) {
+            Ok(data) => Some(Self::from_rdb_file(data)),
+            Err(e) => {
+                eprintln!("Failed to load RDB file: {e:#}");
+                None
+            }
+        }
     }
 
     pub async fn get(&self, key: &str) -> Option<Value> {
         let mut data = self.data.lock().await;
-        match data.get(key) {
+        let value = match data.get(key) {
             Some(wrapper) => match wrapper.expiry {
-                Some(timestamp) if !is_expired(timestamp) => Some(wrapper.value.clone()),
+                Some(timestamp) if !is_expired(timestamp) => return Some(wrapper.value.clone()),
+                Some(_) if self.replica => None,
                 Some(_) => {
                     data.remove(key);
+                    self.touch(key).await;
+                    drop(data);
+                    self.expired.lock().unwrap().push(key.to_string());
+                    self.notify(EventClass::Expired, "expired", key).await;
                     None
                 }
-                None => Some(wrapper.value.clone()),
+                None => return Some(wrapper.value.clone()),
             },
             None => None,
-        }
+        };
+        self.notify(EventClass::KeyMiss, "keymiss", key).await;
+        value
     }
 
     pub async fn set(&self, key: String, value: Value, expiry: Option<u64>) {
         let value = ValueWrapper { value, expiry };
-        self.data.lock().await.insert(key, value);
+        let mut data = self.data.lock().await;
+        let created = data.insert(key.clone(), value).is_none();
+        if expiry.is_some() {
+            self.volatile.lock().unwrap().insert(&key);
+        }
+        self.touch(&key).await;
+        drop(data);
+        self.notify_new(created, &key).await;
+        self.notify(EventClass::String, "set", &key).await;
+        if expiry.is_some() {
+            self.notify(EventClass::Generic, "expire", &key).await;
+        }
+    }
+
+    /// Replaces every key, such as with those of a loaded snapshot.
+    pub async fn replace_data(&self, data: HashMap<String, ValueWrapper>) {
+        let mut current = self.data.lock().await;
+        *self.volatile.lock().unwrap() = volatile_keys(&data);
+        *current = data;
+    }
+
+    /// The keys expired since the last call, whose deletion replicas haven't seen yet.
+    pub fn take_expired(&self) -> Vec<String> {
+        std::mem::take(&mut self.expired.lock().unwrap())
+    }
+
+    /// The code of every function library, as of the last change to them.
+    pub fn functions(&self) -> Vec<String> {
+        self.functions.lock().unwrap().clone()
+    }
+
+    /// Records the function libraries' code for the next snapshot.
+    pub fn set_functions(&self, functions: Vec<String>) {
+        *self.functions.lock().unwrap() = functions;
+    }
+
+    /// Removes the keys, returning how many existed.
+    pub async fn delete(&self, keys: &[String]) -> usize {
+        let mut data = self.data.lock().await;
+        let mut deleted = vec![];
+        for key in keys {
+            if data.remove(key).is_some() {
+                self.touch(key).await;
+                deleted.push(key);
+            }
+        }
+        drop(data);
+        for key in &deleted {
+            self.notify(EventClass::Generic, "del", key).await;
+        }
+        deleted.len()
     }
 
     pub async fn get_keys(&self, pattern: &str) -> Vec<String> {
@@ -68,7 +176,8 @@ impl InMemoryStore {
 
     pub async fn incr(&self, key: String) -> i64 {
         let mut data = self.data.lock().await;
-        let value = data.entry(key).or_insert_with(|| ValueWrapper {
+        let created = !data.contains_key(&key);
+        let value = data.entry(key.clone()).or_insert_with(|| ValueWrapper {
             value: Value::String("0".to_string()),
             expiry: None,
         });
@@ -77,37 +186,55 @@ impl InMemoryStore {
             if let Ok(current_value) = current.parse::<i64>() {
                 let new_value = current_value + 1;
                 *current = new_value.to_string();
+                self.touch(&key).await;
+                drop(data);
+                self.notify_new(created, &key).await;
+                self.notify(EventClass::String, "incrby", &key).await;
                 return new_value;
             }
         }
         0
     }
 
-    fn from_rdb_file(data: RdbFile) -> Self {
-        let map: HashMap<String, ValueWrapper> = data
-            .sections
-            .into_iter()
-            .flat_map(|x| {
-                x.data.into_iter().map(|(k, (v, exp))| {
-                    (
-                        k,
-                        ValueWrapper {
-                            value: Value::from(v),
-                            expiry: exp,
-                        },
-                    )
-                })
+    fn from_rdb_file(mut data: RdbFile) -> Self {
+        let functions = std::mem::take(&mut data.functions);
+        let store = InMemoryStore::new(rdb_data(data));
+        store.set_functions(functions);
+        store
+    }
+}
+
+/// The keys of every database section in a loaded RDB file.
+pub(crate) fn rdb_data(data: RdbFile) -> HashMap<String, ValueWrapper> {
+    data.sections
+        .into_iter()
+        .flat_map(|x| {
+            x.data.into_iter().map(|(k, (v, exp))| {
+                (
+                    k,
+                    ValueWrapper {
+                        value: Value::from(v),
+                        expiry: exp,
+                    },
+                )
             })
-            .collect();
-        InMemoryStore::new(map)
+        })
+        .collect()
+}
+
+fn volatile_keys(data: &HashMap<String, ValueWrapper>) -> VolatileKeys {
+    let mut volatile = VolatileKeys::default();
+    for (key, _) in data.iter().filter(|(_, wrapper)| wrapper.expiry.is_some()) {
+        volatile.insert(key);
     }
+    volatile
 }
 
 fn read_file(path: PathBuf) -> Option<Bytes> {
     std::fs::read(path).ok().map(Bytes::from)
 }
 
-fn is_expired(expiry_timestamp: u64) -> bool {
+pub(crate) fn is_expired(expiry_timestamp: u64) -> bool {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
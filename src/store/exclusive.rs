@@ -0,0 +1,35 @@
+use super::core::InMemoryStore;
+use std::{cell::RefCell, future::Future};
+use tokio::sync::OwnedMutexGuard;
+
+tokio::task_local! {
+    /// Exclusive access held by the blocking command running on this task, given up
+    /// while it waits for new data.
+    static HELD: RefCell<Option<OwnedMutexGuard<()>>>;
+}
+
+impl InMemoryStore {
+    /// Runs a command outside the event loop, such as a blocking one, so that it never
+    /// lands in the middle of a script. The access is given up while it waits.
+    pub async fn run_exclusive<F: Future>(&self, future: F) -> F::Output {
+        let guard = self.exclusive.clone().lock_owned().await;
+        HELD.scope(RefCell::new(Some(guard)), future).await
+    }
+
+    /// Runs `future` without the exclusive access this task holds, if any, taking it
+    /// back once done.
+    pub async fn without_exclusive<F: Future>(&self, future: F) -> F::Output {
+        let held = HELD
+            .try_with(|held| held.borrow_mut().take())
+            .ok()
+            .flatten();
+        let Some(guard) = held else {
+            return future.await;
+        };
+        drop(guard);
+        let output = future.await;
+        let guard = self.exclusive.clone().lock_owned().await;
+        HELD.with(|held| *held.borrow_mut() = Some(guard));
+        output
+    }
+}
@@ -0,0 +1,88 @@
+use super::{
+    core::{is_expired, InMemoryStore},
+    notify::EventClass,
+};
+use hashbrown::HashMap;
+
+/// Keys sampled per round of active expiry.
+const SAMPLE_SIZE: usize = 20;
+
+/// The keys that were given a TTL, so active expiry samples only those. It may still
+/// hold keys since deleted or persisted, dropped once sampled.
+#[derive(Default)]
+pub struct VolatileKeys {
+    keys: Vec<String>,
+    positions: HashMap<String, usize>,
+}
+
+impl VolatileKeys {
+    pub fn insert(&mut self, key: &str) {
+        if !self.positions.contains_key(key) {
+            self.positions.insert(key.to_string(), self.keys.len());
+            self.keys.push(key.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        let Some(pos) = self.positions.remove(key) else {
+            return;
+        };
+        self.keys.swap_remove(pos);
+        if let Some(moved) = self.keys.get(pos) {
+            self.positions.insert(moved.clone(), pos);
+        }
+    }
+
+    /// Up to `n` distinct keys, picked at random.
+    fn sample(&self, n: usize) -> Vec<String> {
+        let n = n.min(self.keys.len());
+        rand::seq::index::sample(&mut rand::rng(), self.keys.len(), n)
+            .into_iter()
+            .map(|i| self.keys[i].clone())
+            .collect()
+    }
+}
+
+impl InMemoryStore {
+    /// Removes expired keys the way Redis does, by sampling keys with a TTL and going
+    /// again while more than a quarter of a sample had expired. Publishes an `expired`
+    /// event for each.
+    pub async fn remove_expired(&self) {
+        loop {
+            let mut data = self.data.lock().await;
+            let (expired, sampled) = {
+                let mut volatile = self.volatile.lock().unwrap();
+                let (mut expired, mut sampled) = (vec![], 0);
+                for key in volatile.sample(SAMPLE_SIZE) {
+                    match data.get(&key).and_then(|wrapper| wrapper.expiry) {
+                        Some(expiry) if is_expired(expiry) => {
+                            data.remove(&key);
+                            volatile.remove(&key);
+                            expired.push(key);
+                        }
+                        Some(_) => {}
+                        None => {
+                            volatile.remove(&key);
+                            continue;
+                        }
+                    }
+                    sampled += 1;
+                }
+                (expired, sampled)
+            };
+            for key in &expired {
+                self.touch(key).await;
+            }
+            drop(data);
+            for key in &expired {
+                self.notify(EventClass::Expired, "expired", key).await;
+            }
+            let done = sampled > 0 && expired.len() * 4 <= sampled;
+            let empty = sampled == 0 && self.volatile.lock().unwrap().keys.is_empty();
+            self.expired.lock().unwrap().extend(expired);
+            if done || empty {
+                return;
+            }
+        }
+    }
+}
@@ -1,12 +1,105 @@
 use crate::store::{
-    coords::{decode, encode, haversine, Point},
+    coords::{decode, encode, geohash, haversine, search_cells, GeoUnit, Point},
     core::InMemoryStore,
-    sorted_set::get_sorted_set,
+    notify::EventClass,
+    sorted_set::{get_sorted_set, get_sorted_set_mut, SortedSet},
+    value::{Value, ValueWrapper},
 };
+use anyhow::{bail, Result};
+use hashbrown::HashMap;
+use rust_decimal::{prelude::FromPrimitive, Decimal};
+
+#[derive(Clone)]
+pub enum GeoOrigin {
+    Member(String),
+    LonLat(Point),
+}
+
+#[derive(Clone)]
+pub enum GeoShape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum GeoSort {
+    Asc,
+    Desc,
+}
+
+#[derive(Clone)]
+pub struct GeoSearchQuery {
+    pub origin: GeoOrigin,
+    pub shape: GeoShape,
+    pub unit: GeoUnit,
+    pub sort: Option<GeoSort>,
+    pub count: Option<(usize, bool)>,
+    pub with_coord: bool,
+    pub with_dist: bool,
+    pub with_hash: bool,
+}
+
+impl GeoSearchQuery {
+    pub fn has_with_flags(&self) -> bool {
+        self.with_coord || self.with_dist || self.with_hash
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct GeoAddOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub ch: bool,
+}
+
+pub struct GeoMatch {
+    pub member: String,
+    pub score: Decimal,
+    pub point: Point,
+    pub distance: f64,
+}
 
 impl InMemoryStore {
-    pub async fn geoadd(&self, key: String, point: Point, member: String) -> i64 {
-        self.zadd(key, encode(point), member).await
+    pub async fn geoadd(
+        &self,
+        key: String,
+        members: Vec<(Point, String)>,
+        options: GeoAddOptions,
+    ) -> Result<i64> {
+        let mut data = self.data.lock().await;
+        let created = !data.contains_key(&key);
+        let set = get_sorted_set_mut(&mut data, key.clone())?;
+        let (mut count, mut changed) = (0, false);
+        for (point, member) in members {
+            let score = encode(point);
+            match set.get_score(&member) {
+                Some(_) if options.nx => continue,
+                None if options.xx => continue,
+                Some(current) if current == score => continue,
+                Some(_) => {
+                    set.insert(member, score);
+                    if options.ch {
+                        count += 1;
+                    }
+                }
+                None => {
+                    set.insert(member, score);
+                    count += 1;
+                }
+            }
+            changed = true;
+        }
+        let emptied = set.set.is_empty();
+        if emptied {
+            data.remove(&key);
+        }
+        if changed {
+            self.touch(&key).await;
+            drop(data);
+            self.notify_new(created && !emptied, &key).await;
+            self.notify(EventClass::ZSet, "zadd", &key).await;
+        }
+        Ok(count)
     }
 
     pub async fn geopos(&self, key: String, members: Vec<String>) -> Vec<Vec<String>> {
@@ -26,37 +119,181 @@ impl InMemoryStore {
         result
     }
 
-    pub async fn geodist(&self, key: String, from: String, to: String) -> Option<String> {
+    pub async fn geodist(
+        &self,
+        key: String,
+        from: String,
+        to: String,
+        unit: GeoUnit,
+    ) -> Option<String> {
         let from = self.zscore(key.clone(), from).await.map(decode)?;
         let to = self.zscore(key.clone(), to).await.map(decode)?;
-        Some(haversine(&from, &to).to_string())
+        Some(format!("{:.4}", unit.from_meters(haversine(&from, &to))))
     }
 
-    pub async fn geosearch(
+    pub async fn geohash(&self, key: String, members: Vec<String>) -> Vec<Option<String>> {
+        let data = self.data.lock().await;
+        let set = get_sorted_set(&data, &key);
+        members
+            .iter()
+            .map(|member| set.and_then(|set| set.get_score(member)).map(geohash))
+            .collect()
+    }
+
+    pub async fn geosearch(&self, key: String, query: &GeoSearchQuery) -> Result<Vec<GeoMatch>> {
+        let data = self.data.lock().await;
+        search(&data, &key, query)
+    }
+
+    /// Searches and stores under a single lock, so no write to the source can land in
+    /// between, even when it's also the destination.
+    pub async fn geosearchstore(
         &self,
+        destination: String,
         key: String,
-        point: Point,
-        radius: f64,
-        unit: String,
-    ) -> Vec<String> {
-        let mut res = vec![];
-        let data = self.data.lock().await;
-        let Some(set) = get_sorted_set(&data, &key) else {
-            return res;
-        };
-        for (member, score) in set.set.iter() {
-            let member_point = decode(*score);
-            let distance = haversine(&point, &member_point);
-            let distance_in_unit = match unit.to_lowercase().as_str() {
-                "km" => distance / 1000.0,
-                "mi" => distance * 0.621371 * 1000.0,
-                "ft" => distance * 3280.84 * 1000.0,
-                _ => distance,
+        query: &GeoSearchQuery,
+        store_dist: bool,
+    ) -> Result<i64> {
+        let mut data = self.data.lock().await;
+        let matches = search(&data, &key, query)?;
+        if matches.is_empty() {
+            if data.remove(&destination).is_some() {
+                self.touch(&destination).await;
+                self.notify(EventClass::Generic, "del", &destination).await;
+            }
+            return Ok(0);
+        }
+        let mut set = SortedSet::default();
+        for GeoMatch {
+            member,
+            score,
+            distance,
+            ..
+        } in matches
+        {
+            let score = match store_dist {
+                true => Decimal::from_f64(distance).unwrap_or_default(),
+                false => score,
+            };
+            set.insert(member, score);
+        }
+        let len = set.set.len() as i64;
+        let created = data
+            .insert(
+                destination.clone(),
+                ValueWrapper {
+                    value: Value::SortedSet(set),
+                    expiry: None,
+                },
+            )
+            .is_none();
+        self.touch(&destination).await;
+        self.notify_new(created, &destination).await;
+        self.notify(EventClass::ZSet, "geosearchstore", &destination)
+            .await;
+        Ok(len)
+    }
+}
+
+/// The members of the sorted set at `key` inside the query's shape.
+fn search(
+    data: &HashMap<String, ValueWrapper>,
+    key: &str,
+    query: &GeoSearchQuery,
+) -> Result<Vec<GeoMatch>> {
+    let Some(set) = get_sorted_set(data, key) else {
+        return Ok(vec![]);
+    };
+    let center = match &query.origin {
+        GeoOrigin::LonLat(point) => point.clone(),
+        GeoOrigin::Member(member) => match set.get_score(member) {
+            Some(score) => decode(score),
+            None => bail!("could not decode requested zset member"),
+        },
+    };
+    let limit = match query.count {
+        Some((count, true)) => count,
+        _ => usize::MAX,
+    };
+
+    let (half_width, half_height) = match query.shape {
+        GeoShape::Radius(radius) => (radius, radius),
+        GeoShape::Box { width, height } => (width / 2.0, height / 2.0),
+    };
+    let cells = search_cells(
+        &center,
+        query.unit.to_meters(half_width),
+        query.unit.to_meters(half_height),
+    );
+
+    let mut res = vec![];
+    'cells: for (min, max) in cells {
+        for (score, member) in set.range_by_score(Decimal::from(min), Decimal::from(max)) {
+            let point = decode(*score);
+            let Some(distance) = distance_in_shape(&center, &point, &query.shape, query.unit)
+            else {
+                continue;
             };
-            if distance_in_unit <= radius {
-                res.push(member.clone());
+            res.push(GeoMatch {
+                member: member.clone(),
+                score: *score,
+                point,
+                distance,
+            });
+            if res.len() >= limit {
+                break 'cells;
+            }
+        }
+    }
+
+    let sort = match (query.sort, query.count) {
+        (None, Some((_, false))) => Some(GeoSort::Asc),
+        (sort, _) => sort,
+    };
+    match sort {
+        Some(GeoSort::Asc) => res.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+        Some(GeoSort::Desc) => res.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+        None => (),
+    }
+    if let Some((count, _)) = query.count {
+        res.truncate(count);
+    }
+    Ok(res)
+}
+
+fn distance_in_shape(
+    center: &Point,
+    point: &Point,
+    shape: &GeoShape,
+    unit: GeoUnit,
+) -> Option<f64> {
+    match shape {
+        GeoShape::Radius(radius) => {
+            let distance = unit.from_meters(haversine(center, point));
+            (distance <= *radius).then_some(distance)
+        }
+        GeoShape::Box { width, height } => {
+            let lat_distance = haversine(
+                &Point {
+                    lat: center.lat,
+                    lon: point.lon,
+                },
+                point,
+            );
+            if unit.from_meters(lat_distance) > height / 2.0 {
+                return None;
+            }
+            let lon_distance = haversine(
+                &Point {
+                    lat: point.lat,
+                    lon: center.lon,
+                },
+                point,
+            );
+            if unit.from_meters(lon_distance) > width / 2.0 {
+                return None;
             }
+            Some(unit.from_meters(haversine(center, point)))
         }
-        res
     }
 }
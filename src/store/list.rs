@@ -2,8 +2,9 @@ use crate::common::convert_range_indices;
 
 use super::{
     core::InMemoryStore,
+    notify::EventClass,
     subscribe::wait_for_new_data,
-    value::{Value, ValueWrapper},
+    value::{Value, ValueWrapper, ERR_WRONG_TYPE},
 };
 use anyhow::{bail, Result};
 
@@ -15,6 +16,7 @@ impl InMemoryStore {
         is_left: bool,
     ) -> Result<usize> {
         let mut data = self.data.lock().await;
+        let created = !data.contains_key(&key);
         let entry = data.entry(key.clone()).or_insert_with(|| ValueWrapper {
             value: Value::List(vec![]),
             expiry: None,
@@ -24,10 +26,14 @@ impl InMemoryStore {
                 true => values.into_iter().for_each(|v| list.insert(0, v)),
                 false => values.into_iter().for_each(|v| list.push(v)),
             }
+            self.touch(&key).await;
+            self.notify_new(created, &key).await;
+            let event = if is_left { "lpush" } else { "rpush" };
+            self.notify(EventClass::List, event, &key).await;
             self.broadcast(&key).await;
             Ok(list.len())
         } else {
-            bail!("Key is not a list");
+            bail!(ERR_WRONG_TYPE);
         }
     }
 
@@ -65,9 +71,15 @@ impl InMemoryStore {
         }) = data.get_mut(&key)
         {
             let popped = list.drain(..count.min(list.len())).collect::<Vec<_>>();
-            if list.is_empty() {
+            let emptied = list.is_empty();
+            if emptied {
                 data.remove(&key);
             }
+            self.touch(&key).await;
+            self.notify(EventClass::List, "lpop", &key).await;
+            if emptied {
+                self.notify(EventClass::Generic, "del", &key).await;
+            }
             Some(popped)
         } else {
             None
@@ -82,9 +94,15 @@ impl InMemoryStore {
             }) = data.get_mut(key)
             {
                 let value = list.remove(0);
-                if list.is_empty() {
+                let emptied = list.is_empty();
+                if emptied {
                     data.remove(key);
                 }
+                self.touch(key).await;
+                self.notify(EventClass::List, "lpop", key).await;
+                if emptied {
+                    self.notify(EventClass::Generic, "del", key).await;
+                }
                 return Some(vec![key.clone(), value]);
             }
         }
@@ -95,11 +113,11 @@ impl InMemoryStore {
 pub async fn blpop_handler(
     store: &InMemoryStore,
     keys: Vec<String>,
-    block_ms: u64,
+    block_ms: Option<u64>,
 ) -> Option<Vec<String>> {
-    match store.blpop(&keys).await {
-        val if val.is_some() => val,
-        _ => {
+    match (store.blpop(&keys).await, block_ms) {
+        (val @ Some(_), _) | (val @ None, None) => val,
+        (None, Some(block_ms)) => {
             let updated_key = wait_for_new_data(&keys, block_ms, store).await?;
             store.blpop(&[updated_key]).await
         }
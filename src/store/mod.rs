@@ -1,8 +1,15 @@
+pub mod consumer_group;
 pub mod coords;
 pub mod core;
+pub mod exclusive;
+pub mod expiry;
 pub mod geo;
 pub mod list;
+pub mod notify;
+pub mod persistence;
 pub mod sorted_set;
 pub mod stream;
 pub mod subscribe;
+pub mod tracking;
 pub mod value;
+pub mod watch;
@@ -0,0 +1,160 @@
+use super::core::InMemoryStore;
+use crate::{channel::ChannelManager, server::config::get_config_value};
+use std::sync::{
+    atomic::{AtomicU16, Ordering},
+    Arc,
+};
+
+/// Event classes of `notify-keyspace-events`, each enabled by its flag character.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventClass {
+    Generic,
+    String,
+    List,
+    Set,
+    Hash,
+    ZSet,
+    Expired,
+    Evicted,
+    Stream,
+    KeyMiss,
+    New,
+}
+
+const KEYSPACE: u16 = 1 << 11;
+const KEYEVENT: u16 = 1 << 12;
+
+/// The classes enabled by `A`. Key misses and new keys have to be asked for explicitly.
+const ALL_CLASSES: &str = "g$lshzxet";
+
+impl EventClass {
+    fn flag(self) -> char {
+        match self {
+            EventClass::Generic => 'g',
+            EventClass::String => '$',
+            EventClass::List => 'l',
+            EventClass::Set => 's',
+            EventClass::Hash => 'h',
+            EventClass::ZSet => 'z',
+            EventClass::Expired => 'x',
+            EventClass::Evicted => 'e',
+            EventClass::Stream => 't',
+            EventClass::KeyMiss => 'm',
+            EventClass::New => 'n',
+        }
+    }
+
+    fn bit(self) -> u16 {
+        1 << (self as u16)
+    }
+}
+
+/// Parses `notify-keyspace-events` flags: `K` and `E` pick the keyspace and keyevent
+/// channels, and the other characters pick event classes.
+pub fn parse_flags(flags: &str) -> Option<u16> {
+    let classes = [
+        EventClass::Generic,
+        EventClass::String,
+        EventClass::List,
+        EventClass::Set,
+        EventClass::Hash,
+        EventClass::ZSet,
+        EventClass::Expired,
+        EventClass::Evicted,
+        EventClass::Stream,
+        EventClass::KeyMiss,
+        EventClass::New,
+    ];
+    let flags = flags.replace('A', ALL_CLASSES);
+    flags.chars().try_fold(0, |bits, c| {
+        let bit = match c {
+            'K' => KEYSPACE,
+            'E' => KEYEVENT,
+            c => classes.iter().find(|class| class.flag() == c)?.bit(),
+        };
+        Some(bits | bit)
+    })
+}
+
+fn is_set(bits: u16, flag: char) -> bool {
+    parse_flags(&flag.to_string()).is_some_and(|bit| bits & bit != 0)
+}
+
+/// Publishes keyspace notifications for the store's writes, expiries and misses.
+#[derive(Clone)]
+pub struct KeyspaceNotifier {
+    channels: ChannelManager,
+    flags: Arc<AtomicU16>,
+}
+
+impl Default for KeyspaceNotifier {
+    fn default() -> Self {
+        Self::new(ChannelManager::default())
+    }
+}
+
+impl KeyspaceNotifier {
+    /// A notifier publishing through `channels`, with the flags from
+    /// `--notify-keyspace-events`. Nothing is published by default.
+    pub fn new(channels: ChannelManager) -> Self {
+        let flags = get_config_value("notify-keyspace-events")
+            .and_then(|flags| parse_flags(&flags))
+            .unwrap_or_default();
+        Self {
+            channels,
+            flags: Arc::new(AtomicU16::new(flags)),
+        }
+    }
+
+    /// Replaces the enabled flags, returning false if they don't parse.
+    pub fn set_flags(&self, flags: &str) -> bool {
+        match parse_flags(flags) {
+            Some(bits) => {
+                self.flags.store(bits, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The enabled flags, in the form `CONFIG GET` reports them.
+    pub fn flags(&self) -> String {
+        let bits = self.flags.load(Ordering::Relaxed);
+        let mut flags = String::new();
+        match parse_flags(ALL_CLASSES) {
+            Some(all) if bits & all == all => flags.push('A'),
+            _ => flags.extend(ALL_CLASSES.chars().filter(|c| is_set(bits, *c))),
+        }
+        flags.extend("mnKE".chars().filter(|c| is_set(bits, *c)));
+        flags
+    }
+
+    async fn notify(&self, class: EventClass, event: &str, key: &str) {
+        let flags = self.flags.load(Ordering::Relaxed);
+        if flags & class.bit() == 0 {
+            return;
+        }
+        if flags & KEYSPACE != 0 {
+            let channel = format!("__keyspace@0__:{key}");
+            self.channels.publish(channel, event.into()).await;
+        }
+        if flags & KEYEVENT != 0 {
+            let channel = format!("__keyevent@0__:{event}");
+            self.channels.publish(channel, key.into()).await;
+        }
+    }
+}
+
+impl InMemoryStore {
+    /// Publishes `event` for `key`, on the channels `notify-keyspace-events` enables.
+    pub async fn notify(&self, class: EventClass, event: &str, key: &str) {
+        self.notifier.notify(class, event, key).await;
+    }
+
+    /// Publishes a `new` event when a write created `key`.
+    pub async fn notify_new(&self, created: bool, key: &str) {
+        if created {
+            self.notify(EventClass::New, "new", key).await;
+        }
+    }
+}
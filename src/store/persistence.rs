@@ -0,0 +1,245 @@
+use super::core::InMemoryStore;
+use crate::{rdb::encode::encode_snapshot, server::config::get_config_value};
+use anyhow::{bail, Result};
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::task::JoinHandle;
+
+pub const ERR_BGSAVE_IN_PROGRESS: &str = "Background save already in progress";
+
+/// The save points used when a snapshot file is configured but `save` isn't: after an
+/// hour with one change, five minutes with 100, or a minute with 10000.
+const DEFAULT_SAVE_POINTS: &str = "3600 1 300 100 60 10000";
+/// How long to wait after a failed background save before a save point tries again.
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+/// Snapshot once at least `changes` writes were made over at least `seconds`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SavePoint {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// Parses `<seconds> <changes>` pairs. An empty string disables automatic saves.
+pub fn parse_save_points(config: &str) -> Option<Vec<SavePoint>> {
+    let numbers = config
+        .split_whitespace()
+        .map(|n| n.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if numbers.len() % 2 != 0 {
+        return None;
+    }
+    Some(
+        numbers
+            .chunks(2)
+            .map(|pair| SavePoint {
+                seconds: pair[0],
+                changes: pair[1],
+            })
+            .collect(),
+    )
+}
+
+/// The configured save points, or the default ones if `dir` or `dbfilename` is set.
+/// Otherwise nothing is saved automatically.
+pub fn save_points() -> Vec<SavePoint> {
+    let default = rdb_configured().then(|| DEFAULT_SAVE_POINTS.to_string());
+    let Some(config) = get_config_value("save").or(default) else {
+        return vec![];
+    };
+    parse_save_points(&config).unwrap_or_else(|| {
+        eprintln!("Invalid save configuration '{config}', automatic saves are disabled");
+        vec![]
+    })
+}
+
+/// Snapshot bookkeeping for LASTSAVE and INFO persistence.
+pub struct PersistenceState {
+    /// Writes since the last successful save.
+    dirty: AtomicU64,
+    /// Unix time of the last successful save, or of startup.
+    last_save: AtomicU64,
+    bgsave_in_progress: AtomicBool,
+    last_bgsave_ok: AtomicBool,
+    /// Unix time the last background save started.
+    last_bgsave_try: AtomicU64,
+}
+
+impl Default for PersistenceState {
+    fn default() -> Self {
+        Self {
+            dirty: AtomicU64::new(0),
+            last_save: AtomicU64::new(unix_time()),
+            bgsave_in_progress: AtomicBool::new(false),
+            last_bgsave_ok: AtomicBool::new(true),
+            last_bgsave_try: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Whether `dir` or `dbfilename` is set. Only then is a snapshot loaded at startup.
+pub fn rdb_configured() -> bool {
+    get_config_value("dir").is_some() || get_config_value("dbfilename").is_some()
+}
+
+/// Where snapshots are saved and loaded from: `dbfilename` in `dir`, by default
+/// `dump.rdb` in the working directory.
+pub fn rdb_path() -> PathBuf {
+    let dir = get_config_value("dir").unwrap_or(".".into());
+    let file_name = get_config_value("dbfilename").unwrap_or("dump.rdb".into());
+    PathBuf::from(dir).join(file_name)
+}
+
+impl InMemoryStore {
+    pub(crate) fn mark_dirty(&self) {
+        self.persistence.dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Forgets the writes replayed while loading, which are already on disk.
+    pub(crate) fn loaded(&self) {
+        self.persistence.dirty.store(0, Ordering::Relaxed);
+    }
+
+    /// Writes a snapshot to `path`, holding the data lock throughout so no command runs
+    /// until it's on disk.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        if self.persistence.bgsave_in_progress.load(Ordering::Acquire) {
+            bail!(ERR_BGSAVE_IN_PROGRESS);
+        }
+        let data = self.data.lock().await;
+        let dirty = self.persistence.dirty.load(Ordering::Relaxed);
+        write_atomically(path, &encode_snapshot(&data, &self.functions(), false))?;
+        drop(data);
+        self.saved(dirty);
+        Ok(())
+    }
+
+    /// Copies the data, then encodes and writes it on a blocking thread. The handle
+    /// completes once the snapshot is written or has failed.
+    pub async fn bgsave(&self, path: PathBuf) -> Result<JoinHandle<()>> {
+        if self
+            .persistence
+            .bgsave_in_progress
+            .swap(true, Ordering::AcqRel)
+        {
+            bail!(ERR_BGSAVE_IN_PROGRESS);
+        }
+        self.persistence
+            .last_bgsave_try
+            .store(unix_time(), Ordering::Release);
+        let (data, functions, dirty) = {
+            let data = self.data.lock().await;
+            let dirty = self.persistence.dirty.load(Ordering::Relaxed);
+            (data.clone(), self.functions(), dirty)
+        };
+        let store = self.clone();
+        Ok(tokio::spawn(async move {
+            let written = tokio::task::spawn_blocking(move || {
+                write_atomically(&path, &encode_snapshot(&data, &functions, false))
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|written| written);
+            if let Err(e) = &written {
+                eprintln!("Background save failed: {e}");
+            }
+            let ok = written.is_ok();
+            if ok {
+                store.saved(dirty);
+            }
+            let persistence = &store.persistence;
+            persistence.last_bgsave_ok.store(ok, Ordering::Release);
+            persistence
+                .bgsave_in_progress
+                .store(false, Ordering::Release);
+        }))
+    }
+
+    /// Starts a background save if any save point was reached since the last save, unless
+    /// one is running or the last one failed moments ago.
+    pub async fn save_if_due(&self, points: &[SavePoint], path: PathBuf) -> Option<JoinHandle<()>> {
+        let persistence = &self.persistence;
+        let now = unix_time();
+        let retrying = !persistence.last_bgsave_ok.load(Ordering::Acquire)
+            && now < persistence.last_bgsave_try.load(Ordering::Acquire) + BGSAVE_RETRY_DELAY;
+        let dirty = persistence.dirty.load(Ordering::Relaxed);
+        let elapsed = now.saturating_sub(self.last_save());
+        let due = points
+            .iter()
+            .any(|point| dirty >= point.changes && elapsed >= point.seconds);
+        if !due || retrying || persistence.bgsave_in_progress.load(Ordering::Acquire) {
+            return None;
+        }
+        let _exclusive = self.exclusive.lock().await;
+        self.bgsave(path).await.ok()
+    }
+
+    /// Waits for a running background save to finish.
+    pub async fn wait_for_bgsave(&self) {
+        while self.persistence.bgsave_in_progress.load(Ordering::Acquire) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Unix time of the last successful save.
+    pub fn last_save(&self) -> u64 {
+        self.persistence.last_save.load(Ordering::Acquire)
+    }
+
+    /// The fields of INFO's persistence section.
+    pub fn persistence_info(&self) -> Vec<(&'static str, String)> {
+        let persistence = &self.persistence;
+        let bgsave_status = match persistence.last_bgsave_ok.load(Ordering::Acquire) {
+            true => "ok",
+            false => "err",
+        };
+        vec![
+            ("loading", "0".into()),
+            (
+                "rdb_changes_since_last_save",
+                persistence.dirty.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "rdb_bgsave_in_progress",
+                (persistence.bgsave_in_progress.load(Ordering::Acquire) as u8).to_string(),
+            ),
+            ("rdb_last_save_time", self.last_save().to_string()),
+            ("rdb_last_bgsave_status", bgsave_status.into()),
+        ]
+    }
+
+    /// Writes made after the snapshot was taken still count as unsaved.
+    fn saved(&self, dirty: u64) {
+        let persistence = &self.persistence;
+        persistence.dirty.fetch_sub(dirty, Ordering::Relaxed);
+        persistence.last_save.store(unix_time(), Ordering::Release);
+    }
+}
+
+/// Writes to a temporary file in the same directory, then renames it over `path`, so a
+/// crash never leaves a partial file behind.
+pub(crate) fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!("temp-{}-{file_name}", std::process::id()));
+    let written = File::create(&temp).and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&temp, path)) {
+        let _ = fs::remove_file(&temp);
+        bail!("Failed to write {}: {e}", path.display());
+    }
+    Ok(())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
@@ -1,13 +1,16 @@
 use hashbrown::HashMap;
 use rust_decimal::Decimal;
 use skiplist::OrderedSkipList;
+use std::ops::Bound;
 
 use crate::common::convert_range_indices;
 
 use super::{
     core::InMemoryStore,
-    value::{Value, ValueWrapper},
+    notify::EventClass,
+    value::{Value, ValueWrapper, ERR_WRONG_TYPE},
 };
+use anyhow::{bail, Result};
 
 pub struct SortedSet {
     pub set: HashMap<String, Decimal>,
@@ -27,6 +30,18 @@ impl Default for SortedSet {
     }
 }
 
+/// The skip list isn't `Clone`, as its comparator is set after construction, so the
+/// copy is rebuilt from the members.
+impl Clone for SortedSet {
+    fn clone(&self) -> Self {
+        let mut set = SortedSet::default();
+        for (member, score) in &self.set {
+            set.insert(member.clone(), *score);
+        }
+        set
+    }
+}
+
 impl SortedSet {
     pub fn insert(&mut self, member: String, score: Decimal) -> i64 {
         let mut updated_count = 1;
@@ -57,6 +72,18 @@ impl SortedSet {
         self.scores.index_of(&(*score, member.to_string()))
     }
 
+    /// Entries with `min <= score < max`, in score order.
+    pub fn range_by_score(
+        &self,
+        min: Decimal,
+        max: Decimal,
+    ) -> impl Iterator<Item = &(Decimal, String)> {
+        self.scores.range(
+            Bound::Included(&(min, String::new())),
+            Bound::Excluded(&(max, String::new())),
+        )
+    }
+
     pub fn list_members(&self, start: isize, end: isize) -> Option<Vec<String>> {
         let (start, end) = convert_range_indices(start, end, self.scores.len() as isize)?;
         Some(
@@ -70,16 +97,28 @@ impl SortedSet {
 }
 
 impl InMemoryStore {
-    pub async fn zadd(&self, key: String, score: Decimal, member: String) -> i64 {
+    pub async fn zadd(&self, key: String, score: Decimal, member: String) -> Result<i64> {
         let mut data = self.data.lock().await;
-        let set = get_sorted_set_mut(&mut data, key);
-        set.insert(member, score)
+        let created = !data.contains_key(&key);
+        let set = get_sorted_set_mut(&mut data, key.clone())?;
+        let added = set.insert(member, score);
+        self.touch(&key).await;
+        self.notify_new(created, &key).await;
+        self.notify(EventClass::ZSet, "zadd", &key).await;
+        Ok(added)
     }
 
-    pub async fn zrem(&self, key: String, member: String) -> i64 {
+    pub async fn zrem(&self, key: String, member: String) -> Result<i64> {
         let mut data = self.data.lock().await;
-        let set = get_sorted_set_mut(&mut data, key);
-        set.remove(member)
+        let removed = match data.contains_key(&key) {
+            true => get_sorted_set_mut(&mut data, key.clone())?.remove(member),
+            false => 0,
+        };
+        if removed > 0 {
+            self.touch(&key).await;
+            self.notify(EventClass::ZSet, "zrem", &key).await;
+        }
+        Ok(removed)
     }
 
     pub async fn zscore(&self, key: String, member: String) -> Option<Decimal> {
@@ -119,7 +158,11 @@ pub fn get_sorted_set<'a>(
     }
 }
 
-fn get_sorted_set_mut(data: &mut HashMap<String, ValueWrapper>, key: String) -> &mut SortedSet {
+/// The sorted set at `key`, created if missing. Other types are a WRONGTYPE error.
+pub fn get_sorted_set_mut(
+    data: &mut HashMap<String, ValueWrapper>,
+    key: String,
+) -> Result<&mut SortedSet> {
     match data.entry(key).or_insert(ValueWrapper {
         value: Value::SortedSet(SortedSet::default()),
         expiry: None,
@@ -127,7 +170,7 @@ fn get_sorted_set_mut(data: &mut HashMap<String, ValueWrapper>, key: String) ->
         ValueWrapper {
             value: Value::SortedSet(set),
             ..
-        } => set,
-        _ => unreachable!("Should be a SortedSet"),
+        } => Ok(set),
+        _ => bail!(ERR_WRONG_TYPE),
     }
 }
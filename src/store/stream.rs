@@ -1,34 +1,203 @@
 use super::{
+    consumer_group::{get_stream_mut, ConsumerGroup},
     core::InMemoryStore,
-    value::{Value, ValueWrapper},
+    notify::EventClass,
+    value::{Value, ValueWrapper, ERR_WRONG_TYPE},
 };
 use crate::command::stream_handlers::{StreamData, StreamFilter};
 use anyhow::{bail, Result};
 use std::{
     collections::BTreeMap,
-    ops::Bound,
+    fmt,
+    ops::Bound::{self, *},
     time::{SystemTime, UNIX_EPOCH},
 };
 
-pub struct StreamQueryResult {
-    pub data: Option<Vec<StreamData>>,
-    pub max_ids: Vec<(String, Option<String>)>,
+pub type StreamRange = (Bound<StreamId>, Bound<StreamId>);
+pub type StreamEntry = Vec<(String, String)>;
+
+/// Entries per radix tree node in Redis; approximate trimming only removes whole nodes.
+pub const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: Self = Self { ms: 0, seq: 0 };
+    pub const MAX: Self = Self {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        Self { ms, seq }
+    }
+
+    /// Parses `ms-seq`, or a bare `ms` with the sequence part set to `default_seq`.
+    pub fn parse(s: &str, default_seq: u64) -> Result<Self> {
+        let parsed = match s.split_once('-') {
+            Some((ms, seq)) => ms.parse().ok().zip(seq.parse().ok()),
+            None => s.parse().ok().map(|ms| (ms, default_seq)),
+        };
+        match parsed {
+            Some((ms, seq)) => Ok(Self { ms, seq }),
+            None => bail!(ERR_INVALID_ID),
+        }
+    }
+
+    /// Start of an XRANGE interval: `-`, `(id` for exclusive, or an (incomplete) ID.
+    pub fn parse_range_start(s: &str) -> Result<Bound<Self>> {
+        match s {
+            "-" => Ok(Unbounded),
+            _ => match s.strip_prefix('(') {
+                Some(id) => Ok(Excluded(Self::parse(id, 0)?)),
+                None => Ok(Included(Self::parse(s, 0)?)),
+            },
+        }
+    }
+
+    /// End of an XRANGE interval: `+`, `(id` for exclusive, or an (incomplete) ID.
+    pub fn parse_range_end(s: &str) -> Result<Bound<Self>> {
+        match s {
+            "+" => Ok(Unbounded),
+            _ => match s.strip_prefix('(') {
+                Some(id) => Ok(Excluded(Self::parse(id, u64::MAX)?)),
+                None => Ok(Included(Self::parse(s, u64::MAX)?)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, StreamEntry>,
+    pub last_id: StreamId,
+    pub max_deleted_id: StreamId,
+    pub entries_added: u64,
+    pub groups: BTreeMap<String, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn first_id(&self) -> Option<StreamId> {
+        self.entries.keys().next().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_entry_id(&self) -> Option<StreamId> {
+        self.entries.keys().next_back().copied()
+    }
+
+    /// Entries in `range`, newest first when `rev` is set, at most `count` of them.
+    pub fn range(
+        &self,
+        range: StreamRange,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Vec<(StreamId, StreamEntry)> {
+        if !is_valid_range(&range) {
+            return vec![];
+        }
+        let count = count.unwrap_or(usize::MAX);
+        let entries = self
+            .entries
+            .range(range)
+            .map(|(id, entry)| (*id, entry.clone()));
+        match rev {
+            true => entries.rev().take(count).collect(),
+            false => entries.take(count).collect(),
+        }
+    }
+
+    /// Number of entries the group has yet to read, `None` when it can't be derived
+    /// because of deletions.
+    pub fn group_lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let first_id = self.first_id().unwrap_or(self.last_id);
+        match group.entries_read {
+            Some(read)
+                if !self.has_tombstones(group.last_delivered_id)
+                    && group.last_delivered_id >= first_id =>
+            {
+                Some(self.entries_added.saturating_sub(read))
+            }
+            _ => self
+                .estimate_entries_read(group.last_delivered_id)
+                .map(|read| self.entries_added.saturating_sub(read)),
+        }
+    }
+
+    fn add(&mut self, id: StreamId, entry: StreamEntry) {
+        self.entries.insert(id, entry);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    /// Removes entries from the head of the stream and returns how many were removed.
+    pub fn trim(&mut self, options: &TrimOptions) -> usize {
+        let limit = match (options.approximate, options.limit) {
+            (false, _) | (true, Some(0)) => usize::MAX,
+            (true, Some(limit)) => limit,
+            (true, None) => STREAM_NODE_MAX_ENTRIES * 100,
+        };
+        let mut count = match options.strategy {
+            TrimStrategy::MaxLen(max_len) => self.len().saturating_sub(max_len).min(limit),
+            TrimStrategy::MinId(min_id) => self.entries.range(..min_id).take(limit).count(),
+        };
+        if options.approximate {
+            count -= count % STREAM_NODE_MAX_ENTRIES;
+        }
+        for _ in 0..count {
+            if let Some((id, _)) = self.entries.pop_first() {
+                self.max_deleted_id = self.max_deleted_id.max(id);
+            }
+        }
+        count
+    }
+}
+
+#[derive(Clone)]
+pub enum TrimStrategy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+#[derive(Clone)]
+pub struct TrimOptions {
+    pub strategy: TrimStrategy,
+    pub approximate: bool,
+    pub limit: Option<usize>,
 }
 
-enum StreamId {
-    Explicit(u64, u64),
+enum IdSpec {
+    Explicit(StreamId),
     TimeOnly(u64),
     Generate,
 }
-impl TryFrom<&str> for StreamId {
+impl TryFrom<&str> for IdSpec {
     type Error = anyhow::Error;
     fn try_from(s: &str) -> Result<Self> {
-        let parts: Vec<_> = s.split('-').collect();
-        match parts.as_slice() {
-            ["*"] => Ok(Self::Generate),
-            [val, "*"] => Ok(Self::TimeOnly(val.parse()?)),
-            [ms, seq] => Ok(Self::Explicit(ms.parse()?, seq.parse()?)),
-            _ => bail!("Invalid StreamId format"),
+        match s.split_once('-') {
+            None if s == "*" => Ok(Self::Generate),
+            Some((ms, "*")) => Ok(Self::TimeOnly(ms.parse()?)),
+            _ => Ok(Self::Explicit(StreamId::parse(s, 0)?)),
         }
     }
 }
@@ -38,101 +207,207 @@ impl InMemoryStore {
         &self,
         key: String,
         stream_id: String,
-        stream_entry: (String, String),
-    ) -> Result<String> {
+        stream_entry: StreamEntry,
+        trim: Option<TrimOptions>,
+        no_mkstream: bool,
+    ) -> Result<Option<String>> {
         let mut data = self.data.lock().await;
+        if no_mkstream && !data.contains_key(&key) {
+            return Ok(None);
+        }
+        let created = !data.contains_key(&key);
         let entry = data.entry(key.clone()).or_insert(ValueWrapper {
-            value: Value::Stream(BTreeMap::new()),
+            value: Value::Stream(Stream::default()),
             expiry: None,
         });
         let Value::Stream(stream) = &mut entry.value else {
-            bail!("Key is not a stream");
+            bail!(ERR_WRONG_TYPE);
+        };
+        let id_spec = IdSpec::try_from(stream_id.as_str());
+        let stream_id = match id_spec.and_then(|spec| get_stream_id(spec, stream.last_id)) {
+            Ok(id) => id,
+            Err(e) => {
+                if created {
+                    data.remove(&key);
+                }
+                return Err(e);
+            }
+        };
+        stream.add(stream_id, stream_entry);
+        let trimmed = trim.map_or(0, |trim| stream.trim(&trim));
+        self.touch(&key).await;
+        self.notify_new(created, &key).await;
+        self.notify(EventClass::Stream, "xadd", &key).await;
+        if trimmed > 0 {
+            self.notify(EventClass::Stream, "xtrim", &key).await;
+        }
+        // Stream reads don't consume anything, so every blocked reader gets to see it.
+        self.notify_all(&key).await;
+
+        Ok(Some(stream_id.to_string()))
+    }
+
+    pub async fn trim_stream(&self, key: String, trim: TrimOptions) -> Result<usize> {
+        let mut data = self.data.lock().await;
+        let trimmed = match data.get_mut(&key).map(|v| &mut v.value) {
+            Some(Value::Stream(stream)) => stream.trim(&trim),
+            Some(_) => bail!(ERR_WRONG_TYPE),
+            None => 0,
         };
-        let stream_id = get_stream_id(
-            StreamId::try_from(stream_id.as_str())?,
-            stream.keys().next_back().map(String::as_str),
-        )?;
-        stream
-            .entry(stream_id.clone())
-            .or_insert(vec![])
-            .push(stream_entry);
-        self.broadcast(&key).await;
+        if trimmed > 0 {
+            self.touch(&key).await;
+            drop(data);
+            self.notify(EventClass::Stream, "xtrim", &key).await;
+        }
+        Ok(trimmed)
+    }
 
-        Ok(stream_id)
+    pub async fn get_stream(&self, key: &str) -> Result<Stream> {
+        self.read_stream(key, Stream::clone).await
     }
 
-    pub async fn get_stream(&self, key: &str) -> Result<BTreeMap<String, Vec<(String, String)>>> {
-        if let Some(Value::Stream(stream)) = self.data.lock().await.get(key).map(|v| &v.value) {
-            Ok(stream.clone())
-        } else {
-            bail!("Key not found or not a stream");
+    /// Runs `read` on the stream under the store's lock, sparing a copy of it.
+    pub async fn read_stream<T>(&self, key: &str, read: impl FnOnce(&Stream) -> T) -> Result<T> {
+        match self.data.lock().await.get(key).map(|v| &v.value) {
+            Some(Value::Stream(stream)) => Ok(read(stream)),
+            Some(_) => bail!(ERR_WRONG_TYPE),
+            None => bail!("no such key"),
         }
     }
 
-    pub async fn get_filtered_streams(&self, filters: Vec<StreamFilter>) -> StreamQueryResult {
+    pub async fn stream_len(&self, key: &str) -> Result<usize> {
+        match self.data.lock().await.get(key).map(|v| &v.value) {
+            Some(Value::Stream(stream)) => Ok(stream.len()),
+            Some(_) => bail!(ERR_WRONG_TYPE),
+            None => Ok(0),
+        }
+    }
+
+    pub async fn stream_range(
+        &self,
+        key: &str,
+        range: StreamRange,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Result<Vec<(StreamId, StreamEntry)>> {
+        match self.data.lock().await.get(key).map(|v| &v.value) {
+            Some(Value::Stream(stream)) => Ok(stream.range(range, count, rev)),
+            Some(_) => bail!(ERR_WRONG_TYPE),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// XDEL: removes entries by ID. Pending entries referring to them stay in the group
+    /// PELs and are reported as deleted on the next read.
+    pub async fn delete_stream_entries(&self, key: String, ids: Vec<StreamId>) -> Result<usize> {
+        let mut data = self.data.lock().await;
+        let Some(stream) = get_stream_mut(&mut data, &key)? else {
+            return Ok(0);
+        };
+        let mut deleted = 0;
+        for id in ids {
+            if stream.entries.remove(&id).is_some() {
+                stream.max_deleted_id = stream.max_deleted_id.max(id);
+                deleted += 1;
+            }
+        }
+        if deleted > 0 {
+            self.touch(&key).await;
+            self.notify(EventClass::Stream, "xdel", &key).await;
+        }
+        Ok(deleted)
+    }
+
+    pub async fn set_stream_id(
+        &self,
+        key: String,
+        last_id: StreamId,
+        entries_added: Option<u64>,
+        max_deleted_id: Option<StreamId>,
+    ) -> Result<()> {
+        let mut data = self.data.lock().await;
+        let Some(stream) = get_stream_mut(&mut data, &key)? else {
+            bail!("no such key");
+        };
+        if stream.last_entry_id().is_some_and(|top| last_id < top) {
+            bail!("The ID specified in XSETID is smaller than the target stream top item");
+        }
+        if entries_added.is_some_and(|added| added < stream.len() as u64) {
+            bail!("The entries_added specified in XSETID is smaller than the target stream length");
+        }
+        if max_deleted_id.is_some_and(|max_deleted| last_id < max_deleted) {
+            bail!("The ID specified in XSETID is smaller than the provided max_deleted_entry_id");
+        }
+        stream.last_id = last_id;
+        if let Some(entries_added) = entries_added {
+            stream.entries_added = entries_added;
+        }
+        if let Some(max_deleted_id) = max_deleted_id {
+            stream.max_deleted_id = max_deleted_id;
+        }
+        self.touch(&key).await;
+        self.notify(EventClass::Stream, "xsetid", &key).await;
+        Ok(())
+    }
+
+    /// ID of the newest entry, used to resolve `$` in XREAD.
+    pub async fn stream_last_id(&self, key: &str) -> Option<StreamId> {
+        match self.data.lock().await.get(key).map(|v| &v.value) {
+            Some(Value::Stream(stream)) => Some(stream.last_id),
+            _ => None,
+        }
+    }
+
+    pub async fn get_filtered_streams(
+        &self,
+        filters: Vec<StreamFilter>,
+    ) -> Option<Vec<StreamData>> {
         let guard = self.data.lock().await;
         let mut streams = vec![];
-        let mut max_ids = vec![];
-        for StreamFilter { key, range } in filters {
+        for StreamFilter { key, range, count } in filters {
             if let Some(Value::Stream(stream)) = guard.get(&key).map(|v| &v.value) {
-                if let (Bound::Excluded(id), _) = &range {
-                    if id == "$" {
-                        max_ids.push((key, stream.keys().next_back().cloned()));
-                        continue;
-                    }
-                }
-                let entries = stream
-                    .range(range)
-                    .map(|(id, entries)| {
-                        (
-                            id.clone(),
-                            entries
-                                .iter()
-                                .map(|(field, value)| (field.clone(), value.clone()))
-                                .collect(),
-                        )
-                    })
-                    .collect::<Vec<_>>();
+                let entries = stream.range(range, count, false);
                 if entries.is_empty() {
                     continue;
                 }
                 streams.push(StreamData { key, entries });
             }
         }
-        StreamQueryResult {
-            data: Some(streams).filter(|v| !v.is_empty()),
-            max_ids,
-        }
+        Some(streams).filter(|v| !v.is_empty())
     }
 }
 
-fn get_stream_id(incoming: StreamId, last: Option<&str>) -> Result<String> {
-    let (last_ms, last_seq) = match last.map(StreamId::try_from).transpose()? {
-        Some(StreamId::Explicit(ms, seq)) => (ms, seq),
-        _ => (0, 0),
-    };
+/// `BTreeMap::range` panics on inverted or empty exclusive intervals.
+pub(crate) fn is_valid_range(range: &StreamRange) -> bool {
+    match range {
+        (Included(start) | Excluded(start), Included(end) | Excluded(end)) if start > end => false,
+        (Excluded(start), Excluded(end)) if start == end => false,
+        _ => true,
+    }
+}
+
+fn get_stream_id(incoming: IdSpec, last: StreamId) -> Result<StreamId> {
     match incoming {
-        StreamId::Explicit(ms, seq) if ms == 0 && seq == 0 => {
-            bail!(ERR_INVALID)
-        }
-        StreamId::Explicit(ms, seq) => {
-            if ms < last_ms || (ms == last_ms && seq <= last_seq) {
-                bail!(ERR_SMALL)
-            }
-            Ok(format!("{ms}-{seq}"))
-        }
-        StreamId::TimeOnly(ms) => {
-            if ms < last_ms {
-                bail!(ERR_SMALL)
+        IdSpec::Explicit(id) if id == StreamId::MIN => bail!(ERR_INVALID),
+        IdSpec::Explicit(id) if id <= last => bail!(ERR_SMALL),
+        IdSpec::Explicit(id) => Ok(id),
+        IdSpec::TimeOnly(ms) if ms < last.ms => bail!(ERR_SMALL),
+        IdSpec::TimeOnly(ms) if ms == last.ms => match last.seq.checked_add(1) {
+            Some(seq) => Ok(StreamId::new(ms, seq)),
+            None => bail!(ERR_SMALL),
+        },
+        IdSpec::TimeOnly(ms) => Ok(StreamId::new(ms, 0)),
+        IdSpec::Generate => {
+            // The clock may have gone backwards since the last entry was added, so never
+            // generate anything below it.
+            let ms = get_unix_ms();
+            if ms > last.ms {
+                return Ok(StreamId::new(ms, 0));
             }
-            if ms == last_ms {
-                return Ok(format!("{ms}-{}", last_seq + 1));
+            match last.seq.checked_add(1) {
+                Some(seq) => Ok(StreamId::new(last.ms, seq)),
+                None => Ok(StreamId::new(last.ms + 1, 0)),
             }
-            Ok(format!("{ms}-0"))
-        }
-        StreamId::Generate => {
-            let ms = get_unix_ms();
-            Ok(format!("{ms}-0"))
         }
     }
 }
@@ -147,3 +422,4 @@ pub fn get_unix_ms() -> u64 {
 const ERR_SMALL: &str =
     "The ID specified in XADD is equal or smaller than the target stream top item";
 const ERR_INVALID: &str = "The ID specified in XADD must be greater than 0-0";
+pub const ERR_INVALID_ID: &str = "Invalid stream ID specified as stream command argument";
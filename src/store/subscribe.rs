@@ -1,4 +1,4 @@
-use tokio::sync::oneshot::Sender;
+use tokio::sync::oneshot::{Receiver, Sender};
 use uuid::Uuid;
 
 use super::{core::InMemoryStore, stream::get_unix_ms};
@@ -45,6 +45,56 @@ impl InMemoryStore {
             let _ = sub.tx.send(updated_key.to_string());
         }
     }
+
+    /// Wakes every subscriber waiting on `updated_key`, unlike `broadcast` which only
+    /// hands the update to the longest waiting one.
+    pub async fn notify_all(&self, updated_key: &str) {
+        let mut subscribers = self.subscribers.lock().await;
+        let woken = subscribers
+            .iter()
+            .filter(|(_, sub)| sub.keys.iter().any(|key| key == updated_key))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in woken {
+            if let Some(sub) = subscribers.remove(&id) {
+                let _ = sub.tx.send(updated_key.to_string());
+            }
+        }
+    }
+}
+
+/// A subscription to new data on some keys, taken before checking for that data so
+/// that a write landing in between still wakes the waiter.
+pub struct DataWaiter {
+    id: Uuid,
+    rx: Receiver<String>,
+}
+
+impl DataWaiter {
+    pub async fn new(keys: &[String], store: &InMemoryStore) -> Self {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let id = store.subscribe(keys.to_vec(), tx).await;
+        Self { id, rx }
+    }
+
+    /// Waits for the first key updated since subscribing, forever if `timeout_ms` is 0.
+    pub async fn wait(self, timeout_ms: u64, store: &InMemoryStore) -> Option<String> {
+        let future = store.without_exclusive(async { self.rx.await.ok() });
+        let result = if timeout_ms == 0 {
+            future.await
+        } else {
+            tokio::time::timeout(tokio::time::Duration::from_millis(timeout_ms), future)
+                .await
+                .ok()
+                .flatten()
+        };
+        store.unsubscribe(self.id).await;
+        result
+    }
+
+    pub async fn cancel(self, store: &InMemoryStore) {
+        store.unsubscribe(self.id).await;
+    }
 }
 
 pub async fn wait_for_new_data(
@@ -52,18 +102,8 @@ pub async fn wait_for_new_data(
     timeout_ms: u64,
     store: &InMemoryStore,
 ) -> Option<String> {
-    let (tx, rx) = tokio::sync::oneshot::channel();
-    let sub_id = store.subscribe(keys.to_vec(), tx).await;
-    let future = async { rx.await.ok() };
-
-    let result = if timeout_ms == 0 {
-        future.await
-    } else {
-        tokio::time::timeout(tokio::time::Duration::from_millis(timeout_ms), future)
-            .await
-            .ok()
-            .flatten()
-    };
-    store.unsubscribe(sub_id).await;
-    result
+    DataWaiter::new(keys, store)
+        .await
+        .wait(timeout_ms, store)
+        .await
 }
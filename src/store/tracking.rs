@@ -0,0 +1,230 @@
+use super::core::InMemoryStore;
+use crate::command::response::encode_array_of_bstrings;
+use anyhow::{bail, Result};
+use hashbrown::{HashMap, HashSet};
+use std::future::Future;
+use tokio::sync::mpsc::UnboundedSender;
+
+tokio::task_local! {
+    /// The client whose command is running on this task, so NOLOOP clients aren't told
+    /// about their own writes.
+    static CURRENT_CLIENT: u64;
+}
+
+/// Runs `future` on behalf of client `id`. Commands from the master have no client.
+pub async fn run_as_client<T>(id: Option<u64>, future: impl Future<Output = T>) -> T {
+    match id {
+        Some(id) => CURRENT_CLIENT.scope(id, future).await,
+        None => future.await,
+    }
+}
+
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientSubcommand {
+    Id,
+    /// `CLIENT TRACKING ON` with its options, or `None` for `OFF`.
+    Tracking(Option<TrackingOptions>),
+    Caching(bool),
+    GetRedir,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrackingOptions {
+    /// The client that receives the invalidations instead.
+    pub redirect: Option<u64>,
+    /// Track every key matching `prefixes` rather than the keys the client read.
+    pub bcast: bool,
+    pub prefixes: Vec<String>,
+    /// Only track reads right after `CLIENT CACHING yes`.
+    pub optin: bool,
+    /// Track reads unless right after `CLIENT CACHING no`.
+    pub optout: bool,
+    /// Don't report the client's own writes.
+    pub noloop: bool,
+}
+
+/// A key some tracking client has to drop from its cache.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invalidation {
+    pub key: String,
+    /// Whether it is for another client that redirected its invalidations here.
+    pub redirected: bool,
+}
+
+impl Invalidation {
+    /// The frame a connection writes for it: an invalidate push on RESP3, redirected or
+    /// not. RESP2 connections only get redirected ones, as a message on
+    /// `__redis__:invalidate` while subscribed to it.
+    pub fn encode(&self, resp3: bool, subscribed: bool) -> Option<String> {
+        let keys = encode_array_of_bstrings(std::slice::from_ref(&self.key));
+        let (header, kind) = match (self.redirected, resp3) {
+            (_, true) => (">2", "$10\r\ninvalidate\r\n".to_string()),
+            (true, false) if subscribed => (
+                "*3",
+                format!(
+                    "$7\r\nmessage\r\n${}\r\n{INVALIDATE_CHANNEL}\r\n",
+                    INVALIDATE_CHANNEL.len()
+                ),
+            ),
+            _ => return None,
+        };
+        Some(format!("{header}\r\n{kind}{keys}"))
+    }
+}
+
+/// Connected clients and the keys they cache, so writes can tell them what to invalidate.
+#[derive(Default)]
+pub struct TrackingTable {
+    next_id: u64,
+    clients: HashMap<u64, TrackedClient>,
+    /// Clients that read each key, in the default mode. Entries are dropped once
+    /// invalidated, as the clients have to read the key again to cache it.
+    keys: HashMap<String, HashSet<u64>>,
+    /// Clients in BCAST mode.
+    broadcasts: HashSet<u64>,
+}
+
+impl TrackingTable {
+    /// Drops the client from the readers of every key, once it no longer tracks them.
+    fn forget_reads(&mut self, id: u64) {
+        self.keys.retain(|_, readers| {
+            readers.remove(&id);
+            !readers.is_empty()
+        });
+    }
+}
+
+struct TrackedClient {
+    sender: UnboundedSender<Invalidation>,
+    tracking: Option<TrackingOptions>,
+}
+
+impl InMemoryStore {
+    /// Registers a connection, returning its client ID. Invalidations for it, or for
+    /// clients redirecting to it, are sent to `sender`.
+    pub async fn register_client(&self, sender: UnboundedSender<Invalidation>) -> u64 {
+        let mut table = self.tracking.lock().await;
+        table.next_id += 1;
+        let id = table.next_id;
+        let client = TrackedClient {
+            sender,
+            tracking: None,
+        };
+        table.clients.insert(id, client);
+        id
+    }
+
+    pub async fn unregister_client(&self, id: u64) {
+        let mut table = self.tracking.lock().await;
+        table.clients.remove(&id);
+        table.broadcasts.remove(&id);
+        table.forget_reads(id);
+    }
+
+    /// `CLIENT TRACKING`: turns tracking on with `options`, or off with `None`.
+    pub async fn set_tracking(&self, id: u64, options: Option<TrackingOptions>) -> Result<()> {
+        let mut table = self.tracking.lock().await;
+        let Some(options) = options else {
+            table.broadcasts.remove(&id);
+            table.forget_reads(id);
+            if let Some(client) = table.clients.get_mut(&id) {
+                client.tracking = None;
+            }
+            return Ok(());
+        };
+        if options.optin && options.optout {
+            bail!("You can't use both OPTIN and OPTOUT");
+        }
+        if options.bcast && (options.optin || options.optout) {
+            bail!("OPTIN and OPTOUT are not compatible with BCAST");
+        }
+        if !options.bcast && !options.prefixes.is_empty() {
+            bail!("PREFIX option requires BCAST mode to be enabled");
+        }
+        if let Some(redirect) = options.redirect {
+            if !table.clients.contains_key(&redirect) {
+                bail!("The client ID you want redirect to does not exist");
+            }
+        }
+        let Some(client) = table.clients.get_mut(&id) else {
+            return Ok(());
+        };
+        if client
+            .tracking
+            .as_ref()
+            .is_some_and(|current| current.bcast != options.bcast)
+        {
+            bail!(
+                "You can't switch BCAST mode on/off before disabling tracking for this client, \
+                 and then re-enabling it with a different mode."
+            );
+        }
+        let bcast = options.bcast;
+        client.tracking = Some(options);
+        if bcast {
+            table.broadcasts.insert(id);
+        }
+        Ok(())
+    }
+
+    pub async fn tracking(&self, id: u64) -> Option<TrackingOptions> {
+        let table = self.tracking.lock().await;
+        table.clients.get(&id)?.tracking.clone()
+    }
+
+    /// Remembers that the client read `keys`. `caching` is what `CLIENT CACHING` set for
+    /// this command, which OPTIN and OPTOUT clients decide by.
+    pub async fn track_reads(&self, id: u64, keys: Vec<String>, caching: Option<bool>) {
+        let mut table = self.tracking.lock().await;
+        let Some(options) = table.clients.get(&id).and_then(|c| c.tracking.as_ref()) else {
+            return;
+        };
+        let tracked = match (options.optin, options.optout) {
+            _ if options.bcast => false,
+            (true, _) => caching == Some(true),
+            (_, true) => caching != Some(false),
+            _ => true,
+        };
+        if tracked {
+            for key in keys {
+                table.keys.entry(key).or_default().insert(id);
+            }
+        }
+    }
+
+    /// Tells the clients caching `key` that it changed.
+    pub(crate) async fn invalidate(&self, key: &str) {
+        let writer = CURRENT_CLIENT.try_with(|id| *id).ok();
+        let mut table = self.tracking.lock().await;
+        let mut readers = table.keys.remove(key).unwrap_or_default();
+        for id in &table.broadcasts {
+            let client = table.clients.get(id).and_then(|c| c.tracking.as_ref());
+            if client.is_some_and(|options| {
+                options.prefixes.is_empty()
+                    || options
+                        .prefixes
+                        .iter()
+                        .any(|prefix| key.starts_with(prefix))
+            }) {
+                readers.insert(*id);
+            }
+        }
+        for id in readers {
+            let Some(options) = table.clients.get(&id).and_then(|c| c.tracking.as_ref()) else {
+                continue;
+            };
+            if options.noloop && writer == Some(id) {
+                continue;
+            }
+            let target = options.redirect.unwrap_or(id);
+            if let Some(client) = table.clients.get(&target) {
+                let _ = client.sender.send(Invalidation {
+                    key: key.to_string(),
+                    redirected: options.redirect.is_some(),
+                });
+            }
+        }
+    }
+}
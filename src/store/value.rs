@@ -1,27 +1,18 @@
-use std::collections::BTreeMap;
-
 use crate::rdb::rdb_file::RdbValue;
 
-use super::sorted_set::SortedSet;
+use super::{sorted_set::SortedSet, stream::Stream};
+use hashbrown::{HashMap, HashSet};
+use rust_decimal::{prelude::FromPrimitive, Decimal};
 
+#[derive(Clone)]
 pub enum Value {
     String(String),
     Integer(i64),
     List(Vec<String>),
-    Stream(BTreeMap<String, Vec<(String, String)>>),
+    Stream(Stream),
     SortedSet(SortedSet),
-}
-
-impl Clone for Value {
-    fn clone(&self) -> Self {
-        match self {
-            Value::String(s) => Value::String(s.clone()),
-            Value::Integer(i) => Value::Integer(*i),
-            Value::List(l) => Value::List(l.clone()),
-            Value::Stream(s) => Value::Stream(s.clone()),
-            _ => panic!("Clone not implemented"),
-        }
-    }
+    Set(HashSet<String>),
+    Hash(HashMap<String, String>),
 }
 
 impl From<String> for Value {
@@ -30,6 +21,9 @@ impl From<String> for Value {
     }
 }
 
+pub const ERR_WRONG_TYPE: &str =
+    "WRONGTYPE Operation against a key holding the wrong kind of value";
+
 #[derive(Clone)]
 pub struct ValueWrapper {
     pub value: Value,
@@ -40,6 +34,26 @@ impl From<RdbValue> for Value {
     fn from(value: RdbValue) -> Self {
         match value {
             RdbValue::String(s) => Value::String(s),
+            RdbValue::List(list) => Value::List(list),
+            RdbValue::Set(members) => Value::Set(members.into_iter().collect()),
+            RdbValue::Hash(fields) => Value::Hash(fields.into_iter().collect()),
+            RdbValue::Stream(stream) => Value::Stream(stream),
+            RdbValue::SortedSet(members) => {
+                let mut set = SortedSet::default();
+                for (member, score) in members {
+                    set.insert(member, score_to_decimal(score));
+                }
+                Value::SortedSet(set)
+            }
         }
     }
 }
+
+/// Scores are stored as decimals, so infinite scores are clamped to the largest ones.
+fn score_to_decimal(score: f64) -> Decimal {
+    match score {
+        f64::INFINITY => Decimal::MAX,
+        f64::NEG_INFINITY => Decimal::MIN,
+        score => Decimal::from_f64(score).unwrap_or_default(),
+    }
+}
@@ -0,0 +1,88 @@
+use super::core::{is_expired, InMemoryStore};
+use hashbrown::HashMap;
+
+/// Versions of the keys some client is watching. Keys nobody watches aren't tracked,
+/// so writes to them only cost a map lookup.
+#[derive(Default)]
+pub struct WatchRegistry {
+    keys: HashMap<String, KeyVersion>,
+    next_version: u64,
+}
+
+struct KeyVersion {
+    version: u64,
+    watchers: usize,
+}
+
+/// State of a key when a client started watching it.
+#[derive(Clone)]
+pub struct WatchedKey {
+    pub key: String,
+    version: u64,
+    expiry: Option<u64>,
+}
+
+impl InMemoryStore {
+    pub async fn watch(&self, keys: Vec<String>) -> Vec<WatchedKey> {
+        let data = self.data.lock().await;
+        let mut registry = self.watches.lock().await;
+        keys.into_iter()
+            .map(|key| {
+                let entry = registry.keys.entry(key.clone()).or_insert(KeyVersion {
+                    version: 0,
+                    watchers: 0,
+                });
+                entry.watchers += 1;
+                let expiry = data
+                    .get(&key)
+                    .and_then(|v| v.expiry)
+                    .filter(|expiry| !is_expired(*expiry));
+                WatchedKey {
+                    version: entry.version,
+                    key,
+                    expiry,
+                }
+            })
+            .collect()
+    }
+
+    pub async fn unwatch(&self, watched: &[WatchedKey]) {
+        let mut registry = self.watches.lock().await;
+        for WatchedKey { key, .. } in watched {
+            if let Some(entry) = registry.keys.get_mut(key) {
+                entry.watchers -= 1;
+                if entry.watchers == 0 {
+                    registry.keys.remove(key);
+                }
+            }
+        }
+    }
+
+    /// Marks `key` as modified for any client watching it or caching it, and as a change
+    /// the next snapshot saves. Callers hold the data lock, so `is_dirty` can't see the
+    /// write without its new version.
+    pub async fn touch(&self, key: &str) {
+        self.mark_dirty();
+        let mut registry = self.watches.lock().await;
+        registry.next_version += 1;
+        let version = registry.next_version;
+        if let Some(entry) = registry.keys.get_mut(key) {
+            entry.version = version;
+        }
+        drop(registry);
+        self.invalidate(key).await;
+    }
+
+    /// Whether any of the keys was written to, or expired, since it was watched.
+    pub async fn is_dirty(&self, watched: &[WatchedKey]) -> bool {
+        let _data = self.data.lock().await;
+        let registry = self.watches.lock().await;
+        watched.iter().any(|watched| {
+            let touched = registry
+                .keys
+                .get(&watched.key)
+                .is_none_or(|entry| entry.version != watched.version);
+            touched || watched.expiry.is_some_and(is_expired)
+        })
+    }
+}
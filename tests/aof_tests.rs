@@ -0,0 +1,307 @@
+#[cfg(test)]
+mod tests {
+    use codecrafters_redis::{
+        command::core::Command,
+        protocol::Data,
+        server::{
+            aof::{
+                parse_command, parse_size, AofConfig, AofFile, AofFileKind, AppendOnlyFile,
+                FsyncPolicy, Manifest,
+            },
+            context::ServerContext,
+        },
+        store::value::Value,
+    };
+    use std::{
+        fs,
+        io::Write,
+        path::{Path, PathBuf},
+    };
+
+    const MANIFEST: &str = "file appendonly.aof.2.base.rdb seq 2 type b\n\
+        file appendonly.aof.2.incr.aof seq 2 type i\n\
+        file appendonly.aof.3.incr.aof seq 3 type i\n";
+
+    fn command(args: &[&str]) -> Command {
+        let args: Vec<Data> = args.iter().map(|arg| Data::BStr(arg.to_string())).collect();
+        Command::from(args.as_slice())
+    }
+
+    async fn run(context: &ServerContext, args: &[&str]) -> String {
+        String::from(context.execute_command(command(args)).await)
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(uuid::Uuid::new_v4().to_string())
+    }
+
+    /// A context with an empty store, logging to `dir`.
+    async fn open(dir: &Path) -> ServerContext {
+        let mut context = ServerContext::default();
+        context.store.data.lock().await.clear();
+        let mut config = AofConfig::new(dir.to_path_buf());
+        config.fsync = FsyncPolicy::Always;
+        context.aof = Some(AppendOnlyFile::open(config, &context).await.unwrap());
+        context
+    }
+
+    fn manifest(dir: &Path) -> Manifest {
+        let manifest = fs::read_to_string(dir.join("appendonly.aof.manifest")).unwrap();
+        Manifest::parse(&manifest).unwrap()
+    }
+
+    async fn info(context: &ServerContext, field: &str) -> String {
+        let info = run(context, &["INFO", "persistence"]).await;
+        info.split("\r\n")
+            .find_map(|line| line.strip_prefix(&format!("{field}:")))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_manifest() {
+        let manifest = Manifest::parse(MANIFEST).unwrap();
+        assert_eq!(
+            manifest.base,
+            Some(AofFile {
+                name: "appendonly.aof.2.base.rdb".into(),
+                seq: 2,
+                kind: AofFileKind::Base,
+            })
+        );
+        assert_eq!(manifest.incrs.len(), 2);
+        assert_eq!(manifest.to_string(), MANIFEST);
+
+        let with_history = "# comment\n\
+            file appendonly.aof.1.base.aof seq 1 type h\n\
+            type i seq 4 file appendonly.aof.4.incr.aof\n";
+        let manifest = Manifest::parse(with_history).unwrap();
+        assert_eq!(manifest.base, None);
+        assert_eq!(manifest.incrs[0].seq, 4);
+
+        assert!(Manifest::parse("file a seq 1 type x").is_err());
+        assert!(Manifest::parse("file a seq one type i").is_err());
+        assert!(Manifest::parse("file a seq 1").is_err());
+        assert!(Manifest::parse("file a seq 1 type b\nfile b seq 2 type b").is_err());
+    }
+
+    #[test]
+    fn test_parse_size_and_policy() {
+        assert_eq!(parse_size("64mb"), Some(64 * 1024 * 1024));
+        assert_eq!(parse_size("1k"), Some(1000));
+        assert_eq!(parse_size("2GB"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("1tb"), None);
+        assert_eq!(parse_size("mb"), None);
+        assert_eq!(FsyncPolicy::parse("EverySec"), Some(FsyncPolicy::EverySec));
+        assert_eq!(FsyncPolicy::parse("sometimes"), None);
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$2\r\nv\n\r\n";
+        let (args, len) = parse_command(set).unwrap().unwrap();
+        assert_eq!(args, ["SET", "k", "v\n"]);
+        assert_eq!(len, set.len());
+        for end in 0..set.len() {
+            assert!(parse_command(&set[..end]).unwrap().is_none());
+        }
+        assert!(parse_command(b"SET k v\r\n").is_err());
+        assert!(parse_command(b"*1\r\n$3\r\nSETX\r\n").is_err());
+        assert!(parse_command(b"*0\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_commands_are_replayed() {
+        let dir = temp_dir();
+        let context = open(&dir).await;
+        assert_eq!(
+            manifest(&dir).to_string(),
+            "file appendonly.aof.1.base.rdb seq 1 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type i\n"
+        );
+        run(&context, &["SET", "a", "1"]).await;
+        run(&context, &["INCR", "a"]).await;
+        run(&context, &["RPUSH", "list", "x", "y"]).await;
+        run(&context, &["SET", "expiring", "v", "PX", "100000"]).await;
+        run(&context, &["GET", "a"]).await;
+        let commands = vec![command(&["LPOP", "list"]), command(&["SET", "b", "2"])];
+        context.process_transaction(commands, vec![]).await;
+        assert_eq!(info(&context, "aof_enabled").await, "1");
+
+        let incr = fs::read_to_string(dir.join("appendonly.aof.1.incr.aof")).unwrap();
+        assert!(incr.starts_with("*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n"));
+        assert!(incr.contains("PXAT"));
+        assert!(!incr.contains("GET"));
+
+        let reopened = open(&dir).await;
+        assert_eq!(run(&reopened, &["GET", "a"]).await, "$1\r\n2\r\n");
+        assert_eq!(run(&reopened, &["GET", "b"]).await, "$1\r\n2\r\n");
+        assert_eq!(
+            run(&reopened, &["LRANGE", "list", "0", "-1"]).await,
+            "*1\r\n$1\r\ny\r\n"
+        );
+        assert!(reopened.store.data.lock().await["expiring"]
+            .expiry
+            .is_some());
+        assert_eq!(info(&reopened, "rdb_changes_since_last_save").await, "0");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_tail_is_truncated() {
+        let dir = temp_dir();
+        let context = open(&dir).await;
+        run(&context, &["SET", "a", "1"]).await;
+        let path = dir.join("appendonly.aof.1.incr.aof");
+        let loaded = fs::metadata(&path).unwrap().len();
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"*1\r\n$5\r\nMULTI\r\n*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n")
+            .unwrap();
+        file.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nc").unwrap();
+
+        let reopened = open(&dir).await;
+        assert_eq!(run(&reopened, &["GET", "a"]).await, "$1\r\n1\r\n");
+        assert_eq!(run(&reopened, &["GET", "b"]).await, "$-1\r\n");
+        assert_eq!(fs::metadata(&path).unwrap().len(), loaded);
+        run(&reopened, &["SET", "c", "3"]).await;
+        let reopened = open(&dir).await;
+        assert_eq!(run(&reopened, &["GET", "c"]).await, "$1\r\n3\r\n");
+
+        // Only the last file may end early.
+        let mut manifest = manifest(&dir);
+        let incr = manifest.incrs[0].clone();
+        fs::copy(&path, dir.join("appendonly.aof.2.incr.aof")).unwrap();
+        manifest.incrs.push(AofFile {
+            name: "appendonly.aof.2.incr.aof".into(),
+            seq: 2,
+            ..incr
+        });
+        fs::write(dir.join("appendonly.aof.manifest"), manifest.to_string()).unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"*2\r\n$3\r\nGET").unwrap();
+        let mut config = AofConfig::new(dir.clone());
+        config.fsync = FsyncPolicy::No;
+        assert!(AppendOnlyFile::open(config, &ServerContext::default())
+            .await
+            .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rewrite() {
+        let dir = temp_dir();
+        let context = open(&dir).await;
+        for i in 0..20 {
+            run(&context, &["SET", "counter", &i.to_string()]).await;
+        }
+        run(&context, &["RPUSH", "list", "a", "b"]).await;
+        let aof = context.aof.clone().unwrap();
+        let rewrite = aof.rewrite(&context.store).await.unwrap();
+        assert_eq!(info(&context, "aof_rewrite_in_progress").await, "1");
+        assert!(aof.rewrite(&context.store).await.is_err());
+        assert!(run(&context, &["BGREWRITEAOF"])
+            .await
+            .contains("already in progress"));
+        // Written while the base is being saved, so only in the new incr file.
+        run(&context, &["SET", "after", "1"]).await;
+        rewrite.await.unwrap();
+        assert_eq!(info(&context, "aof_rewrite_in_progress").await, "0");
+        assert_eq!(info(&context, "aof_last_bgrewrite_status").await, "ok");
+        assert_eq!(
+            info(&context, "aof_current_size").await,
+            info(&context, "aof_base_size").await
+        );
+
+        assert_eq!(
+            manifest(&dir).to_string(),
+            "file appendonly.aof.2.base.rdb seq 2 type b\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n"
+        );
+        assert!(!dir.join("appendonly.aof.1.base.rdb").exists());
+        assert!(!dir.join("appendonly.aof.1.incr.aof").exists());
+        let base = fs::read(dir.join("appendonly.aof.2.base.rdb")).unwrap();
+        assert!(base.windows(8).any(|w| w == b"aof-base"));
+
+        let reopened = open(&dir).await;
+        assert_eq!(run(&reopened, &["GET", "counter"]).await, "$2\r\n19\r\n");
+        assert_eq!(run(&reopened, &["GET", "after"]).await, "$1\r\n1\r\n");
+        let Some(Value::List(list)) = reopened.store.get("list").await else {
+            panic!("Expected a list");
+        };
+        assert_eq!(list, ["a", "b"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_functions_survive_rewrites() {
+        let code = "#!lua name=lib\nredis.register_function('hello', function() return 'hi' end)";
+        let dir = temp_dir();
+        let mut context = ServerContext::default();
+        context.store.data.lock().await.clear();
+        run(&context, &["FUNCTION", "LOAD", code]).await;
+        // The first base is created from the data and libraries already loaded.
+        context.aof = Some(
+            AppendOnlyFile::open(AofConfig::new(dir.clone()), &context)
+                .await
+                .unwrap(),
+        );
+        let reopened = open(&dir).await;
+        assert_eq!(
+            run(&reopened, &["FCALL", "hello", "0"]).await,
+            "$2\r\nhi\r\n"
+        );
+
+        // Only the incr file being replaced knows about the library once it's reloaded.
+        run(&reopened, &["FUNCTION", "DELETE", "lib"]).await;
+        run(&reopened, &["FUNCTION", "LOAD", code]).await;
+        let aof = reopened.aof.clone().unwrap();
+        aof.rewrite(&reopened.store).await.unwrap().await.unwrap();
+        assert!(!dir.join("appendonly.aof.1.incr.aof").exists());
+        let reopened = open(&dir).await;
+        assert_eq!(
+            run(&reopened, &["FCALL", "hello", "0"]).await,
+            "$2\r\nhi\r\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_automatic_rewrite() {
+        let dir = temp_dir();
+        let mut context = ServerContext::default();
+        context.store.data.lock().await.clear();
+        let mut config = AofConfig::new(dir.clone());
+        config.rewrite_min_size = 1000;
+        config.rewrite_percentage = 100;
+        context.aof = Some(AppendOnlyFile::open(config, &context).await.unwrap());
+        let aof = context.aof.clone().unwrap();
+
+        let mut writes = 0;
+        while manifest(&dir).base.unwrap().seq == 1 {
+            assert!(writes < 100, "The log was never rewritten");
+            assert!(!aof.rewrite_due());
+            run(&context, &["SET", "key", &"x".repeat(50)]).await;
+            writes += 1;
+            tokio::task::yield_now().await;
+            while info(&context, "aof_rewrite_in_progress").await == "1" {
+                tokio::task::yield_now().await;
+            }
+        }
+        assert!(writes > 10);
+        let size: u64 = info(&context, "aof_current_size").await.parse().unwrap();
+        assert!(size < 1000);
+        assert!(!aof.rewrite_due());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bgrewriteaof_without_aof() {
+        let context = ServerContext::default();
+        assert_eq!(
+            run(&context, &["BGREWRITEAOF"]).await,
+            "-ERR Append only file is disabled\r\n"
+        );
+        assert_eq!(info(&context, "aof_enabled").await, "0");
+    }
+}
@@ -0,0 +1,229 @@
+#[cfg(test)]
+mod tests {
+    use codecrafters_redis::{
+        command::core::Command,
+        common::glob_match,
+        protocol::Data,
+        server::{context::ServerContext, replica::ReplicaState},
+    };
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Mutex};
+
+    const LIBRARY: &str = "#!lua name=mylib\n\
+        redis.register_function('set_and_get', function(keys, args) \
+            redis.call('SET', keys[1], args[1]) \
+            return redis.call('GET', keys[1]) \
+        end)\n\
+        redis.register_function{function_name = 'peek', \
+            callback = function(keys) return redis.call('GET', keys[1]) end, \
+            flags = {'no-writes'}, description = 'reads a key'}";
+
+    fn command(args: &[&str]) -> Command {
+        let args: Vec<Data> = args.iter().map(|arg| Data::BStr(arg.to_string())).collect();
+        Command::from(args.as_slice())
+    }
+
+    async fn run(context: &ServerContext, args: &[&str]) -> String {
+        String::from(context.execute_command(command(args)).await)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_load_and_call_functions() {
+        let context = ServerContext::default();
+        assert_eq!(
+            run(&context, &["FUNCTION", "LOAD", LIBRARY]).await,
+            "$5\r\nmylib\r\n"
+        );
+        assert_eq!(
+            run(&context, &["FCALL", "set_and_get", "1", "k", "v"]).await,
+            "$1\r\nv\r\n"
+        );
+        assert_eq!(
+            run(&context, &["FCALL_RO", "peek", "1", "k"]).await,
+            "$1\r\nv\r\n"
+        );
+        assert_eq!(
+            run(&context, &["FCALL_RO", "set_and_get", "1", "k", "w"]).await,
+            "-ERR Can not execute a script with write flag using *_ro command.\r\n"
+        );
+        assert_eq!(
+            run(&context, &["FCALL", "missing", "0"]).await,
+            "-ERR Function not found\r\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_only_functions_cannot_write() {
+        let context = ServerContext::default();
+        let library = "#!lua name=ro\n\
+            redis.register_function{function_name = 'sneaky', flags = {'no-writes'}, \
+                callback = function() return redis.call('SET', 'k', 'v') end}";
+        run(&context, &["FUNCTION", "LOAD", library]).await;
+        assert_eq!(
+            run(&context, &["FCALL", "sneaky", "0"]).await,
+            "-ERR Write commands are not allowed from read-only scripts.\r\n"
+        );
+        assert_eq!(run(&context, &["GET", "k"]).await, "$-1\r\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_load_errors() {
+        let context = ServerContext::default();
+        for (code, error) in [
+            ("return 1", "-ERR Missing library metadata\r\n"),
+            ("#!js name=lib\n", "-ERR Engine 'js' not found\r\n"),
+            ("#!lua\n", "-ERR Library name was not given\r\n"),
+            (
+                "#!lua name=lib\nlocal x = 1",
+                "-ERR No functions registered\r\n",
+            ),
+            (
+                "#!lua name=lib\nredis.register_function{function_name = 'f', \
+                 callback = function() end, flags = {'bogus'}}",
+                "-ERR Error registering functions: unknown flag given\r\n",
+            ),
+        ] {
+            assert_eq!(run(&context, &["FUNCTION", "LOAD", code]).await, error);
+        }
+
+        run(&context, &["FUNCTION", "LOAD", LIBRARY]).await;
+        assert_eq!(
+            run(&context, &["FUNCTION", "LOAD", LIBRARY]).await,
+            "-ERR Library 'mylib' already exists\r\n"
+        );
+        let clashing = "#!lua name=other\nredis.register_function('peek', function() end)";
+        assert_eq!(
+            run(&context, &["FUNCTION", "LOAD", clashing]).await,
+            "-ERR Function peek already exists\r\n"
+        );
+        let replacement = "#!lua name=mylib\nredis.register_function('f', function() return 2 end)";
+        assert_eq!(
+            run(&context, &["FUNCTION", "LOAD", "REPLACE", replacement]).await,
+            "$5\r\nmylib\r\n"
+        );
+        assert_eq!(run(&context, &["FCALL", "f", "0"]).await, ":2\r\n");
+        assert_eq!(
+            run(&context, &["FCALL", "peek", "0"]).await,
+            "-ERR Function not found\r\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_list_delete_and_flush() {
+        let context = ServerContext::default();
+        run(&context, &["FUNCTION", "LOAD", LIBRARY]).await;
+        let other = "#!lua name=other\nredis.register_function('f', function() end)";
+        run(&context, &["FUNCTION", "LOAD", other]).await;
+
+        assert_eq!(
+            run(&context, &["FUNCTION", "LIST", "LIBRARYNAME", "my*"]).await,
+            "*1\r\n*6\r\n$12\r\nlibrary_name\r\n$5\r\nmylib\r\n$6\r\nengine\r\n$3\r\nLUA\r\n\
+             $9\r\nfunctions\r\n*2\r\n\
+             *6\r\n$4\r\nname\r\n$4\r\npeek\r\n$11\r\ndescription\r\n$11\r\nreads a key\r\n\
+             $5\r\nflags\r\n*1\r\n$9\r\nno-writes\r\n\
+             *6\r\n$4\r\nname\r\n$11\r\nset_and_get\r\n$11\r\ndescription\r\n$-1\r\n\
+             $5\r\nflags\r\n*0\r\n"
+        );
+        let with_code = run(&context, &["FUNCTION", "LIST", "WITHCODE"]).await;
+        assert!(with_code.starts_with("*2\r\n*8\r\n"), "{with_code}");
+        assert!(with_code.contains(other), "{with_code}");
+
+        assert_eq!(
+            run(&context, &["FUNCTION", "DELETE", "other"]).await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(&context, &["FUNCTION", "DELETE", "other"]).await,
+            "-ERR Library not found\r\n"
+        );
+        assert_eq!(
+            run(&context, &["FUNCTION", "STATS"]).await,
+            "*4\r\n$14\r\nrunning_script\r\n$-1\r\n$7\r\nengines\r\n*2\r\n$3\r\nLUA\r\n\
+             *4\r\n$15\r\nlibraries_count\r\n:1\r\n$15\r\nfunctions_count\r\n:2\r\n"
+        );
+        run(&context, &["FUNCTION", "FLUSH"]).await;
+        assert_eq!(run(&context, &["FUNCTION", "LIST"]).await, "*0\r\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dump_and_restore() {
+        let source = ServerContext::default();
+        run(&source, &["FUNCTION", "LOAD", LIBRARY]).await;
+        let dump = run(&source, &["FUNCTION", "DUMP"]).await;
+        let (Data::BStr(payload), _) = Data::deserialize(&dump) else {
+            panic!("{dump}");
+        };
+
+        let target = ServerContext::default();
+        assert_eq!(
+            run(&target, &["FUNCTION", "RESTORE", &payload]).await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(&target, &["FUNCTION", "RESTORE", &payload]).await,
+            "-ERR Library 'mylib' already exists\r\n"
+        );
+        assert_eq!(
+            run(&target, &["FUNCTION", "RESTORE", &payload, "REPLACE"]).await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(&target, &["FUNCTION", "RESTORE", "garbage"]).await,
+            "-ERR payload version or checksum are wrong\r\n"
+        );
+        assert_eq!(
+            run(&target, &["FCALL", "set_and_get", "1", "k", "v"]).await,
+            "$1\r\nv\r\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_functions_replicate() {
+        let context = ServerContext::default();
+        let (tx, mut rx) = mpsc::channel(10);
+        context.replicas.lock().await.add_channel(
+            "replica".into(),
+            Arc::new(Mutex::new(ReplicaState::new(tx))),
+        );
+        run(&context, &["FUNCTION", "LOAD", LIBRARY]).await;
+        run(&context, &["FUNCTION", "LOAD", LIBRARY]).await;
+        run(&context, &["FCALL", "set_and_get", "1", "k", "v"]).await;
+        run(&context, &["FCALL_RO", "peek", "1", "k"]).await;
+
+        let load = String::from_utf8(rx.try_recv().unwrap()).unwrap();
+        assert_eq!(
+            Command::from(Data::deserialize(&load).0).to_args(),
+            ["FUNCTION", "LOAD", LIBRARY]
+        );
+        assert_eq!(
+            String::from_utf8(rx.try_recv().unwrap()).unwrap(),
+            "*1\r\n$5\r\nMULTI\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*1\r\n$4\r\nEXEC\r\n"
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("h?llo", "hallo"));
+        assert!(glob_match("h*llo", "heeeello"));
+        assert!(glob_match("h[ae]llo", "hello"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-b]llo", "hbllo"));
+        assert!(glob_match("h\\*llo", "h*llo"));
+        assert!(!glob_match("h\\*llo", "hello"));
+        assert!(!glob_match("lib", "library"));
+        assert!(glob_match("a**b*", "axxbyy"));
+        assert!(glob_match("*[xy]", "abcy"));
+        assert!(glob_match("*\\", "a\\"));
+        assert!(!glob_match("a[bc", "ab"));
+        assert!(!glob_match("*a?", "ba"));
+
+        // Backtracking to every star would take exponential time.
+        let channel = "a".repeat(200);
+        let start = std::time::Instant::now();
+        assert!(!glob_match("*a*a*a*a*a*a*a*a*a*a*a*a*b", &channel));
+        assert!(glob_match("*a*a*a*a*a*a*a*a*a*a*a*a*", &channel));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,398 @@
+#[cfg(test)]
+mod tests {
+    use codecrafters_redis::{
+        command::core::Command,
+        protocol::Data,
+        server::context::ServerContext,
+        store::{
+            coords::{decode, encode, haversine, GeoUnit, Point},
+            core::InMemoryStore,
+            geo::{GeoAddOptions, GeoMatch, GeoOrigin, GeoSearchQuery, GeoShape, GeoSort},
+        },
+    };
+
+    async fn sicily() -> InMemoryStore {
+        let store = InMemoryStore::default();
+        let places = [
+            ("Palermo", "13.361389", "38.115556"),
+            ("Catania", "15.087269", "37.502669"),
+            ("edge1", "12.758489", "38.788135"),
+            ("edge2", "17.241510", "38.788135"),
+        ];
+        for (member, long, lat) in places {
+            store
+                .geoadd(
+                    "Sicily".into(),
+                    vec![(Point::new(lat, long), member.into())],
+                    GeoAddOptions::default(),
+                )
+                .await
+                .unwrap();
+        }
+        store
+    }
+
+    async fn run(context: &ServerContext, args: &[&str]) -> String {
+        let args: Vec<Data> = args.iter().map(|arg| Data::BStr(arg.to_string())).collect();
+        String::from(
+            context
+                .execute_command(Command::from(args.as_slice()))
+                .await,
+        )
+    }
+
+    fn query(origin: GeoOrigin, shape: GeoShape) -> GeoSearchQuery {
+        GeoSearchQuery {
+            origin,
+            shape,
+            unit: GeoUnit::Kilometers,
+            sort: Some(GeoSort::Asc),
+            count: None,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+        }
+    }
+
+    fn members(matches: &[GeoMatch]) -> Vec<&str> {
+        matches.iter().map(|m| m.member.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_geosearch_by_radius_sorted() {
+        let store = sicily().await;
+        let q = query(
+            GeoOrigin::LonLat(Point::new("37", "15")),
+            GeoShape::Radius(200.0),
+        );
+        let result = store.geosearch("Sicily".into(), &q).await.unwrap();
+        assert_eq!(members(&result), vec!["Catania", "Palermo"]);
+
+        let q = GeoSearchQuery {
+            sort: Some(GeoSort::Desc),
+            ..q
+        };
+        let result = store.geosearch("Sicily".into(), &q).await.unwrap();
+        assert_eq!(members(&result), vec!["Palermo", "Catania"]);
+    }
+
+    #[tokio::test]
+    async fn test_geosearch_by_box_with_count() {
+        let store = sicily().await;
+        let q = query(
+            GeoOrigin::LonLat(Point::new("37", "15")),
+            GeoShape::Box {
+                width: 400.0,
+                height: 400.0,
+            },
+        );
+        let result = store.geosearch("Sicily".into(), &q).await.unwrap();
+        assert_eq!(
+            members(&result),
+            vec!["Catania", "Palermo", "edge2", "edge1"]
+        );
+        assert_eq!(format!("{:.4}", result[0].distance), "56.4413");
+
+        let q = GeoSearchQuery {
+            count: Some((2, false)),
+            ..q
+        };
+        let result = store.geosearch("Sicily".into(), &q).await.unwrap();
+        assert_eq!(members(&result), vec!["Catania", "Palermo"]);
+    }
+
+    #[tokio::test]
+    async fn test_geosearch_from_member() {
+        let store = sicily().await;
+        let q = query(GeoOrigin::Member("Palermo".into()), GeoShape::Radius(100.0));
+        let result = store.geosearch("Sicily".into(), &q).await.unwrap();
+        assert_eq!(members(&result), vec!["Palermo", "edge1"]);
+
+        let q = query(GeoOrigin::Member("Rome".into()), GeoShape::Radius(100.0));
+        assert!(store.geosearch("Sicily".into(), &q).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_geosearchstore_with_dist() {
+        let store = sicily().await;
+        let q = query(
+            GeoOrigin::LonLat(Point::new("37", "15")),
+            GeoShape::Radius(200.0),
+        );
+        let count = store
+            .geosearchstore("dest".into(), "Sicily".into(), &q, true)
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+        let score = store.zscore("dest".into(), "Catania".into()).await.unwrap();
+        assert_eq!(score.round_dp(4).to_string(), "56.4413");
+
+        // Storing into the source replaces it with the matches.
+        let count = store
+            .geosearchstore("Sicily".into(), "Sicily".into(), &q, false)
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(store.zcard("Sicily".into()).await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_geosearch_matches_full_scan() {
+        use rand::Rng;
+        let mut rng = rand::rng();
+        let store = InMemoryStore::default();
+        let mut points = vec![];
+        for i in 0..2000 {
+            let point = Point {
+                lat: rng.random_range(-85.0..85.0),
+                lon: rng.random_range(-180.0..180.0),
+            };
+            store
+                .geoadd(
+                    "world".into(),
+                    vec![(point.clone(), i.to_string())],
+                    GeoAddOptions::default(),
+                )
+                .await
+                .unwrap();
+            points.push((i.to_string(), decode(encode(point))));
+        }
+
+        let centers = [(0.0, 0.0), (51.5, -0.12), (84.0, 179.9), (-60.0, -179.5)];
+        for (lat, lon) in centers {
+            let center = Point { lat, lon };
+            for radius in [50.0, 500.0, 2500.0, 15000.0] {
+                let q = GeoSearchQuery {
+                    sort: None,
+                    ..query(GeoOrigin::LonLat(center.clone()), GeoShape::Radius(radius))
+                };
+                let mut found = members(&store.geosearch("world".into(), &q).await.unwrap())
+                    .into_iter()
+                    .map(String::from)
+                    .collect::<Vec<_>>();
+                let mut expected = points
+                    .iter()
+                    .filter(|(_, p)| haversine(&center, p) / 1000.0 <= radius)
+                    .map(|(m, _)| m.clone())
+                    .collect::<Vec<_>>();
+                found.sort();
+                expected.sort();
+                assert_eq!(found, expected, "center {lat},{lon} radius {radius}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geodist_units() {
+        let store = sicily().await;
+        let dist = |unit| store.geodist("Sicily".into(), "Palermo".into(), "Catania".into(), unit);
+        assert_eq!(dist(GeoUnit::Meters).await.unwrap(), "166274.1516");
+        assert_eq!(dist(GeoUnit::Kilometers).await.unwrap(), "166.2742");
+        assert_eq!(dist(GeoUnit::Miles).await.unwrap(), "103.3182");
+        assert_eq!(dist(GeoUnit::Feet).await.unwrap(), "545518.8700");
+    }
+
+    #[tokio::test]
+    async fn test_geohash() {
+        let store = sicily().await;
+        let hashes = store
+            .geohash(
+                "Sicily".into(),
+                vec!["Palermo".into(), "Catania".into(), "Rome".into()],
+            )
+            .await;
+        assert_eq!(
+            hashes,
+            vec![Some("sqc8b49rny0".into()), Some("sqdtr74hyu0".into()), None]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_geoadd_options() {
+        let store = sicily().await;
+        let palermo_moved = || vec![(Point::new("38.2", "13.4"), "Palermo".to_string())];
+        let rome = || vec![(Point::new("41.9", "12.5"), "Rome".to_string())];
+
+        let nx = GeoAddOptions {
+            nx: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            store
+                .geoadd("Sicily".into(), palermo_moved(), nx.clone())
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(store.geoadd("Sicily".into(), rome(), nx).await.unwrap(), 1);
+
+        let xx_ch = GeoAddOptions {
+            xx: true,
+            ch: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            store
+                .geoadd("Sicily".into(), palermo_moved(), xx_ch.clone())
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            store.geoadd("Other".into(), rome(), xx_ch).await.unwrap(),
+            0
+        );
+        assert!(store.zcard("Other".into()).await.is_none());
+
+        store
+            .set("string".into(), "v".to_string().into(), None)
+            .await;
+        let error = store
+            .geoadd("string".into(), rome(), GeoAddOptions::default())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("WRONGTYPE"));
+        assert!(store
+            .zadd("string".into(), 1.into(), "m".into())
+            .await
+            .is_err());
+        assert!(store.zrem("string".into(), "m".into()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_georadius_store_options() {
+        let context = ServerContext::default();
+        run(
+            &context,
+            &["GEOADD", "Sicily", "13.361389", "38.115556", "store"],
+        )
+        .await;
+        assert_eq!(
+            run(
+                &context,
+                &["GEORADIUSBYMEMBER", "Sicily", "store", "10", "km"]
+            )
+            .await,
+            "*1\r\n$5\r\nstore\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                &[
+                    "GEORADIUSBYMEMBER",
+                    "Sicily",
+                    "store",
+                    "10",
+                    "km",
+                    "STORE",
+                    "dest"
+                ]
+            )
+            .await,
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                &["GEORADIUS", "Sicily", "13.36", "38.11", "10", "km", "WITHDIST", "STORE", "d"]
+            )
+            .await,
+            "-ERR STORE option in GEORADIUS is not compatible with WITHDIST, WITHHASH and WITHCOORD options\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_geo_argument_errors() {
+        let context = ServerContext::default();
+        let cases: [(&[&str], &str); 6] = [
+            (
+                &[
+                    "GEOSEARCH",
+                    "k",
+                    "FROMLONLAT",
+                    "abc",
+                    "38",
+                    "BYRADIUS",
+                    "1",
+                    "km",
+                ],
+                "value is not a valid float",
+            ),
+            (
+                &[
+                    "GEOSEARCH",
+                    "k",
+                    "FROMLONLAT",
+                    "200",
+                    "38",
+                    "BYRADIUS",
+                    "1",
+                    "km",
+                ],
+                "invalid longitude,latitude pair 200,38",
+            ),
+            (
+                &[
+                    "GEOSEARCH",
+                    "k",
+                    "FROMMEMBER",
+                    "m",
+                    "FROMLONLAT",
+                    "13",
+                    "38",
+                    "BYRADIUS",
+                    "1",
+                    "km",
+                ],
+                "exactly one of FROMMEMBER or FROMLONLAT can be specified",
+            ),
+            (
+                &["GEOSEARCH", "k", "FROMMEMBER", "m", "BYRADIUS", "1", "yd"],
+                "unsupported unit provided. please use M, KM, FT, MI",
+            ),
+            (
+                &["GEORADIUS", "k", "13", "38", "1", "yd"],
+                "unsupported unit provided. please use M, KM, FT, MI",
+            ),
+            (
+                &["GEODIST", "k", "a", "b", "yd"],
+                "unsupported unit provided. please use M, KM, FT, MI",
+            ),
+        ];
+        for (args, error) in cases {
+            assert_eq!(run(&context, args).await, format!("-ERR {error}\r\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geoadd_touches_only_on_change() {
+        let store = sicily().await;
+        let palermo = |lat| vec![(Point::new(lat, "13.361389"), "Palermo".to_string())];
+        let ch = GeoAddOptions {
+            ch: true,
+            ..Default::default()
+        };
+        let watched = store.watch(vec!["Sicily".into()]).await;
+        assert_eq!(
+            store
+                .geoadd("Sicily".into(), palermo("38.115556"), ch)
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(!store.is_dirty(&watched).await);
+
+        let xx = GeoAddOptions {
+            xx: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            store
+                .geoadd("Sicily".into(), palermo("38.2"), xx)
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(store.is_dirty(&watched).await);
+    }
+}
@@ -0,0 +1,202 @@
+#[cfg(test)]
+mod tests {
+    use codecrafters_redis::{
+        channel::ChannelManager, command::core::Command, protocol::Data,
+        server::context::ServerContext, store::notify::parse_flags,
+    };
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    fn command(args: &[&str]) -> Command {
+        let args: Vec<Data> = args.iter().map(|arg| Data::BStr(arg.to_string())).collect();
+        Command::from(args.as_slice())
+    }
+
+    async fn run(context: &ServerContext, args: &[&str]) -> String {
+        String::from(context.execute_command(command(args)).await)
+    }
+
+    /// Subscribes to every keyspace and keyevent channel, returning the messages' receiver.
+    async fn listen(channels: &ChannelManager) -> UnboundedReceiver<String> {
+        let (tx, rx) = unbounded_channel();
+        let (id, _) = channels.init(tx).await;
+        channels
+            .psubscribe(id, "__key*@0__:*".into())
+            .await
+            .unwrap();
+        rx
+    }
+
+    /// The channel and message of each pmessage received so far.
+    fn received(rx: &mut UnboundedReceiver<String>) -> Vec<(String, String)> {
+        let mut messages = vec![];
+        while let Ok(frame) = rx.try_recv() {
+            let (Data::Array(parts), _) = Data::deserialize(&frame) else {
+                panic!("{frame}");
+            };
+            let [_, _, Data::BStr(channel), Data::BStr(message)] = parts.as_slice() else {
+                panic!("{frame}");
+            };
+            messages.push((channel.clone(), message.clone()));
+        }
+        messages
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(channel, message)| (channel.to_string(), message.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        assert_eq!(parse_flags(""), Some(0));
+        assert_eq!(parse_flags("KEA"), parse_flags("KEg$lshzxet"));
+        assert_ne!(parse_flags("KEA"), parse_flags("KEAmn"));
+        assert_eq!(parse_flags("Kq"), None);
+    }
+
+    #[tokio::test]
+    async fn test_nothing_published_by_default() {
+        let context = ServerContext::default();
+        let mut rx = listen(&context.channels).await;
+        run(&context, &["SET", "k", "v"]).await;
+        assert!(received(&mut rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_keyspace_and_keyevent_channels() {
+        let context = ServerContext::default();
+        let mut rx = listen(&context.channels).await;
+        assert!(context.store.notifier.set_flags("KE$l"));
+
+        run(&context, &["SET", "k", "v", "PX", "100000"]).await;
+        run(&context, &["RPUSH", "list", "a"]).await;
+        run(&context, &["LPOP", "list"]).await;
+        assert_eq!(
+            received(&mut rx),
+            pairs(&[
+                ("__keyspace@0__:k", "set"),
+                ("__keyevent@0__:set", "k"),
+                ("__keyspace@0__:list", "rpush"),
+                ("__keyevent@0__:rpush", "list"),
+                ("__keyspace@0__:list", "lpop"),
+                ("__keyevent@0__:lpop", "list"),
+            ])
+        );
+
+        assert!(context.store.notifier.set_flags("Egn"));
+        run(&context, &["SET", "k", "v", "PX", "100000"]).await;
+        run(&context, &["RPUSH", "list", "a"]).await;
+        run(&context, &["LPOP", "list"]).await;
+        assert_eq!(
+            received(&mut rx),
+            pairs(&[
+                ("__keyevent@0__:expire", "k"),
+                ("__keyevent@0__:new", "list"),
+                ("__keyevent@0__:del", "list"),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_set_flags() {
+        let context = ServerContext::default();
+        let mut rx = listen(&context.channels).await;
+        let get = ["CONFIG", "GET", "notify-keyspace-events"];
+        assert_eq!(
+            run(&context, &get).await,
+            "*2\r\n$22\r\nnotify-keyspace-events\r\n$0\r\n\r\n"
+        );
+        assert_eq!(
+            run(&context, &["CONFIG", "SET", "notify-keyspace-events", "Kq"]).await,
+            "-ERR Invalid argument 'Kq' for CONFIG SET 'notify-keyspace-events'\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                &["CONFIG", "SET", "notify-keyspace-events", "Eg$lshzxetn"]
+            )
+            .await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(&context, &get).await,
+            "*2\r\n$22\r\nnotify-keyspace-events\r\n$3\r\nAnE\r\n"
+        );
+        run(&context, &["SET", "k", "v"]).await;
+        assert_eq!(
+            received(&mut rx),
+            pairs(&[("__keyevent@0__:new", "k"), ("__keyevent@0__:set", "k")])
+        );
+        assert!(run(&context, &["CONFIG", "SET", "maxmemory", "1mb"])
+            .await
+            .starts_with("-ERR"));
+    }
+
+    #[tokio::test]
+    async fn test_expired_and_keymiss_events() {
+        let context = ServerContext::default();
+        let mut rx = listen(&context.channels).await;
+        assert!(context.store.notifier.set_flags("Exm"));
+
+        run(&context, &["SET", "lazy", "v", "PX", "1"]).await;
+        run(&context, &["SET", "active", "v", "PX", "1"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        assert_eq!(run(&context, &["GET", "lazy"]).await, "$-1\r\n");
+        context.store.remove_expired().await;
+        assert_eq!(
+            received(&mut rx),
+            pairs(&[
+                ("__keyevent@0__:expired", "lazy"),
+                ("__keyevent@0__:keymiss", "lazy"),
+                ("__keyevent@0__:expired", "active"),
+            ])
+        );
+        assert_eq!(run(&context, &["GET", "active"]).await, "$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_active_expiry_samples_until_few_expired() {
+        let context = ServerContext::default();
+        let mut rx = listen(&context.channels).await;
+        assert!(context.store.notifier.set_flags("Ex"));
+        for i in 0..100 {
+            run(&context, &["SET", &format!("short{i}"), "v", "PX", "1"]).await;
+        }
+        for i in 0..10 {
+            run(&context, &["SET", &format!("long{i}"), "v", "PX", "100000"]).await;
+        }
+        run(&context, &["SET", "short0", "v"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+
+        context.store.remove_expired().await;
+        let left = context.store.data.lock().await.len();
+        assert!((11..=14).contains(&left), "{left} keys left");
+        assert_eq!(received(&mut rx).len(), 110 - left);
+    }
+
+    #[tokio::test]
+    async fn test_stream_and_sorted_set_events() {
+        let context = ServerContext::default();
+        let mut rx = listen(&context.channels).await;
+        assert!(context.store.notifier.set_flags("Etz"));
+
+        run(&context, &["XADD", "s", "1-1", "f", "v"]).await;
+        run(&context, &["XGROUP", "CREATE", "s", "g", "0"]).await;
+        run(&context, &["XDEL", "s", "1-1"]).await;
+        run(&context, &["ZADD", "z", "1", "m"]).await;
+        run(&context, &["ZREM", "z", "m"]).await;
+        run(&context, &["ZREM", "z", "m"]).await;
+        assert_eq!(
+            received(&mut rx),
+            pairs(&[
+                ("__keyevent@0__:xadd", "s"),
+                ("__keyevent@0__:xgroup-create", "s"),
+                ("__keyevent@0__:xdel", "s"),
+                ("__keyevent@0__:zadd", "z"),
+                ("__keyevent@0__:zrem", "z"),
+            ])
+        );
+    }
+}
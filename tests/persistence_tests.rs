@@ -0,0 +1,372 @@
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use codecrafters_redis::{
+        command::{core::Command, response::CommandResponse},
+        protocol::Data,
+        rdb::{
+            packed::{listpack, to_listpack},
+            rdb_file::RdbFile,
+        },
+        server::context::ServerContext,
+        store::{
+            persistence::{parse_save_points, SavePoint},
+            stream::StreamId,
+            value::{Value, ValueWrapper},
+        },
+    };
+    use hashbrown::HashMap;
+    use std::path::{Path, PathBuf};
+
+    fn command(args: &[&str]) -> Command {
+        let args: Vec<Data> = args.iter().map(|arg| Data::BStr(arg.to_string())).collect();
+        Command::from(args.as_slice())
+    }
+
+    async fn run(context: &ServerContext, args: &[&str]) -> String {
+        String::from(context.execute_command(command(args)).await)
+    }
+
+    /// `dump.rdb` in a new temporary directory.
+    fn temp_path() -> PathBuf {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("dump.rdb")
+    }
+
+    fn remove(path: &Path) {
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    fn load(path: &PathBuf) -> HashMap<String, (Value, Option<u64>)> {
+        let mut bytes = Bytes::from(std::fs::read(path).unwrap());
+        let mut rdb = RdbFile::try_from(&mut bytes).unwrap();
+        assert_eq!(rdb.sections.len(), 1);
+        rdb.sections
+            .remove(0)
+            .data
+            .into_iter()
+            .map(|(key, (value, expiry))| (key, (Value::from(value), expiry)))
+            .collect()
+    }
+
+    async fn info(context: &ServerContext, field: &str) -> String {
+        let info = run(context, &["INFO", "persistence"]).await;
+        info.split("\r\n")
+            .find_map(|line| line.strip_prefix(&format!("{field}:")))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_listpack_round_trip() {
+        let items: Vec<String> = [
+            "0",
+            "127",
+            "128",
+            "-1",
+            "-4096",
+            "4095",
+            "-32768",
+            "40000",
+            "-8388608",
+            "9000000",
+            "-2147483648",
+            "5000000000",
+            "007",
+            "-0",
+            "",
+            "text",
+        ]
+        .iter()
+        .map(|item| item.to_string())
+        .chain(["x".repeat(63), "y".repeat(64), "z".repeat(5000)])
+        .collect();
+        assert_eq!(listpack(to_listpack(&items).into()).unwrap(), items);
+    }
+
+    #[tokio::test]
+    async fn test_save_round_trips_every_type() {
+        let context = ServerContext::default();
+        run(&context, &["SET", "string", "hello"]).await;
+        run(&context, &["SET", "number", "-1234567"]).await;
+        run(&context, &["SET", "expiring", "v", "PX", "100000"]).await;
+        run(&context, &["INCR", "counter"]).await;
+        run(&context, &["RPUSH", "list", "a", "1", "b"]).await;
+        run(&context, &["ZADD", "zset", "1.5", "m1"]).await;
+        run(&context, &["ZADD", "zset", "-2", "m2"]).await;
+        // Later entries in the first node have smaller sequence numbers than its first.
+        run(&context, &["XADD", "stream", "999-5", "f", "v"]).await;
+        for (ms, seq) in (0..150).map(|i| (1000 + i / 2, i % 2)) {
+            let field = if ms % 3 == 0 { "other" } else { "f" };
+            run(
+                &context,
+                &["XADD", "stream", &format!("{ms}-{seq}"), field, "v"],
+            )
+            .await;
+        }
+        run(&context, &["XGROUP", "CREATE", "stream", "g", "0"]).await;
+        run(
+            &context,
+            &[
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "alice",
+                "COUNT",
+                "2",
+                "STREAMS",
+                "stream",
+                ">",
+            ],
+        )
+        .await;
+        {
+            let mut data = context.store.data.lock().await;
+            for (key, value) in [
+                (
+                    "set",
+                    Value::Set(["x".into(), "2".into()].into_iter().collect()),
+                ),
+                (
+                    "hash",
+                    Value::Hash([("f".into(), "v".into())].into_iter().collect()),
+                ),
+            ] {
+                let wrapper = ValueWrapper {
+                    value,
+                    expiry: None,
+                };
+                data.insert(key.into(), wrapper);
+            }
+        }
+
+        let path = temp_path();
+        context.store.save(&path).await.unwrap();
+        let mut data = load(&path);
+        remove(&path);
+        assert_eq!(data.len(), 9);
+
+        let (Value::String(string), None) = data.remove("string").unwrap() else {
+            panic!("Expected a string");
+        };
+        assert_eq!(string, "hello");
+        let (Value::String(number), _) = data.remove("number").unwrap() else {
+            panic!("Expected a string");
+        };
+        assert_eq!(number, "-1234567");
+        let (_, expiry) = data.remove("expiring").unwrap();
+        assert!(expiry.is_some());
+        let (Value::String(counter), _) = data.remove("counter").unwrap() else {
+            panic!("Expected a string");
+        };
+        assert_eq!(counter, "1");
+        let (Value::List(list), _) = data.remove("list").unwrap() else {
+            panic!("Expected a list");
+        };
+        assert_eq!(list, ["a", "1", "b"]);
+        let (Value::SortedSet(zset), _) = data.remove("zset").unwrap() else {
+            panic!("Expected a sorted set");
+        };
+        assert_eq!(zset.list_members(0, -1).unwrap(), ["m2", "m1"]);
+        let (Value::Set(set), _) = data.remove("set").unwrap() else {
+            panic!("Expected a set");
+        };
+        assert!(set.contains("x") && set.contains("2"));
+        let (Value::Hash(hash), _) = data.remove("hash").unwrap() else {
+            panic!("Expected a hash");
+        };
+        assert_eq!(hash["f"], "v");
+
+        let (Value::Stream(stream), _) = data.remove("stream").unwrap() else {
+            panic!("Expected a stream");
+        };
+        let Some(Value::Stream(original)) = context.store.get("stream").await else {
+            panic!("Expected a stream");
+        };
+        assert_eq!(
+            stream.entries.keys().collect::<Vec<_>>(),
+            original.entries.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(stream.entries, original.entries);
+        assert_eq!(stream.last_id, original.last_id);
+        assert_eq!(stream.entries_added, 151);
+        let group = &stream.groups["g"];
+        assert_eq!(group.last_delivered_id, StreamId::new(1000, 0));
+        assert_eq!(group.entries_read, Some(2));
+        assert_eq!(group.pending.len(), 2);
+        assert_eq!(group.consumers["alice"].pending.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_changes_since_last_save() {
+        let context = ServerContext::default();
+        let path = temp_path();
+        let started = info(&context, "rdb_last_save_time").await;
+        assert_eq!(
+            run(&context, &["LASTSAVE"]).await,
+            format!(":{started}\r\n")
+        );
+
+        run(&context, &["SET", "a", "1"]).await;
+        run(&context, &["RPUSH", "b", "x", "y"]).await;
+        run(&context, &["GET", "a"]).await;
+        assert_eq!(info(&context, "rdb_changes_since_last_save").await, "2");
+
+        context.store.save(&path).await.unwrap();
+        assert_eq!(info(&context, "rdb_changes_since_last_save").await, "0");
+        run(&context, &["SET", "a", "2"]).await;
+        assert_eq!(info(&context, "rdb_changes_since_last_save").await, "1");
+
+        let bgsave = context.store.bgsave(path.clone()).await.unwrap();
+        assert_eq!(info(&context, "rdb_bgsave_in_progress").await, "1");
+        assert!(context.store.bgsave(path.clone()).await.is_err());
+        assert!(context.store.save(&path).await.is_err());
+        bgsave.await.unwrap();
+        assert_eq!(info(&context, "rdb_bgsave_in_progress").await, "0");
+        assert_eq!(info(&context, "rdb_last_bgsave_status").await, "ok");
+        assert_eq!(info(&context, "rdb_changes_since_last_save").await, "0");
+        let (Value::String(a), _) = load(&path).remove("a").unwrap() else {
+            panic!("Expected a string");
+        };
+        assert_eq!(a, "2");
+        remove(&path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_functions_are_saved() {
+        let code = "#!lua name=lib\nredis.register_function('hello', function() return 'hi' end)";
+        let context = ServerContext::default();
+        let path = temp_path();
+        run(&context, &["FUNCTION", "FLUSH"]).await;
+        context.store.save(&path).await.unwrap();
+        run(&context, &["FUNCTION", "LOAD", code]).await;
+        assert_eq!(info(&context, "rdb_changes_since_last_save").await, "1");
+
+        context
+            .store
+            .bgsave(path.clone())
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        let mut bytes = Bytes::from(std::fs::read(&path).unwrap());
+        remove(&path);
+        let rdb = RdbFile::try_from(&mut bytes).unwrap();
+        assert_eq!(rdb.functions, [code]);
+
+        let restored = ServerContext::default();
+        restored.load_functions(rdb.functions);
+        assert_eq!(
+            run(&restored, &["FCALL", "hello", "0"]).await,
+            "$2\r\nhi\r\n"
+        );
+        assert_eq!(restored.store.functions(), [code]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_bgsave() {
+        let context = ServerContext::default();
+        run(&context, &["SET", "a", "1"]).await;
+        let temp = temp_path();
+        let path = temp.join("missing").join("dump.rdb");
+        context
+            .store
+            .bgsave(path.clone())
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(info(&context, "rdb_last_bgsave_status").await, "err");
+        assert_eq!(info(&context, "rdb_changes_since_last_save").await, "1");
+
+        // Save points wait a few seconds before trying again.
+        let points = [SavePoint {
+            seconds: 0,
+            changes: 1,
+        }];
+        assert!(context.store.save_if_due(&points, path).await.is_none());
+        remove(&temp);
+    }
+
+    #[test]
+    fn test_parse_save_points() {
+        assert_eq!(
+            parse_save_points("900 1  300 10"),
+            Some(vec![
+                SavePoint {
+                    seconds: 900,
+                    changes: 1
+                },
+                SavePoint {
+                    seconds: 300,
+                    changes: 10
+                },
+            ])
+        );
+        assert_eq!(parse_save_points(""), Some(vec![]));
+        assert_eq!(parse_save_points("900"), None);
+        assert_eq!(parse_save_points("900 x"), None);
+    }
+
+    #[tokio::test]
+    async fn test_save_points() {
+        let context = ServerContext::default();
+        let path = temp_path();
+        let points = [
+            SavePoint {
+                seconds: 3600,
+                changes: 1,
+            },
+            SavePoint {
+                seconds: 0,
+                changes: 2,
+            },
+        ];
+        run(&context, &["SET", "a", "1"]).await;
+        let store = &context.store;
+        assert!(store.save_if_due(&points, path.clone()).await.is_none());
+        run(&context, &["SET", "b", "2"]).await;
+        let bgsave = store.save_if_due(&points, path.clone()).await.unwrap();
+        assert!(store.save_if_due(&points, path.clone()).await.is_none());
+        bgsave.await.unwrap();
+        assert_eq!(load(&path).len(), 2);
+        assert!(store.save_if_due(&points, path.clone()).await.is_none());
+        remove(&path);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        assert!(matches!(command(&["SHUTDOWN"]), Command::Shutdown(None)));
+        assert!(matches!(
+            command(&["shutdown", "nosave"]),
+            Command::Shutdown(Some(false))
+        ));
+        assert!(matches!(command(&["SHUTDOWN", "LATER"]), Command::Invalid));
+
+        let context = ServerContext::default();
+        run(&context, &["SET", "a", "1"]).await;
+        let path = temp_path();
+        context.prepare_shutdown(Some(false), &path).await.unwrap();
+        assert!(!path.exists());
+        context.prepare_shutdown(Some(true), &path).await.unwrap();
+        assert_eq!(load(&path).len(), 1);
+
+        let missing = path.join("dump.rdb");
+        assert!(context
+            .prepare_shutdown(Some(true), &missing)
+            .await
+            .is_err());
+        remove(&path);
+
+        // Connections write nothing back for an empty list of responses.
+        let response = context
+            .execute_command(command(&["SHUTDOWN", "NOSAVE"]))
+            .await;
+        assert!(matches!(response, CommandResponse::Multiple(responses) if responses.is_empty()));
+        let notified = context.shutdown.notified();
+        tokio::time::timeout(std::time::Duration::from_secs(1), notified)
+            .await
+            .unwrap();
+    }
+}
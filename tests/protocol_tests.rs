@@ -1,6 +1,6 @@
 #[cfg(test)]
 mod tests {
-    use codecrafters_redis::protocol::Data;
+    use codecrafters_redis::{protocol::Data, scripting::convert::to_lua};
 
     #[test]
     fn test_deserialize() {
@@ -11,4 +11,23 @@ mod tests {
         };
         assert_eq!(s, "hellohello");
     }
+
+    #[test]
+    fn test_try_deserialize_rejects_unknown_types() {
+        assert!(Data::try_deserialize("%1\r\n$1\r\nk\r\n:1\r\n").is_none());
+        assert!(Data::try_deserialize("*2\r\n:1\r\n,1.5\r\n").is_none());
+        assert!(Data::try_deserialize("$10\r\nshort\r\n").is_none());
+        assert!(Data::try_deserialize(":x\r\n").is_none());
+        let (data, len) = Data::try_deserialize("*1\r\n:7\r\n").unwrap();
+        assert!(matches!(data, Data::Array(items) if matches!(items[..], [Data::Int(7)])));
+        assert_eq!(len, 8);
+    }
+
+    #[test]
+    fn test_unsupported_replies_are_script_errors() {
+        let lua = mlua::Lua::new();
+        assert!(to_lua(&lua, "%1\r\n$1\r\nk\r\n:1\r\n").is_err());
+        assert!(to_lua(&lua, ">2\r\n$3\r\nmsg\r\n$1\r\nx\r\n").is_err());
+        assert!(to_lua(&lua, ":1\r\n").is_ok());
+    }
 }
@@ -0,0 +1,321 @@
+#[cfg(test)]
+mod tests {
+    use codecrafters_redis::{
+        channel::{
+            context::SubscriptionContext, ChannelCommand, ChannelManager, OutputBufferLimit,
+        },
+        command::core::Command,
+        common::key_slot,
+        protocol::Data,
+        server::{context::ServerContext, replica::ReplicaState},
+    };
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Mutex};
+
+    fn channel_command(args: &[&str]) -> ChannelCommand {
+        let args: Vec<Data> = args.iter().map(|arg| Data::BStr(arg.to_string())).collect();
+        ChannelCommand::from(args.as_slice())
+    }
+
+    fn command(args: &[&str]) -> Command {
+        let args: Vec<Data> = args.iter().map(|arg| Data::BStr(arg.to_string())).collect();
+        Command::from(args.as_slice())
+    }
+
+    async fn run(context: &SubscriptionContext, args: &[&str]) -> String {
+        context
+            .process_command(channel_command(args))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_psubscribe_replies_per_pattern() {
+        let manager = ChannelManager::default();
+        let context = SubscriptionContext::new(manager).await;
+        assert_eq!(
+            run(&context, &["SUBSCRIBE", "news"]).await,
+            "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+        assert_eq!(
+            run(&context, &["PSUBSCRIBE", "orders.*", "user:*:events"]).await,
+            "*3\r\n$10\r\npsubscribe\r\n$8\r\norders.*\r\n:2\r\n\
+             *3\r\n$10\r\npsubscribe\r\n$13\r\nuser:*:events\r\n:3\r\n"
+        );
+        assert_eq!(
+            run(&context, &["PUNSUBSCRIBE", "orders.*"]).await,
+            "*3\r\n$12\r\npunsubscribe\r\n$8\r\norders.*\r\n:2\r\n"
+        );
+        assert_eq!(
+            run(&context, &["PUNSUBSCRIBE"]).await,
+            "*3\r\n$12\r\npunsubscribe\r\n$13\r\nuser:*:events\r\n:1\r\n"
+        );
+        assert_eq!(
+            run(&context, &["PUNSUBSCRIBE"]).await,
+            "*3\r\n$12\r\npunsubscribe\r\n$-1\r\n:1\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_delivers_pmessages() {
+        let manager = ChannelManager::default();
+        let mut exact = SubscriptionContext::new(manager.clone()).await;
+        let mut patterned = SubscriptionContext::new(manager.clone()).await;
+        run(&exact, &["SUBSCRIBE", "orders.new"]).await;
+        run(&patterned, &["PSUBSCRIBE", "orders.*", "*.new", "user:*"]).await;
+
+        assert_eq!(manager.publish("orders.new".into(), "42".into()).await, 3);
+        assert_eq!(
+            exact.receive_publish().await.unwrap(),
+            "*3\r\n$7\r\nmessage\r\n$10\r\norders.new\r\n$2\r\n42\r\n"
+        );
+        let mut pmessages = vec![
+            patterned.receive_publish().await.unwrap(),
+            patterned.receive_publish().await.unwrap(),
+        ];
+        pmessages.sort();
+        assert_eq!(
+            pmessages,
+            [
+                "*4\r\n$8\r\npmessage\r\n$5\r\n*.new\r\n$10\r\norders.new\r\n$2\r\n42\r\n",
+                "*4\r\n$8\r\npmessage\r\n$8\r\norders.*\r\n$10\r\norders.new\r\n$2\r\n42\r\n",
+            ]
+        );
+        assert_eq!(manager.publish("user".into(), "x".into()).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_and_unsubscribe_many_channels() {
+        let context = SubscriptionContext::new(ChannelManager::default()).await;
+        assert_eq!(
+            run(&context, &["SUBSCRIBE", "a", "b", "a"]).await,
+            "*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n\
+             *3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n\
+             *3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:2\r\n"
+        );
+        run(&context, &["PSUBSCRIBE", "p*"]).await;
+        assert_eq!(
+            run(&context, &["UNSUBSCRIBE", "a", "missing"]).await,
+            "*3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:2\r\n\
+             *3\r\n$11\r\nunsubscribe\r\n$7\r\nmissing\r\n:2\r\n"
+        );
+        assert_eq!(
+            run(&context, &["UNSUBSCRIBE"]).await,
+            "*3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:1\r\n"
+        );
+        assert_eq!(
+            run(&context, &["UNSUBSCRIBE"]).await,
+            "*3\r\n$11\r\nunsubscribe\r\n$-1\r\n:1\r\n"
+        );
+        assert!(context.is_subscribed().await);
+        run(&context, &["PUNSUBSCRIBE"]).await;
+        assert!(!context.is_subscribed().await);
+    }
+
+    #[tokio::test]
+    async fn test_quit_and_reset_drop_subscriptions() {
+        let manager = ChannelManager::default();
+        let context = SubscriptionContext::new(manager.clone()).await;
+        run(&context, &["SUBSCRIBE", "a", "b"]).await;
+        assert_eq!(run(&context, &["RESET"]).await, "+RESET\r\n");
+        assert!(!context.is_subscribed().await);
+
+        run(&context, &["PSUBSCRIBE", "*"]).await;
+        assert_eq!(run(&context, &["QUIT"]).await, "+OK\r\n");
+        assert_eq!(manager.publish("a".into(), "x".into()).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_pubsub_introspection() {
+        let server = ServerContext::default();
+        let first = SubscriptionContext::new(server.channels.clone()).await;
+        let second = SubscriptionContext::new(server.channels.clone()).await;
+        run(&first, &["SUBSCRIBE", "news", "orders.new"]).await;
+        run(&first, &["PSUBSCRIBE", "orders.*"]).await;
+        run(&second, &["SUBSCRIBE", "news"]).await;
+        run(&second, &["PSUBSCRIBE", "orders.*", "user:*"]).await;
+
+        let pubsub = |args: &[&str]| server.execute_command(command(args));
+        assert_eq!(
+            String::from(pubsub(&["PUBSUB", "CHANNELS"]).await),
+            "*2\r\n$4\r\nnews\r\n$10\r\norders.new\r\n"
+        );
+        assert_eq!(
+            String::from(pubsub(&["PUBSUB", "CHANNELS", "order?.*"]).await),
+            "*1\r\n$10\r\norders.new\r\n"
+        );
+        assert_eq!(
+            String::from(pubsub(&["PUBSUB", "NUMSUB", "news", "orders.new", "none"]).await),
+            "*6\r\n$4\r\nnews\r\n:2\r\n$10\r\norders.new\r\n:1\r\n$4\r\nnone\r\n:0\r\n"
+        );
+        assert_eq!(String::from(pubsub(&["PUBSUB", "NUMPAT"]).await), ":2\r\n");
+
+        drop(second);
+        assert_eq!(
+            String::from(pubsub(&["PUBSUB", "NUMSUB", "news"]).await),
+            "*2\r\n$4\r\nnews\r\n:1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(key_slot("123456789"), 12739);
+        assert_eq!(key_slot("foo"), 12182);
+        assert_eq!(
+            key_slot("{user1000}.following"),
+            key_slot("{user1000}.followers")
+        );
+        assert_eq!(key_slot("foo{bar}{zap}"), key_slot("bar"));
+        assert_eq!(key_slot("foo{{bar}}zap"), key_slot("{bar"));
+        assert_ne!(key_slot("foo{}{bar}"), key_slot("bar"));
+    }
+
+    #[tokio::test]
+    async fn test_sharded_pubsub() {
+        let server = ServerContext::default();
+        let (tx, mut rx) = mpsc::channel(10);
+        server.replicas.lock().await.add_channel(
+            "replica".into(),
+            Arc::new(Mutex::new(ReplicaState::new(tx))),
+        );
+        let mut context = SubscriptionContext::new(server.channels.clone()).await;
+        assert_eq!(
+            run(&context, &["SSUBSCRIBE", "{orders}.new", "{orders}.paid"]).await,
+            "*3\r\n$10\r\nssubscribe\r\n$12\r\n{orders}.new\r\n:1\r\n\
+             *3\r\n$10\r\nssubscribe\r\n$13\r\n{orders}.paid\r\n:2\r\n"
+        );
+        run(&context, &["SUBSCRIBE", "{orders}.new"]).await;
+
+        let execute = |args: &[&str]| server.execute_command(command(args));
+        assert_eq!(
+            String::from(execute(&["SPUBLISH", "{orders}.new", "42"]).await),
+            ":1\r\n"
+        );
+        assert_eq!(
+            context.receive_publish().await.unwrap(),
+            "*3\r\n$8\r\nsmessage\r\n$12\r\n{orders}.new\r\n$2\r\n42\r\n"
+        );
+        assert_eq!(
+            String::from(execute(&["SPUBLISH", "{orders}.none", "42"]).await),
+            ":0\r\n"
+        );
+        assert!(rx.try_recv().is_err());
+
+        assert_eq!(
+            String::from(execute(&["PUBSUB", "SHARDCHANNELS", "*paid"]).await),
+            "*1\r\n$13\r\n{orders}.paid\r\n"
+        );
+        assert_eq!(
+            String::from(execute(&["PUBSUB", "SHARDNUMSUB", "{orders}.new", "x"]).await),
+            "*4\r\n$12\r\n{orders}.new\r\n:1\r\n$1\r\nx\r\n:0\r\n"
+        );
+
+        assert_eq!(
+            run(&context, &["SUNSUBSCRIBE"])
+                .await
+                .matches("sunsubscribe")
+                .count(),
+            2
+        );
+        assert_eq!(
+            run(&context, &["SUNSUBSCRIBE"]).await,
+            "*3\r\n$12\r\nsunsubscribe\r\n$-1\r\n:0\r\n"
+        );
+        assert!(context.is_subscribed().await);
+        assert_eq!(
+            String::from(execute(&["PUBSUB", "SHARDCHANNELS"]).await),
+            "*0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_output_buffer_limit() {
+        assert_eq!(
+            OutputBufferLimit::parse("normal 0 0 0 pubsub 1mb 64kb 30"),
+            Some(OutputBufferLimit {
+                hard: 1024 * 1024,
+                soft: 64 * 1024,
+                soft_seconds: 30,
+            })
+        );
+        assert_eq!(
+            OutputBufferLimit::parse("pubsub 2k 0 0"),
+            Some(OutputBufferLimit {
+                hard: 2000,
+                soft: 0,
+                soft_seconds: 0,
+            })
+        );
+        assert_eq!(OutputBufferLimit::parse("replica 1mb 1mb 0"), None);
+        assert_eq!(OutputBufferLimit::parse("pubsub 1xb 0 0"), None);
+        assert_eq!(OutputBufferLimit::parse("pubsub 1mb 0"), None);
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscribers_are_disconnected() {
+        let manager = ChannelManager::new(OutputBufferLimit {
+            hard: 100,
+            soft: 0,
+            soft_seconds: 0,
+        });
+        let mut slow = SubscriptionContext::new(manager.clone()).await;
+        let mut fast = SubscriptionContext::new(manager.clone()).await;
+        run(&slow, &["SUBSCRIBE", "news"]).await;
+        run(&fast, &["SUBSCRIBE", "news"]).await;
+
+        // Each message is 35 bytes, so the slow subscriber's third one goes over 100.
+        for _ in 0..2 {
+            assert_eq!(manager.publish("news".into(), "0123456789".into()).await, 2);
+            assert!(fast.receive_publish().await.is_some());
+        }
+        assert_eq!(manager.publish("news".into(), "0123456789".into()).await, 1);
+        assert!(fast.receive_publish().await.is_some());
+        assert_eq!(slow.receive_publish().await, None);
+        assert!(!slow.is_subscribed().await);
+        assert_eq!(manager.publish("news".into(), "0123456789".into()).await, 1);
+
+        assert!(manager
+            .stats()
+            .await
+            .contains(&("client_output_buffer_limit_disconnections", 1)));
+    }
+
+    #[tokio::test]
+    async fn test_soft_limit_applies_after_its_duration() {
+        let manager = ChannelManager::new(OutputBufferLimit {
+            hard: 0,
+            soft: 40,
+            soft_seconds: 1,
+        });
+        let mut subscriber = SubscriptionContext::new(manager.clone()).await;
+        run(&subscriber, &["SUBSCRIBE", "news"]).await;
+        for _ in 0..3 {
+            assert_eq!(manager.publish("news".into(), "0123456789".into()).await, 1);
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        assert_eq!(manager.publish("news".into(), "0123456789".into()).await, 0);
+        assert_eq!(subscriber.receive_publish().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_info_reports_pubsub_stats() {
+        let server = ServerContext::default();
+        let context = SubscriptionContext::new(server.channels.clone()).await;
+        run(&context, &["SUBSCRIBE", "a", "b"]).await;
+        run(&context, &["PSUBSCRIBE", "a*"]).await;
+        let info = String::from(server.execute_command(command(&["INFO", "stats"])).await);
+        assert!(info.contains("# Stats\r\n"), "{info}");
+        assert!(info.contains("pubsub_channels:2\r\n"), "{info}");
+        assert!(info.contains("pubsub_patterns:1\r\n"), "{info}");
+        assert!(
+            info.contains("client_output_buffer_limit_disconnections:0"),
+            "{info}"
+        );
+        assert!(!info.contains("# Replication"), "{info}");
+
+        let info = String::from(server.execute_command(command(&["INFO"])).await);
+        assert!(info.contains("# Replication\r\n"), "{info}");
+        assert!(info.contains("role:master"), "{info}");
+        assert!(info.contains("# Stats"), "{info}");
+    }
+}
@@ -1,21 +1,44 @@
 #[cfg(test)]
 mod tests {
     use bytes::Bytes;
-    use codecrafters_redis::rdb::{length_encoded_value::LengthEncodedValue, rdb_file::RdbFile};
+    use codecrafters_redis::{
+        rdb::{
+            crc64::crc64,
+            decode::decode_value,
+            hex_to_bytes,
+            length_encoded_value::LengthEncodedValue,
+            lzf,
+            rdb_file::{RdbFile, RdbValue},
+        },
+        store::{stream::StreamId, value::Value},
+    };
+
+    /// Decodes a value from its type byte followed by its encoding.
+    fn decode(hex: &str) -> RdbValue {
+        let mut bytes: Bytes = hex_to_bytes(hex).into();
+        let value_type = bytes.split_to(1)[0];
+        let value = decode_value(&mut bytes, value_type).unwrap();
+        assert!(bytes.is_empty(), "{} bytes left over", bytes.len());
+        value
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
 
     #[test]
     fn test_integer_6bit() {
-        // 0b11xxxx00: 6 bit integer type, next byte is 8 bit integer
+        // 0b11xxxx00: 6 bit integer type, next byte is 8 bit signed integer
         let mut bytes = Bytes::from_static(&[0b11000000, 0b10101010]);
-        let value = LengthEncodedValue::from(&mut bytes);
+        let value = LengthEncodedValue::try_from(&mut bytes).unwrap();
         match value {
-            LengthEncodedValue::Integer(i) => assert_eq!(i, 0b10101010),
+            LengthEncodedValue::Integer(i) => assert_eq!(i, -86),
             _ => panic!("Expected Integer"),
         }
 
         // 0b11000001: 6 bit integer type, next 2 bytes is 16 bit integer (little endian)
         let mut bytes = Bytes::from_static(&[0b11000001, 0x34, 0x12]);
-        let value = LengthEncodedValue::from(&mut bytes);
+        let value = LengthEncodedValue::try_from(&mut bytes).unwrap();
         match value {
             LengthEncodedValue::Integer(i) => assert_eq!(i, 0x1234),
             _ => panic!("Expected Integer"),
@@ -23,7 +46,7 @@ mod tests {
 
         // 0b11000010: 6 bit integer type, next 4 bytes is 32 bit integer (little endian)
         let mut bytes = Bytes::from_static(&[0b11000010, 0x78, 0x56, 0x34, 0x12]);
-        let value = LengthEncodedValue::from(&mut bytes);
+        let value = LengthEncodedValue::try_from(&mut bytes).unwrap();
         match value {
             LengthEncodedValue::Integer(i) => assert_eq!(i, 0x12345678),
             _ => panic!("Expected Integer"),
@@ -34,7 +57,7 @@ mod tests {
     fn test_string_14bit() {
         // 0b01xxxxxx yyyyyyyy: 14 bit string length
         let mut bytes = Bytes::from_static(&[0b01000000, 0x03, b'a', b'b', b'c']);
-        let value = LengthEncodedValue::from(&mut bytes);
+        let value = LengthEncodedValue::try_from(&mut bytes).unwrap();
         match value {
             LengthEncodedValue::String(s) => assert_eq!(s, "abc"),
             _ => panic!("Expected String"),
@@ -45,7 +68,7 @@ mod tests {
     fn test_string_6bit() {
         // 0b00xxxxxx: 6 bit string length
         let mut bytes = Bytes::from_static(&[0b00000011, b'x', b'y', b'z']);
-        let value = LengthEncodedValue::from(&mut bytes);
+        let value = LengthEncodedValue::try_from(&mut bytes).unwrap();
         match value {
             LengthEncodedValue::String(s) => assert_eq!(s, "xyz"),
             _ => panic!("Expected String"),
@@ -56,7 +79,7 @@ mod tests {
     fn test_string_32bit() {
         // 0b10xxxxxx: next 4 bytes are length
         let mut bytes = Bytes::from_static(&[0b10000000, 0x00, 0x00, 0x00, 0x03, b'1', b'2', b'3']);
-        let value = LengthEncodedValue::from(&mut bytes);
+        let value = LengthEncodedValue::try_from(&mut bytes).unwrap();
         match value {
             LengthEncodedValue::String(s) => assert_eq!(s, "123"),
             _ => panic!("Expected String"),
@@ -69,4 +92,226 @@ mod tests {
         let mut bytes: Bytes = codecrafters_redis::rdb::hex_to_bytes(data).into();
         let _rdb = RdbFile::try_from(&mut bytes).unwrap();
     }
+
+    #[test]
+    fn test_decode_ziplist_list() {
+        // A string, 4-bit, 8-bit, 16-bit, 24-bit and 32-bit integers, and a 70 byte string.
+        let RdbValue::List(list) = decode(LIST_ZIPLIST) else {
+            panic!("Expected a list");
+        };
+        let long = "x".repeat(70);
+        assert_eq!(
+            list,
+            strings(&["a", "7", "-100", "1000", "-300000", "70000", &long])
+        );
+    }
+
+    #[test]
+    fn test_decode_listpack_hash() {
+        // 7-bit, 13-bit and 24-bit integers between strings.
+        let RdbValue::Hash(hash) = decode("102d2d0000000800846e616d65058361646104836167650424018564656c746106dc18028362696704f2a0860104ff") else {
+            panic!("Expected a hash");
+        };
+        let fields = [
+            ("name", "ada"),
+            ("age", "36"),
+            ("delta", "-1000"),
+            ("big", "100000"),
+        ];
+        let fields = fields.map(|(field, value)| (field.to_string(), value.to_string()));
+        assert_eq!(hash, fields);
+    }
+
+    #[test]
+    fn test_decode_sets() {
+        let RdbValue::Set(intset) = decode("0b0e0200000003000000feff05002c01") else {
+            panic!("Expected a set");
+        };
+        assert_eq!(intset, strings(&["-2", "5", "300"]));
+        let RdbValue::Set(set) = decode("020201780179") else {
+            panic!("Expected a set");
+        };
+        assert_eq!(set, strings(&["x", "y"]));
+    }
+
+    #[test]
+    fn test_decode_quicklist_2() {
+        // A listpack node, then a plain node holding a single element.
+        let RdbValue::List(list) =
+            decode("12020d0d0000000200816102816202ff020a706c61696e206e6f646501")
+        else {
+            panic!("Expected a list");
+        };
+        assert_eq!(list, strings(&["a", "b", "plain node"]));
+    }
+
+    #[test]
+    fn test_decode_sorted_sets() {
+        for (hex, expected) in [
+            (
+                "05020161000000000000f83f016200000000000000c0",
+                [("a", 1.5), ("b", -2.0)],
+            ),
+            ("0302016103322e350162fe", [("a", 2.5), ("b", f64::INFINITY)]),
+            (
+                "1115150000000400816102010181620284332e323505ff",
+                [("a", 1.0), ("b", 3.25)],
+            ),
+        ] {
+            let RdbValue::SortedSet(members) = decode(hex) else {
+                panic!("Expected a sorted set");
+            };
+            let expected = expected.map(|(member, score)| (member.to_string(), score));
+            assert_eq!(members, expected);
+        }
+    }
+
+    #[test]
+    fn test_decode_hashes() {
+        let RdbValue::Hash(hash) = decode("040101660176") else {
+            panic!("Expected a hash");
+        };
+        assert_eq!(hash, [("f".to_string(), "v".to_string())]);
+        // A zipmap, with a byte of free space after the second value.
+        let RdbValue::Hash(hash) = decode("091202016602007631036b6579030176616c00ff") else {
+            panic!("Expected a hash");
+        };
+        let fields = [("f", "v1"), ("key", "val")];
+        assert_eq!(hash, fields.map(|(f, v)| (f.to_string(), v.to_string())));
+    }
+
+    #[test]
+    fn test_decode_stream_with_groups() {
+        // One node: 1000-0 with the master fields, a deleted 1000-1, and 1005-3 with its
+        // own fields. Group `g` has 1000-0 pending for consumer `alice`.
+        let RdbValue::Stream(stream) = decode(STREAM_LISTPACKS_3) else {
+            panic!("Expected a stream");
+        };
+        let entries = stream.entries.into_iter().collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                (
+                    StreamId::new(1000, 0),
+                    vec![("f1".into(), "v1".into()), ("f2".into(), "v2".into())]
+                ),
+                (StreamId::new(1005, 3), vec![("x".into(), "9".into())]),
+            ]
+        );
+        assert_eq!(stream.last_id, StreamId::new(1005, 3));
+        assert_eq!(stream.max_deleted_id, StreamId::new(1000, 1));
+        assert_eq!(stream.entries_added, 3);
+
+        let group = &stream.groups["g"];
+        assert_eq!(group.last_delivered_id, StreamId::new(1000, 0));
+        assert_eq!(group.entries_read, Some(1));
+        let pending = &group.pending[&StreamId::new(1000, 0)];
+        assert_eq!(pending.consumer, "alice");
+        assert_eq!(pending.delivery_count, 2);
+        assert_eq!(pending.delivery_time, 1700000000000);
+        let consumer = &group.consumers["alice"];
+        assert_eq!(consumer.seen_time, 1700000000001);
+        assert_eq!(consumer.active_time, Some(1700000000002));
+        assert!(consumer.pending.contains(&StreamId::new(1000, 0)));
+    }
+
+    #[test]
+    fn test_load_file_with_collections() {
+        // A ziplist `list`, a listpack `user` hash expiring in 2100 and an `events` stream.
+        let mut bytes: Bytes = hex_to_bytes(FILE_WITH_COLLECTIONS).into();
+        let mut rdb = RdbFile::try_from(&mut bytes).unwrap();
+        let mut data = rdb.sections.remove(0).data;
+        assert_eq!(data.len(), 3);
+
+        let (user, expiry) = data.remove("user").unwrap();
+        assert_eq!(expiry, Some(4102444800000));
+        let Value::Hash(user) = Value::from(user) else {
+            panic!("Expected a hash");
+        };
+        assert_eq!(user["name"], "ada");
+        let (list, _) = data.remove("list").unwrap();
+        assert!(matches!(Value::from(list), Value::List(list) if list.len() == 7));
+        let (events, _) = data.remove("events").unwrap();
+        assert!(matches!(Value::from(events), Value::Stream(stream) if stream.len() == 2));
+    }
+
+    #[test]
+    fn test_lzf_and_crc64() {
+        // A three byte literal run, then a 15 byte back-reference three bytes back.
+        let compressed = [0x02, b'a', b'b', b'c', 0xe0, 0x06, 0x02];
+        let decompressed = lzf::decompress(&compressed, 18).unwrap();
+        assert_eq!(decompressed, b"abc".repeat(6));
+        assert!(lzf::decompress(&compressed, 20).is_err());
+        assert!(lzf::decompress(&[0x20, 0x05], 2).is_err());
+
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn test_load_file_with_aux_fields_and_special_encodings() {
+        // Integer aux fields, module aux data, a function library, a 14-bit RESIZEDB size,
+        // an LZF string, a negative integer string, and a key made of opcode bytes.
+        let mut bytes: Bytes = hex_to_bytes(FILE_WITH_SPECIAL_ENCODINGS).into();
+        let mut rdb = RdbFile::try_from(&mut bytes).unwrap();
+        assert_eq!(rdb.version, 12);
+        assert_eq!(rdb.aux["redis-ver"], "7.4.0");
+        assert_eq!(rdb.aux["redis-bits"], "64");
+        assert_eq!(rdb.aux["ctime"], "1700000000");
+        assert_eq!(rdb.functions.len(), 1);
+        assert!(rdb.functions[0].starts_with("#!lua name=lib"));
+
+        let mut data = rdb.sections.remove(0).data;
+        assert_eq!(data.len(), 3);
+        let (RdbValue::String(compressed), None) = data.remove("compressed").unwrap() else {
+            panic!("Expected a string without expiry");
+        };
+        assert_eq!(compressed, "abc".repeat(6));
+        let (RdbValue::String(negative), None) = data.remove("negative").unwrap() else {
+            panic!("Expected a string without expiry");
+        };
+        assert_eq!(negative, "-1234");
+        let (_, expiry) = data.remove("\u{fffd}\u{fffd}\u{fffd}").unwrap();
+        assert_eq!(expiry, Some(4102444800000));
+    }
+
+    #[test]
+    fn test_corrupted_files_are_errors() {
+        let file = hex_to_bytes(FILE_WITH_SPECIAL_ENCODINGS);
+        let load = |file: &[u8]| RdbFile::try_from(&mut Bytes::copy_from_slice(file));
+
+        for len in 0..file.len() {
+            assert!(load(&file[..len]).is_err(), "Truncated at {len}");
+        }
+
+        // Still well-formed, with a letter of the function library changed.
+        let mut flipped = file.clone();
+        let source = flipped.windows(6).position(|w| w == b"return").unwrap();
+        flipped[source] ^= 1;
+        let error = load(&flipped).err().unwrap().to_string();
+        assert!(error.starts_with("RDB checksum mismatch"), "{error}");
+
+        // A zero checksum means the file was saved without one.
+        let mut unchecked = file.clone();
+        let len = unchecked.len();
+        unchecked[len - 8..].fill(0);
+        assert!(load(&unchecked).is_ok());
+
+        let error = load(b"REDIS0099\xff").err().unwrap().to_string();
+        assert_eq!(error, "Unsupported RDB version 99");
+        let mut unknown_type = hex_to_bytes("524544495330303132");
+        unknown_type.extend_from_slice(b"\x1e\x01k\x01v\xff");
+        let error = format!("{:#}", load(&unknown_type).err().unwrap());
+        assert_eq!(
+            error,
+            "Failed to load key 'k': Unsupported RDB value type 30"
+        );
+    }
+
+    const LIST_ZIPLIST: &str = "0a406a6a0000000a000000070000016103f802fe9c03c0e80304f0206cfb05f070110105404678787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878ff";
+
+    const STREAM_LISTPACKS_3: &str = "15011000000000000003e800000000000000004046460000001900020101010201826631038266320300010201000100018276310382763203050103010001010182643103826432030501000105010301010181780209010601ff0243ed0343e80043e8010301016743e800010100000000000003e800000000000000000068e5cf8b010000020105616c6963650168e5cf8b0100000268e5cf8b0100000100000000000003e80000000000000000";
+
+    const FILE_WITH_COLLECTIONS: &str = "524544495330303131fa0972656469732d76657205372e322e30fe00fb03010a046c697374406a6a0000000a000000070000016103f802fe9c03c0e80304f0206cfb05f070110105404678787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878787878fffc00d8c32cbb0300001004757365722d2d0000000800846e616d65058361646104836167650424018564656c746106dc18028362696704f2a0860104ff15066576656e7473011000000000000003e800000000000000004046460000001900020101010201826631038266320300010201000100018276310382763203050103010001010182643103826432030501000105010301010181780209010601ff0243ed0343e80043e8010301016743e800010100000000000003e800000000000000000068e5cf8b010000020105616c6963650168e5cf8b0100000268e5cf8b0100000100000000000003e80000000000000000ff0b01cf8264c3dc3c";
+
+    const FILE_WITH_SPECIAL_ENCODINGS: &str = "524544495330303132fa0972656469732d76657205372e342e30fa0a72656469732d62697473c040fa056374696d65c200f15365f78100000100000000000202020705016d04000000000000f03f00f5404423216c7561206e616d653d6c69620a72656469732e72656769737465725f66756e6374696f6e282766272c2066756e6374696f6e28292072657475726e203120656e6429fe00fb412c01000a636f6d70726573736564c3071202616263e00602f843e800086e65676174697665c12efbfd005786f4f9050003fefaff01fefff8301d4df70e2cdb";
 }
@@ -0,0 +1,217 @@
+#[cfg(test)]
+mod tests {
+    use codecrafters_redis::{
+        command::core::Command,
+        protocol::Data,
+        server::{context::ServerContext, replica::ReplicaState},
+        store::stream::get_unix_ms,
+    };
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Mutex};
+
+    fn command(args: &[&str]) -> Command {
+        let args: Vec<Data> = args.iter().map(|arg| Data::BStr(arg.to_string())).collect();
+        Command::from(args.as_slice())
+    }
+
+    async fn with_replica() -> (ServerContext, mpsc::Receiver<Vec<u8>>) {
+        let context = ServerContext::default();
+        let (tx, rx) = mpsc::channel(100);
+        context.replicas.lock().await.add_channel(
+            "replica".into(),
+            Arc::new(Mutex::new(ReplicaState::new(tx))),
+        );
+        (context, rx)
+    }
+
+    fn propagated(rx: &mut mpsc::Receiver<Vec<u8>>) -> Vec<String> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|bytes| String::from_utf8(bytes).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_propagated() {
+        let (context, mut rx) = with_replica().await;
+        for args in [
+            &["ZADD", "z", "1.5", "a"][..],
+            &["GEOADD", "g", "13.361389", "38.115556", "Palermo"],
+            &["XDEL", "missing", "1-1"],
+            &["ZREM", "z", "missing"],
+            &["LPOP", "missing"],
+            &["RPUSH", "z", "wrong-type"],
+            &["GET", "missing"],
+        ] {
+            context.execute_command(command(args)).await;
+        }
+        assert_eq!(
+            propagated(&mut rx),
+            [
+                "*4\r\n$4\r\nZADD\r\n$1\r\nz\r\n$3\r\n1.5\r\n$1\r\na\r\n",
+                "*5\r\n$6\r\nGEOADD\r\n$1\r\ng\r\n$9\r\n13.361389\r\n$9\r\n38.115556\r\n\
+                 $7\r\nPalermo\r\n",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_non_deterministic_commands_are_rewritten() {
+        let (context, mut rx) = with_replica().await;
+        let before = get_unix_ms();
+        context
+            .execute_command(command(&["SET", "k", "v", "PX", "1000"]))
+            .await;
+        let id = String::from(
+            context
+                .execute_command(command(&["XADD", "s", "*", "f", "v"]))
+                .await,
+        );
+        context
+            .execute_command(command(&["RPUSH", "list", "a"]))
+            .await;
+        context
+            .execute_command(command(&["BLPOP", "empty", "list", "0"]))
+            .await;
+
+        let propagated = propagated(&mut rx);
+        let expiry: u64 = propagated[0]
+            .strip_prefix("*5\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$4\r\nPXAT\r\n$13\r\n")
+            .and_then(|rest| rest.strip_suffix("\r\n"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((before + 1000..before + 2000).contains(&expiry));
+        let id = id.split("\r\n").nth(1).unwrap();
+        assert_eq!(
+            propagated[1],
+            format!(
+                "*5\r\n$4\r\nXADD\r\n$1\r\ns\r\n${}\r\n{id}\r\n$1\r\nf\r\n$1\r\nv\r\n",
+                id.len()
+            )
+        );
+        assert_eq!(
+            propagated[3],
+            "*3\r\n$4\r\nLPOP\r\n$4\r\nlist\r\n$1\r\n1\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expirations_are_propagated() {
+        let (context, mut rx) = with_replica().await;
+        for key in ["lazy", "active"] {
+            context
+                .execute_command(command(&["SET", key, "v", "PX", "1"]))
+                .await;
+        }
+        context.execute_command(command(&["SET", "k", "v"])).await;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        propagated(&mut rx);
+
+        context.execute_command(command(&["GET", "lazy"])).await;
+        context.remove_expired().await;
+        context
+            .execute_command(command(&["DEL", "k", "missing"]))
+            .await;
+        context.execute_command(command(&["DEL", "missing"])).await;
+        assert_eq!(
+            propagated(&mut rx),
+            [
+                "*2\r\n$3\r\nDEL\r\n$4\r\nlazy\r\n",
+                "*2\r\n$3\r\nDEL\r\n$6\r\nactive\r\n",
+                "*3\r\n$3\r\nDEL\r\n$1\r\nk\r\n$7\r\nmissing\r\n",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replicas_leave_expiry_to_the_master() {
+        let mut context = ServerContext::default();
+        context.store = context.store.clone().as_replica();
+        context
+            .state
+            .lock()
+            .await
+            .set("replication", "role", "slave");
+        context
+            .execute_command(command(&["SET", "k", "v", "PX", "1"]))
+            .await;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        context.remove_expired().await;
+        assert!(context.store.data.lock().await.contains_key("k"));
+        assert_eq!(
+            String::from(context.execute_command(command(&["GET", "k"])).await),
+            "$-1\r\n"
+        );
+        assert!(context.store.data.lock().await.contains_key("k"));
+    }
+
+    #[test]
+    fn test_propagated_args_parse_back() {
+        for args in [
+            &["SET", "k", "v", "PXAT", "1700000000000"][..],
+            &[
+                "XADD",
+                "s",
+                "NOMKSTREAM",
+                "MAXLEN",
+                "~",
+                "10",
+                "LIMIT",
+                "5",
+                "1-1",
+                "f",
+                "v",
+            ],
+            &["DEL", "a", "b"],
+            &["XTRIM", "s", "MINID", "=", "5-0"],
+            &[
+                "XSETID",
+                "s",
+                "5-0",
+                "ENTRIESADDED",
+                "3",
+                "MAXDELETEDID",
+                "2-0",
+            ],
+            &[
+                "XGROUP",
+                "CREATE",
+                "s",
+                "g",
+                "$",
+                "MKSTREAM",
+                "ENTRIESREAD",
+                "0",
+            ],
+            &["XACK", "s", "g", "1-1", "2-0"],
+            &[
+                "GEOADD",
+                "g",
+                "XX",
+                "CH",
+                "-122.27652",
+                "37.805186",
+                "station",
+            ],
+            &[
+                "GEOSEARCHSTORE",
+                "dst",
+                "g",
+                "FROMLONLAT",
+                "15",
+                "37",
+                "BYBOX",
+                "400",
+                "400",
+                "km",
+                "ASC",
+                "COUNT",
+                "3",
+                "ANY",
+                "STOREDIST",
+            ],
+        ] {
+            assert_eq!(command(args).to_args(), args);
+        }
+    }
+}
@@ -0,0 +1,257 @@
+#[cfg(test)]
+mod tests {
+    use codecrafters_redis::{
+        command::core::Command,
+        protocol::Data,
+        scripting::sha1_hex,
+        server::{context::ServerContext, replica::ReplicaState},
+    };
+    use std::{sync::Arc, time::Duration};
+    use tokio::sync::{mpsc, Mutex};
+
+    fn command(args: &[&str]) -> Command {
+        let args: Vec<Data> = args.iter().map(|arg| Data::BStr(arg.to_string())).collect();
+        Command::from(args.as_slice())
+    }
+
+    async fn run(context: &ServerContext, args: &[&str]) -> String {
+        String::from(context.execute_command(command(args)).await)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_eval_converts_replies() {
+        let context = ServerContext::default();
+        let script = "redis.call('SET', KEYS[1], ARGV[1]) \
+                      return {redis.call('GET', KEYS[1]), redis.call('INCR', 'n'), \
+                      redis.call('GET', 'missing'), 3.9, true, nil, 'after nil'}";
+        assert_eq!(
+            run(&context, &["EVAL", script, "1", "k", "v"]).await,
+            "*5\r\n$1\r\nv\r\n:1\r\n$-1\r\n:3\r\n:1\r\n"
+        );
+        assert_eq!(
+            run(&context, &["EVAL", "return redis.call('PING')", "0"]).await,
+            "+PONG\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                &["EVAL", "return redis.status_reply('FINE')", "0"]
+            )
+            .await,
+            "+FINE\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                &["EVAL", "return redis.error_reply('MY error')", "0"]
+            )
+            .await,
+            "-MY error\r\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_call_raises_and_pcall_returns_errors() {
+        let context = ServerContext::default();
+        run(&context, &["RPUSH", "list", "a"]).await;
+        assert_eq!(
+            run(
+                &context,
+                &["EVAL", "return redis.call('INCR', 'list')", "0"]
+            )
+            .await,
+            "-ERR value is not an integer or out of range\r\n"
+        );
+        let script = "local reply = redis.pcall('SET', 'list', 'x', 'PX', 'soon') \
+                      local err = redis.pcall('ZADD', KEYS[1]) \
+                      return {type(reply), err['err']}";
+        assert_eq!(
+            run(&context, &["EVAL", script, "1", "z"]).await,
+            "*2\r\n$5\r\ntable\r\n$44\r\nERR Unknown Redis command called from script\r\n"
+        );
+        assert_eq!(
+            run(
+                &context,
+                &["EVAL", "return redis.call('EVAL', 'return 1', '0')", "0"]
+            )
+            .await,
+            "-ERR This Redis command is not allowed from script\r\n"
+        );
+        let response = run(&context, &["EVAL", "return nosuch.field", "0"]).await;
+        assert!(
+            response.starts_with("-ERR Error running script"),
+            "{response}"
+        );
+        let response = run(&context, &["EVAL", "return (", "0"]).await;
+        assert!(
+            response.starts_with("-ERR Error compiling script"),
+            "{response}"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_script_cache() {
+        let context = ServerContext::default();
+        let script = "return ARGV[1]";
+        let sha = sha1_hex(script);
+        assert_eq!(
+            run(&context, &["EVALSHA", &sha, "0", "x"]).await,
+            "-NOSCRIPT No matching script. Please use EVAL.\r\n"
+        );
+        assert_eq!(
+            run(&context, &["SCRIPT", "LOAD", script]).await,
+            format!("$40\r\n{sha}\r\n")
+        );
+        assert_eq!(
+            run(&context, &["EVALSHA", &sha.to_uppercase(), "0", "x"]).await,
+            "$1\r\nx\r\n"
+        );
+        assert_eq!(
+            run(&context, &["SCRIPT", "EXISTS", &sha, "nope"]).await,
+            "*2\r\n:1\r\n:0\r\n"
+        );
+        run(&context, &["SCRIPT", "FLUSH"]).await;
+        assert_eq!(
+            run(&context, &["SCRIPT", "EXISTS", &sha]).await,
+            "*1\r\n:0\r\n"
+        );
+
+        // Only scripts that compile are cached.
+        let broken = "return (";
+        run(&context, &["EVAL", broken, "0"]).await;
+        assert_eq!(
+            run(&context, &["SCRIPT", "EXISTS", &sha1_hex(broken)]).await,
+            "*1\r\n:0\r\n"
+        );
+        run(&context, &["EVAL", script, "0", "y"]).await;
+        assert_eq!(
+            run(&context, &["SCRIPT", "EXISTS", &sha]).await,
+            "*1\r\n:1\r\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_globals_are_protected() {
+        let context = ServerContext::default();
+        let response = run(&context, &["EVAL", "leaked = 1 return 1", "0"]).await;
+        assert!(
+            response.contains("Attempt to modify a readonly table"),
+            "{response}"
+        );
+        let response = run(&context, &["EVAL", "return leaked", "0"]).await;
+        assert!(
+            response.contains("Script attempted to access nonexistent global variable 'leaked'"),
+            "{response}"
+        );
+        let response = run(&context, &["EVAL", "redis.call = nil return 1", "0"]).await;
+        assert!(
+            response.contains("Attempt to modify a readonly table"),
+            "{response}"
+        );
+        assert_eq!(
+            run(&context, &["EVAL", "return redis.call('PING')", "0"]).await,
+            "+PONG\r\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_keys_and_argv_are_reset() {
+        let context = ServerContext::default();
+        let script = "return {#KEYS, #ARGV}";
+        assert_eq!(
+            run(&context, &["EVAL", script, "2", "a", "b", "c"]).await,
+            "*2\r\n:2\r\n:1\r\n"
+        );
+        assert_eq!(
+            run(&context, &["EVAL", script, "0"]).await,
+            "*2\r\n:0\r\n:0\r\n"
+        );
+
+        let library = "#!lua name=lib\n\
+                       redis.register_function('keys', function() return KEYS end)";
+        run(&context, &["FUNCTION", "LOAD", library]).await;
+        let response = run(&context, &["FCALL", "keys", "1", "a"]).await;
+        assert!(
+            response.contains("nonexistent global variable 'KEYS'"),
+            "{response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scripts_need_the_multi_threaded_runtime() {
+        let context = ServerContext::default();
+        assert_eq!(
+            run(&context, &["EVAL", "return 1", "0"]).await,
+            "-ERR Scripts can't run on a current-thread runtime\r\n"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scripts_replicate_effects() {
+        let context = ServerContext::default();
+        let (tx, mut rx) = mpsc::channel(10);
+        context.replicas.lock().await.add_channel(
+            "replica".into(),
+            Arc::new(Mutex::new(ReplicaState::new(tx))),
+        );
+        let script = "redis.call('GET', 'a') redis.call('SET', 'a', '1') \
+                      return redis.call('INCR', 'a')";
+        run(&context, &["EVAL", script, "0"]).await;
+        assert_eq!(
+            String::from_utf8(rx.try_recv().unwrap()).unwrap(),
+            "*1\r\n$5\r\nMULTI\r\n*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n\
+             *2\r\n$4\r\nINCR\r\n$1\r\na\r\n*1\r\n$4\r\nEXEC\r\n"
+        );
+        run(&context, &["EVAL", "return redis.call('GET', 'a')", "0"]).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_script_kill() {
+        let context = ServerContext::default();
+        assert_eq!(
+            String::from(context.scripts.kill(false)),
+            "-NOTBUSY No scripts in execution right now.\r\n"
+        );
+
+        let runner = context.clone();
+        let script =
+            tokio::spawn(async move { run(&runner, &["EVAL", "while true do end", "0"]).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            String::from(context.scripts.kill(true)),
+            "-NOTBUSY No scripts in execution right now.\r\n"
+        );
+        assert_eq!(String::from(context.scripts.kill(false)), "+OK\r\n");
+        let response = tokio::time::timeout(Duration::from_secs(5), script)
+            .await
+            .expect("script wasn't killed")
+            .unwrap();
+        assert!(response.contains("Script killed by user"), "{response}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scripts_run_atomically() {
+        let context = ServerContext::default();
+        let popper = context.clone();
+        let blocked = tokio::spawn(async move {
+            let blpop = popper.execute_command(command(&["BLPOP", "atomic", "0"]));
+            String::from(popper.store.run_exclusive(blpop).await)
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // The blocked client is woken by the push, but only pops once the script is done.
+        let script = "redis.call('RPUSH', KEYS[1], 'x') \
+                      local n = 0 for i = 1, 5000000 do n = n + i end \
+                      return redis.call('LLEN', KEYS[1])";
+        assert_eq!(
+            run(&context, &["EVAL", script, "1", "atomic"]).await,
+            ":1\r\n"
+        );
+        let popped = tokio::time::timeout(Duration::from_secs(5), blocked)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(popped, "*2\r\n$6\r\natomic\r\n$1\r\nx\r\n");
+    }
+}
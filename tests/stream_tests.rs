@@ -0,0 +1,552 @@
+#[cfg(test)]
+mod tests {
+    use codecrafters_redis::{
+        command::stream_handlers::{self, StreamFilter, XInfoSubcommand},
+        store::{
+            consumer_group::{AutoClaimOptions, ClaimOptions, PendingFilter},
+            core::InMemoryStore,
+            stream::{StreamId, StreamRange, TrimOptions, TrimStrategy},
+            subscribe::DataWaiter,
+        },
+    };
+
+    async fn ids_in_range(store: &InMemoryStore, key: &str, range: StreamRange) -> Vec<String> {
+        store
+            .get_filtered_streams(vec![StreamFilter {
+                key: key.into(),
+                range,
+                count: None,
+            }])
+            .await
+            .and_then(|streams| streams.into_iter().next())
+            .map(|stream| {
+                stream
+                    .entries
+                    .iter()
+                    .map(|(id, _)| id.to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn range(start: &str, end: &str) -> StreamRange {
+        (
+            StreamId::parse_range_start(start).unwrap(),
+            StreamId::parse_range_end(end).unwrap(),
+        )
+    }
+
+    async fn add(store: &InMemoryStore, key: &str, id: &str) -> anyhow::Result<String> {
+        store
+            .add_stream(
+                key.into(),
+                id.into(),
+                vec![("field".into(), "value".into())],
+                None,
+                false,
+            )
+            .await
+            .map(Option::unwrap_or_default)
+    }
+
+    #[test]
+    fn test_stream_id_ordering() {
+        let parse = |s| StreamId::parse(s, 0).unwrap();
+        assert!(parse("9-0") < parse("10-0"));
+        assert!(parse("1-2") < parse("1-10"));
+        assert_eq!(parse("5"), StreamId::new(5, 0));
+        assert_eq!(
+            StreamId::parse("5", u64::MAX).unwrap(),
+            StreamId::new(5, u64::MAX)
+        );
+        assert!(StreamId::parse("5-x", 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_xrange_orders_numerically() {
+        let store = InMemoryStore::default();
+        for id in ["1-2", "1-10", "9-0", "10-0"] {
+            add(&store, "s", id).await.unwrap();
+        }
+        assert_eq!(
+            ids_in_range(&store, "s", range("-", "+")).await,
+            vec!["1-2", "1-10", "9-0", "10-0"]
+        );
+        assert_eq!(
+            ids_in_range(&store, "s", range("1", "9")).await,
+            vec!["1-2", "1-10", "9-0"]
+        );
+        assert_eq!(
+            ids_in_range(&store, "s", range("(1-2", "(10-0")).await,
+            vec!["1-10", "9-0"]
+        );
+        assert!(ids_in_range(&store, "s", range("(9-0", "(9-0"))
+            .await
+            .is_empty());
+        assert!(ids_in_range(&store, "s", range("10", "1")).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_xadd_ids() {
+        let store = InMemoryStore::default();
+        assert!(add(&store, "s", "0-0").await.is_err());
+        assert_eq!(add(&store, "s", "0-*").await.unwrap(), "0-1");
+        assert_eq!(add(&store, "s", "5-*").await.unwrap(), "5-0");
+        assert_eq!(add(&store, "s", "5-*").await.unwrap(), "5-1");
+        assert!(add(&store, "s", "5-1").await.is_err());
+        assert!(add(&store, "s", "4-*").await.is_err());
+
+        // An entry from the future, as after the clock moved backwards.
+        add(&store, "s", "99999999999999-5").await.unwrap();
+        assert_eq!(add(&store, "s", "*").await.unwrap(), "99999999999999-6");
+    }
+
+    #[tokio::test]
+    async fn test_failed_xadd_keeps_existing_empty_stream() {
+        let store = InMemoryStore::default();
+        assert!(add(&store, "new", "0-0").await.is_err());
+        assert!(store.get_stream("new").await.is_err());
+
+        store
+            .xgroup_create("s".into(), "g".into(), "$".into(), true, None)
+            .await
+            .unwrap();
+        assert!(add(&store, "s", "0-0").await.is_err());
+        assert!(store
+            .get_stream("s")
+            .await
+            .unwrap()
+            .groups
+            .contains_key("g"));
+    }
+
+    fn trim(strategy: TrimStrategy, approximate: bool, limit: Option<usize>) -> TrimOptions {
+        TrimOptions {
+            strategy,
+            approximate,
+            limit,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_xtrim_exact_and_approximate() {
+        let store = InMemoryStore::default();
+        for i in 1..=250 {
+            add(&store, "s", &format!("{i}-0")).await.unwrap();
+        }
+        let trimmed = store
+            .trim_stream("s".into(), trim(TrimStrategy::MaxLen(200), false, None))
+            .await
+            .unwrap();
+        assert_eq!(trimmed, 50);
+
+        // Only whole nodes of 100 entries are removed when trimming approximately.
+        let approx = trim(TrimStrategy::MaxLen(50), true, None);
+        assert_eq!(store.trim_stream("s".into(), approx).await.unwrap(), 100);
+        let approx = trim(TrimStrategy::MinId(StreamId::new(240, 0)), true, None);
+        assert_eq!(store.trim_stream("s".into(), approx).await.unwrap(), 0);
+
+        let exact = trim(TrimStrategy::MinId(StreamId::new(240, 0)), false, None);
+        assert_eq!(store.trim_stream("s".into(), exact).await.unwrap(), 89);
+
+        let stream = store.get_stream("s").await.unwrap();
+        assert_eq!(stream.len(), 11);
+        assert_eq!(stream.first_id(), Some(StreamId::new(240, 0)));
+        assert_eq!(stream.last_id, StreamId::new(250, 0));
+        assert_eq!(stream.max_deleted_id, StreamId::new(239, 0));
+        assert_eq!(stream.entries_added, 250);
+    }
+
+    #[tokio::test]
+    async fn test_xadd_nomkstream_and_maxlen() {
+        let store = InMemoryStore::default();
+        let fields = vec![("a".to_string(), "1".to_string()), ("b".into(), "2".into())];
+        let added = store
+            .add_stream("s".into(), "1-0".into(), fields.clone(), None, true)
+            .await
+            .unwrap();
+        assert!(added.is_none());
+        assert!(store.get_stream("s").await.is_err());
+
+        for id in ["1-0", "2-0", "3-0"] {
+            let maxlen = trim(TrimStrategy::MaxLen(2), false, None);
+            store
+                .add_stream("s".into(), id.into(), fields.clone(), Some(maxlen), false)
+                .await
+                .unwrap();
+        }
+        let stream = store.get_stream("s").await.unwrap();
+        assert_eq!(
+            stream.entries.keys().copied().collect::<Vec<_>>(),
+            vec![StreamId::new(2, 0), StreamId::new(3, 0)]
+        );
+        assert_eq!(stream.entries[&StreamId::new(3, 0)], fields);
+    }
+
+    async fn group_store() -> InMemoryStore {
+        let store = InMemoryStore::default();
+        for id in ["1-0", "2-0", "3-0"] {
+            add(&store, "s", id).await.unwrap();
+        }
+        store
+            .xgroup_create("s".into(), "g".into(), "0".into(), false, None)
+            .await
+            .unwrap();
+        store
+    }
+
+    async fn read_new(store: &InMemoryStore, consumer: &str, count: usize) -> Vec<StreamId> {
+        let streams = [("s".to_string(), ">".to_string())];
+        let outcome = store
+            .read_group("g", consumer, &streams, Some(count), false)
+            .await
+            .unwrap();
+        outcome
+            .result
+            .into_iter()
+            .flat_map(|(_, entries)| entries.into_iter().map(|(id, _)| id))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_xgroup_create_errors() {
+        let store = group_store().await;
+        let err = store
+            .xgroup_create("s".into(), "g".into(), "$".into(), false, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("BUSYGROUP"));
+        let err = store
+            .xgroup_create("missing".into(), "g".into(), "$".into(), false, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("MKSTREAM"));
+        store
+            .xgroup_create("missing".into(), "g".into(), "$".into(), true, None)
+            .await
+            .unwrap();
+        assert!(store
+            .get_stream("missing")
+            .await
+            .unwrap()
+            .groups
+            .contains_key("g"));
+
+        let streams = [("s".to_string(), ">".to_string())];
+        let err = store
+            .read_group("nope", "alice", &streams, None, false)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("NOGROUP"));
+    }
+
+    #[tokio::test]
+    async fn test_xreadgroup_pending_and_ack() {
+        let store = group_store().await;
+        assert_eq!(
+            read_new(&store, "alice", 2).await,
+            vec![StreamId::new(1, 0), StreamId::new(2, 0)]
+        );
+        assert_eq!(read_new(&store, "bob", 10).await, vec![StreamId::new(3, 0)]);
+        assert!(read_new(&store, "bob", 10).await.is_empty());
+
+        let stream = store.get_stream("s").await.unwrap();
+        let group = &stream.groups["g"];
+        assert_eq!(group.last_delivered_id, StreamId::new(3, 0));
+        assert_eq!(group.entries_read, Some(3));
+
+        let summary = store.pending_summary("s".into(), "g".into()).await.unwrap();
+        assert_eq!(summary.count, 3);
+        assert_eq!(
+            summary.bounds,
+            Some((StreamId::new(1, 0), StreamId::new(3, 0)))
+        );
+        assert_eq!(
+            summary.consumers,
+            vec![("alice".to_string(), 2), ("bob".to_string(), 1)]
+        );
+
+        let acked = store
+            .ack(
+                "s".into(),
+                "g".into(),
+                vec![StreamId::new(1, 0), StreamId::new(9, 0)],
+            )
+            .await
+            .unwrap();
+        assert_eq!(acked, 1);
+
+        let filter = PendingFilter {
+            min_idle: None,
+            range: range("-", "+"),
+            count: 10,
+            consumer: Some("alice".into()),
+        };
+        let details = store
+            .pending_range("s".into(), "g".into(), filter)
+            .await
+            .unwrap();
+        assert_eq!(details.len(), 1);
+        assert_eq!(details[0].id, StreamId::new(2, 0));
+        assert_eq!(details[0].delivery_count, 1);
+
+        // Reading history returns the consumer's own pending entries, with deleted
+        // entries showing up without fields.
+        store
+            .trim_stream("s".into(), trim(TrimStrategy::MaxLen(1), false, None))
+            .await
+            .unwrap();
+        let streams = [("s".to_string(), "0".to_string())];
+        let outcome = store
+            .read_group("g", "alice", &streams, None, false)
+            .await
+            .unwrap();
+        assert_eq!(outcome.result[0].1, vec![(StreamId::new(2, 0), None)]);
+    }
+
+    #[tokio::test]
+    async fn test_xreadgroup_errors_change_nothing() {
+        let store = group_store().await;
+        add(&store, "t", "1-0").await.unwrap();
+        store
+            .xgroup_create("t".into(), "g".into(), "0".into(), false, None)
+            .await
+            .unwrap();
+        for later in [("t", "bad-id"), ("missing", ">")] {
+            let streams = [
+                ("s".to_string(), ">".to_string()),
+                (later.0.to_string(), later.1.to_string()),
+            ];
+            assert!(store
+                .read_group("g", "alice", &streams, None, false)
+                .await
+                .is_err());
+        }
+        let stream = store.get_stream("s").await.unwrap();
+        let group = &stream.groups["g"];
+        assert_eq!(group.last_delivered_id, StreamId::new(0, 0));
+        assert!(group.pending.is_empty());
+        assert!(group.consumers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_xreadgroup_noack() {
+        let store = group_store().await;
+        let streams = [("s".to_string(), ">".to_string())];
+        let outcome = store
+            .read_group("g", "alice", &streams, None, true)
+            .await
+            .unwrap();
+        assert_eq!(outcome.result[0].1.len(), 3);
+        assert_eq!(outcome.effects.len(), 1);
+        assert_eq!(outcome.effects[0][..2], ["XGROUP", "SETID"]);
+        let summary = store.pending_summary("s".into(), "g".into()).await.unwrap();
+        assert_eq!(summary.count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_xclaim_and_xautoclaim() {
+        let store = group_store().await;
+        read_new(&store, "alice", 10).await;
+
+        let outcome = store
+            .claim(
+                "s".into(),
+                "g".into(),
+                "bob".into(),
+                0,
+                vec![StreamId::new(1, 0), StreamId::new(7, 0)],
+                ClaimOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.result.len(), 1);
+        assert!(outcome.result[0].1.is_some());
+        let stream = store.get_stream("s").await.unwrap();
+        let pending = &stream.groups["g"].pending[&StreamId::new(1, 0)];
+        assert_eq!(pending.consumer, "bob");
+        assert_eq!(pending.delivery_count, 2);
+
+        let not_idle = store
+            .claim(
+                "s".into(),
+                "g".into(),
+                "carol".into(),
+                60_000,
+                vec![StreamId::new(2, 0)],
+                ClaimOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(not_idle.result.is_empty());
+
+        store
+            .trim_stream("s".into(), trim(TrimStrategy::MaxLen(2), false, None))
+            .await
+            .unwrap();
+        let options = AutoClaimOptions {
+            start: StreamId::MIN,
+            count: 1,
+            just_id: true,
+        };
+        let outcome = store
+            .autoclaim("s".into(), "g".into(), "carol".into(), 0, options)
+            .await
+            .unwrap();
+        assert_eq!(outcome.result.deleted, vec![StreamId::new(1, 0)]);
+        assert_eq!(outcome.result.claimed, vec![(StreamId::new(2, 0), None)]);
+        assert_eq!(outcome.result.next_id, StreamId::new(3, 0));
+        let summary = store.pending_summary("s".into(), "g".into()).await.unwrap();
+        assert_eq!(
+            summary.consumers,
+            vec![("alice".to_string(), 1), ("carol".to_string(), 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_xrevrange_with_count() {
+        let store = group_store().await;
+        let ids = |entries: Vec<(StreamId, _)>| {
+            entries
+                .into_iter()
+                .map(|(id, _)| id.to_string())
+                .collect::<Vec<_>>()
+        };
+        let rev = store
+            .stream_range("s", range("-", "+"), Some(2), true)
+            .await
+            .unwrap();
+        assert_eq!(ids(rev), vec!["3-0", "2-0"]);
+        let fwd = store
+            .stream_range("s", range("(1", "+"), Some(5), false)
+            .await
+            .unwrap();
+        assert_eq!(ids(fwd), vec!["2-0", "3-0"]);
+        let none = store
+            .stream_range("s", range("-", "+"), Some(0), false)
+            .await
+            .unwrap();
+        assert!(none.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_xdel_and_group_lag() {
+        let store = group_store().await;
+        read_new(&store, "alice", 1).await;
+        let stream = store.get_stream("s").await.unwrap();
+        assert_eq!(stream.group_lag(&stream.groups["g"]), Some(2));
+
+        let deleted = store
+            .delete_stream_entries("s".into(), vec![StreamId::new(2, 0), StreamId::new(8, 0)])
+            .await
+            .unwrap();
+        assert_eq!(deleted, 1);
+        assert_eq!(store.stream_len("s").await.unwrap(), 2);
+
+        // The deleted entry lies after the group's position, so its lag is unknown
+        // until the group reads past the tombstone.
+        let stream = store.get_stream("s").await.unwrap();
+        assert_eq!(stream.max_deleted_id, StreamId::new(2, 0));
+        assert_eq!(stream.group_lag(&stream.groups["g"]), None);
+
+        read_new(&store, "alice", 10).await;
+        let stream = store.get_stream("s").await.unwrap();
+        assert_eq!(stream.group_lag(&stream.groups["g"]), Some(0));
+        assert_eq!(stream.groups["g"].entries_read, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_xsetid() {
+        let store = group_store().await;
+        let set = |id, added, max_deleted| {
+            store.set_stream_id("s".into(), StreamId::new(id, 0), added, max_deleted)
+        };
+        assert!(set(2, None, None).await.is_err());
+        assert!(set(5, Some(2), None).await.is_err());
+        assert!(set(5, None, Some(StreamId::new(6, 0))).await.is_err());
+        set(5, Some(10), Some(StreamId::new(4, 0))).await.unwrap();
+
+        let stream = store.get_stream("s").await.unwrap();
+        assert_eq!(stream.last_id, StreamId::new(5, 0));
+        assert_eq!(stream.entries_added, 10);
+        assert_eq!(stream.max_deleted_id, StreamId::new(4, 0));
+        assert!(add(&store, "s", "5-0").await.is_err());
+        assert!(store
+            .set_stream_id("missing".into(), StreamId::new(1, 0), None, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_xread_count() {
+        let store = group_store().await;
+        let streams = vec![("s".to_string(), "0".to_string())];
+        let response = stream_handlers::xread(streams, Some(2), None, &store)
+            .await
+            .unwrap();
+        let response = String::from(response);
+        assert!(response.contains("1-0") && response.contains("2-0"));
+        assert!(!response.contains("3-0"));
+    }
+
+    #[tokio::test]
+    async fn test_xread_block_wakes_every_reader() {
+        let store = InMemoryStore::default();
+        add(&store, "other", "1-0").await.unwrap();
+        let mut readers = vec![];
+        for _ in 0..3 {
+            let store = store.clone();
+            readers.push(tokio::spawn(async move {
+                let streams = vec![
+                    ("events".to_string(), "$".to_string()),
+                    ("other".to_string(), "$".to_string()),
+                ];
+                stream_handlers::xread(streams, None, Some(2000), &store)
+                    .await
+                    .map(String::from)
+            }));
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        add(&store, "events", "5-0").await.unwrap();
+
+        for reader in readers {
+            let response = reader.await.unwrap().unwrap();
+            assert!(response.contains("events") && response.contains("5-0"));
+            assert!(!response.contains("other"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_waiter_sees_writes_made_before_waiting() {
+        let store = InMemoryStore::default();
+        let waiter = DataWaiter::new(&["events".to_string()], &store).await;
+        add(&store, "events", "1-0").await.unwrap();
+        let woken = tokio::time::timeout(std::time::Duration::from_secs(1), waiter.wait(0, &store))
+            .await
+            .expect("write before waiting was missed");
+        assert_eq!(woken.as_deref(), Some("events"));
+    }
+
+    #[tokio::test]
+    async fn test_xinfo_groups_and_consumers() {
+        let store = group_store().await;
+        read_new(&store, "alice", 1).await;
+        let info = |subcommand| stream_handlers::xinfo(subcommand, &store);
+
+        let groups = String::from(info(XInfoSubcommand::Groups("s".into())).await);
+        assert!(groups.starts_with("*1\r\n*12\r\n$4\r\nname\r\n$1\r\ng\r\n"));
+        assert!(groups.contains("$3\r\nlag\r\n:2\r\n"));
+
+        let consumers = XInfoSubcommand::Consumers {
+            key: "s".into(),
+            group: "missing".into(),
+        };
+        assert_eq!(
+            String::from(info(consumers).await),
+            "-NOGROUP No such consumer group 'missing' for key name 's'\r\n"
+        );
+        let missing = String::from(info(XInfoSubcommand::Groups("none".into())).await);
+        assert_eq!(missing, "-ERR no such key\r\n");
+    }
+}
@@ -0,0 +1,222 @@
+#[cfg(test)]
+mod tests {
+    use codecrafters_redis::{
+        command::core::Command,
+        protocol::Data,
+        server::context::ServerContext,
+        store::tracking::{run_as_client, ClientSubcommand, Invalidation, TrackingOptions},
+    };
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    fn command(args: &[&str]) -> Command {
+        let args: Vec<Data> = args.iter().map(|arg| Data::BStr(arg.to_string())).collect();
+        Command::from(args.as_slice())
+    }
+
+    /// Runs a command as client `id`, registering what it reads first like connections do.
+    async fn run(context: &ServerContext, id: u64, args: &[&str]) -> String {
+        let command = command(args);
+        context
+            .store
+            .track_reads(id, command.read_keys(), None)
+            .await;
+        String::from(run_as_client(Some(id), context.execute_command(command)).await)
+    }
+
+    async fn client(context: &ServerContext) -> (u64, UnboundedReceiver<Invalidation>) {
+        let (tx, rx) = unbounded_channel();
+        (context.store.register_client(tx).await, rx)
+    }
+
+    fn received(rx: &mut UnboundedReceiver<Invalidation>) -> Vec<(String, bool)> {
+        let mut invalidations = vec![];
+        while let Ok(invalidation) = rx.try_recv() {
+            invalidations.push((invalidation.key, invalidation.redirected));
+        }
+        invalidations
+    }
+
+    #[test]
+    fn test_parse_client_tracking() {
+        let Command::Client(ClientSubcommand::Tracking(Some(options))) = command(&[
+            "CLIENT", "TRACKING", "on", "REDIRECT", "7", "BCAST", "PREFIX", "a:", "PREFIX", "b:",
+            "NOLOOP",
+        ]) else {
+            panic!("expected CLIENT TRACKING ON");
+        };
+        assert_eq!(
+            options,
+            TrackingOptions {
+                redirect: Some(7),
+                bcast: true,
+                prefixes: vec!["a:".into(), "b:".into()],
+                noloop: true,
+                ..Default::default()
+            }
+        );
+        assert!(matches!(
+            command(&["CLIENT", "TRACKING", "off"]),
+            Command::Client(ClientSubcommand::Tracking(None))
+        ));
+        assert!(matches!(
+            command(&["CLIENT", "TRACKING", "on", "REDIRECT"]),
+            Command::Invalid
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_default_mode_invalidates_keys_read_once() {
+        let context = ServerContext::default();
+        let (reader, mut invalidations) = client(&context).await;
+        let (writer, _) = client(&context).await;
+        let options = TrackingOptions::default();
+        context
+            .store
+            .set_tracking(reader, Some(options))
+            .await
+            .unwrap();
+
+        run(&context, reader, &["GET", "k"]).await;
+        run(&context, writer, &["SET", "other", "v"]).await;
+        run(&context, writer, &["SET", "k", "v"]).await;
+        run(&context, writer, &["SET", "k", "w"]).await;
+        assert_eq!(received(&mut invalidations), [("k".to_string(), false)]);
+
+        run(&context, reader, &["GET", "k"]).await;
+        context.store.set_tracking(reader, None).await.unwrap();
+        run(&context, writer, &["SET", "k", "v"]).await;
+        assert!(received(&mut invalidations).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bcast_prefixes_and_noloop() {
+        let context = ServerContext::default();
+        let (id, mut invalidations) = client(&context).await;
+        let options = TrackingOptions {
+            bcast: true,
+            prefixes: vec!["user:".into()],
+            noloop: true,
+            ..Default::default()
+        };
+        context.store.set_tracking(id, Some(options)).await.unwrap();
+        let (writer, _) = client(&context).await;
+
+        run(&context, writer, &["SET", "user:1", "v"]).await;
+        run(&context, writer, &["SET", "user:1", "w"]).await;
+        run(&context, writer, &["SET", "order:1", "v"]).await;
+        run(&context, id, &["SET", "user:2", "v"]).await;
+        assert_eq!(
+            received(&mut invalidations),
+            [("user:1".to_string(), false), ("user:1".to_string(), false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_optin_and_redirect() {
+        let context = ServerContext::default();
+        let (target, mut redirected) = client(&context).await;
+        let (id, mut own) = client(&context).await;
+        let options = TrackingOptions {
+            optin: true,
+            redirect: Some(target),
+            ..Default::default()
+        };
+        context.store.set_tracking(id, Some(options)).await.unwrap();
+
+        run(&context, id, &["GET", "skipped"]).await;
+        context
+            .store
+            .track_reads(id, vec!["cached".into()], Some(true))
+            .await;
+        run(&context, target, &["SET", "skipped", "v"]).await;
+        run(&context, target, &["SET", "cached", "v"]).await;
+        assert_eq!(received(&mut redirected), [("cached".to_string(), true)]);
+        assert!(received(&mut own).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tracking_off_forgets_reads() {
+        let context = ServerContext::default();
+        let (id, mut rx) = client(&context).await;
+        let (writer, _) = client(&context).await;
+        let on = || Some(TrackingOptions::default());
+        context.store.set_tracking(id, on()).await.unwrap();
+        run(&context, id, &["GET", "k"]).await;
+        context.store.set_tracking(id, None).await.unwrap();
+        context.store.set_tracking(id, on()).await.unwrap();
+
+        run(&context, writer, &["SET", "k", "v"]).await;
+        assert!(received(&mut rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tracking_errors() {
+        let context = ServerContext::default();
+        let (id, _) = client(&context).await;
+        for (options, error) in [
+            (
+                TrackingOptions {
+                    optin: true,
+                    optout: true,
+                    ..Default::default()
+                },
+                "You can't use both OPTIN and OPTOUT",
+            ),
+            (
+                TrackingOptions {
+                    prefixes: vec!["a".into()],
+                    ..Default::default()
+                },
+                "PREFIX option requires BCAST mode to be enabled",
+            ),
+            (
+                TrackingOptions {
+                    redirect: Some(99),
+                    ..Default::default()
+                },
+                "The client ID you want redirect to does not exist",
+            ),
+        ] {
+            let result = context.store.set_tracking(id, Some(options)).await;
+            assert_eq!(result.unwrap_err().to_string(), error);
+        }
+
+        let store = &context.store;
+        store
+            .set_tracking(id, Some(TrackingOptions::default()))
+            .await
+            .unwrap();
+        let bcast = TrackingOptions {
+            bcast: true,
+            ..Default::default()
+        };
+        assert!(store.set_tracking(id, Some(bcast)).await.is_err());
+    }
+
+    #[test]
+    fn test_invalidation_frames() {
+        let own = Invalidation {
+            key: "k".into(),
+            redirected: false,
+        };
+        assert_eq!(
+            own.encode(true, false).unwrap(),
+            ">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\nk\r\n"
+        );
+        assert_eq!(own.encode(false, true), None);
+
+        let redirected = Invalidation {
+            key: "k".into(),
+            redirected: true,
+        };
+        assert_eq!(
+            redirected.encode(false, true).unwrap(),
+            "*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*1\r\n$1\r\nk\r\n"
+        );
+        assert_eq!(
+            redirected.encode(true, false).unwrap(),
+            ">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\nk\r\n"
+        );
+        assert_eq!(redirected.encode(false, false), None);
+    }
+}
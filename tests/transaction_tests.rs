@@ -0,0 +1,198 @@
+#[cfg(test)]
+mod tests {
+    use codecrafters_redis::{
+        command::core::Command,
+        server::{context::ServerContext, replica::ReplicaState},
+        store::{core::InMemoryStore, stream::get_unix_ms, value::Value},
+    };
+
+    fn set(key: &str, value: &str) -> Command {
+        Command::Set {
+            key: key.into(),
+            value: value.to_string().into(),
+            expiry: None,
+        }
+    }
+
+    fn encode_set(key: &str, value: &str) -> String {
+        format!(
+            "*3\r\n$3\r\nSET\r\n${}\r\n{key}\r\n${}\r\n{value}\r\n",
+            key.len(),
+            value.len()
+        )
+    }
+
+    #[tokio::test]
+    async fn test_watch_detects_writes() {
+        let store = InMemoryStore::default();
+        store
+            .set("balance".into(), "10".to_string().into(), None)
+            .await;
+        let watched = store.watch(vec!["balance".into(), "other".into()]).await;
+        assert!(!store.is_dirty(&watched).await);
+
+        store
+            .set("unrelated".into(), "1".to_string().into(), None)
+            .await;
+        assert!(!store.is_dirty(&watched).await);
+
+        store.incr("balance".into()).await;
+        assert!(store.is_dirty(&watched).await);
+    }
+
+    #[tokio::test]
+    async fn test_watch_detects_deletes_and_creation() {
+        let store = InMemoryStore::default();
+        store
+            .list_push("queue".into(), vec!["a".into()], false)
+            .await
+            .unwrap();
+        let watched = store.watch(vec!["queue".into()]).await;
+        store.list_pop("queue".into(), 1).await;
+        assert!(store.is_dirty(&watched).await);
+
+        let watched = store.watch(vec!["missing".into()]).await;
+        store
+            .list_push("missing".into(), vec!["a".into()], false)
+            .await
+            .unwrap();
+        assert!(store.is_dirty(&watched).await);
+    }
+
+    #[tokio::test]
+    async fn test_watch_detects_expiry() {
+        let store = InMemoryStore::default();
+        let expiry = get_unix_ms() + 50;
+        store
+            .set("session".into(), "x".to_string().into(), Some(expiry))
+            .await;
+        let watched = store.watch(vec!["session".into()]).await;
+        assert!(!store.is_dirty(&watched).await);
+        tokio::time::sleep(std::time::Duration::from_millis(80)).await;
+        assert!(store.is_dirty(&watched).await);
+    }
+
+    #[tokio::test]
+    async fn test_watch_sees_writes_in_progress() {
+        let store = InMemoryStore::default();
+        let watched = store.watch(vec!["k".into()]).await;
+        let data = store.data.lock().await;
+        let writer = tokio::spawn({
+            let store = store.clone();
+            async move { store.set("k".into(), "v".to_string().into(), None).await }
+        });
+        tokio::task::yield_now().await;
+        let check = tokio::spawn({
+            let store = store.clone();
+            async move { store.is_dirty(&watched).await }
+        });
+        tokio::task::yield_now().await;
+        drop(data);
+        writer.await.unwrap();
+        assert!(check.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unwatched_keys_are_forgotten() {
+        let store = InMemoryStore::default();
+        let first = store.watch(vec!["k".into()]).await;
+        let second = store.watch(vec!["k".into()]).await;
+        store.unwatch(&first).await;
+        store.set("k".into(), "v".to_string().into(), None).await;
+        assert!(store.is_dirty(&second).await);
+
+        store.unwatch(&second).await;
+        let third = store.watch(vec!["k".into()]).await;
+        assert!(!store.is_dirty(&third).await);
+    }
+
+    #[tokio::test]
+    async fn test_exec_aborts_when_watched_key_changed() {
+        let context = ServerContext::default();
+        let watched = context.store.watch(vec!["k".into()]).await;
+        context
+            .store
+            .set("k".into(), "v".to_string().into(), None)
+            .await;
+        let response = context
+            .process_transaction(vec![set("k", "mine")], watched)
+            .await;
+        assert_eq!(String::from(response), "*-1\r\n");
+        assert!(matches!(
+            context.store.get("k").await,
+            Some(Value::String(v)) if v == "v"
+        ));
+
+        let watched = context.store.watch(vec!["k".into()]).await;
+        let response = context
+            .process_transaction(vec![set("k", "mine")], watched)
+            .await;
+        assert_eq!(String::from(response), "*1\r\n+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_exec_reports_errors_per_command() {
+        let context = ServerContext::default();
+        let commands = vec![
+            set("k", "v"),
+            Command::ListPush {
+                key: "k".into(),
+                values: vec!["a".into()],
+                is_left: false,
+            },
+            set("k2", "v2"),
+        ];
+        let response = context.process_transaction(commands, vec![]).await;
+        assert_eq!(
+            String::from(response),
+            "*3\r\n+OK\r\n\
+             -WRONGTYPE Operation against a key holding the wrong kind of value\r\n\
+             +OK\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocking_commands_do_not_block_in_exec() {
+        let context = ServerContext::default();
+        let commands = vec![
+            Command::BLPop(vec!["empty".into()], Some(0)),
+            Command::XRead {
+                streams: vec![("stream".into(), "$".into())],
+                count: None,
+                block: Some(0),
+            },
+        ];
+        let response = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            context.process_transaction(commands, vec![]),
+        )
+        .await
+        .expect("transaction blocked");
+        assert_eq!(String::from(response), "*2\r\n*-1\r\n*-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_exec_propagates_single_block() {
+        let context = ServerContext::default();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        context.replicas.lock().await.add_channel(
+            "replica".into(),
+            std::sync::Arc::new(tokio::sync::Mutex::new(ReplicaState::new(tx))),
+        );
+
+        let commands = vec![set("a", "1"), Command::Get("a".into()), set("b", "2")];
+        context.process_transaction(commands, vec![]).await;
+        let expected = format!(
+            "*1\r\n$5\r\nMULTI\r\n{}{}*1\r\n$4\r\nEXEC\r\n",
+            encode_set("a", "1"),
+            encode_set("b", "2")
+        );
+        assert_eq!(rx.try_recv().unwrap(), expected.into_bytes());
+        assert!(rx.try_recv().is_err());
+
+        context
+            .process_transaction(vec![Command::Get("a".into())], vec![])
+            .await;
+        assert!(rx.try_recv().is_err());
+    }
+}